@@ -0,0 +1,37 @@
+use crate::actions::action::{Action, ActionFuture};
+use crate::actions::volume_functions::VolumeFunctions;
+use std::sync::Arc;
+
+pub struct ChangeVolumeAction<V>
+where
+    V: VolumeFunctions,
+{
+    control: String,
+    step: f32,
+    volume_functions: Arc<V>,
+}
+
+impl<V> ChangeVolumeAction<V>
+where
+    V: VolumeFunctions,
+{
+    pub fn new(volume_functions: Arc<V>, control: String, step: f32) -> ChangeVolumeAction<V> {
+        ChangeVolumeAction {
+            control,
+            step,
+            volume_functions,
+        }
+    }
+}
+
+impl<V> Action for ChangeVolumeAction<V>
+where
+    V: VolumeFunctions,
+{
+    fn run(&self) -> ActionFuture {
+        let res = self
+            .volume_functions
+            .change_volume(&self.control, self.step);
+        Box::pin(std::future::ready(res))
+    }
+}
@@ -0,0 +1,113 @@
+use crate::actions::action::{Action, ActionFuture};
+use crate::actions::tag_dispatcher::TagDispatcher;
+use crate::read_config::{SmtpConfig, SmtpSecurity};
+use crate::util::error::DynResult;
+use crate::util::template::expand_template;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{error, info};
+use std::sync::Arc;
+
+/// Sends an email with tag values inserted in the subject and body.
+/// The action doesn't wait for the email to be sent, failures are
+/// only logged.
+pub struct EmailAction<D>
+where
+    D: TagDispatcher + Send + Sync,
+{
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    subject: String,
+    body: String,
+    dispatcher: Arc<D>,
+}
+
+impl<D> EmailAction<D>
+where
+    D: TagDispatcher + Send + Sync,
+{
+    pub fn new(
+        smtp: &SmtpConfig,
+        to: &[String],
+        subject: String,
+        body: String,
+        dispatcher: Arc<D>,
+    ) -> DynResult<EmailAction<D>> {
+        let builder = match smtp.security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.server)?,
+            SmtpSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.server)?
+            }
+            SmtpSecurity::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.server)
+            }
+        };
+        let builder = match smtp.port {
+            Some(port) => builder.port(port),
+            None => builder,
+        };
+        let builder = match (&smtp.user, &smtp.password) {
+            (Some(user), Some(password)) => {
+                builder.credentials(Credentials::new(user.clone(), password.clone()))
+            }
+            _ => builder,
+        };
+        let to = to
+            .iter()
+            .map(|addr| addr.parse())
+            .collect::<Result<Vec<Mailbox>, _>>()?;
+        Ok(EmailAction {
+            mailer: builder.build(),
+            from: smtp.from.parse()?,
+            to,
+            subject,
+            body,
+            dispatcher,
+        })
+    }
+
+    fn message(&self) -> DynResult<Message> {
+        let lookup = |tag: &str| self.dispatcher.get_value(tag);
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(expand_template(&self.subject, lookup)?);
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        Ok(builder.body(expand_template(&self.body, lookup)?)?)
+    }
+}
+
+impl<D> Action for EmailAction<D>
+where
+    D: TagDispatcher + Send + Sync,
+{
+    fn run(&self) -> ActionFuture {
+        let message = self.message();
+        let mailer = self.mailer.clone();
+        Box::pin(async move {
+            let message = match message {
+                Ok(m) => m,
+                Err(e) => {
+                    error!("Failed to create email: {}", e);
+                    return Ok(());
+                }
+            };
+            tokio::spawn(async move {
+                let to: Vec<String> = message
+                    .envelope()
+                    .to()
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect();
+                match mailer.send(message).await {
+                    Ok(_) => info!("Sent email to {}", to.join(", ")),
+                    Err(e) => error!("Failed to send email to {}: {}", to.join(", "), e),
+                }
+            });
+            Ok(())
+        })
+    }
+}
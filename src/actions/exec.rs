@@ -0,0 +1,84 @@
+use crate::actions::action::{Action, ActionFuture};
+use crate::actions::tag_dispatcher::TagDispatcher;
+use crate::util::template::expand_template;
+use log::{debug, error, warn};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time;
+
+/// Runs an external program with tag values inserted in the arguments
+/// and waits for it to exit. The program is killed if it runs longer
+/// than the timeout or if the action is cancelled. Failures are logged
+/// but don't stop the state machine.
+pub struct ExecAction<D>
+where
+    D: TagDispatcher + Send + Sync,
+{
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+    dispatcher: Arc<D>,
+}
+
+impl<D> ExecAction<D>
+where
+    D: TagDispatcher + Send + Sync,
+{
+    pub fn new(
+        program: String,
+        args: Vec<String>,
+        timeout: Duration,
+        dispatcher: Arc<D>,
+    ) -> ExecAction<D> {
+        ExecAction {
+            program,
+            args,
+            timeout,
+            dispatcher,
+        }
+    }
+
+    fn command(&self) -> Result<Command, String> {
+        let lookup = |tag: &str| self.dispatcher.get_value(tag);
+        let mut cmd = Command::new(&self.program);
+        for arg in &self.args {
+            cmd.arg(expand_template(arg, lookup)?);
+        }
+        cmd.stdin(Stdio::null()).kill_on_drop(true);
+        Ok(cmd)
+    }
+}
+
+impl<D> Action for ExecAction<D>
+where
+    D: TagDispatcher + Send + Sync,
+{
+    fn run(&self) -> ActionFuture {
+        let cmd = self.command();
+        let program = self.program.clone();
+        let timeout = self.timeout;
+        Box::pin(async move {
+            let mut child = match cmd.and_then(|mut cmd| cmd.spawn().map_err(|e| e.to_string())) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("Failed to run {}: {}", program, e);
+                    return Ok(());
+                }
+            };
+            match time::timeout(timeout, child.wait()).await {
+                Ok(Ok(status)) if status.success() => debug!("{} finished", program),
+                Ok(Ok(status)) => warn!("{} failed: {}", program, status),
+                Ok(Err(e)) => error!("Failed to wait for {}: {}", program, e),
+                Err(_) => {
+                    error!("{} didn't finish within {:?}, killing it", program, timeout);
+                    if let Err(e) = child.kill().await {
+                        error!("Failed to kill {}: {}", program, e);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,59 @@
+use crate::actions::action::{Action, ActionFuture};
+use crate::actions::tag_dispatcher::TagDispatcher;
+use crate::expr::Expr;
+use log::warn;
+use std::sync::Arc;
+
+/// Runs one of two actions depending on an expression over tag values.
+/// The condition is false if it can't be evaluated.
+pub struct IfAction<D>
+where
+    D: TagDispatcher + Send,
+{
+    condition: Expr,
+    then: Arc<dyn Action + Send + Sync>,
+    otherwise: Option<Arc<dyn Action + Send + Sync>>,
+    dispatcher: Arc<D>,
+}
+
+impl<D> IfAction<D>
+where
+    D: TagDispatcher + Send,
+{
+    pub fn new(
+        condition: Expr,
+        then: Arc<dyn Action + Send + Sync>,
+        otherwise: Option<Arc<dyn Action + Send + Sync>>,
+        dispatcher: Arc<D>,
+    ) -> IfAction<D> {
+        IfAction {
+            condition,
+            then,
+            otherwise,
+            dispatcher,
+        }
+    }
+}
+
+impl<D> Action for IfAction<D>
+where
+    D: TagDispatcher + Send + Sync + 'static,
+{
+    fn run(&self) -> ActionFuture {
+        let lookup = |tag: &str| self.dispatcher.get_value(tag);
+        let cond = match self.condition.evaluate(&lookup) {
+            Ok(v) => v.as_bool(),
+            Err(e) => {
+                warn!("Condition {} is false: {}", self.condition, e);
+                false
+            }
+        };
+        if cond {
+            self.then.run()
+        } else if let Some(otherwise) = &self.otherwise {
+            otherwise.run()
+        } else {
+            Box::pin(async { Ok(()) })
+        }
+    }
+}
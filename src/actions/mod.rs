@@ -2,16 +2,27 @@ pub mod action;
 pub mod alarm_dispatcher;
 pub mod alarm_function;
 pub mod alarm_functions;
+pub mod change_volume;
 pub mod debug;
+#[cfg(feature = "email")]
+pub mod email;
+pub mod exec;
 pub mod goto;
+pub mod if_expr;
 pub mod parallel;
 pub mod play;
+pub mod plugin;
 pub mod repeat;
 pub mod sequence;
+pub mod set_balance;
+pub mod set_gpio;
 pub mod set_tag;
 pub mod set_volume;
 pub mod tag_dispatcher;
 pub mod tag_setter;
+pub mod volume_functions;
 pub mod wait;
 pub mod wait_alarm;
+pub mod wait_expr;
 pub mod wait_tag;
+pub mod wait_time;
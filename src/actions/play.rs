@@ -5,6 +5,9 @@ use std::sync::Arc;
 use tokio::time::Duration;
 
 pub struct PlayAction {
+    sound: String,
+    // The state playing the clip
+    source: String,
     priority: i32,
     clip_queue: Arc<ClipQueue>,
     timeout: Option<Duration>,
@@ -13,12 +16,16 @@ pub struct PlayAction {
 
 impl PlayAction {
     pub fn new(
+        sound: &str,
+        source: &str,
         clip_queue: Arc<ClipQueue>,
         priority: i32,
         timeout: Option<Duration>,
         samples: Arc<SampleBuffer>,
     ) -> PlayAction {
         PlayAction {
+            sound: sound.to_string(),
+            source: source.to_string(),
             priority,
             clip_queue,
             timeout,
@@ -30,11 +37,15 @@ impl PlayAction {
 impl Action for PlayAction {
     fn run(&self) -> ActionFuture {
         let clip_queue = self.clip_queue.clone();
+        let sound = self.sound.clone();
+        let source = self.source.clone();
         let samples = self.samples.clone();
         let priority = self.priority;
         let timeout = self.timeout;
         Box::pin(async move {
-            clip_queue.play(samples, priority, timeout).await?;
+            clip_queue
+                .play(&sound, &source, samples, priority, timeout)
+                .await?;
             Ok(())
         })
     }
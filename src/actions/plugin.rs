@@ -0,0 +1,105 @@
+//! Actions added by library users
+//!
+//! A plugin handles an element name that isn't a built-in action. The
+//! element is checked by the plugin when the configuration is read and
+//! turned into an [`Action`] when the state machines are set up.
+//! Plugins must be registered before the configuration is read.
+
+use crate::actions::action::Action;
+use crate::app_config::{AlarmContext, PlaybackContext, TagContext};
+use crate::read_config::{PluginElement, ACTION_ELEMENTS};
+use crate::util::error::DynResult;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+/// What a plugin action may use
+pub struct PluginContext<'a> {
+    pub tag_ctxt: &'a Arc<TagContext>,
+    pub alarm_ctxt: &'a Arc<AlarmContext>,
+    pub playback_ctxt: &'a PlaybackContext,
+    /// Name of the state machine the action belongs to
+    pub state_machine: &'a str,
+    pub state: &'a str,
+}
+
+pub trait ActionPlugin: Send + Sync {
+    /// Check the element when the configuration is read. Errors are
+    /// reported with the position of the element.
+    fn parse(&self, element: &PluginElement) -> DynResult<()>;
+
+    /// Create the action for an element that has been parsed
+    fn build(
+        &self,
+        element: &PluginElement,
+        ctxt: &PluginContext,
+    ) -> DynResult<Arc<dyn Action + Send + Sync>>;
+}
+
+static PLUGINS: RwLock<BTreeMap<String, Arc<dyn ActionPlugin>>> = RwLock::new(BTreeMap::new());
+
+/// Handle action elements named `element` with `plugin`. Built-in
+/// actions can't be replaced and each name can only be registered once.
+pub fn register_action(element: &str, plugin: Arc<dyn ActionPlugin>) -> DynResult<()> {
+    if ACTION_ELEMENTS.contains(&element) {
+        return Err(format!("'{}' is a built-in action", element).into());
+    }
+    let mut plugins = PLUGINS.write().unwrap_or_else(|e| e.into_inner());
+    if plugins.contains_key(element) {
+        return Err(format!("Action '{}' is already registered", element).into());
+    }
+    plugins.insert(element.to_string(), plugin);
+    Ok(())
+}
+
+/// The plugin handling `element`, if any
+pub fn find_action(element: &str) -> Option<Arc<dyn ActionPlugin>> {
+    let plugins = PLUGINS.read().unwrap_or_else(|e| e.into_inner());
+    plugins.get(element).cloned()
+}
+
+#[test]
+fn test_register_action() {
+    use crate::read_config::{read_str, ActionType};
+
+    struct Blink;
+    impl ActionPlugin for Blink {
+        fn parse(&self, element: &PluginElement) -> DynResult<()> {
+            match element.attributes.get("lamp") {
+                Some(_) => Ok(()),
+                None => Err("No lamp".into()),
+            }
+        }
+
+        fn build(
+            &self,
+            _element: &PluginElement,
+            _ctxt: &PluginContext,
+        ) -> DynResult<Arc<dyn Action + Send + Sync>> {
+            Err("Not used".into())
+        }
+    }
+    register_action("test_blink", Arc::new(Blink)).unwrap();
+    assert!(register_action("test_blink", Arc::new(Blink)).is_err());
+    assert!(register_action("play", Arc::new(Blink)).is_err());
+
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="lamps">
+    <state id="on"><test_blink lamp="red">3</test_blink></state>
+  </state_machine>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    match &conf.state_machines[0].states[0].action {
+        ActionType::Plugin(element) => {
+            assert_eq!(element.name, "test_blink");
+            assert_eq!(element.attributes["lamp"], "red");
+            assert_eq!(element.text, "3");
+        }
+        _ => panic!("Not a plugin action"),
+    }
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="lamps">
+    <state id="on"><test_blink/></state>
+  </state_machine>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
@@ -1,15 +1,26 @@
 use crate::actions::action::{Action, ActionFuture};
+use crate::util::event_limit::EventLimit;
 use std::num::NonZeroU32;
 use std::sync::Arc;
 
 pub struct RepeatAction {
     action: Arc<dyn Action + Send + Sync>,
     count: Option<NonZeroU32>,
+    // Fails the action if it repeats too fast
+    event_limit: EventLimit,
 }
 
 impl RepeatAction {
-    pub fn new(action: Arc<dyn Action + Send + Sync>, count: Option<NonZeroU32>) -> RepeatAction {
-        RepeatAction { action, count }
+    pub fn new(
+        action: Arc<dyn Action + Send + Sync>,
+        count: Option<NonZeroU32>,
+        event_limit: EventLimit,
+    ) -> RepeatAction {
+        RepeatAction {
+            action,
+            count,
+            event_limit,
+        }
     }
 }
 
@@ -17,13 +28,16 @@ impl Action for RepeatAction {
     fn run(&self) -> ActionFuture {
         let action = self.action.clone();
         let count = self.count;
+        let event_limit = self.event_limit.clone();
         Box::pin(async move {
             if let Some(count) = count {
                 for _ in 0..u32::from(count) {
+                    event_limit.count()?;
                     action.run().await?;
                 }
             } else {
                 loop {
+                    event_limit.count()?;
                     action.run().await?;
                 }
             }
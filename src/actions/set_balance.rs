@@ -0,0 +1,37 @@
+use crate::actions::action::{Action, ActionFuture};
+use crate::actions::volume_functions::VolumeFunctions;
+use std::sync::Arc;
+
+pub struct SetBalanceAction<V>
+where
+    V: VolumeFunctions,
+{
+    control: String,
+    levels: Vec<f32>,
+    volume_functions: Arc<V>,
+}
+
+impl<V> SetBalanceAction<V>
+where
+    V: VolumeFunctions,
+{
+    pub fn new(volume_functions: Arc<V>, control: String, levels: Vec<f32>) -> SetBalanceAction<V> {
+        SetBalanceAction {
+            control,
+            levels,
+            volume_functions,
+        }
+    }
+}
+
+impl<V> Action for SetBalanceAction<V>
+where
+    V: VolumeFunctions,
+{
+    fn run(&self) -> ActionFuture {
+        let res = self
+            .volume_functions
+            .set_balance(&self.control, &self.levels);
+        Box::pin(std::future::ready(res))
+    }
+}
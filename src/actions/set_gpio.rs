@@ -0,0 +1,31 @@
+use crate::actions::action::{Action, ActionFuture};
+use crate::gpio_output::GpioOutputs;
+use log::error;
+use std::sync::Arc;
+
+/// Turns a GPIO output on or off. Failures are logged but don't stop
+/// the state machine.
+pub struct SetGpioAction {
+    outputs: Arc<GpioOutputs>,
+    pin: String,
+    value: bool,
+}
+
+impl SetGpioAction {
+    pub fn new(outputs: Arc<GpioOutputs>, pin: String, value: bool) -> SetGpioAction {
+        SetGpioAction {
+            outputs,
+            pin,
+            value,
+        }
+    }
+}
+
+impl Action for SetGpioAction {
+    fn run(&self) -> ActionFuture {
+        if let Err(e) = self.outputs.set(&self.pin, self.value) {
+            error!("Failed to set GPIO output {}: {}", self.pin, e);
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
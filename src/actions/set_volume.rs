@@ -1,7 +1,13 @@
 use crate::actions::action::{Action, ActionFuture};
 use crate::actions::tag_dispatcher::TagDispatcher;
-use crate::volume_control::VolumeControl;
-use std::sync::{Arc, Mutex};
+use crate::actions::volume_functions::VolumeFunctions;
+use crate::util::error::DynResult;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+// Time between volume changes when ramping
+const RAMP_STEP: Duration = Duration::from_millis(20);
 
 #[derive(Clone, Debug)]
 enum TagOrConst<D>
@@ -15,50 +21,100 @@ where
     Const(f32),
 }
 
-pub struct SetVolumeAction<D>
+pub struct SetVolumeAction<D, V>
 where
     D: TagDispatcher + Send,
+    V: VolumeFunctions,
 {
     value: TagOrConst<D>,
-    control: Arc<Mutex<VolumeControl>>,
+    control: String,
+    volume_functions: Arc<V>,
+    // Change the volume gradually over this time
+    ramp: Option<Duration>,
 }
-impl<D> SetVolumeAction<D>
+impl<D, V> SetVolumeAction<D, V>
 where
     D: TagDispatcher + Send,
+    V: VolumeFunctions,
 {
-    pub fn new_const(control: Arc<Mutex<VolumeControl>>, value: f32) -> SetVolumeAction<D> {
+    pub fn new_const(
+        volume_functions: Arc<V>,
+        control: String,
+        value: f32,
+        ramp: Option<Duration>,
+    ) -> SetVolumeAction<D, V> {
         SetVolumeAction {
             control,
+            volume_functions,
             value: TagOrConst::Const(value),
+            ramp,
         }
     }
     pub fn new_tag(
-        control: Arc<Mutex<VolumeControl>>,
+        volume_functions: Arc<V>,
+        control: String,
         tag_name: String,
         dispatcher: Arc<D>,
-    ) -> SetVolumeAction<D> {
+        ramp: Option<Duration>,
+    ) -> SetVolumeAction<D, V> {
         SetVolumeAction {
             control,
+            volume_functions,
             value: TagOrConst::Tag {
                 tag_name,
                 dispatcher,
             },
+            ramp,
         }
     }
 }
 
-impl<D> Action for SetVolumeAction<D>
+// Volume after step of steps when going from start to target
+fn ramp_level(start: f32, target: f32, step: u32, steps: u32) -> f32 {
+    start + (target - start) * step as f32 / steps as f32
+}
+
+async fn set_volume<V>(
+    volume_functions: &V,
+    control: &str,
+    volume: f32,
+    ramp: Option<Duration>,
+) -> DynResult<()>
+where
+    V: VolumeFunctions,
+{
+    let ramp = match ramp {
+        Some(ramp) if !ramp.is_zero() => ramp,
+        _ => return volume_functions.set_volume(control, volume),
+    };
+    let start = volume_functions.get_volume(control)?;
+    let steps = ramp.as_nanos().div_ceil(RAMP_STEP.as_nanos()) as u32;
+    let mut interval = time::interval(ramp / steps);
+    // The first tick is immediate
+    interval.tick().await;
+    for step in 1..steps {
+        interval.tick().await;
+        volume_functions.set_volume_unpublished(control, ramp_level(start, volume, step, steps))?;
+    }
+    // Only the final level is published
+    interval.tick().await;
+    volume_functions.set_volume(control, volume)
+}
+
+impl<D, V> Action for SetVolumeAction<D, V>
 where
     D: TagDispatcher + Send + Sync + 'static,
+    V: VolumeFunctions + Send + Sync + 'static,
 {
     fn run(&self) -> ActionFuture {
+        let volume_functions = self.volume_functions.clone();
         let control = self.control.clone();
+        let ramp = self.ramp;
         match &self.value {
             TagOrConst::Const(volume) => {
                 let volume = *volume;
                 Box::pin(async move {
-                    let control = control.lock().unwrap();
-                    control.set_volume(volume)?;
+                    set_volume(&*volume_functions, &control, volume, ramp).await?;
                     Ok(())
                 })
             }
@@ -71,8 +127,7 @@ where
                 Box::pin(async move {
                     if let Some(vstr) = dispatcher.get_value(&tag_name) {
                         if let Ok(volume) = str::parse(&vstr) {
-                            let control = control.lock().unwrap();
-                            control.set_volume(volume)?;
+                            set_volume(&*volume_functions, &control, volume, ramp).await?;
                         }
                     }
                     Ok(())
@@ -81,3 +136,46 @@ where
         }
     }
 }
+
+#[tokio::test(start_paused = true)]
+async fn test_ramp() {
+    use std::sync::Mutex;
+
+    // Levels set and levels published
+    struct Recorder(Mutex<Vec<f32>>, Mutex<Vec<f32>>);
+    impl VolumeFunctions for Recorder {
+        fn set_volume(&self, _control: &str, volume: f32) -> DynResult<()> {
+            self.0.lock().unwrap().push(volume);
+            self.1.lock().unwrap().push(volume);
+            Ok(())
+        }
+        fn set_volume_unpublished(&self, _control: &str, volume: f32) -> DynResult<()> {
+            self.0.lock().unwrap().push(volume);
+            Ok(())
+        }
+        fn get_volume(&self, _control: &str) -> DynResult<f32> {
+            Ok(self.0.lock().unwrap().last().copied().unwrap_or(0.0))
+        }
+        fn change_volume(&self, _control: &str, _step: f32) -> DynResult<()> {
+            Ok(())
+        }
+        fn set_balance(&self, _control: &str, _levels: &[f32]) -> DynResult<()> {
+            Ok(())
+        }
+    }
+
+    let recorder = Recorder(Mutex::new(vec![0.2]), Mutex::new(Vec::new()));
+    let start = time::Instant::now();
+    set_volume(&recorder, "main", 0.6, Some(Duration::from_millis(100)))
+        .await
+        .unwrap();
+    assert_eq!(start.elapsed(), Duration::from_millis(100));
+    let levels = recorder.0.lock().unwrap().clone();
+    assert_eq!(levels.len(), 6);
+    assert!(levels.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(levels[5], 0.6);
+    assert_eq!(*recorder.1.lock().unwrap(), [0.6]);
+
+    set_volume(&recorder, "main", 0.1, None).await.unwrap();
+    assert_eq!(recorder.0.lock().unwrap().last(), Some(&0.1));
+}
@@ -1,5 +1,6 @@
 use std::future::Future;
 use std::pin::Pin;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum Error {
@@ -27,4 +28,11 @@ pub trait TagDispatcher {
 
     /// Get the current value of a tag. None is returned if the value is unknown
     fn get_value(&self, tag: &str) -> Option<String>;
+
+    /// Get the value a tag had some time ago. None is returned if
+    /// the value is unknown or the history doesn't go back that far.
+    fn get_value_ago(&self, tag: &str, age: Duration) -> Option<String>;
+
+    /// Number of times the tag has changed during the most recent period
+    fn changes_within(&self, tag: &str, period: Duration) -> usize;
 }
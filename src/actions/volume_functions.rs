@@ -0,0 +1,21 @@
+use crate::util::error::DynResult;
+
+pub trait VolumeFunctions {
+    /// Set the volume of a control. The volume is in the range 0.0 to 1.0.
+    fn set_volume(&self, control: &str, volume: f32) -> DynResult<()>;
+
+    /// Like set_volume, but the new level isn't published. Used for
+    /// the steps of a ramp that will be followed by a set_volume.
+    fn set_volume_unpublished(&self, control: &str, volume: f32) -> DynResult<()>;
+
+    /// Get the current volume of a control.
+    fn get_volume(&self, control: &str) -> DynResult<f32>;
+
+    /// Add step to the current volume of a control. The result is
+    /// limited to the range 0.0 to 1.0.
+    fn change_volume(&self, control: &str, step: f32) -> DynResult<()>;
+
+    /// Set the relative level of each channel, starting with left
+    /// and right. The loudest channel plays at the current volume.
+    fn set_balance(&self, control: &str, levels: &[f32]) -> DynResult<()>;
+}
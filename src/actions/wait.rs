@@ -21,3 +21,14 @@ impl Action for WaitAction {
         })
     }
 }
+
+#[tokio::test(start_paused = true)]
+async fn test_wait() {
+    let start = time::Instant::now();
+    WaitAction::new(Duration::from_secs(3600))
+        .run()
+        .await
+        .unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(3600) && elapsed < Duration::from_secs(3601));
+}
@@ -0,0 +1,62 @@
+use crate::actions::action::{Action, ActionFuture};
+use crate::actions::tag_dispatcher::TagDispatcher;
+use crate::expr::Expr;
+use futures::future;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Waits until an expression over tag values is true. The expression
+/// is evaluated again whenever one of its tags changes. Unknown tag
+/// values make the expression false.
+pub struct WaitExprAction<D>
+where
+    D: TagDispatcher + Send,
+{
+    expr: Expr,
+    dispatcher: Arc<D>,
+}
+
+impl<D> WaitExprAction<D>
+where
+    D: TagDispatcher + Send,
+{
+    pub fn new(expr: Expr, dispatcher: Arc<D>) -> WaitExprAction<D> {
+        WaitExprAction { expr, dispatcher }
+    }
+}
+
+impl<D> Action for WaitExprAction<D>
+where
+    D: TagDispatcher + Send + Sync + 'static,
+{
+    fn run(&self) -> ActionFuture {
+        let expr = self.expr.clone();
+        let dispatcher = self.dispatcher.clone();
+        let tags: Vec<String> = expr.tags().into_iter().collect();
+        Box::pin(async move {
+            loop {
+                // Start waiting before evaluating so no change is missed
+                let mut values = HashMap::new();
+                let mut waits = Vec::new();
+                for tag in &tags {
+                    let (value, wait) = dispatcher.wait_value(tag)?;
+                    if let Some(value) = value {
+                        values.insert(tag.as_str(), value);
+                    }
+                    waits.push(wait);
+                }
+                match expr.evaluate(&|tag: &str| values.get(tag).cloned()) {
+                    Ok(v) if v.as_bool() => return Ok(()),
+                    Ok(_) => {}
+                    Err(e) => debug!("Waiting for {}: {}", expr, e),
+                }
+                if waits.is_empty() {
+                    // No tags, so the value never changes
+                    future::pending::<()>().await;
+                }
+                future::select_all(waits).await.0?;
+            }
+        })
+    }
+}
@@ -2,6 +2,7 @@ use crate::actions::action::{Action, ActionFuture};
 use crate::actions::tag_dispatcher::TagDispatcher;
 use std::num::ParseFloatError;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum TagCondition {
@@ -14,6 +15,12 @@ pub enum TagCondition {
     EqualString(String),
     NotEqualString(String),
     Changed,
+    // The tag has changed at least count times within the period
+    ChangeCount { count: u32, period: Duration },
+    // The value has increased by at least delta compared to period ago
+    Rise { delta: f64, period: Duration },
+    // The value has decreased by at least delta compared to period ago
+    Fall { delta: f64, period: Duration },
 }
 
 /// Parse float and map true and false to 1 and 0 respectively
@@ -39,6 +46,42 @@ impl TagCondition {
             EqualString(cmp) => new_tag == cmp,
             NotEqualString(cmp) => new_tag != cmp,
             Changed => old_tag.map_or(false, |ref v| &new_tag != v),
+            // These need the tag history, see check_history
+            ChangeCount { .. } | Rise { .. } | Fall { .. } => false,
+        }
+    }
+
+    /// Like check but also handles conditions that depend on the
+    /// history of the tag
+    pub fn check_history<D>(
+        &self,
+        tag: &str,
+        new_tag: &str,
+        old_tag: Option<&String>,
+        dispatcher: &D,
+    ) -> bool
+    where
+        D: TagDispatcher + ?Sized,
+    {
+        use TagCondition::*;
+        let value_ago = |period: &Duration| {
+            dispatcher
+                .get_value_ago(tag, *period)
+                .and_then(|v| parse_number(&v).ok())
+        };
+        match self {
+            ChangeCount { count, period } => {
+                dispatcher.changes_within(tag, *period) >= *count as usize
+            }
+            Rise { delta, period } => match (parse_number(new_tag), value_ago(period)) {
+                (Ok(new), Some(old)) => new - old >= *delta,
+                _ => false,
+            },
+            Fall { delta, period } => match (parse_number(new_tag), value_ago(period)) {
+                (Ok(new), Some(old)) => old - new >= *delta,
+                _ => false,
+            },
+            _ => self.check(new_tag, old_tag),
         }
     }
 }
@@ -77,13 +120,13 @@ where
             loop {
                 let (value, wait) = dispatcher.wait_value(&tag)?;
                 if let Some(value) = value.as_ref() {
-                    if cond.check(value, prev.as_ref()) {
+                    if cond.check_history(&tag, value, prev.as_ref(), dispatcher.as_ref()) {
                         return Ok(());
                     }
                 }
                 prev = value;
                 let value = wait.await?;
-                if cond.check(&value, prev.as_ref()) {
+                if cond.check_history(&tag, &value, prev.as_ref(), dispatcher.as_ref()) {
                     return Ok(());
                 }
                 prev = Some(value);
@@ -0,0 +1,46 @@
+use crate::actions::action::{Action, ActionFuture};
+use crate::util::schedule::Schedule;
+use chrono::{Local, LocalResult, TimeZone};
+use tokio::time::{self, Duration};
+
+// Longest sleep before checking the clock again, in case it's adjusted
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Waits until the next time in a schedule, in local time
+pub struct WaitTimeAction {
+    schedule: Schedule,
+}
+
+impl WaitTimeAction {
+    pub fn new(schedule: Schedule) -> WaitTimeAction {
+        WaitTimeAction { schedule }
+    }
+}
+
+impl Action for WaitTimeAction {
+    fn run(&self) -> ActionFuture {
+        let schedule = self.schedule.clone();
+        Box::pin(async move {
+            let mut next = Local::now().naive_local();
+            let target = loop {
+                next = match schedule.next_after(next) {
+                    Some(next) => next,
+                    None => return Err(format!("'{}' never happens", schedule).into()),
+                };
+                // Times skipped when changing to daylight saving time are
+                // not used
+                match Local.from_local_datetime(&next) {
+                    LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => break t,
+                    LocalResult::None => {}
+                }
+            };
+            loop {
+                let left = match (target - Local::now()).to_std() {
+                    Ok(left) if !left.is_zero() => left,
+                    _ => return Ok(()),
+                };
+                time::sleep(left.min(MAX_SLEEP)).await;
+            }
+        })
+    }
+}
@@ -654,21 +654,13 @@ fn test_filter_parser_failure() {
 
 #[test]
 fn test_filter_evaluate() {
-    let alarm_data = AlarmData {
-        name: "Foo".to_string(),
-        id: 0,
-        alarm_class_name: "Warning".to_string(),
-        alarm_class_symbol: "W".to_string(),
-        event_text: "This is a warning".to_string(),
-        instance_id: 52,
-        priority: 7,
-        state: 1,
-        state_text: "Incoming".to_string(),
-        state_machine: 7,
-        modification_time: chrono::DateTime::parse_from_rfc3339("1988-12-09T19:23:02Z")
-            .unwrap()
-            .with_timezone(&chrono::Utc),
-    };
+    let alarm_data = crate::testing::AlarmBuilder::new(0)
+        .name("Foo")
+        .class("Warning")
+        .event_text("This is a warning")
+        .instance_id(52)
+        .priority(7)
+        .build();
 
     let filter_text = "Name='Foo' AND ID=0 AND InstanceID=52 AND AlarmClassName ='Warning' AND Priority=7 AND State=1";
     let filter = parse_filter(filter_text).unwrap();
@@ -1,30 +1,68 @@
+use crate::read_config::VolumeScale;
 use crate::util::error::DynResult;
-use alsa::mixer::{Mixer, Selem, SelemId};
+use crate::util::volume_mapping;
+use alsa::mixer::{MilliBel, Mixer, Selem, SelemChannelId, SelemId};
+use alsa::Round;
 use log::info;
 
 pub struct VolumeControl {
     mixer: Mixer,
     selem_id: SelemId,
+    scale: VolumeScale,
 }
 
 impl VolumeControl {
-    pub fn new(device: &str) -> DynResult<VolumeControl> {
+    /// Use the mixer element with the given name and index, or the
+    /// first one with a playback volume if None.
+    pub fn new(
+        device: &str,
+        element: Option<(&str, u32)>,
+        scale: VolumeScale,
+    ) -> DynResult<VolumeControl> {
         let mixer = Mixer::new(device, false)?;
-        let mut found_selem = None;
-        for elem in mixer.iter() {
-            if let Some(selem) = Selem::new(elem) {
-                if selem.has_playback_volume() {
-                    found_selem = Some(selem);
-                    break;
+        let selem_id = match element {
+            Some((name, index)) => {
+                let selem_id = SelemId::new(name, index);
+                match mixer.find_selem(&selem_id) {
+                    Some(selem) if selem.has_playback_volume() => {}
+                    Some(_) => {
+                        return Err(format!(
+                            "Mixer element '{}',{} on {} has no playback volume",
+                            name, index, device
+                        )
+                        .into())
+                    }
+                    None => {
+                        return Err(format!(
+                            "No mixer element '{}',{} on {}. Available elements: {}",
+                            name,
+                            index,
+                            device,
+                            Self::element_names(&mixer).join(" ")
+                        )
+                        .into())
+                    }
                 }
+                selem_id
+            }
+            None => {
+                let mut found_selem = None;
+                for elem in mixer.iter() {
+                    if let Some(selem) = Selem::new(elem) {
+                        if selem.has_playback_volume() {
+                            found_selem = Some(selem);
+                            break;
+                        }
+                    }
+                }
+                let selem = if let Some(selem) = found_selem {
+                    selem
+                } else {
+                    return Err(format!("No playback volume control found for {}", device).into());
+                };
+                selem.get_id()
             }
-        }
-        let selem = if let Some(selem) = found_selem {
-            selem
-        } else {
-            return Err(format!("No playback volume control found for {}", device).into());
         };
-        let selem_id = selem.get_id();
 
         info!(
             "Using {} on {} as volume control",
@@ -32,17 +70,106 @@ impl VolumeControl {
             device
         );
 
-        Ok(VolumeControl { mixer, selem_id })
+        Ok(VolumeControl {
+            mixer,
+            selem_id,
+            scale,
+        })
+    }
+
+    // Names of all elements with a playback volume
+    fn element_names(mixer: &Mixer) -> Vec<String> {
+        let mut names = Vec::new();
+        for elem in mixer.iter() {
+            if let Some(selem) = Selem::new(elem) {
+                if selem.has_playback_volume() {
+                    let id = selem.get_id();
+                    if let Ok(name) = id.get_name() {
+                        names.push(format!("'{}',{}", name, id.get_index()));
+                    }
+                }
+            }
+        }
+        names
+    }
+
+    /// Update cached values with changes made by other programs
+    pub fn refresh(&self) -> DynResult<()> {
+        self.mixer.handle_events()?;
+        Ok(())
+    }
+
+    fn selem(&self) -> DynResult<Selem<'_>> {
+        match self.mixer.find_selem(&self.selem_id) {
+            Some(s) => Ok(s),
+            None => Err("Selem not found".into()),
+        }
     }
 
     pub fn set_volume(&self, volume: f32) -> DynResult<()> {
-        let selem = match self.mixer.find_selem(&self.selem_id) {
-            Some(s) => s,
-            None => return Err("Selem not found".into()),
-        };
+        let selem = self.selem()?;
+        if self.scale == VolumeScale::Db {
+            let (min, max) = selem.get_playback_db_range();
+            let db = volume_mapping::volume_to_db(volume, min.to_db() as f64, max.to_db() as f64);
+            selem.set_playback_db_all(MilliBel::from_db(db as f32), Round::Floor)?;
+            return Ok(());
+        }
         let (min, max) = selem.get_playback_volume_range();
         selem
             .set_playback_volume_all(min + ((max - min) as f32 * volume.clamp(0.0, 1.0)) as i64)?;
         Ok(())
     }
+
+    /// Set the volume of a single channel. Channels are numbered in
+    /// ALSA order, starting with front left and front right. Channels
+    /// the mixer element doesn't have are ignored.
+    pub fn set_channel_volume(&self, channel: usize, volume: f32) -> DynResult<()> {
+        let selem = self.selem()?;
+        let channel = match SelemChannelId::all().get(channel) {
+            Some(c) if selem.has_playback_channel(*c) => *c,
+            _ => return Ok(()),
+        };
+        if self.scale == VolumeScale::Db {
+            let (min, max) = selem.get_playback_db_range();
+            let db = volume_mapping::volume_to_db(volume, min.to_db() as f64, max.to_db() as f64);
+            selem.set_playback_db(channel, MilliBel::from_db(db as f32), Round::Floor)?;
+            return Ok(());
+        }
+        let (min, max) = selem.get_playback_volume_range();
+        selem.set_playback_volume(
+            channel,
+            min + ((max - min) as f32 * volume.clamp(0.0, 1.0)) as i64,
+        )?;
+        Ok(())
+    }
+
+    /// The volume of the loudest channel
+    pub fn get_volume(&self) -> DynResult<f32> {
+        let selem = self.selem()?;
+        let mut volume: f32 = 0.0;
+        for channel in SelemChannelId::all() {
+            if selem.has_playback_channel(*channel) {
+                volume = volume.max(self.get_channel_volume(&selem, *channel)?);
+            }
+        }
+        Ok(volume)
+    }
+
+    fn get_channel_volume(&self, selem: &Selem, channel: SelemChannelId) -> DynResult<f32> {
+        if self.scale == VolumeScale::Db {
+            let (min, max) = selem.get_playback_db_range();
+            let db = selem.get_playback_vol_db(channel)?;
+            return Ok(volume_mapping::db_to_volume(
+                db.to_db() as f64,
+                min.to_db() as f64,
+                max.to_db() as f64,
+            ));
+        }
+        let (min, max) = selem.get_playback_volume_range();
+        if max <= min {
+            return Ok(0.0);
+        }
+        let volume = selem.get_playback_volume(channel)?;
+        Ok((volume - min) as f32 / (max - min) as f32)
+    }
 }
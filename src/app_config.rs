@@ -1,225 +1,432 @@
 use crate::actions::action::Action;
+#[cfg(feature = "email")]
+use crate::actions::email::EmailAction;
 use crate::actions::{
     alarm_dispatcher::{self, AlarmDispatched, AlarmDispatcher},
     alarm_function::AlarmFunctionAction,
     alarm_function::AlarmOp,
     alarm_functions::AlarmFunctions,
+    change_volume::ChangeVolumeAction,
     debug::DebugAction,
+    exec::ExecAction,
     goto::GotoAction,
+    if_expr::IfAction,
     parallel::ParallelAction,
     play::PlayAction,
+    plugin::{self, PluginContext},
     repeat::RepeatAction,
     sequence::SequenceAction,
+    set_balance::SetBalanceAction,
+    set_gpio::SetGpioAction,
     set_tag::SetTagAction,
     set_volume::SetVolumeAction,
     tag_dispatcher::{self, TagDispatched, TagDispatcher},
     tag_setter::{TagSetFuture, TagSetter},
+    volume_functions::VolumeFunctions,
     wait::WaitAction,
     wait_alarm::WaitAlarmAction,
+    wait_expr::WaitExprAction,
     wait_tag::WaitTagAction,
+    wait_time::WaitTimeAction,
 };
 use crate::alarm_filter::BoolOp as AlarmBoolOp;
+use crate::audio_file;
+use crate::audit_log::{AuditEvent, AuditLog};
 use crate::clip_queue::ClipQueue;
+use crate::expr::Expr;
+use crate::gpio_output::GpioOutputs;
+use crate::metrics;
 use crate::open_pipe::alarm_data::AlarmData;
 use crate::open_pipe::alarm_data::AlarmId;
 use crate::read_config::ActionType;
+use crate::read_config::EventLimitConfig;
+use crate::read_config::SmtpConfig;
 use crate::read_config::TagOrConst;
-use crate::sample_buffer::{Sample as BufferSample, SampleBuffer};
+use crate::read_config::TagSourceConfig;
+use crate::sample_buffer::{Sample as BufferSample, SampleBuffer, SampleFormat};
+use crate::sample_stream::StreamSource;
 use crate::state_machine::StateMachine;
+use crate::tag_source;
 use crate::util::error::DynResult;
+use crate::util::event_limit::{EventLimit, EventLimitStats};
+use crate::util::glob;
+use crate::util::persist_writer::PersistWriter;
+use crate::util::volume_mapping;
 use crate::volume_control::VolumeControl;
 use crate::{
-    clip_player::ClipPlayer,
-    read_config::{ClipType, PlayerConfig},
+    clip_player::{ClipPlayer, SoftwareGain},
+    read_config::{
+        ClipDirConfig, ClipProfile, ClipType, PlaybackDeviceConfig, PlayerConfig, ResamplerQuality,
+        VolumeScale, VolumeSchedule,
+    },
 };
-use cpal::SampleFormat;
-use log::{debug, error};
+use chrono::NaiveTime;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use simple_samplerate::{sample::Sample, samplerate::Samplerate};
-use std::collections::{HashMap, HashSet};
-use std::io::Read;
-use std::path::Path;
-use std::sync::{Arc, Mutex, Weak};
-use tokio::sync::mpsc::UnboundedSender;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
 use tokio::sync::oneshot;
 use tokio::sync::watch;
-use tokio::time::{timeout, Duration};
+use tokio::time::{timeout, Duration, Instant};
 
 const BLOCK_SIZE: usize = 1024;
 
-fn convert_samples<S, R>(
-    reader: &mut hound::WavReader<R>,
-    file_name: &Path,
-    from_rate: u32,
-    to_rate: u32,
-    channels: usize,
-    amplitude: f32,
-) -> DynResult<Vec<S>>
+// Number of values remembered for each tag
+const TAG_HISTORY_LENGTH: usize = 32;
+
+// Remove frames where all channels are below threshold from the start and end
+fn trim_silence(samples: &mut Vec<f32>, channels: usize, threshold: f32) {
+    let loud = |frame: &[f32]| frame.iter().any(|s| s.abs() > threshold);
+    let frames: Vec<&[f32]> = samples.chunks(channels).collect();
+    let start = frames.iter().position(|f| loud(f)).unwrap_or(frames.len());
+    let end = frames
+        .iter()
+        .rposition(|f| loud(f))
+        .map_or(start, |e| e + 1);
+    samples.truncate(end * channels);
+    samples.drain(..start * channels);
+}
+
+// Scale the samples so that the peak is at level dBFS
+fn normalize_peak(samples: &mut [f32], level: f32) {
+    let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    if peak > 0.0 {
+        let gain = 10f32.powf(level / 20.0) / peak;
+        for s in samples {
+            *s *= gain;
+        }
+    }
+}
+
+fn resample_high<S>(input: &[f32], from_rate: u32, to_rate: u32, channels: usize) -> Vec<S>
 where
-    R: Read,
     S: Clone + BufferSample + Sample,
 {
     let mut conv = Samplerate::new(from_rate, to_rate, channels).unwrap();
-    let mut input = Vec::<f32>::new();
     let mut out_buffer: Vec<S> = Vec::new();
     let out_block_size = BLOCK_SIZE * to_rate as usize / from_rate as usize + 8 * channels;
-    let scale = amplitude / 32767.0;
-    for s in reader.samples::<i16>() {
-        match s {
-            Ok(s) => {
-                let s = f32::from(s) * scale;
-                input.push(s);
-                if input.len() >= BLOCK_SIZE {
-                    //println!("Writing block");
-                    let start = out_buffer.len();
-                    out_buffer.resize(out_block_size + start, S::SAMPLE_OFFSET);
-                    let count = conv.process_buffer(&input, &mut out_buffer[start..]);
-                    out_buffer.truncate(count + start);
-                    input.clear();
-                }
-            }
-            Err(err) => {
-                return Err(format!(
-                    "Failed to read samples from file \"{}\": {}",
-                    file_name.to_string_lossy(),
-                    err
-                )
-                .into())
-            }
+    for block in input.chunks(BLOCK_SIZE) {
+        let start = out_buffer.len();
+        out_buffer.resize(out_block_size + start, S::SAMPLE_OFFSET);
+        let count = conv.process_buffer(block, &mut out_buffer[start..]);
+        out_buffer.truncate(count + start);
+    }
+    out_buffer
+}
+
+// Sample rate conversion using linear interpolation
+fn resample_fast<S: BufferSample>(
+    input: &[f32],
+    from_rate: u32,
+    to_rate: u32,
+    channels: usize,
+) -> Vec<S> {
+    let in_frames = input.len() / channels;
+    if in_frames == 0 {
+        return Vec::new();
+    }
+    let out_frames = (in_frames as u64 * u64::from(to_rate) / u64::from(from_rate)) as usize;
+    let step = f64::from(from_rate) / f64::from(to_rate);
+    let mut out_buffer = Vec::with_capacity(out_frames * channels);
+    for i in 0..out_frames {
+        let pos = i as f64 * step;
+        let i0 = (pos as usize).min(in_frames - 1);
+        let i1 = (i0 + 1).min(in_frames - 1);
+        let frac = (pos - i0 as f64) as f32;
+        for c in 0..channels {
+            let a = input[i0 * channels + c];
+            let b = input[i1 * channels + c];
+            out_buffer.push(S::from_f32(a + (b - a) * frac));
         }
     }
-    Ok(out_buffer)
+    out_buffer
 }
 
-fn load_clip(
+fn convert_samples<S>(
+    input: &[f32],
+    from_rate: u32,
+    to_rate: u32,
+    channels: usize,
+    quality: ResamplerQuality,
+) -> Vec<S>
+where
+    S: Clone + BufferSample + Sample,
+{
+    match quality {
+        ResamplerQuality::High => resample_high(input, from_rate, to_rate, channels),
+        ResamplerQuality::Fast => resample_fast(input, from_rate, to_rate, channels),
+    }
+}
+
+// Check the file now, it's read when played
+fn stream_clip(
     os_file: &Path,
-    sample_format: SampleFormat,
     sample_rate: u32,
     channels: usize,
     amplitude: f32,
+    profile: &ClipProfile,
 ) -> DynResult<Arc<SampleBuffer>> {
-    let mut reader = hound::WavReader::open(os_file)
-        .map_err::<Box<dyn std::error::Error + Send + Sync>, _>(|err| {
+    let spec = hound::WavReader::open(os_file)
+        .map_err(|e| {
             format!(
-                "Failed to open audio file \"{}\": {}",
+                "Failed to stream audio file \"{}\": {}",
                 os_file.to_string_lossy(),
-                err
+                e
             )
-            .into()
-        })?;
-    let spec = reader.spec();
+        })?
+        .spec();
+    if usize::from(spec.channels) != channels {
+        return Err(format!(
+            "Audio file \"{}\" has {} channels, {} needed for streaming",
+            os_file.to_string_lossy(),
+            spec.channels,
+            channels
+        )
+        .into());
+    }
+    Ok(Arc::new(SampleBuffer::Stream(StreamSource {
+        path: os_file.to_path_buf(),
+        rate: sample_rate,
+        channels,
+        amplitude,
+        resampler: profile.resampler,
+    })))
+}
+
+fn load_clip(
+    os_file: &Path,
+    sample_format: SampleFormat,
+    sample_rate: u32,
+    channels: usize,
+    amplitude: f32,
+    profile: &ClipProfile,
+) -> DynResult<Arc<SampleBuffer>> {
+    if profile.stream {
+        return stream_clip(os_file, sample_rate, channels, amplitude, profile);
+    }
+    let (mut input, from_rate) = audio_file::read(os_file)?;
+    if let Some(threshold) = profile.trim {
+        trim_silence(&mut input, channels, threshold);
+    }
+    if let Some(level) = profile.normalize {
+        normalize_peak(&mut input, level);
+    }
+    for s in &mut input {
+        *s *= amplitude;
+    }
 
+    let quality = profile.resampler;
     let samples = match sample_format {
         SampleFormat::I16 => SampleBuffer::I16(convert_samples(
-            &mut reader,
-            os_file,
-            spec.sample_rate,
+            &input,
+            from_rate,
             sample_rate,
             channels,
-            amplitude,
-        )?),
+            quality,
+        )),
         SampleFormat::U16 => SampleBuffer::U16(convert_samples(
-            &mut reader,
-            os_file,
-            spec.sample_rate,
+            &input,
+            from_rate,
             sample_rate,
             channels,
-            amplitude,
-        )?),
+            quality,
+        )),
         SampleFormat::F32 => SampleBuffer::F32(convert_samples(
-            &mut reader,
-            os_file,
-            spec.sample_rate,
+            &input,
+            from_rate,
             sample_rate,
             channels,
-            amplitude,
-        )?),
+            quality,
+        )),
     };
 
     Ok(Arc::new(samples))
 }
 
-pub fn load_clips(
+/// Load or generate the samples for a single clip
+pub fn load_clip_type(
     clip_root: &Path,
-    clip_conf: &HashMap<String, ClipType>,
+    conf: &ClipType,
+    profiles: &HashMap<String, ClipProfile>,
     sample_format: SampleFormat,
     rate: u32,
     channels: u8,
-) -> DynResult<HashMap<String, Arc<SampleBuffer>>> {
-    let mut clips = HashMap::<String, Arc<SampleBuffer>>::new();
-    for (name, conf) in clip_conf {
-        match conf {
-            ClipType::File {
-                file_name,
+) -> DynResult<Arc<SampleBuffer>> {
+    match conf {
+        ClipType::File {
+            file_name,
+            amplitude,
+            profile,
+        } => {
+            let profile = match profile {
+                Some(name) => profiles
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| format!("No clip profile named '{}'", name))?,
+                None => ClipProfile::default(),
+            };
+            let amplitude = amplitude.or(profile.amplitude).unwrap_or(1.0);
+            let os_name = clip_root.join(file_name);
+            load_clip(
+                &os_name,
+                sample_format,
+                rate,
+                channels as usize,
                 amplitude,
-            } => {
-                let os_name = clip_root.join(file_name);
-                let samples =
-                    load_clip(&os_name, sample_format, rate, channels as usize, *amplitude)?;
-                clips.insert(name.clone(), samples);
+                &profile,
+            )
+        }
+        ClipType::Sine {
+            amplitude,
+            frequency,
+            duration,
+        } => {
+            let rate = f64::from(rate);
+            let ramp = 100;
+            let length = (rate * duration.as_secs_f64()).round() as usize;
+            let sample_max;
+            let sample_offset;
+            let mut samples;
+            match sample_format {
+                SampleFormat::I16 => {
+                    sample_max = i16::SAMPLE_MAX as f64;
+                    sample_offset = i16::SAMPLE_OFFSET as f64;
+                    samples = SampleBuffer::I16(Vec::<i16>::with_capacity(
+                        length * usize::from(channels),
+                    ));
+                }
+                SampleFormat::U16 => {
+                    sample_max = u16::SAMPLE_MAX as f64;
+                    sample_offset = u16::SAMPLE_OFFSET as f64;
+                    samples = SampleBuffer::U16(Vec::<u16>::with_capacity(
+                        length * usize::from(channels),
+                    ));
+                }
+                SampleFormat::F32 => {
+                    sample_max = f32::SAMPLE_MAX as f64;
+                    sample_offset = f32::SAMPLE_OFFSET as f64;
+                    samples =
+                        SampleBuffer::F32(Vec::<f32>::with_capacity(length * usize::from(channels)))
+                }
             }
-            ClipType::Sine {
-                amplitude,
-                frequency,
-                duration,
-            } => {
-                let rate = f64::from(rate);
-                let ramp = 100;
-                let length = (rate * duration.as_secs_f64()).round() as usize;
-                let sample_max;
-                let sample_offset;
-                let mut samples;
-                match sample_format {
-                    SampleFormat::I16 => {
-                        sample_max = i16::SAMPLE_MAX as f64;
-                        sample_offset = i16::SAMPLE_OFFSET as f64;
-                        samples = SampleBuffer::I16(Vec::<i16>::with_capacity(
-                            length * usize::from(channels),
-                        ));
-                    }
-                    SampleFormat::U16 => {
-                        sample_max = u16::SAMPLE_MAX as f64;
-                        sample_offset = u16::SAMPLE_OFFSET as f64;
-                        samples = SampleBuffer::U16(Vec::<u16>::with_capacity(
-                            length * usize::from(channels),
-                        ));
-                    }
-                    SampleFormat::F32 => {
-                        sample_max = f32::SAMPLE_MAX as f64;
-                        sample_offset = f32::SAMPLE_OFFSET as f64;
-                        samples = SampleBuffer::F32(Vec::<f32>::with_capacity(
-                            length * usize::from(channels),
-                        ))
-                    }
+            let scale = amplitude * sample_max;
+
+            let fscale = frequency * std::f64::consts::TAU / rate;
+            for i in 0..length {
+                let env;
+                if i < ramp {
+                    env = scale * (i as f64) / (ramp as f64);
+                } else if i > length - ramp {
+                    env = scale * ((length - i) as f64) / (ramp as f64);
+                } else {
+                    env = scale;
                 }
-                let scale = amplitude * sample_max;
-
-                let fscale = frequency * std::f64::consts::TAU / rate;
-                for i in 0..length {
-                    let env;
-                    if i < ramp {
-                        env = scale * (i as f64) / (ramp as f64);
-                    } else if i > length - ramp {
-                        env = scale * ((length - i) as f64) / (ramp as f64);
-                    } else {
-                        env = scale;
-                    }
-                    let s = f64::sin((i as f64) * fscale) * env + sample_offset;
-                    for _ in 0..channels {
-                        match &mut samples {
-                            SampleBuffer::I16(buf) => buf.push(s as i16),
-                            SampleBuffer::U16(buf) => buf.push(s as u16),
-                            SampleBuffer::F32(buf) => buf.push(s as f32),
-                        }
+                let s = f64::sin((i as f64) * fscale) * env + sample_offset;
+                for _ in 0..channels {
+                    match &mut samples {
+                        SampleBuffer::I16(buf) => buf.push(s as i16),
+                        SampleBuffer::U16(buf) => buf.push(s as u16),
+                        SampleBuffer::F32(buf) => buf.push(s as f32),
+                        SampleBuffer::Stream(_) => unreachable!(),
                     }
                 }
+            }
+
+            Ok(Arc::new(samples))
+        }
+    }
+}
 
-                clips.insert(name.clone(), Arc::new(samples));
+pub fn load_clips(
+    clip_root: &Path,
+    clip_conf: &HashMap<String, ClipType>,
+    profiles: &HashMap<String, ClipProfile>,
+    sample_format: SampleFormat,
+    rate: u32,
+    channels: u8,
+) -> DynResult<HashMap<String, Arc<SampleBuffer>>> {
+    let mut clips = HashMap::<String, Arc<SampleBuffer>>::new();
+    for (name, conf) in clip_conf {
+        let samples = load_clip_type(clip_root, conf, profiles, sample_format, rate, channels)?;
+        clips.insert(name.clone(), samples);
+    }
+    Ok(clips)
+}
+
+/// Find all supported audio files in the clip directories. The clip
+/// name is the prefix followed by the file name without extension.
+pub fn scan_clip_dirs(
+    clip_root: &Path,
+    dirs: &[ClipDirConfig],
+) -> DynResult<HashMap<String, ClipType>> {
+    let mut clips = HashMap::new();
+    for dir in dirs {
+        let dir_path = clip_root.join(&dir.path);
+        let entries = std::fs::read_dir(&dir_path).map_err(|e| {
+            format!(
+                "Failed to read clip directory \"{}\": {}",
+                dir_path.to_string_lossy(),
+                e
+            )
+        })?;
+        for entry in entries {
+            let path = entry?.path();
+            if !audio_file::is_supported(&path) || !path.is_file() {
+                continue;
             }
+            let (stem, file_name) = match (path.file_stem(), path.file_name()) {
+                (Some(stem), Some(file_name)) => (stem, file_name),
+                _ => continue,
+            };
+            let name = format!("{}{}", dir.id_prefix, stem.to_string_lossy());
+            let file_name = Path::new(&dir.path).join(file_name);
+            clips.insert(
+                name,
+                ClipType::File {
+                    file_name: file_name.to_string_lossy().into_owned(),
+                    amplitude: dir.amplitude,
+                    profile: dir.profile.clone(),
+                },
+            );
         }
     }
     Ok(clips)
 }
 
+/// Load a single clip, configured explicitly or found in a clip
+/// directory, the same way as when setting up playback
+pub fn load_named_clip(
+    player_conf: &PlayerConfig,
+    base_dir: &Path,
+    name: &str,
+) -> DynResult<Arc<SampleBuffer>> {
+    let clip_root = base_dir.join(&player_conf.clip_root);
+    let dir_clips;
+    let clip = match player_conf.clips.get(name) {
+        Some(clip) => clip,
+        None => {
+            dir_clips = scan_clip_dirs(&clip_root, &player_conf.clip_dirs)?;
+            dir_clips
+                .get(name)
+                .ok_or_else(|| PlaybackError::NameNotFound(name.to_string()))?
+        }
+    };
+    load_clip_type(
+        &clip_root,
+        clip,
+        &player_conf.clip_profiles,
+        player_conf.sample_format,
+        player_conf.rate,
+        player_conf.channels,
+    )
+}
+
 #[derive(Debug)]
 pub enum PlaybackError {
     NameNotFound(String),
@@ -238,46 +445,239 @@ impl std::fmt::Display for PlaybackError {
 pub struct PlaybackContext {
     pub rate: u32,
     pub channels: u8,
+    /// How the device was opened
+    pub device_conf: PlaybackDeviceConfig,
+    pub gain: Arc<SoftwareGain>,
     pub clip_queue: Arc<ClipQueue>,
     pub clips: HashMap<String, Arc<SampleBuffer>>,
+    /// Additional playback devices by id, each with its own queue.
+    /// Empty for the additional devices themselves.
+    pub devices: HashMap<String, PlaybackContext>,
+    /// Shared by all devices
+    pub audit_log: Option<Arc<AuditLog>>,
 }
 
 impl PlaybackContext {
+    /// The device with the given id, or this device if `id` is None
+    pub fn device(&self, id: Option<&str>) -> DynResult<&PlaybackContext> {
+        match id {
+            Some(id) => self
+                .devices
+                .get(id)
+                .ok_or_else(|| format!("No playback device with id '{}'", id).into()),
+            None => Ok(self),
+        }
+    }
+
+    fn clip_queues(&self) -> impl Iterator<Item = &Arc<ClipQueue>> {
+        std::iter::once(&self.clip_queue).chain(self.devices.values().map(|d| &d.clip_queue))
+    }
+
+    /// Stop starting new clips on all devices
+    pub fn drain(&self) {
+        self.clip_queues().for_each(|q| q.drain());
+    }
+
+    /// Fade out the clips playing on all devices
+    pub async fn fade_out(&self, fade: Duration) {
+        futures::future::join_all(self.clip_queues().map(|q| q.fade_out(fade))).await;
+    }
+
+    /// True if no clip is playing on any device
+    pub fn is_idle(&self) -> bool {
+        self.clip_queues().all(|q| q.is_idle())
+    }
+
+    /// Wait until no clip is playing on any device
+    pub async fn wait_idle(&self) {
+        for clip_queue in self.clip_queues() {
+            clip_queue.wait_idle().await;
+        }
+    }
+
+    /// True if clips can be played on all devices
+    pub fn is_healthy(&self) -> bool {
+        self.clip_queues().all(|q| q.is_healthy())
+    }
+
     pub async fn play(&self, clip_name: &str, priority: i32) -> DynResult<()> {
         let clip = self
             .clips
             .get(clip_name)
             .ok_or_else(|| PlaybackError::NameNotFound(clip_name.to_string()))?;
-        self.clip_queue.play(clip.clone(), priority, None).await?;
+        self.clip_queue
+            .play(clip_name, "-", clip.clone(), priority, None)
+            .await?;
 
         Ok(())
     }
 }
 
-pub fn setup_clip_playback(
+// Load the configured clips and those found in clip directories in
+// the format of a device
+fn load_all_clips(
     player_conf: &PlayerConfig,
     base_dir: &Path,
-) -> DynResult<PlaybackContext> {
+    device: &PlaybackDeviceConfig,
+) -> DynResult<HashMap<String, Arc<SampleBuffer>>> {
     let clip_root = base_dir.join(&player_conf.clip_root);
-    let clips = load_clips(
+    let mut clips = load_clips(
         &clip_root,
         &player_conf.clips,
-        player_conf.sample_format,
-        player_conf.rate,
-        player_conf.channels,
+        &player_conf.clip_profiles,
+        device.sample_format,
+        device.rate,
+        device.channels,
     )?;
-    let rate = player_conf.rate;
-    let channels = player_conf.channels;
-    let sample_format = player_conf.sample_format;
-    let clip_player = ClipPlayer::new(&player_conf.playback_device, rate, channels, sample_format)
-        .map_err(|e| format!("Failed to initialise playback: {}", e))?;
+    // Explicitly configured clips take precedence
+    let mut dir_clips = scan_clip_dirs(&clip_root, &player_conf.clip_dirs)?;
+    dir_clips.retain(|name, _| !clips.contains_key(name));
+    clips.extend(load_clips(
+        &clip_root,
+        &dir_clips,
+        &player_conf.clip_profiles,
+        device.sample_format,
+        device.rate,
+        device.channels,
+    )?);
+    Ok(clips)
+}
 
-    let clip_queue = ClipQueue::new(clip_player);
+// Clips are only loaded again for devices with a different format than
+// the default device
+fn load_device_clips(
+    player_conf: &PlayerConfig,
+    base_dir: &Path,
+    device: &PlaybackDeviceConfig,
+    default_clips: &HashMap<String, Arc<SampleBuffer>>,
+) -> DynResult<HashMap<String, Arc<SampleBuffer>>> {
+    if device.rate == player_conf.rate
+        && device.channels == player_conf.channels
+        && device.sample_format == player_conf.sample_format
+    {
+        Ok(default_clips.clone())
+    } else {
+        load_all_clips(player_conf, base_dir, device)
+    }
+}
+
+fn open_device(
+    device: &PlaybackDeviceConfig,
+    clips: HashMap<String, Arc<SampleBuffer>>,
+    audit_log: &Option<Arc<AuditLog>>,
+) -> DynResult<PlaybackContext> {
+    let clip_player = ClipPlayer::with_buffer_size(
+        &device.device,
+        device.rate,
+        device.channels,
+        device.sample_format,
+        device.buffer_size,
+    )
+    .map_err(|e| format!("Failed to initialise playback on {}: {}", device.device, e))?;
+
+    let gain = clip_player.gain();
+    let mut clip_queue = ClipQueue::new(clip_player);
+    if let Some(audit_log) = audit_log {
+        clip_queue.set_audit_log(audit_log.clone());
+    }
     Ok(PlaybackContext {
-        rate,
-        channels,
+        rate: device.rate,
+        channels: device.channels,
+        device_conf: device.clone(),
+        gain,
         clip_queue: Arc::new(clip_queue),
         clips,
+        devices: HashMap::new(),
+        audit_log: audit_log.clone(),
+    })
+}
+
+pub fn setup_clip_playback(
+    player_conf: &PlayerConfig,
+    base_dir: &Path,
+) -> DynResult<PlaybackContext> {
+    let default_device = player_conf.default_device();
+    let clips = load_all_clips(player_conf, base_dir, &default_device)?;
+    let audit_log = match &player_conf.audit_log {
+        Some(conf) => Some(Arc::new(AuditLog::open(
+            &base_dir.join(&conf.path),
+            conf.max_size,
+            conf.keep,
+        )?)),
+        None => None,
+    };
+    let mut devices = HashMap::new();
+    for (id, device) in &player_conf.playback_devices {
+        let device_clips = load_device_clips(player_conf, base_dir, device, &clips)?;
+        devices.insert(id.clone(), open_device(device, device_clips, &audit_log)?);
+    }
+    let mut playback_ctxt = open_device(&default_device, clips, &audit_log)?;
+    playback_ctxt.devices = devices;
+    Ok(playback_ctxt)
+}
+
+// A device is only kept if it's configured as when it was opened, since
+// clips are converted to the format of the new configuration
+fn check_kept_device(
+    what: &str,
+    opened: Option<&PlaybackDeviceConfig>,
+    device: &PlaybackDeviceConfig,
+) -> DynResult<()> {
+    match opened {
+        None => Err(format!("{} added, restart needed", what).into()),
+        Some(opened) if opened != device => Err(format!("{} changed, restart needed", what).into()),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Load the clips of a new configuration, keeping the playback devices
+/// of the current one. Devices that aren't in the new configuration
+/// are dropped. Fails if a device is added or configured differently.
+pub fn reload_clip_playback(
+    player_conf: &PlayerConfig,
+    base_dir: &Path,
+    current: &PlaybackContext,
+) -> DynResult<PlaybackContext> {
+    let default_device = player_conf.default_device();
+    check_kept_device(
+        "Default playback device",
+        Some(&current.device_conf),
+        &default_device,
+    )?;
+    let clips = load_all_clips(player_conf, base_dir, &default_device)?;
+    let mut devices = HashMap::new();
+    for (id, device) in &player_conf.playback_devices {
+        let current_device = current.devices.get(id);
+        check_kept_device(
+            &format!("Playback device '{}'", id),
+            current_device.map(|d| &d.device_conf),
+            device,
+        )?;
+        if let Some(current_device) = current_device {
+            devices.insert(
+                id.clone(),
+                PlaybackContext {
+                    rate: current_device.rate,
+                    channels: current_device.channels,
+                    device_conf: current_device.device_conf.clone(),
+                    gain: current_device.gain.clone(),
+                    clip_queue: current_device.clip_queue.clone(),
+                    clips: load_device_clips(player_conf, base_dir, device, &clips)?,
+                    devices: HashMap::new(),
+                    audit_log: current_device.audit_log.clone(),
+                },
+            );
+        }
+    }
+    Ok(PlaybackContext {
+        rate: current.rate,
+        channels: current.channels,
+        device_conf: current.device_conf.clone(),
+        gain: current.gain.clone(),
+        clip_queue: current.clip_queue.clone(),
+        clips,
+        devices,
+        audit_log: current.audit_log.clone(),
     })
 }
 
@@ -287,11 +687,17 @@ pub struct ActionContext {
 
 struct ActionBuildData<'a> {
     playback_ctxt: &'a PlaybackContext,
+    clip_devices: &'a HashMap<String, String>,
     tag_ctxt: &'a Arc<TagContext>,
     volume_control: &'a Arc<VolumeControlContext>,
     alarm_ctxt: &'a Arc<AlarmContext>,
     state_machine_map: &'a HashMap<String, Arc<StateMachine>>,
     current_state_machine: &'a Arc<StateMachine>,
+    current_state: &'a str,
+    repeat_limit: &'a EventLimit,
+    #[cfg_attr(not(feature = "email"), allow(dead_code))]
+    smtp: Option<&'a SmtpConfig>,
+    gpio_outputs: &'a Arc<GpioOutputs>,
 }
 
 fn action_conf_to_action(
@@ -319,14 +725,24 @@ fn action_conf_to_action(
             priority,
             timeout,
             sound,
+            device,
         } => {
-            let samples = build_data
-                .playback_ctxt
+            let device = device
+                .as_deref()
+                .or_else(|| build_data.clip_devices.get(sound).map(String::as_str));
+            let playback_ctxt = build_data.playback_ctxt.device(device)?;
+            let samples = playback_ctxt
                 .clips
                 .get(sound)
                 .ok_or_else(|| format!("No clip named '{}'", sound))?;
+            let source = format!(
+                "{}:{}",
+                build_data.current_state_machine.name, build_data.current_state
+            );
             let action = PlayAction::new(
-                build_data.playback_ctxt.clip_queue.clone(),
+                sound,
+                &source,
+                playback_ctxt.clip_queue.clone(),
                 *priority,
                 *timeout,
                 samples.clone(),
@@ -334,9 +750,34 @@ fn action_conf_to_action(
             Ok(Arc::new(action))
         }
         ActionType::Wait(timeout) => Ok(Arc::new(WaitAction::new(*timeout))),
+        ActionType::WaitTime(schedule) => Ok(Arc::new(WaitTimeAction::new(schedule.clone()))),
         ActionType::Repeat { count, action } => {
             let repeated = action_conf_to_action(build_data, action)?;
-            Ok(Arc::new(RepeatAction::new(repeated, *count)))
+            Ok(Arc::new(RepeatAction::new(
+                repeated,
+                *count,
+                build_data.repeat_limit.for_user(&format!(
+                    "{}:{} repeat",
+                    build_data.current_state_machine.name, build_data.current_state
+                )),
+            )))
+        }
+        ActionType::If {
+            condition,
+            then,
+            otherwise,
+        } => {
+            let then = action_conf_to_action(build_data, then)?;
+            let otherwise = match otherwise {
+                Some(action) => Some(action_conf_to_action(build_data, action)?),
+                None => None,
+            };
+            Ok(Arc::new(IfAction::new(
+                condition.clone(),
+                then,
+                otherwise,
+                build_data.tag_ctxt.clone(),
+            )))
         }
         ActionType::Goto(state_name) => {
             let state_machine;
@@ -374,6 +815,10 @@ fn action_conf_to_action(
             condition.clone(),
             build_data.tag_ctxt.clone(),
         ))),
+        ActionType::WaitExpr(expr) => Ok(Arc::new(WaitExprAction::new(
+            expr.clone(),
+            build_data.tag_ctxt.clone(),
+        ))),
         ActionType::WaitAlarm {
             filter_name,
             condition,
@@ -402,21 +847,92 @@ fn action_conf_to_action(
         ))),
 
         ActionType::Debug(text) => Ok(Arc::new(DebugAction::new(text.clone()))),
-        ActionType::SetVolume { control, value } => {
-            let ctrl = match build_data.volume_control.controls.get(control) {
-                Some(ctrl) => ctrl,
-                None => return Err(format!("No volume control named '{}' found.", control).into()),
+        #[cfg(feature = "email")]
+        ActionType::Email { to, subject, body } => {
+            let smtp = build_data
+                .smtp
+                .ok_or("Email actions require a mail server configuration")?;
+            Ok(Arc::new(EmailAction::new(
+                smtp,
+                to,
+                subject.clone(),
+                body.clone(),
+                build_data.tag_ctxt.clone(),
+            )?))
+        }
+        #[cfg(not(feature = "email"))]
+        ActionType::Email { .. } => Err("Built without email support".into()),
+        ActionType::Exec {
+            program,
+            args,
+            timeout,
+        } => Ok(Arc::new(ExecAction::new(
+            program.clone(),
+            args.clone(),
+            *timeout,
+            build_data.tag_ctxt.clone(),
+        ))),
+        ActionType::Plugin(element) => {
+            let plugin = plugin::find_action(&element.name)
+                .ok_or_else(|| format!("No plugin registered for '{}'", element.name))?;
+            let ctxt = PluginContext {
+                tag_ctxt: build_data.tag_ctxt,
+                alarm_ctxt: build_data.alarm_ctxt,
+                playback_ctxt: build_data.playback_ctxt,
+                state_machine: &build_data.current_state_machine.name,
+                state: build_data.current_state,
             };
+            plugin.build(element, &ctxt)
+        }
+        ActionType::SetGpio { pin, value } => Ok(Arc::new(SetGpioAction::new(
+            build_data.gpio_outputs.clone(),
+            pin.clone(),
+            *value,
+        ))),
+        ActionType::ChangeVolume { control, step } => {
+            if !build_data.volume_control.controls.contains_key(control) {
+                return Err(format!("No volume control named '{}' found.", control).into());
+            }
+            Ok(Arc::new(ChangeVolumeAction::new(
+                build_data.volume_control.clone(),
+                control.clone(),
+                *step,
+            )))
+        }
+        ActionType::SetBalance { control, levels } => {
+            if !build_data.volume_control.controls.contains_key(control) {
+                return Err(format!("No volume control named '{}' found.", control).into());
+            }
+            Ok(Arc::new(SetBalanceAction::new(
+                build_data.volume_control.clone(),
+                control.clone(),
+                levels.clone(),
+            )))
+        }
+        ActionType::SetVolume {
+            control,
+            value,
+            ramp,
+        } => {
+            if !build_data.volume_control.controls.contains_key(control) {
+                return Err(format!("No volume control named '{}' found.", control).into());
+            }
             match value {
                 TagOrConst::Tag(tag_name) => Ok(Arc::new(SetVolumeAction::new_tag(
-                    ctrl.clone(),
+                    build_data.volume_control.clone(),
+                    control.clone(),
                     tag_name.to_string(),
                     build_data.tag_ctxt.clone(),
+                    *ramp,
                 ))),
-                TagOrConst::Const(level) => Ok(Arc::new(SetVolumeAction::<TagContext>::new_const(
-                    ctrl.clone(),
-                    *level,
-                ))),
+                TagOrConst::Const(level) => {
+                    Ok(Arc::new(SetVolumeAction::<TagContext, _>::new_const(
+                        build_data.volume_control.clone(),
+                        control.clone(),
+                        *level,
+                        *ramp,
+                    )))
+                }
             }
         }
     }
@@ -428,78 +944,543 @@ pub struct TagSetRequest {
     pub done: oneshot::Sender<DynResult<()>>,
 }
 
-struct TagObservable {
+/// Number of tag writes that can be queued for the pipe or a tag source
+pub const TAG_WRITE_QUEUE_LEN: usize = 256;
+
+/// Queue for writes to the pipe or a tag source
+pub fn tag_write_channel() -> (Sender<TagSetRequest>, Receiver<TagSetRequest>) {
+    tokio::sync::mpsc::channel(TAG_WRITE_QUEUE_LEN)
+}
+
+/// Counters for the tag write queues
+#[derive(Debug, Default)]
+pub struct WriteQueueStats {
+    /// Writes dropped because a queue was full
+    pub dropped: AtomicU64,
+    // Set while writes are dropped, so that it's only logged once
+    overflowing: AtomicBool,
+}
+
+impl WriteQueueStats {
+    fn write_dropped(&self, tag_name: &str) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        if !self.overflowing.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Tag write queue full, dropping writes starting with {}",
+                tag_name
+            );
+        }
+    }
+
+    fn write_queued(&self) {
+        if self.overflowing.swap(false, Ordering::Relaxed) {
+            info!("Tag write queue no longer full");
+        }
+    }
+}
+
+// Result of queuing a write
+enum QueuedWrite {
+    // Receives the result when the write is done
+    Sent(oneshot::Receiver<DynResult<()>>),
+    // Postponed because of rate limiting
+    Postponed,
+    // The queue is full. The request has to wait for room.
+    Full(
+        Sender<TagSetRequest>,
+        TagSetRequest,
+        oneshot::Receiver<DynResult<()>>,
+    ),
+}
+
+// Current value and recent history of a tag
+struct TagValue {
     state: Option<String>,
+    // Recent values, oldest first. Only changes are recorded.
+    history: VecDeque<(Instant, String)>,
+    // The oldest entry in history is the first value recorded, which
+    // isn't a change
+    history_has_initial: bool,
+}
+
+impl TagValue {
+    fn record_history(&mut self, value: &str) {
+        if self.history.back().is_some_and(|(_, last)| last == value) {
+            return;
+        }
+        if self.history.len() >= TAG_HISTORY_LENGTH {
+            self.history.pop_front();
+            self.history_has_initial = false;
+        }
+        self.history.push_back((Instant::now(), value.to_string()));
+    }
+}
+
+struct TagObservable {
+    // Each tag has its own lock so that updates of different tags
+    // don't block each other
+    value: Mutex<TagValue>,
     observers: (watch::Sender<String>, watch::Receiver<String>),
+    // Set if the value is computed from other tags
+    expr: Option<Expr>,
+    // Derived tags that depends on this tag
+    dependents: Vec<String>,
+    // Not subscribed or written to the pipe
+    local: bool,
+    // Saved to the persistence file when changed
+    persist: bool,
+}
+
+impl TagObservable {
+    fn new(state: Option<String>, expr: Option<Expr>) -> TagObservable {
+        TagObservable {
+            value: Mutex::new(TagValue {
+                state,
+                history: VecDeque::with_capacity(TAG_HISTORY_LENGTH),
+                history_has_initial: true,
+            }),
+            observers: watch::channel("".to_string()),
+            expr,
+            dependents: Vec::new(),
+            local: false,
+            persist: false,
+        }
+    }
+
+    /// True if the value of the tag is kept in the pipe
+    fn on_pipe(&self) -> bool {
+        !self.local && self.expr.is_none()
+    }
+
+    fn value(&self) -> MutexGuard<'_, TagValue> {
+        // The value is always consistent so a poisoned lock is harmless
+        self.value.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn state(&self) -> Option<String> {
+        self.value().state.clone()
+    }
+}
+
+// Limits how often a tag is written to the pipe
+struct WriteLimit {
+    interval: Duration,
+    last_write: Option<Instant>,
+    // Latest value that has not been written yet
+    pending: Option<String>,
 }
 
 pub struct TagContext {
-    tags: Mutex<HashMap<String, TagObservable>>,
-    tag_send_tx: UnboundedSender<TagSetRequest>,
+    // The map is modified during setup and when a tag matching a pattern
+    // is first seen. Tag values are updated through the per-tag lock
+    // while holding a read lock. No guard is held across an await.
+    tags: RwLock<HashMap<String, TagObservable>>,
+    tag_send_tx: Sender<TagSetRequest>,
+    write_stats: Arc<WriteQueueStats>,
+    persist_writer: Option<PersistWriter>,
+    // Values read from the persistence file at startup
+    restored: HashMap<String, String>,
+    // Maps internal names to pipe names
+    pipe_names: HashMap<String, String>,
+    // Maps pipe names to internal names
+    internal_names: HashMap<String, String>,
+    write_limits: HashMap<String, Arc<Mutex<WriteLimit>>>,
+    // Tags matching these patterns are added when first seen
+    patterns: Vec<String>,
+    // Writes to tags from other sources than the pipe
+    source_writers: HashMap<String, Sender<TagSetRequest>>,
 }
 
 impl TagContext {
-    pub fn new(tag_send_tx: UnboundedSender<TagSetRequest>) -> TagContext {
+    pub fn new(tag_send_tx: Sender<TagSetRequest>) -> TagContext {
         TagContext {
-            tags: Mutex::new(HashMap::new()),
+            tags: RwLock::new(HashMap::new()),
             tag_send_tx,
+            write_stats: Arc::new(WriteQueueStats::default()),
+            persist_writer: None,
+            restored: HashMap::new(),
+            pipe_names: HashMap::new(),
+            internal_names: HashMap::new(),
+            write_limits: HashMap::new(),
+            patterns: Vec::new(),
+            source_writers: HashMap::new(),
         }
     }
 
-    pub fn tag_changed(&self, name: &str, new_value: &str) {
-        debug!("{}: -> {}", name, new_value);
-        if let Ok(mut tags) = self.tags.lock() {
-            if let Some(data) = tags.get_mut(name) {
-                data.state = Some(new_value.to_string());
-                if let Err(err) = data.observers.0.send(new_value.to_string()) {
-                    error!("Failed to notify tag observers: {}", err);
-                }
-            }
-        }
+    /// Counters for writes to the pipe and tag sources
+    pub fn write_queue_stats(&self) -> Arc<WriteQueueStats> {
+        self.write_stats.clone()
     }
 
-    pub fn tag_names(&self) -> Vec<String> {
-        let tags = self.tags.lock().unwrap();
-        tags.keys().cloned().collect()
+    /// Send writes to the tag to `writer` instead of the pipe
+    pub fn set_tag_source(&mut self, name: &str, writer: Sender<TagSetRequest>) {
+        self.source_writers.insert(name.to_string(), writer);
     }
 
-    pub fn add_tag(&self, name: &str, state: Option<String>) {
-        let mut tags = self.tags.lock().unwrap();
-        tags.insert(
+    /// Subscribe to all tags matching pattern. The tags are added
+    /// when the first value is received.
+    pub fn add_tag_pattern(&mut self, pattern: &str) {
+        self.patterns.push(pattern.to_string());
+    }
+
+    // Add the tag if it's unknown and matches a pattern
+    fn add_matching_tag(&self, name: &str) {
+        if self.read_tags().contains_key(name)
+            || !self.patterns.iter().any(|p| glob::glob_match(p, name))
+        {
+            return;
+        }
+        debug!("Adding tag {} matching pattern", name);
+        self.write_tags()
+            .entry(name.to_string())
+            .or_insert_with(|| TagObservable::new(None, None));
+    }
+
+    /// Write the tag to the pipe at most rate times per second.
+    /// Values set in between are coalesced and only the latest is written.
+    pub fn set_max_rate(&mut self, name: &str, rate: f64) {
+        self.write_limits.insert(
             name.to_string(),
-            TagObservable {
-                state,
-                observers: watch::channel("".to_string()),
-            },
+            Arc::new(Mutex::new(WriteLimit {
+                interval: Duration::from_secs_f64(1.0 / rate),
+                last_write: None,
+                pending: None,
+            })),
         );
     }
-}
 
-impl TagSetter for TagContext {
-    fn async_set_tag(&self, tag_name: &str, value: &str) -> TagSetFuture {
-        self.tag_changed(tag_name, value);
+    /// Queue a write request for the pipe
+    fn queue_write(&self, tag_name: &str, value: &str) -> DynResult<QueuedWrite> {
+        // Tags from other sources aren't renamed
+        let (tag_send_tx, pipe_name) = match self.source_writers.get(tag_name) {
+            Some(writer) => (writer, tag_name.to_string()),
+            None => (&self.tag_send_tx, self.pipe_name(tag_name).to_string()),
+        };
+        if let Some(limit_ref) = self.write_limits.get(tag_name) {
+            let mut limit = limit_ref.lock().unwrap();
+            let now = Instant::now();
+            if let Some(last) = limit.last_write {
+                let next_write = last + limit.interval;
+                if limit.pending.is_some() || now < next_write {
+                    if limit.pending.replace(value.to_string()).is_none() {
+                        // Write the latest value when the interval has passed
+                        let limit_ref = limit_ref.clone();
+                        let tag_send_tx = tag_send_tx.clone();
+                        let write_stats = self.write_stats.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep_until(next_write).await;
+                            let value = {
+                                let mut limit = limit_ref.lock().unwrap();
+                                limit.last_write = Some(Instant::now());
+                                limit.pending.take()
+                            };
+                            if let Some(value) = value {
+                                let (done, _) = oneshot::channel();
+                                let req = TagSetRequest {
+                                    tag_name: pipe_name,
+                                    value,
+                                    done,
+                                };
+                                match tag_send_tx.try_send(req) {
+                                    Ok(()) => write_stats.write_queued(),
+                                    Err(TrySendError::Full(req)) => {
+                                        write_stats.write_dropped(&req.tag_name)
+                                    }
+                                    Err(TrySendError::Closed(_)) => {
+                                        error!("Failed to queue rate limited tag write")
+                                    }
+                                }
+                            }
+                        });
+                    }
+                    return Ok(QueuedWrite::Postponed);
+                }
+            }
+            limit.last_write = Some(now);
+        }
         let (done_send, done_recv) = oneshot::channel();
         let req = TagSetRequest {
-            tag_name: tag_name.to_string(),
+            tag_name: pipe_name,
             value: value.to_string(),
             done: done_send,
         };
-        if self.tag_send_tx.send(req).is_err() {
-            return Box::pin(std::future::ready(Err("Failed to queue request".into())));
+        match tag_send_tx.try_send(req) {
+            Ok(()) => {
+                self.write_stats.write_queued();
+                Ok(QueuedWrite::Sent(done_recv))
+            }
+            Err(TrySendError::Full(req)) => {
+                Ok(QueuedWrite::Full(tag_send_tx.clone(), req, done_recv))
+            }
+            Err(TrySendError::Closed(_)) => Err("Failed to queue request".into()),
+        }
+    }
+
+    /// Refer to the pipe tag pipe_name as name
+    pub fn add_alias(&mut self, name: &str, pipe_name: &str) {
+        self.pipe_names
+            .insert(name.to_string(), pipe_name.to_string());
+        self.internal_names
+            .insert(pipe_name.to_string(), name.to_string());
+    }
+
+    fn pipe_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.pipe_names.get(name).map_or(name, |n| n.as_str())
+    }
+
+    /// Called when a tag changes on the pipe
+    pub fn pipe_tag_changed(&self, pipe_name: &str, new_value: &str) {
+        let name = self
+            .internal_names
+            .get(pipe_name)
+            .map_or(pipe_name, |n| n.as_str());
+        self.tag_changed(name, new_value);
+    }
+
+    /// Read persisted values from file. The values are used as
+    /// initial values for tags marked as persistent. A missing file
+    /// is not an error.
+    pub fn set_persist_file(&mut self, path: PathBuf) -> DynResult<()> {
+        match File::open(&path) {
+            Ok(file) => {
+                self.restored = serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+                    format!(
+                        "Failed to read persisted tags from '{}': {}",
+                        path.to_string_lossy(),
+                        e
+                    )
+                })?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.persist_writer = Some(PersistWriter::new(path, "persisted tags"));
+        Ok(())
+    }
+
+    // The file is written by another thread
+    fn save_persisted(&self) {
+        let writer = match &self.persist_writer {
+            Some(w) => w,
+            None => return,
+        };
+        let values: HashMap<String, String> = self
+            .read_tags()
+            .iter()
+            .filter(|(_, data)| data.persist)
+            .filter_map(|(name, data)| data.state().map(|v| (name.clone(), v)))
+            .collect();
+        writer.save(&values);
+    }
+
+    /// Write the values read from the persistence file to the pipe, for
+    /// tags that the subscription gave no value. `subscribed` holds the
+    /// values by pipe name. Live values are never overwritten.
+    pub fn restore_persisted(&self, subscribed: &HashMap<String, String>) {
+        for (name, value) in &self.restored {
+            let persist = self.read_tags().get(name).is_some_and(|data| data.persist);
+            if !persist || subscribed.contains_key(self.pipe_name(name)) {
+                continue;
+            }
+            if let Err(e) = self.set_tag(name, value) {
+                error!("Failed to restore tag {}: {}", name, e);
+            }
+        }
+    }
+
+    fn read_tags(&self) -> RwLockReadGuard<'_, HashMap<String, TagObservable>> {
+        self.tags.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_tags(&self) -> RwLockWriteGuard<'_, HashMap<String, TagObservable>> {
+        self.tags.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update_tag(tags: &HashMap<String, TagObservable>, name: &str, new_value: &str) {
+        let data = match tags.get(name) {
+            Some(data) => data,
+            None => return,
+        };
+        {
+            let mut value = data.value();
+            value.state = Some(new_value.to_string());
+            value.record_history(new_value);
+            // Notify while holding the lock so that observers never
+            // see the notifications out of order
+            if let Err(err) = data.observers.0.send(new_value.to_string()) {
+                error!("Failed to notify tag observers: {}", err);
+            }
+        }
+        for dependent in &data.dependents {
+            if let Some(value) = Self::evaluate_derived(tags, dependent) {
+                if tags[dependent].state().as_ref() != Some(&value) {
+                    debug!("{}: -> {}", dependent, value);
+                    Self::update_tag(tags, dependent, &value);
+                }
+            }
+        }
+    }
+
+    fn evaluate_derived(tags: &HashMap<String, TagObservable>, name: &str) -> Option<String> {
+        let expr = tags.get(name)?.expr.as_ref()?;
+        let lookup = |tag: &str| tags.get(tag).and_then(|data| data.state());
+        match expr.evaluate(&lookup) {
+            Ok(value) => Some(value.to_tag_string()),
+            Err(e) => {
+                debug!("Failed to evaluate {}: {}", name, e);
+                None
+            }
+        }
+    }
+
+    pub fn tag_changed(&self, name: &str, new_value: &str) {
+        debug!("{}: -> {}", name, new_value);
+        self.add_matching_tag(name);
+        let persist = {
+            let tags = self.read_tags();
+            Self::update_tag(&tags, name, new_value);
+            tags.get(name).is_some_and(|data| data.persist)
+        };
+        if persist {
+            self.save_persisted();
+        }
+    }
+
+    /// Current value of every tag, None if not known yet
+    pub fn values(&self) -> BTreeMap<String, Option<String>> {
+        self.read_tags()
+            .iter()
+            .map(|(name, data)| (name.clone(), data.state()))
+            .collect()
+    }
+
+    /// Pipe names of all tags and patterns that should be subscribed from the pipe
+    pub fn tag_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .read_tags()
+            .iter()
+            .filter(|(name, data)| data.on_pipe() && !self.source_writers.contains_key(*name))
+            .map(|(name, _)| self.pipe_name(name).to_string())
+            .collect();
+        names.extend(self.patterns.iter().cloned());
+        names
+    }
+
+    /// Returns false if writes to the tag should not be forwarded
+    /// to the pipe. Unknown tags are forwarded.
+    fn forward_to_pipe(&self, name: &str) -> bool {
+        self.read_tags().get(name).is_none_or(|data| data.on_pipe())
+    }
+
+    fn insert_tag(&self, name: &str, state: Option<String>, local: bool) {
+        let mut tags = self.write_tags();
+        let persist = tags.get(name).is_some_and(|data| data.persist);
+        let dependents = tags
+            .remove(name)
+            .map(|data| data.dependents)
+            .unwrap_or_default();
+        let mut data = TagObservable::new(state, None);
+        data.dependents = dependents;
+        data.local = local;
+        data.persist = persist;
+        tags.insert(name.to_string(), data);
+    }
+
+    /// Mark a tag as persistent. If a value was read from the
+    /// persistence file, it's used as the current value.
+    pub fn set_persistent(&self, name: &str) {
+        let mut tags = self.write_tags();
+        if let Some(data) = tags.get_mut(name) {
+            data.persist = true;
+            if let Some(value) = self.restored.get(name) {
+                data.value().state = Some(value.clone());
+            }
+        }
+    }
+
+    pub fn add_tag(&self, name: &str, state: Option<String>) {
+        self.insert_tag(name, state, false)
+    }
+
+    /// Add a tag that only exists in this context
+    pub fn add_local_tag(&self, name: &str, state: Option<String>) {
+        self.insert_tag(name, state, true)
+    }
+
+    /// Add a tag whose value is computed from other tags. All tags
+    /// used by the expression must already have been added.
+    pub fn add_derived_tag(&self, name: &str, expr: Expr) -> DynResult<()> {
+        let mut tags = self.write_tags();
+        if tags.contains_key(name) {
+            return Err(format!("Derived tag '{}' is already defined", name).into());
+        }
+        for dep in expr.tags() {
+            match tags.get_mut(&dep) {
+                Some(data) => data.dependents.push(name.to_string()),
+                None => {
+                    return Err(format!(
+                        "Tag '{}' used by derived tag '{}' is not defined",
+                        dep, name
+                    )
+                    .into())
+                }
+            }
+        }
+        tags.insert(name.to_string(), TagObservable::new(None, Some(expr)));
+        let state = Self::evaluate_derived(&tags, name);
+        if let Some(data) = tags.get(name) {
+            data.value().state = state;
+        }
+        Ok(())
+    }
+}
+
+impl TagSetter for TagContext {
+    fn async_set_tag(&self, tag_name: &str, value: &str) -> TagSetFuture {
+        self.tag_changed(tag_name, value);
+        if !self.forward_to_pipe(tag_name) {
+            return Box::pin(std::future::ready(Ok(())));
+        }
+        match self.queue_write(tag_name, value) {
+            Ok(QueuedWrite::Sent(done_recv)) => {
+                Box::pin(async move { timeout(Duration::from_millis(500), done_recv).await?? })
+            }
+            Ok(QueuedWrite::Postponed) => Box::pin(std::future::ready(Ok(()))),
+            Ok(QueuedWrite::Full(tag_send_tx, req, done_recv)) => {
+                // Wait for room in the queue, within the same time limit
+                let write_stats = self.write_stats.clone();
+                Box::pin(async move {
+                    let tag_name = req.tag_name.clone();
+                    let queued_stats = write_stats.clone();
+                    let write = async move {
+                        tag_send_tx
+                            .send(req)
+                            .await
+                            .map_err(|_| "Failed to queue request")?;
+                        queued_stats.write_queued();
+                        done_recv.await?
+                    };
+                    match timeout(Duration::from_millis(500), write).await {
+                        Ok(res) => res,
+                        Err(e) => {
+                            write_stats.write_dropped(&tag_name);
+                            Err(e.into())
+                        }
+                    }
+                })
+            }
+            Err(e) => Box::pin(std::future::ready(Err(e))),
         }
-        Box::pin(async move { timeout(Duration::from_millis(500), done_recv).await?? })
     }
 
     fn set_tag(&self, tag_name: &str, value: &str) -> DynResult<()> {
         self.tag_changed(tag_name, value);
-        let (done_send, _done_recv) = oneshot::channel();
-        let req = TagSetRequest {
-            tag_name: tag_name.to_string(),
-            value: value.to_string(),
-            done: done_send,
-        };
-        if self.tag_send_tx.send(req).is_err() {
-            return Err("Failed to queue request".into());
+        if !self.forward_to_pipe(tag_name) {
+            return Ok(());
+        }
+        if let QueuedWrite::Full(_, req, _) = self.queue_write(tag_name, value)? {
+            self.write_stats.write_dropped(&req.tag_name);
+            return Err("Tag write queue full".into());
         }
         Ok(())
     }
@@ -510,15 +1491,12 @@ impl TagDispatcher for TagContext {
         &self,
         tag: &str,
     ) -> Result<(Option<String>, TagDispatched), tag_dispatcher::Error> {
-        let mut tags = self
-            .tags
-            .lock()
-            .map_err(|_| tag_dispatcher::Error::DispatcherNotAvailable)?;
-        let data = tags
-            .get_mut(tag)
-            .ok_or(tag_dispatcher::Error::TagNotFound)?;
-        let value = data.state.clone();
-        let mut rx = data.observers.1.clone();
+        let tags = self.read_tags();
+        let data = tags.get(tag).ok_or(tag_dispatcher::Error::TagNotFound)?;
+        let (value, mut rx) = {
+            let value = data.value();
+            (value.state.clone(), data.observers.1.clone())
+        };
         let wait_tag = Box::pin(async move {
             rx.borrow_and_update(); // Make sure that changed will block until next change
             rx.changed()
@@ -530,26 +1508,130 @@ impl TagDispatcher for TagContext {
     }
 
     fn get_value(&self, tag: &str) -> Option<String> {
-        let mut tags = self.tags.lock().ok()?;
-        let data = tags.get_mut(tag)?;
-        data.state.clone()
+        self.read_tags().get(tag)?.state()
+    }
+
+    fn get_value_ago(&self, tag: &str, age: Duration) -> Option<String> {
+        let tags = self.read_tags();
+        let then = Instant::now().checked_sub(age)?;
+        let value = tags.get(tag)?.value();
+        value
+            .history
+            .iter()
+            .rev()
+            .find(|(changed, _)| *changed <= then)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn changes_within(&self, tag: &str, period: Duration) -> usize {
+        let tags = self.read_tags();
+        let value = match tags.get(tag) {
+            Some(data) => data.value(),
+            None => return 0,
+        };
+        let now = Instant::now();
+        value
+            .history
+            .iter()
+            .skip(usize::from(value.history_has_initial))
+            .rev()
+            .take_while(|(changed, _)| now.duration_since(*changed) <= period)
+            .count()
     }
 }
 
 pub fn setup_tags(
     player_conf: &PlayerConfig,
-    tag_send_tx: UnboundedSender<TagSetRequest>,
+    base_dir: &Path,
+    tag_send_tx: Sender<TagSetRequest>,
 ) -> DynResult<TagContext> {
-    let tag_ctxt = TagContext::new(tag_send_tx);
+    let mut tag_ctxt = TagContext::new(tag_send_tx);
+    if let Some(persist_file) = &player_conf.tag_persist_file {
+        tag_ctxt.set_persist_file(base_dir.join(persist_file))?;
+    }
     {
-        for name in &player_conf.tags {
-            tag_ctxt.add_tag(name, None);
+        for tag in &player_conf.tags {
+            let name = tag.internal_name();
+            if glob::is_pattern(name) {
+                tag_ctxt.add_tag_pattern(name);
+                continue;
+            }
+            if tag.local {
+                tag_ctxt.add_local_tag(name, None);
+            } else {
+                tag_ctxt.add_tag(name, None);
+                if tag.alias.is_some() {
+                    tag_ctxt.add_alias(name, &tag.name);
+                }
+            }
+            if tag.persist {
+                tag_ctxt.set_persistent(name);
+            }
+            if let Some(rate) = tag.max_rate {
+                tag_ctxt.set_max_rate(name, rate);
+            }
+        }
+        for source in &player_conf.tag_sources {
+            for name in source.tag_names() {
+                let defined = tag_ctxt.read_tags().contains_key(name);
+                if !defined {
+                    tag_ctxt.add_tag(name, None);
+                }
+            }
+        }
+        for derived in &player_conf.derived_tags {
+            tag_ctxt.add_derived_tag(&derived.name, derived.expr.clone())?;
+        }
+        for machine in &player_conf.state_machines {
+            for name in [&machine.state_tag, &machine.command_tag]
+                .into_iter()
+                .flatten()
+            {
+                let defined = tag_ctxt.read_tags().contains_key(name);
+                if !defined {
+                    tag_ctxt.add_tag(name, None);
+                }
+            }
         }
     }
     Ok(tag_ctxt)
 }
 
+/// Tag sources that have been set up but not started
+pub struct TagSources {
+    sources: Vec<(TagSourceConfig, Receiver<TagSetRequest>)>,
+}
+
+impl TagSources {
+    /// Start exchanging values with the sources
+    pub fn start(self, tag_ctxt: &Arc<TagContext>) -> DynResult<()> {
+        for (conf, writes) in self.sources {
+            tag_source::start(conf, Arc::downgrade(tag_ctxt), writes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Route writes to tags from other sources than the pipe. The sources
+/// are started later, when the tag context is shared.
+pub fn setup_tag_sources(
+    player_conf: &PlayerConfig,
+    tag_ctxt: &mut TagContext,
+) -> DynResult<TagSources> {
+    let mut sources = Vec::new();
+    for conf in &player_conf.tag_sources {
+        tag_source::check_supported(conf)?;
+        let (writer, writes) = tag_write_channel();
+        for name in conf.tag_names() {
+            tag_ctxt.set_tag_source(name, writer.clone());
+        }
+        sources.push((conf.clone(), writes));
+    }
+    Ok(TagSources { sources })
+}
+
 struct AlarmFilterState {
+    name: String,
     filter: Box<AlarmBoolOp>,
     matching: HashSet<AlarmId>,
     ignore: HashSet<AlarmId>,
@@ -561,12 +1643,22 @@ struct AlarmFilterState {
 }
 
 impl AlarmFilterState {
-    pub fn handle_notification(&mut self, new_alarm: &AlarmData) -> DynResult<()> {
+    pub fn handle_notification(
+        &mut self,
+        new_alarm: &AlarmData,
+        audit_log: Option<&AuditLog>,
+    ) -> DynResult<()> {
         if new_alarm.state == 128 {
             return Ok(());
         }
         if self.filter.evaluate(new_alarm) {
             if self.matching.insert(AlarmId::from(new_alarm)) {
+                self.audit(
+                    audit_log,
+                    AuditEvent::AlarmRaised,
+                    &new_alarm.name,
+                    new_alarm.priority,
+                );
                 self.update_alarm_counts();
             }
         } else {
@@ -574,12 +1666,62 @@ impl AlarmFilterState {
                 self.ignore.remove(&AlarmId::from(new_alarm));
             }
             if self.matching.remove(&AlarmId::from(new_alarm)) {
+                self.audit(
+                    audit_log,
+                    AuditEvent::AlarmCleared,
+                    &new_alarm.name,
+                    new_alarm.priority,
+                );
                 self.update_alarm_counts();
             }
         }
         Ok(())
     }
 
+    fn audit(&self, audit_log: Option<&AuditLog>, event: AuditEvent, alarm: &str, priority: i32) {
+        if let Some(audit_log) = audit_log {
+            if let Err(e) = audit_log.record(event, alarm, priority, &self.name) {
+                error!("Failed to write audit log: {}", e);
+            }
+        }
+    }
+
+    // Match against a complete list of the current alarms
+    fn replace_alarms(&mut self, alarms: &[AlarmData], audit_log: Option<&AuditLog>) {
+        let matching: HashSet<AlarmId> = alarms
+            .iter()
+            .filter(|alarm| alarm.state != 128 && self.filter.evaluate(alarm))
+            .map(AlarmId::from)
+            .collect();
+        if matching != self.matching {
+            for alarm in alarms {
+                let id = AlarmId::from(alarm);
+                if matching.contains(&id) && !self.matching.contains(&id) {
+                    self.audit(
+                        audit_log,
+                        AuditEvent::AlarmRaised,
+                        &alarm.name,
+                        alarm.priority,
+                    );
+                }
+            }
+            // Only the id is known of alarms that went away
+            for id in self.matching.difference(&matching) {
+                self.audit(
+                    audit_log,
+                    AuditEvent::AlarmCleared,
+                    &format!("#{}", id.id),
+                    0,
+                );
+            }
+            if !self.ignore_permanent {
+                self.ignore.retain(|id| matching.contains(id));
+            }
+            self.matching = matching;
+            self.update_alarm_counts();
+        }
+    }
+
     fn matching_count(&self) -> usize {
         self.matching.difference(&self.ignore).count()
     }
@@ -603,21 +1745,127 @@ impl AlarmFilterState {
             error!("Failed to notify alarm observers: {}", err);
         }
         self.update_tags(count, self.ignore.len());
+        metrics::alarm_filter_matches(&self.name, count);
     }
 }
 
+// Ignored alarms of a filter as stored in the persistence file
+#[derive(Serialize, Deserialize)]
+struct PersistedFilter {
+    ignored: Vec<AlarmId>,
+    permanent: bool,
+}
+
 pub struct AlarmContext {
     alarm_filters: Mutex<HashMap<String, AlarmFilterState>>,
+    persist_writer: Option<PersistWriter>,
+    audit_log: Option<Arc<AuditLog>>,
 }
 
 impl AlarmContext {
+    /// Record alarms starting and stopping to match filters
+    pub fn set_audit_log(&mut self, audit_log: Arc<AuditLog>) {
+        self.audit_log = Some(audit_log);
+    }
+
     pub fn handle_notification(&self, new_alarm: &AlarmData) -> DynResult<()> {
         let mut filters = self
             .alarm_filters
             .lock()
             .map_err(|e| format!("Failed to lock alarm filters: {}", e))?;
+        let mut changed = false;
+        for filter in filters.values_mut() {
+            let ignored = filter.ignore.len();
+            filter.handle_notification(new_alarm, self.audit_log.as_deref())?;
+            changed |= filter.ignore.len() != ignored;
+        }
+        if changed {
+            self.save_persisted(&filters);
+        }
+        Ok(())
+    }
+
+    /// Read ignored alarms from file. A missing file is not an error.
+    /// Filters that are no longer configured are skipped.
+    fn set_persist_file(&mut self, path: PathBuf) -> DynResult<()> {
+        match File::open(&path) {
+            Ok(file) => {
+                let persisted: HashMap<String, PersistedFilter> =
+                    serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+                        format!(
+                            "Failed to read ignored alarms from '{}': {}",
+                            path.to_string_lossy(),
+                            e
+                        )
+                    })?;
+                let filters = self
+                    .alarm_filters
+                    .get_mut()
+                    .unwrap_or_else(|e| e.into_inner());
+                for (name, state) in persisted {
+                    if let Some(filter) = filters.get_mut(&name) {
+                        filter.ignore = state.ignored.into_iter().collect();
+                        filter.ignore_permanent = state.permanent;
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.persist_writer = Some(PersistWriter::new(path, "ignored alarms"));
+        Ok(())
+    }
+
+    // The file is written by another thread
+    fn save_persisted(&self, filters: &HashMap<String, AlarmFilterState>) {
+        let writer = match &self.persist_writer {
+            Some(w) => w,
+            None => return,
+        };
+        let persisted: BTreeMap<&str, PersistedFilter> = filters
+            .iter()
+            .filter(|(_, filter)| !filter.ignore.is_empty())
+            .map(|(name, filter)| {
+                let mut ignored: Vec<AlarmId> = filter.ignore.iter().cloned().collect();
+                ignored.sort();
+                (
+                    name.as_str(),
+                    PersistedFilter {
+                        ignored,
+                        permanent: filter.ignore_permanent,
+                    },
+                )
+            })
+            .collect();
+        writer.save(&persisted);
+    }
+
+    /// Number of matching alarms that aren't ignored, for each filter
+    pub fn filter_counts(&self) -> BTreeMap<String, u32> {
+        match self.alarm_filters.lock() {
+            Ok(filters) => filters
+                .iter()
+                .map(|(name, filter)| (name.clone(), filter.matching_count() as u32))
+                .collect(),
+            Err(_) => BTreeMap::new(),
+        }
+    }
+
+    /// Replace all alarms with the current ones, e.g. after reconnecting.
+    /// Alarms that went away in the meantime no longer match.
+    pub fn replace_alarms(&self, alarms: &[AlarmData]) -> DynResult<()> {
+        let mut filters = self
+            .alarm_filters
+            .lock()
+            .map_err(|e| format!("Failed to lock alarm filters: {}", e))?;
+        let mut changed = false;
         for filter in filters.values_mut() {
-            filter.handle_notification(new_alarm)?;
+            let ignored = filter.ignore.len();
+            filter.replace_alarms(alarms, self.audit_log.as_deref());
+            changed |= filter.ignore.len() != ignored;
+        }
+        if changed {
+            self.save_persisted(&filters);
         }
         Ok(())
     }
@@ -667,6 +1915,7 @@ impl AlarmFunctions for AlarmContext {
                 filter.ignore_permanent = permanent;
                 filter.update_alarm_counts();
             }
+            self.save_persisted(&filters);
         }
     }
 
@@ -676,12 +1925,14 @@ impl AlarmFunctions for AlarmContext {
                 filter.ignore = HashSet::new();
                 filter.update_alarm_counts();
             }
+            self.save_persisted(&filters);
         }
     }
 }
 
 pub fn setup_alarms(
     player_conf: &PlayerConfig,
+    base_dir: &Path,
     tag_setter: Weak<TagContext>,
 ) -> DynResult<AlarmContext> {
     let mut alarm_filters = HashMap::new();
@@ -693,7 +1944,9 @@ pub fn setup_alarms(
         } else {
             tag_setter.clone()
         };
+        metrics::alarm_filter_matches(name, 0);
         let filter_state = AlarmFilterState {
+            name: name.to_string(),
             filter: Box::new(filter_conf.filter_predicate.clone()),
             matching: HashSet::new(),
             observers: watch::channel(0),
@@ -705,17 +1958,63 @@ pub fn setup_alarms(
         };
         alarm_filters.insert(name.to_string(), filter_state);
     }
-    let alarm_ctxt = AlarmContext {
+    let mut alarm_ctxt = AlarmContext {
         alarm_filters: Mutex::new(alarm_filters),
+        persist_writer: None,
+        audit_log: None,
     };
+    if let Some(persist_file) = &player_conf.alarm_persist_file {
+        alarm_ctxt.set_persist_file(base_dir.join(persist_file))?;
+    }
     Ok(alarm_ctxt)
 }
 
 pub struct StateMachineContext {
     state_machines: Vec<Arc<StateMachine>>,
+    signal_actions: Vec<(String, Arc<dyn Action + Send + Sync>)>,
+    state_change_limit: EventLimit,
+    repeat_limit: EventLimit,
 }
 
 impl StateMachineContext {
+    /// How close each state machine has come to the state change limit
+    pub fn state_change_limit_stats(&self) -> BTreeMap<String, EventLimitStats> {
+        self.state_change_limit.stats()
+    }
+
+    /// How close each repeat has come to the repeat limit
+    pub fn repeat_limit_stats(&self) -> BTreeMap<String, EventLimitStats> {
+        self.repeat_limit.stats()
+    }
+
+    /// The action of a state, without running its state machine
+    pub fn state_action(
+        &self,
+        machine: &str,
+        state: &str,
+    ) -> Option<Arc<dyn Action + Send + Sync>> {
+        let machine = self.state_machines.iter().find(|sm| sm.name == machine)?;
+        machine.state_action(machine.find_state_index(state)?)
+    }
+
+    /// Actions to run when a signal is received, by signal name
+    pub fn signal_actions(&self) -> &[(String, Arc<dyn Action + Send + Sync>)] {
+        &self.signal_actions
+    }
+
+    /// All state machines, for observing their states
+    pub fn state_machines(&self) -> Vec<Arc<StateMachine>> {
+        self.state_machines.clone()
+    }
+
+    /// Name of each state machine and its active state
+    pub fn active_states(&self) -> Vec<(String, Option<String>)> {
+        self.state_machines
+            .iter()
+            .map(|sm| (sm.name.clone(), sm.active_state_name()))
+            .collect()
+    }
+
     pub async fn run_all(&self) -> DynResult<()> {
         let mut running = Vec::new();
         for sm in &self.state_machines {
@@ -725,23 +2024,348 @@ impl StateMachineContext {
         Ok(())
     }
 }
+enum VolumeBackend {
+    Hardware(VolumeControl),
+    // Gain applied when playing clips, used if there's no mixer
+    Software(Arc<SoftwareGain>, VolumeScale),
+}
+
+impl VolumeBackend {
+    fn set_volume(&self, volume: f32) -> DynResult<()> {
+        match self {
+            VolumeBackend::Hardware(control) => control.set_volume(volume),
+            VolumeBackend::Software(gain, VolumeScale::Linear) => {
+                gain.set(volume);
+                Ok(())
+            }
+            VolumeBackend::Software(gain, VolumeScale::Db) => {
+                gain.set(volume_mapping::volume_to_amplitude(volume));
+                Ok(())
+            }
+        }
+    }
+
+    fn set_channel_volume(&self, channel: usize, volume: f32) -> DynResult<()> {
+        match self {
+            VolumeBackend::Hardware(control) => control.set_channel_volume(channel, volume),
+            VolumeBackend::Software(gain, VolumeScale::Linear) => {
+                gain.set_channel(channel, volume);
+                Ok(())
+            }
+            VolumeBackend::Software(gain, VolumeScale::Db) => {
+                gain.set_channel(channel, volume_mapping::volume_to_amplitude(volume));
+                Ok(())
+            }
+        }
+    }
+
+    fn refresh(&self) -> DynResult<()> {
+        match self {
+            VolumeBackend::Hardware(control) => control.refresh(),
+            VolumeBackend::Software(..) => Ok(()),
+        }
+    }
+
+    fn get_volume(&self) -> DynResult<f32> {
+        match self {
+            VolumeBackend::Hardware(control) => control.get_volume(),
+            VolumeBackend::Software(gain, VolumeScale::Linear) => Ok(gain.get()),
+            VolumeBackend::Software(gain, VolumeScale::Db) => {
+                Ok(volume_mapping::amplitude_to_volume(gain.get()))
+            }
+        }
+    }
+}
+
+struct VolumeState {
+    backend: VolumeBackend,
+    // Relative level of each channel. Empty if all channels have the
+    // same level.
+    balance: Vec<f32>,
+    // Allowed range of the volume
+    min: f32,
+    max: f32,
+}
+
+impl VolumeState {
+    // The volume of every channel is limited to the allowed range
+    fn set_volume(&self, volume: f32) -> DynResult<()> {
+        let volume = volume.clamp(self.min, self.max);
+        if self.balance.is_empty() {
+            return self.backend.set_volume(volume);
+        }
+        for (channel, level) in self.balance.iter().enumerate() {
+            let channel_volume = (volume * level).clamp(self.min, self.max);
+            self.backend.set_channel_volume(channel, channel_volume)?;
+        }
+        Ok(())
+    }
+
+    // The volume of the loudest channel
+    fn get_volume(&self) -> DynResult<f32> {
+        self.backend.get_volume()
+    }
+
+    // Levels are relative, the loudest channel gets the current volume
+    fn set_balance(&mut self, levels: &[f32]) -> DynResult<()> {
+        let volume = self.get_volume()?;
+        let max = levels.iter().cloned().fold(0.0, f32::max);
+        self.balance = if max > 0.0 {
+            levels.iter().map(|l| l.max(0.0) / max).collect()
+        } else {
+            vec![0.0; levels.len()]
+        };
+        self.set_volume(volume)
+    }
+}
+
+struct VolumeControlEntry {
+    control: Mutex<VolumeState>,
+    // The current volume is written to this tag
+    tag_level: Option<String>,
+    // Last value written to the tag
+    published_level: Mutex<Option<f32>>,
+    // The volume is not allowed to use the full range
+    limited: bool,
+    // Volume outside of scheduled periods
+    default_volume: Option<f32>,
+    schedule: Vec<VolumeSchedule>,
+}
+
+// Milliseconds from now until the time of day t. A full day if t is now.
+fn millis_until(now: NaiveTime, t: NaiveTime) -> u64 {
+    match (t - now).num_milliseconds().rem_euclid(24 * 60 * 60 * 1000) {
+        0 => 24 * 60 * 60 * 1000,
+        ms => ms as u64,
+    }
+}
+
+// Longest time between checks of volume schedules, in case the clock is changed
+const MAX_SCHEDULE_WAIT: u64 = 60_000;
+
+// How often volume controls are checked for changes made by other programs
+const VOLUME_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 pub struct VolumeControlContext {
-    controls: HashMap<String, Arc<Mutex<VolumeControl>>>,
+    controls: HashMap<String, VolumeControlEntry>,
+    tag_setter: Weak<TagContext>,
+}
+
+impl VolumeControlContext {
+    fn control(&self, control: &str) -> DynResult<&VolumeControlEntry> {
+        self.controls
+            .get(control)
+            .ok_or_else(|| format!("No volume control named '{}' found.", control).into())
+    }
+
+    fn publish_level(&self, entry: &VolumeControlEntry) -> DynResult<()> {
+        if let Some(tag) = &entry.tag_level {
+            if let Some(tag_setter) = self.tag_setter.upgrade() {
+                let level = entry.control.lock().unwrap().get_volume()?;
+                *entry.published_level.lock().unwrap() = Some(level);
+                tag_setter.set_tag(tag, &level.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Publish the level if it was changed by someone else
+    fn check_level(&self, entry: &VolumeControlEntry) -> DynResult<()> {
+        let level = {
+            let control = entry.control.lock().unwrap();
+            control.backend.refresh()?;
+            let level = control.get_volume()?;
+            if level < control.min - 1e-3 || level > control.max + 1e-3 {
+                warn!(
+                    "Volume {} is outside of the range {} to {}",
+                    level, control.min, control.max
+                );
+                control.set_volume(level)?;
+                control.get_volume()?
+            } else {
+                level
+            }
+        };
+        let published = *entry.published_level.lock().unwrap();
+        match published {
+            Some(p) if (p - level).abs() < 1e-3 => Ok(()),
+            _ => self.publish_level(entry),
+        }
+    }
+
+    /// Set the scheduled volume when a period starts and restore the
+    /// default volume when it ends. Volumes set by actions are kept
+    /// until the next start or end of a period. Never returns.
+    pub async fn run_schedules(&self) {
+        // Active period of each control. Missing before the first check.
+        let mut active: HashMap<&str, Option<usize>> = HashMap::new();
+        // Volume before a period started. Restored when it ends if
+        // there's no default volume.
+        let mut saved: HashMap<&str, f32> = HashMap::new();
+        loop {
+            let now = chrono::Local::now().time();
+            for (name, entry) in &self.controls {
+                if entry.schedule.is_empty() {
+                    continue;
+                }
+                let current = entry.schedule.iter().position(|s| s.contains(now));
+                let previous = active.insert(name.as_str(), current);
+                if previous == Some(current) {
+                    continue;
+                }
+                let volume = match current {
+                    Some(index) => {
+                        if previous.flatten().is_none() {
+                            if let Ok(volume) = self.get_volume(name) {
+                                saved.insert(name.as_str(), volume);
+                            }
+                        }
+                        Some(entry.schedule[index].volume)
+                    }
+                    // The initial volume is already set at startup
+                    None if previous.is_none() => None,
+                    None => entry
+                        .default_volume
+                        .or_else(|| saved.get(name.as_str()).copied()),
+                };
+                if let Some(volume) = volume {
+                    debug!("Scheduled volume {} for '{}'", volume, name);
+                    if let Err(e) = self.set_volume(name, volume) {
+                        error!("Failed to set scheduled volume for '{}': {}", name, e);
+                    }
+                }
+            }
+            let wait = self
+                .controls
+                .values()
+                .flat_map(|e| e.schedule.iter())
+                .flat_map(|s| [s.from, s.to])
+                .map(|t| millis_until(now, t))
+                .min()
+                .unwrap_or(MAX_SCHEDULE_WAIT)
+                .min(MAX_SCHEDULE_WAIT);
+            // Wake up just after the boundary
+            tokio::time::sleep(Duration::from_millis(wait + 1)).await;
+        }
+    }
+
+    /// Poll all volume controls with a level tag or a limited range for
+    /// changes made by other programs, e.g. alsamixer. Never returns.
+    pub async fn monitor_levels(&self) {
+        loop {
+            tokio::time::sleep(VOLUME_POLL_INTERVAL).await;
+            for (name, entry) in &self.controls {
+                if entry.tag_level.is_none() && !entry.limited {
+                    continue;
+                }
+                if let Err(e) = self.check_level(entry) {
+                    error!("Failed to read volume control '{}': {}", name, e);
+                }
+            }
+        }
+    }
+
+    /// Write the current volume of all controls to their level tags
+    pub fn publish_levels(&self) -> DynResult<()> {
+        for entry in self.controls.values() {
+            self.publish_level(entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl VolumeFunctions for VolumeControlContext {
+    fn set_volume(&self, control: &str, volume: f32) -> DynResult<()> {
+        let entry = self.control(control)?;
+        entry.control.lock().unwrap().set_volume(volume)?;
+        self.publish_level(entry)
+    }
+
+    fn set_volume_unpublished(&self, control: &str, volume: f32) -> DynResult<()> {
+        let entry = self.control(control)?;
+        entry.control.lock().unwrap().set_volume(volume)
+    }
+
+    fn get_volume(&self, control: &str) -> DynResult<f32> {
+        self.control(control)?.control.lock().unwrap().get_volume()
+    }
+
+    fn change_volume(&self, control: &str, step: f32) -> DynResult<()> {
+        let entry = self.control(control)?;
+        {
+            let state = entry.control.lock().unwrap();
+            let volume = state.get_volume()?;
+            state.set_volume((volume + step).clamp(0.0, 1.0))?;
+        }
+        self.publish_level(entry)
+    }
+
+    fn set_balance(&self, control: &str, levels: &[f32]) -> DynResult<()> {
+        let entry = self.control(control)?;
+        entry.control.lock().unwrap().set_balance(levels)?;
+        self.publish_level(entry)
+    }
 }
 
-pub fn setup_volume_control(player_conf: &PlayerConfig) -> DynResult<VolumeControlContext> {
+/// Volume controls without a matching mixer fall back to changing
+/// the gain of the playback, unless a mixer element is configured.
+/// Only one control can use the playback gain.
+pub fn setup_volume_control(
+    player_conf: &PlayerConfig,
+    playback_ctxt: &PlaybackContext,
+    tag_setter: Weak<TagContext>,
+) -> DynResult<VolumeControlContext> {
     let mut ctxt = VolumeControlContext {
         controls: HashMap::new(),
+        tag_setter,
     };
 
+    let mut software_control: Option<&str> = None;
     for conf in &player_conf.volume_config {
-        let control = VolumeControl::new(&conf.device)?;
+        let element = conf.control.as_ref().map(|(n, i)| (n.as_str(), *i));
+        let control = match VolumeControl::new(&conf.device, element, conf.scale) {
+            Ok(control) => VolumeBackend::Hardware(control),
+            Err(e) if element.is_none() => {
+                if let Some(other) = software_control {
+                    return Err(format!(
+                        "Volume controls '{}' and '{}' can't both use software volume: {}",
+                        other, conf.id, e
+                    )
+                    .into());
+                }
+                warn!(
+                    "Using software volume for volume control '{}': {}",
+                    conf.id, e
+                );
+                software_control = Some(&conf.id);
+                VolumeBackend::Software(playback_ctxt.gain.clone(), conf.scale)
+            }
+            Err(e) => return Err(e),
+        };
+        let state = VolumeState {
+            backend: control,
+            balance: Vec::new(),
+            min: conf.min_volume,
+            max: conf.max_volume,
+        };
+        let limited = conf.min_volume > 0.0 || conf.max_volume < 1.0;
         if let Some(volume) = conf.initial_volume {
-            control.set_volume(volume)?;
+            state.set_volume(volume)?;
+        } else if limited {
+            // Move the current volume into the allowed range
+            state.set_volume(state.get_volume()?)?;
         }
-        ctxt.controls
-            .insert(conf.id.clone(), Arc::new(Mutex::new(control)));
+        let entry = VolumeControlEntry {
+            control: Mutex::new(state),
+            tag_level: conf.tag_level.clone(),
+            published_level: Mutex::new(None),
+            limited,
+            default_volume: conf.initial_volume,
+            schedule: conf.schedule.clone(),
+        };
+        ctxt.controls.insert(conf.id.clone(), entry);
     }
+    ctxt.publish_levels()?;
     Ok(ctxt)
 }
 
@@ -751,11 +2375,24 @@ pub fn setup_state_machines(
     tag_ctxt: &Arc<TagContext>,
     volume_control: &Arc<VolumeControlContext>,
     alarm_ctxt: &Arc<AlarmContext>,
+    gpio_outputs: &Arc<GpioOutputs>,
 ) -> DynResult<StateMachineContext> {
+    let limit =
+        |conf: &EventLimitConfig| EventLimit::new(conf.max_events, conf.window, conf.cooldown);
+    let state_change_limit = limit(&player_conf.state_change_limit);
+    let repeat_limit = limit(&player_conf.repeat_limit);
     let mut state_machines = Vec::new();
     let mut state_machine_map = HashMap::new();
     for state_machine_conf in &player_conf.state_machines {
         let state_machine = StateMachine::new(&state_machine_conf.id);
+        state_machine.set_event_limit(state_change_limit.for_user(&state_machine_conf.id));
+        if state_machine_conf.state_tag.is_some() || state_machine_conf.command_tag.is_some() {
+            state_machine.set_state_tags(
+                state_machine_conf.state_tag.clone(),
+                state_machine_conf.command_tag.clone(),
+                tag_ctxt,
+            );
+        }
         for state_conf in &state_machine_conf.states {
             state_machine.add_state(&state_conf.id);
             debug!("Added: {}:{}", state_machine_conf.id, state_conf.id);
@@ -770,16 +2407,272 @@ pub fn setup_state_machines(
             let action_conf = &state_conf.action;
             let build_data = ActionBuildData {
                 playback_ctxt,
+                clip_devices: &player_conf.clip_devices,
                 tag_ctxt,
                 volume_control,
                 alarm_ctxt,
                 state_machine_map: &state_machine_map,
                 current_state_machine: state_machine,
+                current_state: &state_conf.id,
+                repeat_limit: &repeat_limit,
+                smtp: player_conf.smtp.as_ref(),
+                gpio_outputs,
             };
             let action = action_conf_to_action(&build_data, action_conf)?;
             state_machine.set_action(state_index, action);
         }
         state_machines.push(state_machine.clone());
     }
-    Ok(StateMachineContext { state_machines })
+
+    let mut signal_actions = Vec::new();
+    for signal_conf in &player_conf.signals {
+        // Only gotos naming a state machine make sense here
+        let signal_machine = StateMachine::new("signal");
+        let build_data = ActionBuildData {
+            playback_ctxt,
+            clip_devices: &player_conf.clip_devices,
+            tag_ctxt,
+            volume_control,
+            alarm_ctxt,
+            state_machine_map: &state_machine_map,
+            current_state_machine: &signal_machine,
+            current_state: &signal_conf.name,
+            repeat_limit: &repeat_limit,
+            smtp: player_conf.smtp.as_ref(),
+            gpio_outputs,
+        };
+        let action = action_conf_to_action(&build_data, &signal_conf.action)?;
+        signal_actions.push((signal_conf.name.clone(), action));
+    }
+    Ok(StateMachineContext {
+        state_machines,
+        signal_actions,
+        state_change_limit,
+        repeat_limit,
+    })
+}
+
+#[test]
+fn test_clip_processing() {
+    let mut samples = vec![0.0, 0.01, 0.2, -0.5, 0.1, 0.0, 0.001, 0.0];
+    trim_silence(&mut samples, 2, 0.05);
+    assert_eq!(samples, [0.2, -0.5, 0.1, 0.0]);
+    normalize_peak(&mut samples, 0.0);
+    assert_eq!(samples, [0.4, -1.0, 0.2, 0.0]);
+    let mut silent = vec![0.0; 4];
+    trim_silence(&mut silent, 2, 0.05);
+    assert!(silent.is_empty());
+
+    let resampled: Vec<f32> = resample_fast(&[0.0, 1.0, 0.0, -1.0], 1, 2, 1);
+    assert_eq!(resampled, [0.0, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -1.0]);
+    let resampled: Vec<i16> = resample_fast(&[0.0, 1.0, -1.0, 0.5], 2, 1, 1);
+    assert_eq!(resampled, [0, -32767]);
+}
+
+#[test]
+fn test_reload_device_format() {
+    let opened = PlaybackDeviceConfig {
+        device: "hw:1".to_string(),
+        rate: 48000,
+        channels: 2,
+        sample_format: SampleFormat::I16,
+        buffer_size: None,
+    };
+    assert!(check_kept_device("Chimes", Some(&opened), &opened).is_ok());
+    let resampled = PlaybackDeviceConfig {
+        rate: 44100,
+        ..opened.clone()
+    };
+    assert!(check_kept_device("Chimes", Some(&opened), &resampled).is_err());
+    assert!(check_kept_device("Chimes", None, &opened).is_err());
+}
+
+#[test]
+fn test_millis_until() {
+    let t = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    assert_eq!(millis_until(t(21, 0), t(22, 0)), 3_600_000);
+    assert_eq!(millis_until(t(23, 0), t(6, 0)), 7 * 3_600_000);
+    assert_eq!(millis_until(t(6, 0), t(6, 0)), 24 * 3_600_000);
+}
+
+#[test]
+fn test_volume_limits() {
+    let gain = Arc::new(SoftwareGain::new(1.0, 2));
+    let state = VolumeState {
+        backend: VolumeBackend::Software(gain.clone(), VolumeScale::Linear),
+        balance: vec![1.0, 0.5],
+        min: 0.2,
+        max: 0.8,
+    };
+    state.set_volume(1.0).unwrap();
+    assert_eq!(state.get_volume().unwrap(), 0.8);
+    assert_eq!(gain.levels(), [0.8, 0.4]);
+    state.set_volume(0.0).unwrap();
+    assert_eq!(state.get_volume().unwrap(), 0.2);
+    // The quieter channel is kept within the limits too
+    assert_eq!(gain.levels(), [0.2, 0.2]);
+    state.set_volume(0.5).unwrap();
+    assert_eq!(state.get_volume().unwrap(), 0.5);
+    assert_eq!(gain.levels(), [0.5, 0.25]);
+}
+
+#[test]
+fn test_alarm_persistence() {
+    use crate::read_config::AlarmFilterConfig;
+    use crate::testing::AlarmBuilder;
+
+    let dir = std::env::temp_dir().join(format!("audioplayer_alarms_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let conf = PlayerConfig::builder()
+        .alarm_filter(
+            "warnings",
+            AlarmFilterConfig::new("AlarmClassName = 'Warning'").unwrap(),
+        )
+        .alarm_persist_file("ignored.json")
+        .build();
+    let warning = AlarmBuilder::new(4).class("Warning").build();
+
+    let alarm_ctxt = setup_alarms(&conf, &dir, Weak::new()).unwrap();
+    alarm_ctxt.handle_notification(&warning).unwrap();
+    assert_eq!(alarm_ctxt.filter_counts()["warnings"], 1);
+    alarm_ctxt.ignore_matched_alarms("warnings", true);
+    assert_eq!(alarm_ctxt.filter_counts()["warnings"], 0);
+    drop(alarm_ctxt);
+
+    // Still ignored after a restart
+    let alarm_ctxt = setup_alarms(&conf, &dir, Weak::new()).unwrap();
+    alarm_ctxt.handle_notification(&warning).unwrap();
+    assert_eq!(alarm_ctxt.filter_counts()["warnings"], 0);
+    alarm_ctxt.restore_ignored_alarms("warnings");
+    assert_eq!(alarm_ctxt.filter_counts()["warnings"], 1);
+    drop(alarm_ctxt);
+
+    let alarm_ctxt = setup_alarms(&conf, &dir, Weak::new()).unwrap();
+    alarm_ctxt.handle_notification(&warning).unwrap();
+    assert_eq!(alarm_ctxt.filter_counts()["warnings"], 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_tag_persistence() {
+    let path = std::env::temp_dir().join(format!("audioplayer_tags_{}.json", std::process::id()));
+    let (tx, _rx) = tag_write_channel();
+    let mut tag_ctxt = TagContext::new(tx);
+    tag_ctxt.set_persist_file(path.clone()).unwrap();
+    tag_ctxt.add_tag("Volume", None);
+    tag_ctxt.add_tag("Mute", None);
+    tag_ctxt.set_persistent("Volume");
+    tag_ctxt.set_persistent("Mute");
+    tag_ctxt.tag_changed("Volume", "42");
+    tag_ctxt.tag_changed("Mute", "1");
+    drop(tag_ctxt);
+
+    let (tx, mut rx) = tag_write_channel();
+    let mut tag_ctxt = TagContext::new(tx);
+    tag_ctxt.set_persist_file(path.clone()).unwrap();
+    tag_ctxt.add_tag("Volume", None);
+    tag_ctxt.add_tag("Mute", None);
+    tag_ctxt.set_persistent("Volume");
+    tag_ctxt.set_persistent("Mute");
+    assert_eq!(tag_ctxt.values()["Volume"].as_deref(), Some("42"));
+
+    // Only tags without a live value are written back to the pipe
+    let live = HashMap::from([("Mute".to_string(), "0".to_string())]);
+    for (name, value) in &live {
+        tag_ctxt.pipe_tag_changed(name, value);
+    }
+    tag_ctxt.restore_persisted(&live);
+    let req = rx.try_recv().unwrap();
+    assert_eq!(
+        (req.tag_name.as_str(), req.value.as_str()),
+        ("Volume", "42")
+    );
+    assert!(rx.try_recv().is_err());
+    assert_eq!(tag_ctxt.values()["Mute"].as_deref(), Some("0"));
+    drop(tag_ctxt);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_write_limit() {
+    let (tx, mut rx) = tag_write_channel();
+    let mut tag_ctxt = TagContext::new(tx);
+    tag_ctxt.add_tag("Level", None);
+    tag_ctxt.add_tag("Other", None);
+    tag_ctxt.set_max_rate("Level", 2.0);
+    let start = Instant::now();
+
+    // The first write goes through, the rest are coalesced into the latest
+    for value in ["1", "2", "3"] {
+        tag_ctxt.set_tag("Level", value).unwrap();
+    }
+    assert_eq!(rx.try_recv().unwrap().value, "1");
+    assert!(rx.try_recv().is_err());
+    assert_eq!(rx.recv().await.unwrap().value, "3");
+    assert_eq!(start.elapsed(), Duration::from_millis(500));
+
+    // The postponed write counts as the last write
+    tag_ctxt.set_tag("Level", "4").unwrap();
+    assert!(rx.try_recv().is_err());
+    assert_eq!(rx.recv().await.unwrap().value, "4");
+    assert_eq!(start.elapsed(), Duration::from_millis(1000));
+
+    // Other tags aren't limited
+    tag_ctxt.set_tag("Other", "1").unwrap();
+    tag_ctxt.set_tag("Other", "2").unwrap();
+    assert_eq!(rx.try_recv().unwrap().value, "1");
+    assert_eq!(rx.try_recv().unwrap().value, "2");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_tag_history() {
+    use crate::actions::wait_tag::TagCondition;
+    use tokio::time::advance;
+
+    let (tx, _rx) = tag_write_channel();
+    let tag_ctxt = TagContext::new(tx);
+    tag_ctxt.add_tag("Level", None);
+    let period = Duration::from_secs(10);
+    let changes = TagCondition::ChangeCount { count: 3, period };
+    let rise = TagCondition::Rise { delta: 5.0, period };
+    let fall = TagCondition::Fall { delta: 5.0, period };
+    let check = |cond: &TagCondition| {
+        let value = tag_ctxt.get_value("Level").unwrap();
+        cond.check_history("Level", &value, None, &tag_ctxt)
+    };
+
+    tag_ctxt.tag_changed("Level", "10");
+    advance(Duration::from_secs(11)).await;
+    // Repeated values aren't changes
+    for _ in 0..3 {
+        tag_ctxt.tag_changed("Level", "10");
+    }
+    assert_eq!(tag_ctxt.changes_within("Level", period), 0);
+    assert!(!check(&changes));
+
+    tag_ctxt.tag_changed("Level", "12");
+    advance(Duration::from_secs(1)).await;
+    tag_ctxt.tag_changed("Level", "16");
+    assert!(check(&rise));
+    assert!(!check(&fall));
+    assert!(!check(&changes));
+
+    advance(Duration::from_secs(1)).await;
+    tag_ctxt.tag_changed("Level", "14");
+    assert!(check(&changes));
+    assert!(!check(&fall));
+
+    // Compared to the value 10 seconds ago, 14
+    advance(Duration::from_secs(11)).await;
+    tag_ctxt.tag_changed("Level", "9");
+    assert!(check(&fall));
+    assert!(!check(&rise));
+    assert_eq!(tag_ctxt.changes_within("Level", period), 1);
+    assert!(!check(&changes));
+
+    // Once the history has wrapped the oldest entry is a change too
+    for i in 0..TAG_HISTORY_LENGTH + 8 {
+        tag_ctxt.tag_changed("Level", &(100 + i).to_string());
+    }
+    assert_eq!(tag_ctxt.changes_within("Level", period), TAG_HISTORY_LENGTH);
 }
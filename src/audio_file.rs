@@ -0,0 +1,118 @@
+//! Reading clips from audio files
+//!
+//! WAV files are always supported. MP3 and Ogg Vorbis files are decoded
+//! when built with the `compressed-audio` feature.
+
+use crate::util::error::DynResult;
+use std::path::Path;
+
+fn read_wav(file_name: &Path) -> DynResult<(Vec<f32>, u32)> {
+    let mut reader = hound::WavReader::open(file_name)?;
+    let rate = reader.spec().sample_rate;
+    let mut samples = Vec::new();
+    for s in reader.samples::<i16>() {
+        samples.push(f32::from(s?) / 32767.0);
+    }
+    Ok((samples, rate))
+}
+
+#[cfg(feature = "compressed-audio")]
+fn read_compressed(file_name: &Path) -> DynResult<(Vec<f32>, u32)> {
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::errors::Error;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    let file = std::fs::File::open(file_name)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = file_name.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format.default_track().ok_or("No audio track found")?;
+    let track_id = track.id;
+    let rate = track
+        .codec_params
+        .sample_rate
+        .ok_or("Unknown sample rate")?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    let mut samples = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => d,
+            // Skip corrupt frames
+            Err(Error::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend_from_slice(buffer.samples());
+    }
+    Ok((samples, rate))
+}
+
+#[cfg(not(feature = "compressed-audio"))]
+fn read_compressed(_file_name: &Path) -> DynResult<(Vec<f32>, u32)> {
+    Err("Built without MP3 and Ogg support".into())
+}
+
+fn has_extension(file_name: &Path, extensions: &[&str]) -> bool {
+    file_name
+        .extension()
+        .is_some_and(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+/// True if clips can be read from the file, judging by the extension
+pub fn is_supported(file_name: &Path) -> bool {
+    has_extension(file_name, &["wav"])
+        || (cfg!(feature = "compressed-audio") && has_extension(file_name, &["mp3", "ogg"]))
+}
+
+/// Read all samples of a file, interleaved and in the range -1.0 to 1.0.
+/// Returns the samples and the sample rate.
+pub fn read(file_name: &Path) -> DynResult<(Vec<f32>, u32)> {
+    let res = if has_extension(file_name, &["mp3", "ogg"]) {
+        read_compressed(file_name)
+    } else {
+        read_wav(file_name)
+    };
+    res.map_err(|err| {
+        format!(
+            "Failed to read audio file \"{}\": {}",
+            file_name.to_string_lossy(),
+            err
+        )
+        .into()
+    })
+}
+
+#[test]
+fn test_is_supported() {
+    assert!(is_supported(Path::new("clips/alarm.wav")));
+    assert!(is_supported(Path::new("clips/alarm.WAV")));
+    assert!(!is_supported(Path::new("clips/alarm.txt")));
+    assert!(!is_supported(Path::new("clips/alarm")));
+    assert_eq!(
+        is_supported(Path::new("clips/alarm.mp3")),
+        cfg!(feature = "compressed-audio")
+    );
+}
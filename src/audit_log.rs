@@ -0,0 +1,176 @@
+//! Append-only record of all clips played and alarm transitions
+//!
+//! Each line is a JSON object with the time, the event, the clip or
+//! alarm name, the priority and the state or alarm filter that caused
+//! the event. Every line is synced to disk before playback continues.
+//! When the file grows beyond its maximum size it's renamed with the
+//! suffix `.1`, older files get higher numbers.
+
+use crate::util::error::DynResult;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditEvent {
+    Start,
+    End,
+    // Stopped by a clip with higher or equal priority
+    Preempted,
+    // Stopped because the action playing the clip was stopped
+    Cancelled,
+    Failed,
+    // An alarm started matching a filter
+    AlarmRaised,
+    // An alarm no longer matches a filter
+    AlarmCleared,
+}
+
+impl AuditEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuditEvent::Start => "start",
+            AuditEvent::End => "end",
+            AuditEvent::Preempted => "preempted",
+            AuditEvent::Cancelled => "cancelled",
+            AuditEvent::Failed => "failed",
+            AuditEvent::AlarmRaised => "alarm_raised",
+            AuditEvent::AlarmCleared => "alarm_cleared",
+        }
+    }
+
+    fn is_alarm(&self) -> bool {
+        matches!(self, AuditEvent::AlarmRaised | AuditEvent::AlarmCleared)
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    // Rotate before the file grows beyond this size
+    max_size: Option<u64>,
+    // Number of rotated files
+    keep: u32,
+    file: Mutex<LogFile>,
+}
+
+fn format_entry(time: &str, event: AuditEvent, name: &str, priority: i32, source: &str) -> String {
+    let name_key = if event.is_alarm() { "alarm" } else { "clip" };
+    serde_json::json!({
+        "time": time,
+        "event": event.as_str(),
+        name_key: name,
+        "priority": priority,
+        "source": source,
+    })
+    .to_string()
+}
+
+// Path of the rotated file with the given number
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn open_file(path: &Path) -> DynResult<LogFile> {
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open audit log {}: {}", path.display(), e))?;
+    let size = file.metadata()?.len();
+    Ok(LogFile { file, size })
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, max_size: Option<u64>, keep: u32) -> DynResult<AuditLog> {
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file: Mutex::new(open_file(path)?),
+        })
+    }
+
+    // Shift the rotated files and start a new file
+    fn rotate(&self, log_file: &mut LogFile) -> DynResult<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        *log_file = open_file(&self.path)?;
+        Ok(())
+    }
+
+    pub fn record(
+        &self,
+        event: AuditEvent,
+        name: &str,
+        priority: i32,
+        source: &str,
+    ) -> DynResult<()> {
+        let time = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+        let line = format_entry(&time, event, name, priority, source) + "\n";
+        let mut log_file = self.file.lock().unwrap();
+        if let Some(max_size) = self.max_size {
+            if log_file.size > 0 && log_file.size + line.len() as u64 > max_size {
+                self.rotate(&mut log_file)?;
+            }
+        }
+        log_file.file.write_all(line.as_bytes())?;
+        log_file.file.sync_data()?;
+        log_file.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_format_entry() {
+    let line = format_entry(
+        "2024-05-01T12:00:00.000+02:00",
+        AuditEvent::Preempted,
+        "evacuation",
+        10,
+        "alarm:active",
+    );
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(value["event"], "preempted");
+    assert_eq!(value["clip"], "evacuation");
+    assert_eq!(value["priority"], 10);
+    assert_eq!(value["source"], "alarm:active");
+}
+
+#[test]
+fn test_rotation() {
+    let dir = std::env::temp_dir().join(format!("audit_log_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.log");
+    let log = AuditLog::open(&path, Some(200), 2).unwrap();
+    for _ in 0..10 {
+        log.record(AuditEvent::AlarmRaised, "Fire", 10, "horn")
+            .unwrap();
+    }
+    assert!(fs::metadata(&path).unwrap().len() <= 200);
+    assert!(rotated_path(&path, 1).exists());
+    assert!(rotated_path(&path, 2).exists());
+    assert!(!rotated_path(&path, 3).exists());
+    let line = fs::read_to_string(&path).unwrap();
+    let value: serde_json::Value = serde_json::from_str(line.lines().next().unwrap()).unwrap();
+    assert_eq!(value["event"], "alarm_raised");
+    assert_eq!(value["alarm"], "Fire");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,12 +1,19 @@
-use clap::{Arg, Command};
-use cpal::SampleFormat;
+use clap::{Arg, ArgMatches, Command};
+use cpal::traits::{DeviceTrait, HostTrait};
 use log::error;
+use mtp_audioplayer::actions::tag_dispatcher::TagDispatcher;
+use mtp_audioplayer::app_config::{
+    AlarmContext, StateMachineContext, TagContext, VolumeControlContext,
+};
+use mtp_audioplayer::gpio_output::GpioOutputs;
 use mtp_audioplayer::util::error::DynResult;
 use mtp_audioplayer::{
-    app_config, clip_player::ClipPlayer, read_config, read_config::PlayerConfig,
-    sample_buffer::SampleBuffer,
+    app_config, clip_player::ClipPlayer, read_config, read_config::ClipType,
+    read_config::PlayerConfig, sample_buffer::SampleBuffer, sample_buffer::SampleFormat,
 };
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 /*
@@ -51,6 +58,102 @@ async fn main() {
                 .help("Load configuration file")
                 .takes_value(true),
         )
+        .subcommand(Command::new("devices").about(
+            "List audio output devices and their configurations. \
+             Only devices of the default host can be used for playback",
+        ))
+        .subcommand(
+            Command::new("tone")
+                .about("Play a sine tone, no configuration needed")
+                .arg(
+                    Arg::new("freq")
+                        .long("freq")
+                        .value_name("HZ")
+                        .default_value("1000")
+                        .value_parser(clap::value_parser!(f64))
+                        .help("Frequency of the tone"),
+                )
+                .arg(
+                    Arg::new("amplitude")
+                        .long("amplitude")
+                        .value_name("AMPLITUDE")
+                        .default_value("0.5")
+                        .value_parser(clap::value_parser!(f64))
+                        .help("Amplitude between 0.0 and 1.0"),
+                )
+                .arg(
+                    Arg::new("rate")
+                        .long("rate")
+                        .value_name("RATE")
+                        .default_value("48000")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Sample rate"),
+                )
+                .arg(
+                    Arg::new("channels")
+                        .long("channels")
+                        .value_name("CHANNELS")
+                        .default_value("2")
+                        .value_parser(clap::value_parser!(u8))
+                        .help("Number of channels"),
+                )
+                .arg(
+                    Arg::new("duration")
+                        .long("duration")
+                        .value_name("DURATION")
+                        .default_value("5s")
+                        .help("How long to play, e.g. 5s or 500ms"),
+                )
+                .arg(
+                    Arg::new("device")
+                        .long("device")
+                        .value_name("DEVICE")
+                        .default_value("default")
+                        .help("Playback device, as listed by the devices subcommand"),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Measure playback latency and underruns for different buffer sizes")
+                .arg(
+                    Arg::new("buffer_sizes")
+                        .long("buffer-sizes")
+                        .value_name("FRAMES")
+                        .default_value("default,256,512,1024,2048")
+                        .help("Comma separated buffer sizes in frames, or 'default'"),
+                )
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .value_name("COUNT")
+                        .default_value("20")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Number of clicks played for each buffer size"),
+                )
+                .arg(
+                    Arg::new("rate")
+                        .long("rate")
+                        .value_name("RATE")
+                        .default_value("48000")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Sample rate"),
+                )
+                .arg(
+                    Arg::new("channels")
+                        .long("channels")
+                        .value_name("CHANNELS")
+                        .default_value("2")
+                        .value_parser(clap::value_parser!(u8))
+                        .help("Number of channels"),
+                )
+                .arg(
+                    Arg::new("device")
+                        .long("device")
+                        .value_name("DEVICE")
+                        .default_value("default")
+                        .help("Playback device, as listed by the devices subcommand"),
+                ),
+        )
         .subcommand(
             Command::new("playfile")
                 .about("Play a sound file")
@@ -65,15 +168,27 @@ async fn main() {
             ),
         )
         .subcommand(
-            Command::new("action").about("Run a named action").arg(
-                Arg::new("ACTION")
-                    .help("Name of the action to run")
-                    .required(true),
-            ),
+            Command::new("export")
+                .about("Write a clip to a WAV file, processed as when it's played")
+                .arg(
+                    Arg::new("CLIP")
+                        .help("Name of the clip to export")
+                        .required(true),
+                )
+                .arg(Arg::new("FILE").help("WAV file to write").required(true)),
+        )
+        .subcommand(
+            Command::new("action")
+                .about("Run the action of a state, without running the state machine")
+                .arg(
+                    Arg::new("ACTION")
+                        .help("State whose action to run, as MACHINE:STATE")
+                        .required(true),
+                ),
         )
         .subcommand(
             Command::new("toggle_tag")
-                .about("Run the toggle action for the tag")
+                .about("Run all state machines and toggle the tag between 0 and 1")
                 .arg(
                     Arg::new("TAG")
                         .help("Name of the tag to toggle")
@@ -93,13 +208,28 @@ async fn main() {
                 return;
             }
         }
-        base_dir = Some(Path::new(conf_file).parent().unwrap());
+        base_dir = Some(read_config::base_dir(Path::new(conf_file)));
     } else {
         app_config = None;
         base_dir = None;
     };
 
     match args.subcommand() {
+        Some(("devices", _)) => {
+            if let Err(e) = list_devices() {
+                error!("{}", e);
+            }
+        }
+        Some(("tone", args)) => {
+            if let Err(e) = play_tone(args).await {
+                error!("{}", e);
+            }
+        }
+        Some(("bench", args)) => {
+            if let Err(e) = bench(args).await {
+                error!("{}", e);
+            }
+        }
         Some(("playfile", args)) => {
             if let Some(file) = args.value_of("FILE") {
                 if let Err(e) = play_file(file).await {
@@ -124,10 +254,315 @@ async fn main() {
                 }
             }
         }
+        Some(("export", args)) => {
+            let app_conf = match app_config {
+                Some(c) => c,
+                None => {
+                    error!("No configuration");
+                    return;
+                }
+            };
+            let clip = args.value_of("CLIP").unwrap();
+            let file = args.value_of("FILE").unwrap();
+            if let Err(e) = export_clip(&app_conf, base_dir.unwrap(), clip, file) {
+                error!("{}", e);
+            }
+        }
+        Some((cmd @ ("action" | "toggle_tag"), args)) => {
+            let app_conf = match app_config {
+                Some(c) => c,
+                None => {
+                    error!("No configuration");
+                    return;
+                }
+            };
+            let res = if cmd == "action" {
+                run_action(
+                    app_conf,
+                    base_dir.unwrap(),
+                    args.value_of("ACTION").unwrap(),
+                )
+                .await
+            } else {
+                toggle_tag(app_conf, base_dir.unwrap(), args.value_of("TAG").unwrap()).await
+            };
+            if let Err(e) = res {
+                error!("{}", e);
+            }
+        }
         _ => {}
     }
 }
 
+// Everything needed for running actions, without an Open Pipe
+// connection. Tag writes are printed instead.
+struct OfflineContext {
+    tag_ctxt: Arc<TagContext>,
+    _alarm_ctxt: Arc<AlarmContext>,
+    _volume_ctxt: Arc<VolumeControlContext>,
+    state_machine_ctxt: StateMachineContext,
+}
+
+fn setup_offline(app_conf: &PlayerConfig, base_dir: &Path) -> DynResult<OfflineContext> {
+    let (pipe_send_tx, mut pipe_send_rx) = app_config::tag_write_channel();
+    tokio::spawn(async move {
+        while let Some(req) = pipe_send_rx.recv().await {
+            println!("Set tag {} = {}", req.tag_name, req.value);
+            let _ = req.done.send(Ok(()));
+        }
+    });
+    let playback_ctxt = app_config::setup_clip_playback(app_conf, base_dir)?;
+    let tag_ctxt = Arc::new(app_config::setup_tags(app_conf, base_dir, pipe_send_tx)?);
+    let volume_ctxt = Arc::new(app_config::setup_volume_control(
+        app_conf,
+        &playback_ctxt,
+        Arc::downgrade(&tag_ctxt),
+    )?);
+    let alarm_ctxt = Arc::new(app_config::setup_alarms(
+        app_conf,
+        base_dir,
+        Arc::downgrade(&tag_ctxt),
+    )?);
+    // No lines are requested offline, setting an output logs an error
+    let gpio_outputs = Arc::new(GpioOutputs::default());
+    let state_machine_ctxt = app_config::setup_state_machines(
+        app_conf,
+        &playback_ctxt,
+        &tag_ctxt,
+        &volume_ctxt,
+        &alarm_ctxt,
+        &gpio_outputs,
+    )?;
+    Ok(OfflineContext {
+        tag_ctxt,
+        _alarm_ctxt: alarm_ctxt,
+        _volume_ctxt: volume_ctxt,
+        state_machine_ctxt,
+    })
+}
+
+// Gotos in the action have no effect since no state machine is running
+async fn run_action(app_conf: PlayerConfig, base_dir: &Path, name: &str) -> DynResult<()> {
+    let ctxt = setup_offline(&app_conf, base_dir)?;
+    let (machine, state) = name
+        .split_once(':')
+        .ok_or_else(|| format!("Action '{}' is not given as MACHINE:STATE", name))?;
+    let action = ctxt
+        .state_machine_ctxt
+        .state_action(machine, state)
+        .ok_or_else(|| format!("No action for state '{}'", name))?;
+    action.run().await
+}
+
+// Runs until interrupted
+async fn toggle_tag(app_conf: PlayerConfig, base_dir: &Path, tag: &str) -> DynResult<()> {
+    let ctxt = setup_offline(&app_conf, base_dir)?;
+    let (value, _) = ctxt
+        .tag_ctxt
+        .wait_value(tag)
+        .map_err(|e| format!("Tag '{}': {}", tag, e))?;
+    let new_value = match value.as_deref().map(str::trim) {
+        None | Some("") | Some("0") => "1",
+        _ => "0",
+    };
+    let running = ctxt.state_machine_ctxt.run_all();
+    tokio::pin!(running);
+    // Let the state machines reach their initial states first
+    tokio::select! {
+        res = &mut running => return res,
+        _ = tokio::time::sleep(tokio::time::Duration::from_millis(100)) => {}
+    }
+    println!("Toggling tag {} to {}", tag, new_value);
+    ctxt.tag_ctxt.tag_changed(tag, new_value);
+    tokio::select! {
+        res = running => res,
+        res = tokio::signal::ctrl_c() => Ok(res?),
+    }
+}
+
+fn list_devices() -> DynResult<()> {
+    let default_host = cpal::default_host().id();
+    for host_id in cpal::available_hosts() {
+        let host = match cpal::host_from_id(host_id) {
+            Ok(h) => h,
+            Err(e) => {
+                println!("Host {}: {}", host_id.name(), e);
+                continue;
+            }
+        };
+        let default_marker = if host_id == default_host {
+            " (default)"
+        } else {
+            ""
+        };
+        println!("Host {}{}", host_id.name(), default_marker);
+        let default_device = host.default_output_device().and_then(|d| d.name().ok());
+        for device in host.output_devices()? {
+            let name = device.name()?;
+            let default_marker = if Some(&name) == default_device.as_ref() {
+                " (default)"
+            } else {
+                ""
+            };
+            println!("  {}{}", name, default_marker);
+            match device.supported_output_configs() {
+                Ok(configs) => {
+                    for config in configs {
+                        println!(
+                            "    {} channels, {}-{} Hz, {:?}",
+                            config.channels(),
+                            config.min_sample_rate().0,
+                            config.max_sample_rate().0,
+                            config.sample_format()
+                        );
+                    }
+                }
+                Err(e) => println!("    No configurations: {}", e),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn play_tone(args: &ArgMatches) -> DynResult<()> {
+    let rate = *args.get_one::<u32>("rate").unwrap();
+    let channels = *args.get_one::<u8>("channels").unwrap();
+    let tone = ClipType::Sine {
+        amplitude: *args.get_one::<f64>("amplitude").unwrap(),
+        frequency: *args.get_one::<f64>("freq").unwrap(),
+        duration: read_config::parse_duration(args.value_of("duration").unwrap())?,
+    };
+    let samples = app_config::load_clip_type(
+        Path::new(""),
+        &tone,
+        &HashMap::new(),
+        SampleFormat::I16,
+        rate,
+        channels,
+    )?;
+    let device = args.value_of("device").unwrap();
+    let clip_player = ClipPlayer::new(device, rate, channels, SampleFormat::I16)
+        .map_err(|e| format!("Failed to initialise playback: {}", e))?;
+    clip_player.start_clip(samples).await?;
+    clip_player.shutdown();
+    Ok(())
+}
+
+// A short click of full scale samples followed by silence
+fn click(rate: u32, channels: u8) -> Arc<SampleBuffer> {
+    let frames = rate as usize / 20;
+    let mut samples = vec![0i16; frames * usize::from(channels)];
+    for s in &mut samples[..(rate as usize / 1000) * usize::from(channels)] {
+        *s = i16::MAX;
+    }
+    Arc::new(SampleBuffer::I16(samples))
+}
+
+async fn bench(args: &ArgMatches) -> DynResult<()> {
+    let rate = *args.get_one::<u32>("rate").unwrap();
+    let channels = *args.get_one::<u8>("channels").unwrap();
+    let count = *args.get_one::<u32>("count").unwrap();
+    let device = args.value_of("device").unwrap();
+    let click = click(rate, channels);
+    println!(
+        "{:>10} {:>10} {:>10} {:>10} {:>8} {:>8}",
+        "Buffer", "Min ms", "Avg ms", "Max ms", "Errors", "Late"
+    );
+    for size in args.value_of("buffer_sizes").unwrap().split(',') {
+        let size = size.trim();
+        let buffer_size = match size {
+            "default" => None,
+            s => Some(
+                s.parse::<u32>()
+                    .map_err(|e| format!("Invalid buffer size '{}': {}", s, e))?,
+            ),
+        };
+        let clip_player = match ClipPlayer::with_buffer_size(
+            device,
+            rate,
+            channels,
+            SampleFormat::I16,
+            buffer_size,
+        ) {
+            Ok(c) => c,
+            Err(e) => {
+                println!("{:>10} Failed to start playback: {}", size, e);
+                continue;
+            }
+        };
+        let stats = clip_player.stream_stats();
+        let mut latencies = Vec::new();
+        for _ in 0..count {
+            clip_player.start_clip(click.clone()).await?;
+            latencies.push(stats.last_latency_us.load(Ordering::Relaxed) as f64 / 1000.0);
+        }
+        clip_player.shutdown();
+        let min = latencies.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = latencies.iter().cloned().fold(0.0, f64::max);
+        let avg = latencies.iter().sum::<f64>() / latencies.len().max(1) as f64;
+        println!(
+            "{:>10} {:>10.2} {:>10.2} {:>10.2} {:>8} {:>8}",
+            size,
+            min,
+            avg,
+            max,
+            stats.errors.load(Ordering::Relaxed),
+            stats.late_callbacks.load(Ordering::Relaxed)
+        );
+    }
+    Ok(())
+}
+
+fn export_clip(app_conf: &PlayerConfig, base_dir: &Path, clip: &str, file: &str) -> DynResult<()> {
+    let samples = app_config::load_named_clip(app_conf, base_dir, clip)?;
+    let (bits_per_sample, sample_format) = match &*samples {
+        SampleBuffer::I16(_) | SampleBuffer::U16(_) => (16, hound::SampleFormat::Int),
+        SampleBuffer::F32(_) => (32, hound::SampleFormat::Float),
+        SampleBuffer::Stream(source) => {
+            return Err(format!(
+                "Clip {} is streamed from {}, nothing to export",
+                clip,
+                source.path.to_string_lossy()
+            )
+            .into())
+        }
+    };
+    let spec = hound::WavSpec {
+        channels: u16::from(app_conf.channels),
+        sample_rate: app_conf.rate,
+        bits_per_sample,
+        sample_format,
+    };
+    let mut writer = hound::WavWriter::create(file, spec)
+        .map_err(|e| format!("Failed to create {}: {}", file, e))?;
+    match &*samples {
+        SampleBuffer::I16(buf) => {
+            for &s in buf {
+                writer.write_sample(s)?;
+            }
+        }
+        // WAV files only have signed 16 bit samples
+        SampleBuffer::U16(buf) => {
+            for &s in buf {
+                writer.write_sample((i32::from(s) - 32768) as i16)?;
+            }
+        }
+        SampleBuffer::F32(buf) => {
+            for &s in buf {
+                writer.write_sample(s)?;
+            }
+        }
+        SampleBuffer::Stream(_) => {}
+    }
+    writer.finalize()?;
+    println!(
+        "Wrote {} frames to {}",
+        samples.len() / usize::from(app_conf.channels.max(1)),
+        file
+    );
+    Ok(())
+}
+
 async fn play_file(sound_file: &str) -> DynResult<()> {
     let mut samples;
     println!("File: {:?}", sound_file);
@@ -0,0 +1,187 @@
+//! Prevents more than one server from using the same pipe and sound device
+//!
+//! The lock is held on the open lock file, so the OS releases it when
+//! the process exits, whatever the reason. A file left behind by a
+//! crash doesn't prevent a new server from starting.
+
+use mtp_audioplayer::util::error::DynResult;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Lock file holding the PID, removed when dropped
+pub struct InstanceLock {
+    path: PathBuf,
+    // Locked for as long as it's open
+    file: Option<File>,
+}
+
+// FNV-1a, so that the name is the same for every build of the server
+fn stable_hash(parts: &[&str]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for part in parts {
+        // Terminate each part so that moving a character between them changes the hash
+        for b in part.bytes().chain(Some(0)) {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+// Created by the server, so it can be given to the user the server
+// changes to
+#[cfg(unix)]
+const ROOT_RUNTIME_DIR: &str = "/run/mtp_audioplayer";
+
+// XDG_RUNTIME_DIR is private to the user
+fn runtime_dir() -> PathBuf {
+    #[cfg(unix)]
+    if unsafe { libc::geteuid() } == 0 {
+        return PathBuf::from(ROOT_RUNTIME_DIR);
+    }
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        return dir.into();
+    }
+    std::env::temp_dir()
+}
+
+/// Name of the lock file for a pipe and sound device, suffixed by the
+/// instance name if any
+pub fn lock_file_path(bind: &str, device: &str, instance: Option<&str>) -> PathBuf {
+    let hash = stable_hash(&[bind, device]);
+    let name = match instance {
+        Some(instance) => format!("mtp_audioplayer-{:016x}-{}.pid", hash, instance),
+        None => format!("mtp_audioplayer-{:016x}.pid", hash),
+    };
+    runtime_dir().join(name)
+}
+
+// Returns None if another process holds the lock
+#[cfg(unix)]
+fn lock(path: &Path) -> io::Result<Option<File>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    loop {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        // The previous holder removes the file while holding the lock, so
+        // the lock may be on a file that no longer has this path
+        let opened = file.metadata()?;
+        match fs::metadata(path) {
+            Ok(current) if current.dev() == opened.dev() && current.ino() == opened.ino() => {
+                return Ok(Some(file))
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// No sharing makes the open file exclusive
+#[cfg(windows)]
+fn lock(path: &Path) -> io::Result<Option<File>> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    match OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .share_mode(0)
+        .open(path)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl InstanceLock {
+    /// Fails if another process holds the lock
+    pub fn acquire(path: &Path) -> DynResult<InstanceLock> {
+        #[cfg(unix)]
+        if path.parent() == Some(Path::new(ROOT_RUNTIME_DIR)) {
+            fs::create_dir_all(ROOT_RUNTIME_DIR)?;
+        }
+        let mut file = match lock(path) {
+            Ok(Some(file)) => file,
+            Ok(None) => {
+                // Not readable on all platforms
+                let pid = fs::read_to_string(path)
+                    .ok()
+                    .and_then(|pid| pid.trim().parse::<u32>().ok());
+                let holder = match pid {
+                    Some(pid) => format!("Another instance (PID {})", pid),
+                    None => "Another instance".to_string(),
+                };
+                return Err(format!(
+                    "{} is using the same pipe and sound device (lock file {})",
+                    holder,
+                    path.display()
+                )
+                .into());
+            }
+            Err(e) => return Err(format!("Failed to lock file {}: {}", path.display(), e).into()),
+        };
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(InstanceLock {
+            path: path.to_path_buf(),
+            file: Some(file),
+        })
+    }
+
+    /// Give the lock file to the user the server changes to, so that it
+    /// can still be removed. The directory is included if the server
+    /// created it.
+    #[cfg(unix)]
+    pub fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        std::os::unix::fs::chown(&self.path, uid, gid)?;
+        if self.path.parent() == Some(Path::new(ROOT_RUNTIME_DIR)) {
+            std::os::unix::fs::chown(ROOT_RUNTIME_DIR, uid, gid)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for InstanceLock {
+    // Windows can't remove an open file. On Unix the file is removed
+    // while still locked, see lock().
+    fn drop(&mut self) {
+        #[cfg(windows)]
+        drop(self.file.take());
+        let _ = fs::remove_file(&self.path);
+        drop(self.file.take());
+    }
+}
+
+#[test]
+fn test_instance_lock() {
+    let path = std::env::temp_dir().join(format!("instance_lock_{}.pid", std::process::id()));
+    let lock = InstanceLock::acquire(&path).unwrap();
+    assert!(InstanceLock::acquire(&path).is_err());
+    drop(lock);
+    assert!(!path.exists());
+    let lock = InstanceLock::acquire(&path).unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        std::process::id().to_string()
+    );
+    drop(lock);
+
+    // A file left by a process that didn't exit cleanly isn't locked
+    fs::write(&path, "1").unwrap();
+    drop(InstanceLock::acquire(&path).unwrap());
+}
@@ -1,140 +1,922 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgMatches, Command};
 use git_version::git_version;
-use log::{debug, error, warn};
+use log::{debug, error, info, warn, LevelFilter};
 use mtp_audioplayer::actions::tag_setter::TagSetter;
 use mtp_audioplayer::app_config::{
-    self, AlarmContext, StateMachineContext, TagContext, TagSetRequest, VolumeControlContext,
+    self, AlarmContext, PlaybackContext, StateMachineContext, TagContext, TagSetRequest,
+    TagSources, VolumeControlContext,
 };
+use mtp_audioplayer::clip_queue::ClipQueue;
+use mtp_audioplayer::config_check;
 use mtp_audioplayer::daemon;
+use mtp_audioplayer::gpio_output::{self, GpioOutputs};
 use mtp_audioplayer::open_pipe::alarm_data::AlarmData;
 use mtp_audioplayer::open_pipe::connection as open_pipe;
-use mtp_audioplayer::read_config::{self, PlayerConfig};
+use mtp_audioplayer::read_config::{self, ParseMode, PlayerConfig};
+use mtp_audioplayer::util::backoff::Backoff;
 use mtp_audioplayer::util::error::DynResult;
 use open_pipe::{MessageVariant, WriteTagValue};
 use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fmt::Display;
+use std::future::Future;
 use std::path::Path;
+use std::pin::Pin;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use tokio::runtime::{EnterGuard, Runtime};
 use tokio::signal;
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::time::{timeout, Duration};
+use tokio::sync::mpsc::Sender;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{interval, sleep_until, timeout_at, Duration, Instant, Interval};
+
+mod instance_lock;
+mod privileges;
+mod signal_actions;
+mod simulate;
+mod watch;
+
+use instance_lock::InstanceLock;
 
 const DEFAULT_CONFIG_FILE: &str = "mtp_audioplayer.xml";
 
-async fn subscribe_tags(
-    pipe: &mut open_pipe::Connection,
-    tag_names: &mut [String],
-) -> DynResult<(String, HashMap<String, String>)> {
-    let mut tag_values = HashMap::<String, String>::new();
+// Exit codes, so that service managers and scripts can tell failures apart
+const EXIT_CONFIG: u8 = 2; // The configuration is invalid
+const EXIT_STARTUP: u8 = 3; // Failed to prepare the process, e.g. another instance is running
+const EXIT_PIPE: u8 = 4; // Open Pipe failed when reconnecting isn't possible, e.g. shutting down
+const EXIT_RUNTIME: u8 = 5; // A state machine failed
+const EXIT_PANIC: u8 = 6; // Some thread or task panicked
 
-    let value_tags: Vec<&str> = tag_names.iter().map(|c| c.as_str()).collect();
-    debug!("Subcribing: {:?}", value_tags);
-    let subscription = pipe.subscribe_tags(&value_tags).await?;
+// How often the status reported to the service manager is updated
+const STATUS_INTERVAL: Duration = Duration::from_secs(2);
 
-    'next_event: loop {
-        match timeout(Duration::from_secs(1), pipe.get_message()).await {
-            Err(_) => {
-                return Err("No reply for tag subscription".to_string().into());
-            }
-            Ok(res) => match res {
-                Ok(event) => {
-                    if let MessageVariant::NotifySubscribeTag(params) = event.message {
-                        for tag in params.params.tags {
-                            if tag.error.error_code == 0 {
-                                tag_values.insert(tag.data.name, tag.data.value);
-                            } else {
-                                warn!("Failed to subscribe to {}", tag.data.name);
-                            }
-                        }
-                        break 'next_event;
+// How long tasks of a replaced configuration get to finish
+const TASK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long to wait for the first notification of a subscription
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Tag and alarm subscriptions waiting for their first notification
+struct PendingSubscription {
+    tag_subscription: String,
+    alarm_subscription: String,
+    tag_values: Option<HashMap<String, String>>,
+    alarms: Option<Vec<AlarmData>>,
+    deadline: Instant,
+}
+
+// Current tag values and alarms of new subscriptions
+struct Subscribed {
+    tag_subscription: String,
+    tag_values: HashMap<String, String>,
+    alarm_subscription: String,
+    alarms: Vec<AlarmData>,
+}
+
+impl PendingSubscription {
+    async fn send(
+        pipe: &mut open_pipe::Connection,
+        tag_names: &[String],
+    ) -> DynResult<PendingSubscription> {
+        if tag_names.is_empty() {
+            return Err("No tags subscribed".into());
+        }
+        let value_tags: Vec<&str> = tag_names.iter().map(|c| c.as_str()).collect();
+        debug!("Subcribing: {:?}", value_tags);
+        let tag_subscription = pipe.subscribe_tags(&value_tags).await?;
+        debug!("Subcribing alarms");
+        let alarm_subscription = pipe.subscribe_alarms().await?;
+        Ok(PendingSubscription {
+            tag_subscription,
+            alarm_subscription,
+            tag_values: None,
+            alarms: None,
+            deadline: Instant::now() + SUBSCRIBE_TIMEOUT,
+        })
+    }
+
+    // Record the tag values or alarms if the message is a notification
+    // for one of the subscriptions
+    fn handle(&mut self, msg: &open_pipe::Message) -> DynResult<()> {
+        match &msg.message {
+            MessageVariant::NotifySubscribeTag(params)
+                if msg.client_cookie == self.tag_subscription =>
+            {
+                let values = self.tag_values.get_or_insert_with(HashMap::new);
+                for tag in &params.params.tags {
+                    if tag.error.error_code == 0 {
+                        values.insert(tag.data.name.clone(), tag.data.value.clone());
+                    } else {
+                        warn!("Failed to subscribe to {}", tag.data.name);
                     }
                 }
-                Err(e) => return Err(e),
-            },
+            }
+            MessageVariant::NotifySubscribeAlarm(params)
+                if msg.client_cookie == self.alarm_subscription =>
+            {
+                debug!("Subcribed alarms: {:?}", params);
+                let alarms = params.params.alarms.iter().cloned().map(AlarmData::from);
+                self.alarms.get_or_insert_with(Vec::new).extend(alarms);
+            }
+            MessageVariant::ErrorSubscribeTag(error)
+                if msg.client_cookie == self.tag_subscription =>
+            {
+                return Err(error.clone().into());
+            }
+            MessageVariant::ErrorSubscribeAlarm(error)
+                if msg.client_cookie == self.alarm_subscription =>
+            {
+                return Err(error.clone().into());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // The tag values and alarms, once both subscriptions have been notified
+    fn finish(&mut self) -> Option<Subscribed> {
+        if self.tag_values.is_none() || self.alarms.is_none() {
+            return None;
         }
+        Some(Subscribed {
+            tag_subscription: self.tag_subscription.clone(),
+            tag_values: self.tag_values.take().unwrap_or_default(),
+            alarm_subscription: self.alarm_subscription.clone(),
+            alarms: self.alarms.take().unwrap_or_default(),
+        })
     }
-    Ok((subscription, tag_values))
 }
-async fn subscribe_alarms(pipe: &mut open_pipe::Connection) -> DynResult<Vec<AlarmData>> {
-    debug!("Subcribing alarms");
-    let _subscription = pipe.subscribe_alarms().await?;
-    let alarms;
-    'next_event: loop {
-        match timeout(Duration::from_secs(5), pipe.get_message()).await {
-            Err(_) => {
-                return Err("No reply for alarm subscription".to_string().into());
-            }
-            Ok(res) => match res {
-                Ok(event) => match event.message {
-                    MessageVariant::NotifySubscribeAlarm(params) => {
-                        debug!("Subcribed alarms: {:?}", params);
-                        alarms = params
-                            .params
-                            .alarms
-                            .into_iter()
-                            .map(AlarmData::from)
-                            .collect();
-                        break 'next_event;
-                    }
-                    MessageVariant::ErrorSubscribeAlarm(error) => return Err(error.into()),
-                    _ => {}
-                },
-                Err(e) => {
-                    return Err(e);
-                }
-            },
+
+impl Subscribed {
+    // Value of the log level tag
+    fn log_level(&self, app_conf: &PlayerConfig) -> Option<String> {
+        app_conf
+            .log_level_tag
+            .as_ref()
+            .and_then(|t| self.tag_values.get(t))
+            .cloned()
+    }
+}
+
+// Subscribe tags and alarms and wait for their current values
+async fn subscribe(
+    pipe: &mut open_pipe::Connection,
+    tag_names: &[String],
+) -> DynResult<Subscribed> {
+    let mut pending = PendingSubscription::send(pipe, tag_names).await?;
+    loop {
+        let msg = match timeout_at(pending.deadline, pipe.get_message()).await {
+            Ok(res) => res?,
+            Err(_) => return Err("No reply for subscriptions".into()),
+        };
+        // Anything else is left over from a previous configuration
+        pending.handle(&msg)?;
+        if let Some(subscribed) = pending.finish() {
+            return Ok(subscribed);
         }
     }
-    Ok(alarms)
 }
 
-fn trig_on_tag(tag_ctxt: &Arc<TagContext>, tag_name: &str, tag_value: &str) {
-    tag_ctxt.tag_changed(tag_name, tag_value);
+// Cancel the subscriptions with the given cookies
+async fn unsubscribe(
+    pipe: &mut open_pipe::Connection,
+    tag_subscription: &str,
+    alarm_subscription: &str,
+) {
+    if let Err(e) = pipe.unsubscribe_tags(tag_subscription).await {
+        warn!("Failed to unsubscribe tags: {}", e);
+    }
+    if let Err(e) = pipe.unsubscribe_alarms(alarm_subscription).await {
+        warn!("Failed to unsubscribe alarms: {}", e);
+    }
+}
+
+// Contexts built from the configuration
+struct Configuration {
+    app_conf: PlayerConfig,
+    tag_ctxt: Arc<TagContext>,
+    alarm_ctxt: Arc<AlarmContext>,
+    volume_ctxt: Arc<VolumeControlContext>,
+    state_machine_ctxt: Arc<StateMachineContext>,
+    playback_ctxt: Arc<PlaybackContext>,
+    tag_sources: TagSources,
+    gpio_outputs: Arc<GpioOutputs>,
 }
 
-type ConfigurationResult = DynResult<(
-    PlayerConfig,
-    Arc<TagContext>,
-    Arc<AlarmContext>,
-    Arc<VolumeControlContext>,
-    StateMachineContext,
-    UnboundedReceiver<TagSetRequest>,
-)>;
+// How the configuration file is read and values from the command
+// line that replace the ones in the file
+#[derive(Clone)]
+struct ConfigOptions {
+    parse_mode: ParseMode,
+    bind: Option<String>,
+    device: Option<String>,
+    clip_root: Option<String>,
+}
+
+impl ConfigOptions {
+    fn from_args(args: &ArgMatches) -> ConfigOptions {
+        ConfigOptions {
+            parse_mode: if args.is_present("lenient") {
+                ParseMode::Lenient
+            } else {
+                ParseMode::Strict
+            },
+            bind: args.value_of("bind").map(str::to_string),
+            device: args.value_of("device").map(str::to_string),
+            clip_root: args.value_of("clip-root").map(str::to_string),
+        }
+    }
+
+    fn read(&self, path: &Path) -> DynResult<PlayerConfig> {
+        let mut conf = read_config::read_file_with_mode(path, self.parse_mode)?;
+        if let Some(bind) = &self.bind {
+            conf.bind = bind.clone();
+        }
+        if let Some(device) = &self.device {
+            conf.playback_device = device.clone();
+        }
+        if let Some(clip_root) = &self.clip_root {
+            conf.clip_root = clip_root.clone();
+        }
+        Ok(conf)
+    }
+}
 
-fn read_configuration(path: &Path) -> ConfigurationResult {
-    let app_conf = read_config::read_file(path)?;
-    let base_dir = Path::new(path)
-        .parent()
-        .ok_or("Configuration file has no parent")?;
+// Playback devices and GPIO outputs of the running configuration, kept
+// when it is replaced
+struct Kept {
+    playback_ctxt: Arc<PlaybackContext>,
+    gpio_outputs: Arc<GpioOutputs>,
+}
 
-    let (pipe_send_tx, pipe_send_rx) = tokio::sync::mpsc::unbounded_channel::<TagSetRequest>();
-    let playback_ctxt = app_config::setup_clip_playback(&app_conf, base_dir)?;
-    let volume_ctxt = Arc::new(app_config::setup_volume_control(&app_conf)?);
-    let tag_ctxt = app_config::setup_tags(&app_conf, pipe_send_tx)?;
+// Build the contexts. Kept devices are used instead of opening new ones.
+fn setup_configuration(
+    app_conf: PlayerConfig,
+    base_dir: &Path,
+    pipe_send_tx: Sender<TagSetRequest>,
+    kept: Option<&Kept>,
+) -> DynResult<Configuration> {
+    let playback_ctxt = match kept {
+        Some(kept) => app_config::reload_clip_playback(&app_conf, base_dir, &kept.playback_ctxt)?,
+        None => app_config::setup_clip_playback(&app_conf, base_dir)?,
+    };
+    let mut tag_ctxt = app_config::setup_tags(&app_conf, base_dir, pipe_send_tx)?;
+    let tag_sources = app_config::setup_tag_sources(&app_conf, &mut tag_ctxt)?;
     let tag_ctxt = Arc::new(tag_ctxt);
-    let alarm_ctxt = app_config::setup_alarms(&app_conf, Arc::downgrade(&tag_ctxt))?;
+    tag_ctxt.add_tag("AUDIO_SERVER_VERSION", None);
+    let volume_ctxt =
+        app_config::setup_volume_control(&app_conf, &playback_ctxt, Arc::downgrade(&tag_ctxt))?;
+    let volume_ctxt = Arc::new(volume_ctxt);
+    let mut alarm_ctxt = app_config::setup_alarms(&app_conf, base_dir, Arc::downgrade(&tag_ctxt))?;
+    if let (Some(conf), Some(audit_log)) = (&app_conf.audit_log, &playback_ctxt.audit_log) {
+        if conf.alarms {
+            alarm_ctxt.set_audit_log(audit_log.clone());
+        }
+    }
     let alarm_ctxt = Arc::new(alarm_ctxt);
+    // GPIO lines can only be requested once
+    let gpio_outputs = match kept {
+        Some(kept) => kept.gpio_outputs.clone(),
+        None => Arc::new(GpioOutputs::new(&app_conf.gpio_outputs)?),
+    };
     let state_machine_ctxt = app_config::setup_state_machines(
         &app_conf,
         &playback_ctxt,
         &tag_ctxt,
         &volume_ctxt,
         &alarm_ctxt,
+        &gpio_outputs,
     )?;
-    Ok((
+    Ok(Configuration {
+        app_conf,
+        tag_ctxt,
+        alarm_ctxt,
+        volume_ctxt,
+        state_machine_ctxt: Arc::new(state_machine_ctxt),
+        playback_ctxt: Arc::new(playback_ctxt),
+        tag_sources,
+        gpio_outputs,
+    })
+}
+
+// Why a configuration couldn't be started, with the exit code to use if
+// there's nothing to fall back on
+struct StartError {
+    exit_code: u8,
+    message: String,
+}
+
+impl StartError {
+    fn new(exit_code: u8, message: impl Display) -> StartError {
+        StartError {
+            exit_code,
+            message: message.to_string(),
+        }
+    }
+}
+
+// Error mapper adding what failed to the error message
+fn failed<E: Display>(exit_code: u8, what: &'static str) -> impl FnOnce(E) -> StartError {
+    move |e| StartError::new(exit_code, format!("{}: {}", what, e))
+}
+
+// Runtime for the tasks started for a configuration, so that they can be
+// stopped together when the configuration is replaced
+struct TaskRuntime(Option<Runtime>);
+
+impl TaskRuntime {
+    fn new() -> std::io::Result<TaskRuntime> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("config-tasks")
+            .enable_all()
+            .build()?;
+        Ok(TaskRuntime(Some(runtime)))
+    }
+
+    // Tasks spawned while the guard is held run on this runtime
+    fn enter(&self) -> EnterGuard<'_> {
+        self.0.as_ref().unwrap().enter()
+    }
+
+    async fn shutdown(mut self) {
+        if let Some(runtime) = self.0.take() {
+            let _ = tokio::task::spawn_blocking(move || {
+                runtime.shutdown_timeout(TASK_SHUTDOWN_TIMEOUT)
+            })
+            .await;
+        }
+    }
+}
+
+impl Drop for TaskRuntime {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which isn't allowed in async code
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+type RunningStateMachines = Pin<Box<dyn Future<Output = DynResult<()>>>>;
+
+// A configuration that is running
+struct Generation {
+    app_conf: Arc<PlayerConfig>,
+    tag_ctxt: Arc<TagContext>,
+    alarm_ctxt: Arc<AlarmContext>,
+    state_machine_ctxt: Arc<StateMachineContext>,
+    playback_ctxt: Arc<PlaybackContext>,
+    gpio_outputs: Arc<GpioOutputs>,
+    running_sm: RunningStateMachines,
+    // Cookies of the Open Pipe subscriptions
+    tag_subscription: String,
+    alarm_subscription: String,
+    runtime: TaskRuntime,
+    // None while the services are moved to a new configuration
+    services: Option<Services>,
+}
+
+// Services that only one configuration can run at a time, since they
+// hold a port, a bus name or GPIO lines, or are seen from outside
+struct Services {
+    runtime: TaskRuntime,
+    // Dropped after the runtime is shut down
+    #[cfg(feature = "dbus")]
+    _dbus_conn: Option<zbus::Connection>,
+}
+
+impl Services {
+    async fn stop(self) {
+        // The remaining fields are dropped when the runtime is gone
+        self.runtime.shutdown().await;
+    }
+}
+
+// Start the tasks of a configuration and bring it up to date with the
+// subscribed tags and alarms. Its services are started separately.
+fn start_generation(conf: Configuration, subscribed: Subscribed) -> Result<Generation, StartError> {
+    let Configuration {
         app_conf,
         tag_ctxt,
         alarm_ctxt,
         volume_ctxt,
         state_machine_ctxt,
-        pipe_send_rx,
-    ))
+        playback_ctxt,
+        tag_sources,
+        gpio_outputs,
+    } = conf;
+    let runtime = TaskRuntime::new().map_err(failed(EXIT_STARTUP, "Failed to create runtime"))?;
+    let guard = runtime.enter();
+
+    tag_sources
+        .start(&tag_ctxt)
+        .map_err(failed(EXIT_CONFIG, "Failed to start tag sources"))?;
+
+    let schedule_ctxt = volume_ctxt.clone();
+    tokio::spawn(async move { schedule_ctxt.run_schedules().await });
+    tokio::spawn(async move { volume_ctxt.monitor_levels().await });
+    drop(guard);
+
+    for (k, v) in &subscribed.tag_values {
+        tag_ctxt.pipe_tag_changed(k, v);
+    }
+    tag_ctxt.restore_persisted(&subscribed.tag_values);
+    for alarm_data in &subscribed.alarms {
+        if let Err(e) = alarm_ctxt.handle_notification(alarm_data) {
+            error!("Failed to handle alarm notification: {}", e);
+        }
+    }
+
+    // Polled by the main loop, so that errors are noticed
+    let sm_ctxt = state_machine_ctxt.clone();
+    let running_sm = Box::pin(async move { sm_ctxt.run_all().await });
+    Ok(Generation {
+        app_conf: Arc::new(app_conf),
+        tag_ctxt,
+        alarm_ctxt,
+        state_machine_ctxt,
+        playback_ctxt,
+        gpio_outputs,
+        running_sm,
+        tag_subscription: subscribed.tag_subscription,
+        alarm_subscription: subscribed.alarm_subscription,
+        runtime,
+        services: None,
+    })
+}
+
+impl Generation {
+    async fn start_services(&self) -> Result<Services, StartError> {
+        let app_conf = &self.app_conf;
+        let runtime =
+            TaskRuntime::new().map_err(failed(EXIT_STARTUP, "Failed to create runtime"))?;
+        let guard = runtime.enter();
+
+        if let Some(mqtt) = &app_conf.mqtt {
+            #[cfg(feature = "mqtt")]
+            mtp_audioplayer::mqtt_bridge::start(mqtt, &self.tag_ctxt)
+                .map_err(failed(EXIT_CONFIG, "Failed to start MQTT bridge"))?;
+            #[cfg(not(feature = "mqtt"))]
+            return Err(StartError::new(
+                EXIT_CONFIG,
+                format!(
+                    "MQTT broker {} configured, built without MQTT support",
+                    mqtt.host
+                ),
+            ));
+        }
+
+        if let Some(snmp) = &app_conf.snmp {
+            #[cfg(feature = "snmp")]
+            mtp_audioplayer::snmp::start(snmp, &self.alarm_ctxt)
+                .map_err(failed(EXIT_CONFIG, "Failed to start SNMP traps"))?;
+            #[cfg(not(feature = "snmp"))]
+            return Err(StartError::new(
+                EXIT_CONFIG,
+                format!(
+                    "SNMP traps to {} configured, built without SNMP support",
+                    snmp.target
+                ),
+            ));
+        }
+
+        gpio_output::start_following(&self.gpio_outputs, &app_conf.gpio_outputs, &self.alarm_ctxt)
+            .map_err(failed(EXIT_CONFIG, "Failed to start GPIO outputs"))?;
+
+        #[cfg(feature = "dbus")]
+        let mut dbus_conn = None;
+        if let Some(dbus) = &app_conf.dbus {
+            #[cfg(feature = "dbus")]
+            {
+                let filters: Vec<String> = app_conf.named_alarm_filters.keys().cloned().collect();
+                let conn = mtp_audioplayer::dbus_service::start(
+                    dbus,
+                    &self.playback_ctxt,
+                    &self.state_machine_ctxt,
+                    &self.alarm_ctxt,
+                    &filters,
+                )
+                .await
+                .map_err(failed(EXIT_STARTUP, "Failed to start D-Bus service"))?;
+                dbus_conn = Some(conn);
+            }
+            #[cfg(not(feature = "dbus"))]
+            return Err(StartError::new(
+                EXIT_CONFIG,
+                format!(
+                    "D-Bus service configured on {:?} bus, built without D-Bus support",
+                    dbus.bus
+                ),
+            ));
+        }
+
+        if let Some(control_api) = &app_conf.control_api {
+            mtp_audioplayer::control_api::start(
+                control_api,
+                &self.tag_ctxt,
+                &self.alarm_ctxt,
+                &self.playback_ctxt.clip_queue,
+                &self.state_machine_ctxt,
+            )
+            .map_err(failed(EXIT_STARTUP, "Failed to start control API"))?;
+        }
+
+        for (name, action) in self.state_machine_ctxt.signal_actions() {
+            if let Err(e) = signal_actions::spawn(name, action.clone()) {
+                return Err(StartError::new(
+                    EXIT_STARTUP,
+                    format!("Failed to install handler for signal {}: {}", name, e),
+                ));
+            }
+        }
+        drop(guard);
+
+        Ok(Services {
+            runtime,
+            #[cfg(feature = "dbus")]
+            _dbus_conn: dbus_conn,
+        })
+    }
+
+    // What replacing this configuration keeps
+    fn kept(&self) -> Kept {
+        Kept {
+            playback_ctxt: self.playback_ctxt.clone(),
+            gpio_outputs: self.gpio_outputs.clone(),
+        }
+    }
+}
+
+// Bring the running configuration up to date with the subscriptions
+// made after reconnecting. Returns the value of the log level tag.
+fn resubscribed(generation: &mut Generation, subscribed: Subscribed) -> DynResult<Option<String>> {
+    let log_level = subscribed.log_level(&generation.app_conf);
+    for (name, value) in &subscribed.tag_values {
+        generation.tag_ctxt.pipe_tag_changed(name, value);
+    }
+    generation.alarm_ctxt.replace_alarms(&subscribed.alarms)?;
+    generation.tag_subscription = subscribed.tag_subscription;
+    generation.alarm_subscription = subscribed.alarm_subscription;
+    Ok(log_level)
+}
+
+// Wait for the next message. Never returns while disconnected.
+async fn next_message(
+    pipe: &mut Option<open_pipe::Connection>,
+) -> open_pipe::Result<open_pipe::Message> {
+    match pipe {
+        Some(pipe) => pipe.get_message().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Wait for a task to finish. Never returns if there is none.
+async fn task_done<T>(task: &mut Option<JoinHandle<T>>) -> Result<T, JoinError> {
+    match task {
+        Some(handle) => {
+            let res = handle.await;
+            *task = None;
+            res
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Settings that can't be changed without restarting the server
+fn restart_required(current: &PlayerConfig, new: &PlayerConfig) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    if current.bind != new.bind {
+        changed.push("bind");
+    }
+    // Devices are opened once and kept
+    if current.default_device() != new.default_device()
+        || current.playback_devices != new.playback_devices
+    {
+        changed.push("playback device");
+    }
+    // GPIO lines are requested once and kept
+    if current.gpio_outputs != new.gpio_outputs {
+        changed.push("gpio_outputs");
+    }
+    if current.audit_log != new.audit_log {
+        changed.push("audit_log");
+    }
+    if current.syslog != new.syslog {
+        changed.push("syslog");
+    }
+    if current.snapcast != new.snapcast {
+        changed.push("snapcast");
+    }
+    if current.monitor_stream != new.monitor_stream {
+        changed.push("monitor_stream");
+    }
+    if current.metrics != new.metrics {
+        changed.push("metrics");
+    }
+    changed
+}
+
+// Read the configuration file and build its contexts without touching
+// the running configuration. Runs on a blocking thread, since all clips
+// are loaded.
+fn prepare_reload(
+    path: &Path,
+    options: &ConfigOptions,
+    running: &Generation,
+    pipe_send_tx: &Sender<TagSetRequest>,
+) -> JoinHandle<Result<Configuration, String>> {
+    let path = path.to_path_buf();
+    let options = options.clone();
+    let current = running.app_conf.clone();
+    let kept = running.kept();
+    let pipe_send_tx = pipe_send_tx.clone();
+    tokio::task::spawn_blocking(move || {
+        let app_conf = options
+            .read(&path)
+            .map_err(|e| format!("failed to read configuration: {}", e))?;
+        let base_dir = read_config::base_dir(&path);
+        let report = config_check::check_config(&app_conf, base_dir);
+        if !report.is_ok() {
+            return Err(format!("invalid configuration:\n{}", report));
+        }
+        let changed = restart_required(&current, &app_conf);
+        if !changed.is_empty() {
+            return Err(format!("restart needed to change {}", changed.join(", ")));
+        }
+        setup_configuration(app_conf, base_dir, pipe_send_tx, Some(&kept))
+            .map_err(|e| format!("failed to set up configuration: {}", e))
+    })
+}
+
+// Replace the running configuration with a subscribed one. The new
+// configuration is started before the old one is stopped, only the
+// services are moved over. If they fail to start, they are moved back
+// and the running configuration is kept. Returns the value of the log
+// level tag.
+async fn replace_generation(
+    running: &mut Generation,
+    conf: Configuration,
+    subscribed: Subscribed,
+    pipe: &mut open_pipe::Connection,
+) -> Result<Option<String>, StartError> {
+    let log_level = subscribed.log_level(&conf.app_conf);
+    let (tag_subscription, alarm_subscription) = (
+        subscribed.tag_subscription.clone(),
+        subscribed.alarm_subscription.clone(),
+    );
+    let mut new = match start_generation(conf, subscribed) {
+        Ok(new) => new,
+        Err(e) => {
+            unsubscribe(pipe, &tag_subscription, &alarm_subscription).await;
+            return Err(e);
+        }
+    };
+    if let Some(services) = running.services.take() {
+        services.stop().await;
+    }
+    match new.start_services().await {
+        Ok(services) => new.services = Some(services),
+        Err(e) => {
+            unsubscribe(pipe, &tag_subscription, &alarm_subscription).await;
+            match running.start_services().await {
+                Ok(services) => running.services = Some(services),
+                Err(e) => error!("Failed to restart services: {}", e.message),
+            }
+            return Err(e);
+        }
+    }
+    let old = std::mem::replace(running, new);
+    unsubscribe(pipe, &old.tag_subscription, &old.alarm_subscription).await;
+    // Tasks of the old configuration are given time to finish without
+    // holding up the main loop
+    tokio::spawn(old.runtime.shutdown());
+    Ok(log_level)
+}
+
+fn heartbeat_interval(app_conf: &PlayerConfig) -> Option<(String, Interval)> {
+    app_conf
+        .heartbeat
+        .as_ref()
+        .map(|conf| (conf.tag.clone(), interval(conf.interval)))
+}
+
+// Wait for the next heartbeat. Never returns if there's no heartbeat.
+async fn heartbeat_tick(heartbeat: &mut Option<(String, Interval)>) -> String {
+    match heartbeat {
+        Some((tag, interval)) => {
+            interval.tick().await;
+            tag.clone()
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Signals that should make the server exit
+struct ShutdownSignal {
+    #[cfg(unix)]
+    sigterm: signal::unix::Signal,
+}
+
+impl ShutdownSignal {
+    fn new() -> std::io::Result<ShutdownSignal> {
+        Ok(ShutdownSignal {
+            #[cfg(unix)]
+            sigterm: signal::unix::signal(signal::unix::SignalKind::terminate())?,
+        })
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) -> std::io::Result<()> {
+        tokio::select! {
+            res = signal::ctrl_c() => res,
+            _ = self.sigterm.recv() => Ok(())
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) -> std::io::Result<()> {
+        signal::ctrl_c().await
+    }
+}
+
+// Signal that toggles debug logging
+struct DebugSignal {
+    // Not used if SIGUSR2 is bound to an action
+    #[cfg(unix)]
+    sigusr2: Option<signal::unix::Signal>,
+}
+
+impl DebugSignal {
+    fn new(enabled: bool) -> std::io::Result<DebugSignal> {
+        #[cfg(unix)]
+        let sigusr2 = if enabled {
+            Some(signal::unix::signal(
+                signal::unix::SignalKind::user_defined2(),
+            )?)
+        } else {
+            None
+        };
+        #[cfg(not(unix))]
+        let _ = enabled;
+        Ok(DebugSignal {
+            #[cfg(unix)]
+            sigusr2,
+        })
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        match &mut self.sigusr2 {
+            Some(sigusr2) => {
+                sigusr2.recv().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+// Signal that reloads the configuration
+struct ReloadSignal {
+    #[cfg(unix)]
+    sighup: signal::unix::Signal,
+}
+
+impl ReloadSignal {
+    fn new() -> std::io::Result<ReloadSignal> {
+        Ok(ReloadSignal {
+            #[cfg(unix)]
+            sighup: signal::unix::signal(signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        self.sighup.recv().await;
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+// Log level selected by the value of the log level tag. None means
+// the level set at startup.
+fn tag_log_level(value: &str) -> Option<LevelFilter> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" | "0" | "false" => None,
+        "1" | "true" => Some(LevelFilter::Debug),
+        level => match level.parse() {
+            Ok(level) => Some(level),
+            Err(_) => {
+                warn!("Unknown log level '{}'", level);
+                None
+            }
+        },
+    }
+}
+
+// True if the value of the reload tag requests a reload
+fn tag_reload_requested(value: &str) -> bool {
+    !matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "" | "0" | "false"
+    )
+}
+
+// Wait until the deadline. Never returns if there is none.
+async fn deadline_reached(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+// One line summary of what the server is doing
+fn status_line(
+    connection: &str,
+    state_machine_ctxt: &StateMachineContext,
+    clip_queue: &ClipQueue,
+) -> String {
+    let states: Vec<String> = state_machine_ctxt
+        .active_states()
+        .into_iter()
+        .map(|(machine, state)| format!("{}:{}", machine, state.as_deref().unwrap_or("-")))
+        .collect();
+    format!(
+        "{}; states {}; last clip {}",
+        connection,
+        states.join(", "),
+        clip_queue.last_played().as_deref().unwrap_or("none")
+    )
+}
+
+// Wait for the next watchdog notification. Never returns if the
+// watchdog isn't enabled.
+async fn watchdog_tick(watchdog: &mut Option<Interval>) {
+    match watchdog {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Default configuration file for a named instance
+fn instance_config_file(instance: &str) -> String {
+    let (stem, ext) = DEFAULT_CONFIG_FILE.rsplit_once('.').unwrap();
+    format!("{}-{}.{}", stem, instance, ext)
+}
+
+// Check the configuration without opening the audio device or the
+// pipe. Returns the exit code.
+fn check_configuration(path: &Path, options: &ConfigOptions) -> i32 {
+    let app_conf = match options.read(path) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("{}", e);
+            return EXIT_CONFIG.into();
+        }
+    };
+    let base_dir = read_config::base_dir(path);
+    let report = config_check::check_config(&app_conf, base_dir);
+    println!("{}", report);
+    if report.is_ok() {
+        0
+    } else {
+        EXIT_CONFIG.into()
+    }
+}
+
+// Log panics through the active logger and exit, instead of leaving
+// the server running without the task that panicked
+fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        error!("{}\nBacktrace:\n{}", info, backtrace);
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(s) => s.clone(),
+                None => "unknown".to_string(),
+            },
+        };
+        daemon::panicked(&message);
+        log::logger().flush();
+        std::process::exit(EXIT_PANIC.into());
+    }));
 }
 
 type MessageHandler = Box<dyn FnMut(&open_pipe::Message) -> DynResult<bool>>;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let version = env!("CARGO_PKG_VERSION").to_string() + " " + git_version!();
     let app_args = Command::new("MTP audio player")
         .version(version.as_str())
@@ -142,122 +924,528 @@ async fn main() {
         .arg(
             Arg::new("CONF")
                 .default_value(DEFAULT_CONFIG_FILE)
-                .help("Configuration file"),
+                .help("Configuration file or directory of configuration files"),
+        )
+        .arg(
+            Arg::new("check")
+                .long("check")
+                .help("Check the configuration and all clips, then exit"),
+        )
+        .arg(
+            Arg::new("lenient")
+                .long("lenient")
+                .help("Ignore unknown elements and attributes in the configuration"),
+        )
+        .arg(
+            Arg::new("bind")
+                .long("bind")
+                .takes_value(true)
+                .help("Path of the Open Pipe socket, overrides the configuration"),
+        )
+        .arg(
+            Arg::new("device")
+                .long("device")
+                .takes_value(true)
+                .help("Playback device, overrides the configuration"),
+        )
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .takes_value(true)
+                .help("Run as this user after opening the sound device and the pipe"),
+        )
+        .arg(
+            Arg::new("group")
+                .long("group")
+                .takes_value(true)
+                .help("Run as this group after opening the sound device and the pipe"),
+        )
+        .arg(
+            Arg::new("lock-file")
+                .long("lock-file")
+                .takes_value(true)
+                .help("File preventing several instances from using the same pipe and device"),
+        )
+        .arg(
+            Arg::new("simulate")
+                .long("simulate")
+                .value_name("SCRIPT")
+                .takes_value(true)
+                .help("Feed tags and alarms from a script instead of Open Pipe, - for stdin"),
+        )
+        .arg(
+            Arg::new("clip-root")
+                .long("clip-root")
+                .takes_value(true)
+                .help("Directory containing clips, overrides the configuration"),
+        )
+        .subcommand_precedence_over_arg(true)
+        .subcommand(
+            Command::new("watch")
+                .about("Show a continuously updated table of tags and alarms")
+                .arg(
+                    Arg::new("PATTERN")
+                        .default_value("*")
+                        .help("Only show tags and alarms with names matching this pattern"),
+                ),
         );
 
     let app_args = daemon::add_args(app_args);
     let args = app_args.get_matches();
 
-    let conf_path_str = OsStr::new(args.value_of("CONF").unwrap());
+    let instance = args.value_of("instance");
+    // Each instance has its own default configuration
+    let default_conf;
+    let conf_path_str = match instance {
+        Some(instance) if args.occurrences_of("CONF") == 0 => {
+            default_conf = instance_config_file(instance);
+            OsStr::new(&default_conf)
+        }
+        _ => OsStr::new(args.value_of("CONF").unwrap()),
+    };
+
+    let conf_options = ConfigOptions::from_args(&args);
 
-    let logger = daemon::start(&args);
+    if args.is_present("check") {
+        std::process::exit(check_configuration(Path::new(conf_path_str), &conf_options));
+    }
+
+    let mut logger = daemon::start(&args);
+    install_panic_hook();
 
-    let (app_conf, tag_ctxt, alarm_ctxt, _volume_ctxt, state_machine_ctxt, mut pipe_send_rx) =
-        match read_configuration(Path::new(&conf_path_str)) {
-            Ok(ctxt) => ctxt,
+    if let Some(("watch", watch_args)) = args.subcommand() {
+        let app_conf = match conf_options.read(Path::new(&conf_path_str)) {
+            Ok(c) => c,
             Err(e) => {
                 error!(
                     "Failed to read configuration file '{}': {}",
                     conf_path_str.to_string_lossy(),
                     e
                 );
-                return;
+                return ExitCode::from(EXIT_CONFIG);
             }
         };
-    tag_ctxt.add_tag("AUDIO_SERVER_VERSION", None);
-    let mut pipe = match open_pipe::Connection::connect(&app_conf.bind).await {
-        Err(err) => {
-            error!("Failed open connection to {}: {}", app_conf.bind, err);
-            return;
+        let pattern = watch_args.value_of("PATTERN").unwrap();
+        let tag_names: Vec<String> = app_conf
+            .tags
+            .iter()
+            .filter(|t| !t.local)
+            .map(|t| t.name.clone())
+            .collect();
+        if let Err(e) = watch::watch(&app_conf.bind, &tag_names, pattern).await {
+            error!("Watch failed: {}", e);
+            return ExitCode::from(EXIT_PIPE);
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let user = args.value_of("user");
+    let group = args.value_of("group");
+    // Looked up at once so that an unknown name is reported before starting
+    let credentials = if user.is_some() || group.is_some() {
+        match privileges::Credentials::lookup(user, group) {
+            Ok(credentials) => Some(credentials),
+            Err(e) => {
+                error!("Failed to change user: {}", e);
+                return ExitCode::from(EXIT_STARTUP);
+            }
+        }
+    } else {
+        None
+    };
+    // Held until the server exits
+    let instance_lock;
+    let conf_path = Path::new(conf_path_str);
+    // Tag writes from all configurations
+    let (pipe_send_tx, mut pipe_send_rx) = app_config::tag_write_channel();
+    let conf = match conf_options.read(conf_path) {
+        Ok(app_conf) => {
+            // The command line takes precedence
+            if let (Some(url), false) = (&app_conf.syslog, args.is_present("syslog")) {
+                if let Err(e) = mtp_audioplayer::syslog::set_server(url) {
+                    error!("{}", e);
+                }
+            }
+            let lock_path = match args.value_of("lock-file") {
+                Some(path) => path.into(),
+                None => instance_lock::lock_file_path(
+                    &app_conf.bind,
+                    &app_conf.playback_device,
+                    instance,
+                ),
+            };
+            instance_lock = match InstanceLock::acquire(&lock_path) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    error!("Not starting: {}", e);
+                    return ExitCode::from(EXIT_STARTUP);
+                }
+            };
+            // The file must be removable after changing user
+            #[cfg(unix)]
+            if let Some(credentials) = &credentials {
+                if let Err(e) = instance_lock.chown(credentials.uid(), credentials.gid()) {
+                    error!("Failed to change owner of lock file: {}", e);
+                    return ExitCode::from(EXIT_STARTUP);
+                }
+            }
+            let base_dir = read_config::base_dir(conf_path);
+            match setup_configuration(app_conf, base_dir, pipe_send_tx.clone(), None) {
+                Ok(conf) => conf,
+                Err(e) => {
+                    error!("Failed to set up configuration: {}", e);
+                    return ExitCode::from(EXIT_CONFIG);
+                }
+            }
+        }
+        Err(e) => {
+            error!(
+                "Failed to read configuration file '{}': {}",
+                conf_path_str.to_string_lossy(),
+                e
+            );
+            return ExitCode::from(EXIT_CONFIG);
         }
-        Ok(c) => c,
     };
+    // Playback is kept when the configuration is reloaded
+    let playback_ctxt = conf.playback_ctxt.clone();
+    let clip_queue = playback_ctxt.clip_queue.clone();
+    if let Some(script) = args.value_of("simulate") {
+        daemon::ready();
+        let res = simulate::run(
+            script,
+            &conf.tag_ctxt,
+            &conf.alarm_ctxt,
+            &conf.state_machine_ctxt,
+            &clip_queue,
+            pipe_send_rx,
+        )
+        .await;
+        let exit_code = match res {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!("Simulation failed: {}", e);
+                ExitCode::from(EXIT_RUNTIME)
+            }
+        };
+        drop(instance_lock);
+        daemon::exiting(logger);
+        return exit_code;
+    }
+    let bind = conf.app_conf.bind.clone();
+    let mut credentials = credentials;
+    let mut backoff = Backoff::new(conf.app_conf.reconnect.initial, conf.app_conf.reconnect.max);
+    // Open Pipe may not be up yet, e.g. when starting at boot
+    let (pipe, subscribed) = loop {
+        daemon::status(&format!("Connecting to {}", bind));
+        let mut pipe = match open_pipe::Connection::connect(&bind).await {
+            Ok(c) => c,
+            Err(err) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "Failed to connect to {}, retrying in {:?}: {}",
+                    bind, delay, err
+                );
+                sleep_until(Instant::now() + delay).await;
+                continue;
+            }
+        };
 
-    let running_sm = state_machine_ctxt.run_all();
-    tokio::pin!(running_sm);
+        if let Some(credentials) = credentials.take() {
+            if let Err(e) = credentials.apply() {
+                error!("Failed to change user: {}", e);
+                return ExitCode::from(EXIT_STARTUP);
+            }
+            info!("Running as user {:?}, group {:?}", user, group);
+        }
 
-    let mut tag_names: Vec<String> = tag_ctxt.tag_names();
-    match subscribe_tags(&mut pipe, &mut tag_names).await {
+        match subscribe(&mut pipe, &conf.tag_ctxt.tag_names()).await {
+            Ok(subscribed) => break (pipe, subscribed),
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!("Failed to subscribe, retrying in {:?}: {}", delay, e);
+                sleep_until(Instant::now() + delay).await;
+            }
+        }
+    };
+    backoff.reset();
+    if let Some(value) = subscribed.log_level(&conf.app_conf) {
+        daemon::set_log_level(&mut logger, tag_log_level(&value));
+    }
+    let mut generation = match start_generation(conf, subscribed) {
+        Ok(generation) => generation,
         Err(e) => {
-            error!("Failed to subscribe tags: {}", e);
-            return;
+            error!("{}", e.message);
+            return ExitCode::from(e.exit_code);
         }
-        Ok((_, mut values)) => {
-            for (k, v) in values.drain() {
-                tag_ctxt.tag_changed(&k, &v);
-            }
+    };
+    match generation.start_services().await {
+        Ok(services) => generation.services = Some(services),
+        Err(e) => {
+            error!("{}", e.message);
+            return ExitCode::from(e.exit_code);
         }
     }
 
-    if tag_names.is_empty() {
-        error!("No tags subscribed");
-        return;
+    if let Some(snapcast) = &generation.app_conf.snapcast {
+        if let Err(e) = mtp_audioplayer::snapcast::start(snapcast, &clip_queue) {
+            error!("Failed to start Snapcast stream: {}", e);
+            return ExitCode::from(EXIT_STARTUP);
+        }
     }
 
-    match subscribe_alarms(&mut pipe).await {
-        Err(e) => {
-            error!("Failed to subscribe alarms: {}", e);
-            return;
-        }
-        Ok(alarms) => {
-            for alarm_data in alarms {
-                if let Err(e) = alarm_ctxt.handle_notification(&alarm_data) {
-                    error!("Failed to handle alarm notification: {}", e);
-                }
-            }
+    if let Some(monitor_stream) = &generation.app_conf.monitor_stream {
+        if let Err(e) = mtp_audioplayer::monitor_stream::start(monitor_stream, &playback_ctxt) {
+            error!("Failed to start monitor stream: {}", e);
+            return ExitCode::from(EXIT_STARTUP);
         }
     }
 
-    let mut handler_list = Vec::<MessageHandler>::new();
+    if let Some(metrics) = &generation.app_conf.metrics {
+        if let Err(e) = mtp_audioplayer::metrics::start(metrics) {
+            error!("Failed to start metrics: {}", e);
+            return ExitCode::from(EXIT_STARTUP);
+        }
+    }
 
-    // Handle NotifySubscribeTag message
-    handler_list.push(Box::new(|msg: &open_pipe::Message| {
-        if let MessageVariant::NotifySubscribeTag(notify) = &msg.message {
-            for notify_tag in &notify.params.tags {
-                trig_on_tag(&tag_ctxt, &notify_tag.data.name, &notify_tag.data.value);
-            }
+    let mut shutdown_signal = match ShutdownSignal::new() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to install signal handler: {}", e);
+            return ExitCode::from(EXIT_STARTUP);
         }
-        Ok(true)
-    }));
+    };
+    let mut reload_signal = match ReloadSignal::new() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to install signal handler: {}", e);
+            return ExitCode::from(EXIT_STARTUP);
+        }
+    };
 
-    // Handle NotifySubscribeAlarm message
-    handler_list.push(Box::new(|msg: &open_pipe::Message| {
-        if let MessageVariant::NotifySubscribeAlarm(notify) = &msg.message {
-            for notify_alarm in &notify.params.alarms {
-                debug!("Received alarm: {:?}", notify_alarm);
-                let alarm_data = AlarmData::from(notify_alarm.clone());
-                if let Err(e) = alarm_ctxt.handle_notification(&alarm_data) {
-                    error!("Failed to handle alarm notification: {}", e);
-                }
-            }
+    let usr2_bound = generation.app_conf.signals.iter().any(|s| s.name == "usr2");
+    let mut debug_signal = match DebugSignal::new(!usr2_bound) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to install signal handler: {}", e);
+            return ExitCode::from(EXIT_STARTUP);
         }
-        Ok(true)
-    }));
-    if let Err(e) = tag_ctxt
+    };
+    let mut debug_logging = false;
+
+    // Handlers waiting for tag writes to be confirmed
+    let mut handler_list = Vec::<MessageHandler>::new();
+
+    if let Err(e) = generation
+        .tag_ctxt
         .async_set_tag("AUDIO_SERVER_VERSION", version.as_str())
         .await
     {
         error!("Failed to set AUDIO_SERVER_VERSION: {}", e);
     }
 
+    let mut heartbeat = heartbeat_interval(&generation.app_conf);
+    // Wraps at 16 bits so the counter fits in a PLC word
+    let mut heartbeat_count: u16 = 0;
+
+    // Notify the watchdog twice per timeout period
+    let mut watchdog = daemon::watchdog_interval().map(|t| interval(t / 2));
+    // Cleared if writing to the pipe fails, set again when a message is received
+    let mut pipe_ok = true;
+    // None while reconnecting
+    let mut pipe = Some(pipe);
+    let mut reconnect_at: Option<Instant> = None;
+    // Reconnecting is done in steps, so that the loop keeps running
+    let mut connecting: Option<JoinHandle<DynResult<open_pipe::Connection>>> = None;
+    let mut resubscribing: Option<PendingSubscription> = None;
+    let mut status_interval = interval(STATUS_INTERVAL);
+    let mut last_status = String::new();
+    // Last value written to the state change and repeat limit tags
+    let mut last_limit_triggered = [None; 2];
+    let mut write_stats = generation.tag_ctxt.write_queue_stats();
+    let mut last_dropped = 0;
+
+    // Set when shutting down and waiting for clips and tag writes to finish
+    let mut drain_deadline: Option<Instant> = None;
+
+    // A reloaded configuration is first set up, then subscribed, while
+    // the running one keeps going
+    let mut preparing: Option<JoinHandle<Result<Configuration, String>>> = None;
+    let mut subscribing: Option<(Configuration, PendingSubscription)> = None;
+
     daemon::ready();
     let mut done = false;
+    let mut exit_code = ExitCode::SUCCESS;
     while !done {
+        if drain_deadline.is_some() && playback_ctxt.is_idle() && handler_list.is_empty() {
+            break;
+        }
+        let mut stop = false;
+        let mut reload = false;
+        // Set when a reload has either replaced the configuration or failed
+        let mut reload_finished = false;
+        // Set to the reason when the connection has to be reopened
+        let mut lost_connection: Option<String> = None;
         tokio::select! {
-            res = signal::ctrl_c() => {
+            res = shutdown_signal.recv() => {
                 if let Err(e) = res {
-                    error!("Failed to wait for ctrl-c: {}",e);
+                    error!("Failed to wait for signal: {}",e);
                 }
+                stop = true;
+            },
+            _ = daemon::stop_requested(), if drain_deadline.is_none() => {
+                stop = true;
+            },
+            _ = deadline_reached(drain_deadline) => {
+                warn!("Shutting down before all clips and tag writes finished");
+                playback_ctxt.fade_out(generation.app_conf.shutdown_fade).await;
                 done = true;
             },
-            res = pipe_send_rx.recv() => {
-                if let  Some(req) = res {
+            _ = playback_ctxt.wait_idle(),
+                if drain_deadline.is_some() && !playback_ctxt.is_idle() => {},
+            _ = reload_signal.recv(),
+                if drain_deadline.is_none() && pipe.is_some() && resubscribing.is_none() => {
+                reload = true;
+            },
+            res = task_done(&mut preparing) => {
+                let res = res.unwrap_or_else(|e| Err(e.to_string()));
+                match (res, pipe.as_mut().filter(|_| resubscribing.is_none())) {
+                    (Ok(conf), Some(conn)) => {
+                        match PendingSubscription::send(conn, &conf.tag_ctxt.tag_names()).await {
+                            Ok(pending) => subscribing = Some((conf, pending)),
+                            Err(e) => {
+                                error!("Not reloading, failed to subscribe: {}", e);
+                                reload_finished = true;
+                            }
+                        }
+                    }
+                    (Ok(_), None) => {
+                        error!("Not reloading, lost connection to Open Pipe");
+                        reload_finished = true;
+                    }
+                    (Err(e), _) => {
+                        error!("Not reloading, {}", e);
+                        reload_finished = true;
+                    }
+                }
+            },
+            _ = deadline_reached(subscribing.as_ref().map(|(_, pending)| pending.deadline)) => {
+                if let (Some((_, pending)), Some(conn)) = (subscribing.take(), pipe.as_mut()) {
+                    error!("Not reloading, no reply for subscriptions");
+                    unsubscribe(conn, &pending.tag_subscription, &pending.alarm_subscription).await;
+                    reload_finished = true;
+                }
+            },
+            _ = deadline_reached(reconnect_at) => {
+                reconnect_at = None;
+                let bind = bind.clone();
+                connecting = Some(tokio::spawn(async move {
+                    let conn = open_pipe::Connection::connect(&bind).await?;
+                    DynResult::Ok(conn)
+                }));
+            },
+            res = task_done(&mut connecting) => {
+                let res = res.unwrap_or_else(|e| Err(e.into()));
+                let res = match res {
+                    Ok(mut conn) => {
+                        let tag_names = generation.tag_ctxt.tag_names();
+                        let sent = PendingSubscription::send(&mut conn, &tag_names).await;
+                        sent.map(|pending| (conn, pending))
+                    }
+                    Err(e) => Err(e),
+                };
+                match res {
+                    // Done when the subscriptions are notified
+                    Ok((conn, pending)) => {
+                        pipe = Some(conn);
+                        resubscribing = Some(pending);
+                    }
+                    Err(e) => {
+                        let delay = backoff.next_delay();
+                        warn!("Failed to reconnect to {}, retrying in {:?}: {}", bind, delay, e);
+                        reconnect_at = Some(Instant::now() + delay);
+                    }
+                }
+            },
+            _ = deadline_reached(resubscribing.as_ref().map(|pending| pending.deadline)) => {
+                lost_connection = Some("No reply for subscriptions".to_string());
+            },
+            _ = watchdog_tick(&mut watchdog) => {
+                // Reconnecting is not a reason to be restarted
+                if !pipe_ok && pipe.is_some() {
+                    warn!("Open Pipe connection not working, skipping watchdog notification");
+                } else if !playback_ctxt.is_healthy() {
+                    warn!("Playback not running, skipping watchdog notification");
+                } else {
+                    daemon::watchdog();
+                }
+            },
+            _ = debug_signal.recv() => {
+                debug_logging = !debug_logging;
+                let level = debug_logging.then_some(LevelFilter::Debug);
+                daemon::set_log_level(&mut logger, level);
+                info!("Debug logging {}", if debug_logging { "enabled" } else { "disabled" });
+            },
+            _ = status_interval.tick() => {
+                let connection = if drain_deadline.is_some() {
+                    "Shutting down"
+                } else if pipe.is_none() || resubscribing.is_some() {
+                    "Reconnecting"
+                } else if pipe_ok {
+                    "Connected"
+                } else {
+                    "Open Pipe error"
+                };
+                let status = status_line(connection, &generation.state_machine_ctxt, &clip_queue);
+                if status != last_status {
+                    daemon::status(&status);
+                    last_status = status;
+                }
+                let dropped = write_stats.dropped.load(Ordering::Relaxed);
+                if dropped != last_dropped {
+                    warn!("{} tag writes dropped since start", dropped);
+                    last_dropped = dropped;
+                }
+                let limits = [
+                    (
+                        &generation.app_conf.state_change_limit.tag,
+                        generation.state_machine_ctxt.state_change_limit_stats(),
+                    ),
+                    (
+                        &generation.app_conf.repeat_limit.tag,
+                        generation.state_machine_ctxt.repeat_limit_stats(),
+                    ),
+                ];
+                for ((tag, stats), last) in limits.iter().zip(&mut last_limit_triggered) {
+                    if let Some(tag) = tag {
+                        let triggered: u64 = stats.values().map(|s| s.triggered).sum();
+                        if *last != Some(triggered) {
+                            let value = triggered.to_string();
+                            if let Err(e) = generation.tag_ctxt.set_tag(tag, &value) {
+                                error!("Failed to set event limit tag {}: {}", tag, e);
+                            }
+                            *last = Some(triggered);
+                        }
+                    }
+                }
+            },
+            tag = heartbeat_tick(&mut heartbeat) => {
+                heartbeat_count = heartbeat_count.wrapping_add(1);
+                if let Err(e) = generation.tag_ctxt.set_tag(&tag, &heartbeat_count.to_string()) {
+                    error!("Failed to set heartbeat tag {}: {}", tag, e);
+                }
+            },
+            // Writes are kept queued while reconnecting
+            res = pipe_send_rx.recv(), if pipe.is_some() => {
+                if let (Some(req), Some(pipe)) = (res, pipe.as_mut()) {
                     let write_tag = WriteTagValue {
                         name: req.tag_name.clone(),
                             value: req.value
                     };
+                    debug!(tag = req.tag_name.as_str(); "Writing tag {}", req.tag_name);
                     if let Err(e) = pipe.write_tags(&[write_tag]).await {
-                        error!("Failed to write tag to pipe: {}",e);
+                        error!(tag = req.tag_name.as_str(); "Failed to write tag to pipe: {}",e);
+                        pipe_ok = false;
                     }
                     let mut done = Some(req.done);
                     let name = req.tag_name;
@@ -278,13 +1466,92 @@ async fn main() {
                     ));
                 }
             },
-            res = pipe.get_message() => {
+            res = next_message(&mut pipe) => {
                 match res {
-                    Err(e) => {
+                    Err(e) if drain_deadline.is_some() => {
                         error!("Failed to get messge from Open Pipe: {e}");
+                        exit_code = ExitCode::from(EXIT_PIPE);
                         done = true;
                     },
+                    Err(e) => {
+                        lost_connection = Some(format!("Lost connection to Open Pipe: {}", e));
+                    },
                     Ok(msg) => {
+                        pipe_ok = true;
+                        if let Some((_, pending)) = &mut subscribing {
+                            if let Err(e) = pending.handle(&msg) {
+                                error!("Not reloading, failed to subscribe: {}", e);
+                                let (_, pending) = subscribing.take().unwrap();
+                                if let Some(conn) = pipe.as_mut() {
+                                    let (tags, alarms) =
+                                        (&pending.tag_subscription, &pending.alarm_subscription);
+                                    unsubscribe(conn, tags, alarms).await;
+                                }
+                                reload_finished = true;
+                            }
+                        }
+                        match &msg.message {
+                            MessageVariant::NotifySubscribeTag(notify)
+                                if msg.client_cookie == generation.tag_subscription =>
+                            {
+                                let app_conf = &generation.app_conf;
+                                for notify_tag in &notify.params.tags {
+                                    let name = &notify_tag.data.name;
+                                    let value = &notify_tag.data.value;
+                                    if app_conf.log_level_tag.as_ref() == Some(name) {
+                                        let level = tag_log_level(value);
+                                        debug_logging = level.is_some();
+                                        daemon::set_log_level(&mut logger, level);
+                                    }
+                                    if app_conf.reload_tag.as_ref() == Some(name) {
+                                        reload = tag_reload_requested(value);
+                                    }
+                                    generation.tag_ctxt.pipe_tag_changed(name, value);
+                                }
+                            }
+                            MessageVariant::NotifySubscribeAlarm(notify)
+                                if msg.client_cookie == generation.alarm_subscription =>
+                            {
+                                let alarm_ctxt = &generation.alarm_ctxt;
+                                for notify_alarm in &notify.params.alarms {
+                                    debug!("Received alarm: {:?}", notify_alarm);
+                                    let alarm_data = AlarmData::from(notify_alarm.clone());
+                                    if let Err(e) = alarm_ctxt.handle_notification(&alarm_data) {
+                                        error!("Failed to handle alarm notification: {}", e);
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                        // After the generation, which still has the old subscriptions
+                        if let Some(pending) = &mut resubscribing {
+                            let res = pending.handle(&msg).map(|_| pending.finish());
+                            match res {
+                                Ok(Some(subscribed)) => {
+                                    resubscribing = None;
+                                    match resubscribed(&mut generation, subscribed) {
+                                        Ok(log_level) => {
+                                            info!("Reconnected to {}", bind);
+                                            mtp_audioplayer::metrics::openpipe_reconnected();
+                                            backoff.reset();
+                                            if let Some(value) = log_level {
+                                                let level = tag_log_level(&value);
+                                                debug_logging = level.is_some();
+                                                daemon::set_log_level(&mut logger, level);
+                                            }
+                                        }
+                                        Err(e) => {
+                                            lost_connection =
+                                                Some(format!("Failed to resubscribe: {}", e));
+                                        }
+                                    }
+                                }
+                                Ok(None) => {}
+                                Err(e) => {
+                                    lost_connection = Some(format!("Failed to resubscribe: {}", e));
+                                }
+                            }
+                        }
                         let mut i = 0;
                         while i < handler_list.len() {
                             match handler_list[i](&msg) {
@@ -297,7 +1564,9 @@ async fn main() {
                                 },
                                 Err(e) => {
                                     error!("Failed to handle Open Pipe message: {}",e);
-                                    done = true
+                                    exit_code = ExitCode::from(EXIT_PIPE);
+                                    done = true;
+                                    break;
                                 }
                             }
                         }
@@ -305,20 +1574,150 @@ async fn main() {
                 }
             }
 
-            res = &mut running_sm => {
+            res = &mut generation.running_sm => {
                 match res {
                     Ok(_) => {
                         error!("State machine stopped");
+                        exit_code = ExitCode::from(EXIT_RUNTIME);
                         done = true;
                     }
                     Err(err) => {
                         error!("State machine error: {}", err);
+                        exit_code = ExitCode::from(EXIT_RUNTIME);
                         done = true;
                     }
                 }
             }
         }
+        if let Some(reason) = lost_connection.filter(|_| !done) {
+            let delay = backoff.next_delay();
+            error!("{}, reconnecting in {:?}", reason, delay);
+            pipe = None;
+            resubscribing = None;
+            reconnect_at = Some(Instant::now() + delay);
+            // The writes will never be confirmed
+            handler_list.clear();
+            if subscribing.take().is_some() {
+                error!("Not reloading, lost connection to Open Pipe");
+                reload_finished = true;
+            }
+        }
+        if reload && !done && drain_deadline.is_none() {
+            if preparing.is_some() || subscribing.is_some() {
+                warn!("Reload already in progress");
+            } else {
+                info!("Reloading configuration");
+                preparing = Some(prepare_reload(
+                    conf_path,
+                    &conf_options,
+                    &generation,
+                    &pipe_send_tx,
+                ));
+            }
+        }
+        let subscribed = subscribing
+            .as_mut()
+            .and_then(|(_, pending)| pending.finish());
+        if let (Some(subscribed), Some(conn)) = (subscribed, pipe.as_mut()) {
+            let (conf, _) = subscribing.take().unwrap();
+            match replace_generation(&mut generation, conf, subscribed, conn).await {
+                Ok(log_level) => {
+                    info!("Configuration reloaded");
+                    if let Some(value) = log_level {
+                        let level = tag_log_level(&value);
+                        debug_logging = level.is_some();
+                        daemon::set_log_level(&mut logger, level);
+                    }
+                }
+                Err(e) => error!("Not reloading, {}", e.message),
+            }
+            reload_finished = true;
+        }
+        if reload_finished {
+            heartbeat = heartbeat_interval(&generation.app_conf);
+            backoff = Backoff::new(
+                generation.app_conf.reconnect.initial,
+                generation.app_conf.reconnect.max,
+            );
+            last_limit_triggered = [None; 2];
+            let stats = generation.tag_ctxt.write_queue_stats();
+            // A new configuration counts from zero
+            if !Arc::ptr_eq(&stats, &write_stats) {
+                write_stats = stats;
+                last_dropped = 0;
+            }
+            let usr2_bound = generation.app_conf.signals.iter().any(|s| s.name == "usr2");
+            match DebugSignal::new(!usr2_bound) {
+                Ok(s) => debug_signal = s,
+                Err(e) => error!("Failed to install signal handler: {}", e),
+            }
+            if let Some(tag) = &generation.app_conf.reload_tag {
+                // Ready for the next reload
+                if let Err(e) = generation.tag_ctxt.set_tag(tag, "0") {
+                    error!("Failed to reset reload tag {}: {}", tag, e);
+                }
+            }
+        }
+        if stop {
+            // A second request while draining exits immediately
+            if drain_deadline.is_some() {
+                done = true;
+            } else if generation.app_conf.shutdown_drain.is_zero() {
+                playback_ctxt
+                    .fade_out(generation.app_conf.shutdown_fade)
+                    .await;
+                done = true;
+            } else {
+                info!("Shutting down, waiting for clips and tag writes to finish");
+                playback_ctxt.drain();
+                drain_deadline = Some(Instant::now() + generation.app_conf.shutdown_drain);
+            }
+        }
     }
 
+    drop(instance_lock);
     daemon::exiting(logger);
+    exit_code
+}
+
+#[test]
+fn test_tag_reload_requested() {
+    assert!(tag_reload_requested("1"));
+    assert!(tag_reload_requested("TRUE"));
+    assert!(!tag_reload_requested("0"));
+    assert!(!tag_reload_requested(" false "));
+    assert!(!tag_reload_requested(""));
+}
+
+#[test]
+fn test_restart_required() {
+    let current = PlayerConfig::default();
+    let mut new = PlayerConfig {
+        clip_root: "other".to_string(),
+        reload_tag: Some("RELOAD".to_string()),
+        ..PlayerConfig::default()
+    };
+    assert!(restart_required(&current, &new).is_empty());
+    new.rate += 1;
+    new.bind = "other".to_string();
+    assert_eq!(
+        restart_required(&current, &new),
+        vec!["bind", "playback device"]
+    );
+
+    let mut new = PlayerConfig {
+        buffer_size: Some(256),
+        ..PlayerConfig::default()
+    };
+    new.gpio_outputs.push(read_config::GpioOutputConfig {
+        name: "beacon".to_string(),
+        chip: "gpiochip0".to_string(),
+        line: 17,
+        active_low: false,
+        follow_filter: None,
+    });
+    assert_eq!(
+        restart_required(&current, &new),
+        vec!["playback device", "gpio_outputs"]
+    );
 }
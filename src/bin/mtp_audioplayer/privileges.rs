@@ -0,0 +1,105 @@
+//! Run as an unprivileged user once the sound device and the pipe are open
+
+use mtp_audioplayer::util::error::DynResult;
+
+#[cfg(unix)]
+fn os_error(what: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    format!("{} failed: {}", what, std::io::Error::last_os_error()).into()
+}
+
+/// User and group to change to
+#[cfg(unix)]
+pub struct Credentials {
+    user: Option<(std::ffi::CString, libc::uid_t)>,
+    gid: Option<libc::gid_t>,
+}
+
+#[cfg(unix)]
+impl Credentials {
+    /// Look up the user and group. If only a user is given the primary
+    /// group of that user is used.
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> DynResult<Credentials> {
+        use std::ffi::CString;
+        let mut user_gid = None;
+        let user = match user {
+            Some(name) => {
+                let c_name = CString::new(name)?;
+                let pw = unsafe { libc::getpwnam(c_name.as_ptr()) };
+                if pw.is_null() {
+                    return Err(format!("No user named '{}'", name).into());
+                }
+                user_gid = Some(unsafe { (*pw).pw_gid });
+                Some((c_name, unsafe { (*pw).pw_uid }))
+            }
+            None => None,
+        };
+        let gid = match group {
+            Some(name) => {
+                let c_name = CString::new(name)?;
+                let gr = unsafe { libc::getgrnam(c_name.as_ptr()) };
+                if gr.is_null() {
+                    return Err(format!("No group named '{}'", name).into());
+                }
+                Some(unsafe { (*gr).gr_gid })
+            }
+            None => user_gid,
+        };
+        Ok(Credentials { user, gid })
+    }
+
+    pub fn uid(&self) -> Option<libc::uid_t> {
+        self.user.as_ref().map(|(_, uid)| *uid)
+    }
+
+    pub fn gid(&self) -> Option<libc::gid_t> {
+        self.gid
+    }
+
+    /// Change to the user and group. Supplementary groups are set to
+    /// those of the user.
+    pub fn apply(&self) -> DynResult<()> {
+        if let Some(gid) = self.gid {
+            // Don't keep the supplementary groups of root
+            match &self.user {
+                Some((c_name, _)) => {
+                    if unsafe { libc::initgroups(c_name.as_ptr(), gid as _) } != 0 {
+                        return Err(os_error("initgroups"));
+                    }
+                }
+                None => {
+                    if unsafe { libc::setgroups(1, &gid) } != 0 {
+                        return Err(os_error("setgroups"));
+                    }
+                }
+            }
+            if unsafe { libc::setgid(gid) } != 0 {
+                return Err(os_error("setgid"));
+            }
+        }
+        if let Some((_, uid)) = self.user {
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(os_error("setuid"));
+            }
+            // Some systems let a process regain root after setuid
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err("Root privileges could be regained after setuid".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Never created, since lookup always fails
+#[cfg(not(unix))]
+pub enum Credentials {}
+
+#[cfg(not(unix))]
+impl Credentials {
+    pub fn lookup(_user: Option<&str>, _group: Option<&str>) -> DynResult<Credentials> {
+        Err("Changing user or group is only supported on Unix".into())
+    }
+
+    pub fn apply(&self) -> DynResult<()> {
+        match *self {}
+    }
+}
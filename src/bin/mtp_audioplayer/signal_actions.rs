@@ -0,0 +1,84 @@
+//! Runs configured actions when the server receives a signal
+//!
+//! On Unix the signals usr1 and usr2 can be used. On Windows the name is
+//! that of an event object, the action is run each time the event is set.
+
+use log::{error, info};
+use mtp_audioplayer::actions::action::Action;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Run `action` each time the signal `name` is received
+pub fn spawn(name: &str, action: Arc<dyn Action + Send + Sync>) -> std::io::Result<()> {
+    let mut received = listen(name)?;
+    let name = name.to_string();
+    tokio::spawn(async move {
+        while received.recv().await.is_some() {
+            info!("Received signal {}", name);
+            if let Err(e) = action.run().await {
+                error!("Action for signal {} failed: {}", name, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Signals received while the action is running are merged into one
+#[cfg(unix)]
+fn listen(name: &str) -> std::io::Result<mpsc::Receiver<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let kind = match name {
+        "usr1" => SignalKind::user_defined1(),
+        "usr2" => SignalKind::user_defined2(),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported signal {}", name),
+            ))
+        }
+    };
+    let mut signal = signal(kind)?;
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        while signal.recv().await.is_some() {
+            let _ = tx.try_send(());
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(windows)]
+fn listen(name: &str) -> std::io::Result<mpsc::Receiver<()>> {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use tokio::sync::mpsc::error::TrySendError;
+    use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+    use winapi::um::winbase::{INFINITE, WAIT_OBJECT_0};
+    use winapi::um::winnt::HANDLE;
+
+    let wide: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+    // Auto reset, so that each SetEvent is seen once
+    let event = unsafe { CreateEventW(std::ptr::null_mut(), 0, 0, wide.as_ptr()) };
+    if event.is_null() {
+        return Err(std::io::Error::last_os_error());
+    }
+    // Handles aren't Send, but the event is never closed
+    let event = event as usize;
+    let (tx, rx) = mpsc::channel(1);
+    let name = name.to_string();
+    std::thread::spawn(move || loop {
+        let res = unsafe { WaitForSingleObject(event as HANDLE, INFINITE) };
+        if res != WAIT_OBJECT_0 {
+            error!(
+                "Waiting for event {} failed: {}",
+                name,
+                std::io::Error::last_os_error()
+            );
+            break;
+        }
+        if let Err(TrySendError::Closed(_)) = tx.try_send(()) {
+            break;
+        }
+    });
+    Ok(rx)
+}
@@ -0,0 +1,287 @@
+//! Runs the configuration without an Open Pipe connection
+//!
+//! Tag changes and alarms are read from a script, one command per line:
+//!
+//! ```text
+//! # Comment
+//! tag NAME VALUE
+//! alarm {"name": "Fire", "state": 1, "priority": 10}
+//! wait 1.5s
+//! at 10s
+//! ```
+//!
+//! `at` waits until the given time since the start of the simulation.
+//! Scripts starting with `<` are XML, where each element may have an
+//! `at` attribute:
+//!
+//! ```xml
+//! <simulation>
+//!   <tag name="NAME" at="2s">VALUE</tag>
+//!   <alarm at="3s" name="Fire" state="1" priority="10"/>
+//!   <wait>1.5s</wait>
+//! </simulation>
+//! ```
+//!
+//! Tag writes made by the server are logged. The simulation ends when
+//! the script is done and no clip is playing.
+
+use chrono::Utc;
+use log::info;
+use mtp_audioplayer::app_config::{AlarmContext, StateMachineContext, TagContext, TagSetRequest};
+use mtp_audioplayer::clip_queue::ClipQueue;
+use mtp_audioplayer::open_pipe::alarm_data::AlarmData;
+use mtp_audioplayer::read_config;
+use mtp_audioplayer::util::error::DynResult;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::Instant;
+
+// Alarm fields with integer values
+const ALARM_INT_FIELDS: [&str; 5] = ["id", "instance_id", "priority", "state", "state_machine"];
+
+#[derive(Debug, PartialEq)]
+enum ScriptCommand {
+    Tag { name: String, value: String },
+    Alarm(String),
+    Wait(Duration),
+    // Time since the start of the simulation
+    At(Duration),
+}
+
+fn parse_script(script: &str) -> DynResult<Vec<ScriptCommand>> {
+    let mut commands = Vec::new();
+    for (line_no, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+        let command = match command {
+            "tag" => match args.split_once(char::is_whitespace) {
+                Some((name, value)) => ScriptCommand::Tag {
+                    name: name.to_string(),
+                    value: value.trim().to_string(),
+                },
+                None => ScriptCommand::Tag {
+                    name: args.to_string(),
+                    value: String::new(),
+                },
+            },
+            "alarm" => ScriptCommand::Alarm(args.to_string()),
+            "wait" => ScriptCommand::Wait(
+                read_config::parse_duration(args)
+                    .map_err(|e| format!("Line {}: {}", line_no + 1, e))?,
+            ),
+            "at" => ScriptCommand::At(
+                read_config::parse_duration(args)
+                    .map_err(|e| format!("Line {}: {}", line_no + 1, e))?,
+            ),
+            _ => return Err(format!("Line {}: Unknown command '{}'", line_no + 1, command).into()),
+        };
+        if let ScriptCommand::Tag { name, .. } = &command {
+            if name.is_empty() {
+                return Err(format!("Line {}: No tag name", line_no + 1).into());
+            }
+        }
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+fn parse_xml_script(script: &str) -> DynResult<Vec<ScriptCommand>> {
+    let doc = roxmltree::Document::parse(script)?;
+    let mut commands = Vec::new();
+    for node in doc.root_element().children().filter(|n| n.is_element()) {
+        let line = doc.text_pos_at(node.range().start).row;
+        let duration = |s: &str| {
+            read_config::parse_duration(s.trim()).map_err(|e| format!("Line {}: {}", line, e))
+        };
+        if let Some(at) = node.attribute("at") {
+            commands.push(ScriptCommand::At(duration(at)?));
+        }
+        let text = node.text().unwrap_or_default().trim();
+        let command = match node.tag_name().name() {
+            "tag" => ScriptCommand::Tag {
+                name: node
+                    .attribute("name")
+                    .ok_or_else(|| format!("Line {}: No tag name", line))?
+                    .to_string(),
+                value: text.to_string(),
+            },
+            "alarm" => {
+                let mut alarm = serde_json::Map::new();
+                for attr in node.attributes().iter().filter(|a| a.name() != "at") {
+                    let value = if ALARM_INT_FIELDS.contains(&attr.name()) {
+                        let int = attr.value().parse::<i64>().map_err(|e| {
+                            format!("Line {}: Invalid {}: {}", line, attr.name(), e)
+                        })?;
+                        serde_json::Value::from(int)
+                    } else {
+                        serde_json::Value::from(attr.value())
+                    };
+                    alarm.insert(attr.name().to_string(), value);
+                }
+                ScriptCommand::Alarm(serde_json::Value::Object(alarm).to_string())
+            }
+            "wait" => ScriptCommand::Wait(duration(text)?),
+            // Only a timestamp
+            "at" => continue,
+            name => return Err(format!("Line {}: Unknown command '{}'", line, name).into()),
+        };
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+// Fields missing from the JSON object get default values
+fn parse_alarm(json: &str) -> DynResult<AlarmData> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    let string = |key: &str| {
+        value
+            .get(key)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let int = |key: &str| value.get(key).and_then(|v| v.as_i64()).unwrap_or(0) as i32;
+    Ok(AlarmData {
+        name: string("name"),
+        id: int("id"),
+        alarm_class_name: string("alarm_class_name"),
+        alarm_class_symbol: string("alarm_class_symbol"),
+        event_text: string("event_text"),
+        instance_id: int("instance_id"),
+        priority: int("priority"),
+        state: int("state"),
+        state_text: string("state_text"),
+        state_machine: int("state_machine"),
+        modification_time: Utc::now(),
+    })
+}
+
+async fn run_script(
+    commands: Vec<ScriptCommand>,
+    tag_ctxt: &TagContext,
+    alarm_ctxt: &AlarmContext,
+    clip_queue: &ClipQueue,
+) -> DynResult<()> {
+    let start = Instant::now();
+    for command in commands {
+        match command {
+            ScriptCommand::Tag { name, value } => {
+                info!("Simulated tag {} = {}", name, value);
+                tag_ctxt.pipe_tag_changed(&name, &value);
+            }
+            ScriptCommand::Alarm(json) => {
+                let alarm = parse_alarm(&json)?;
+                info!("Simulated alarm {} in state {}", alarm.name, alarm.state);
+                alarm_ctxt.handle_notification(&alarm)?;
+            }
+            ScriptCommand::Wait(duration) => tokio::time::sleep(duration).await,
+            ScriptCommand::At(time) => tokio::time::sleep_until(start + time).await,
+        }
+    }
+    clip_queue.wait_idle().await;
+    Ok(())
+}
+
+/// Run the script at `path`, or standard input if the path is "-"
+pub async fn run(
+    path: &str,
+    tag_ctxt: &Arc<TagContext>,
+    alarm_ctxt: &Arc<AlarmContext>,
+    state_machine_ctxt: &StateMachineContext,
+    clip_queue: &ClipQueue,
+    mut pipe_send_rx: Receiver<TagSetRequest>,
+) -> DynResult<()> {
+    let script = if path == "-" {
+        let mut script = String::new();
+        std::io::stdin().read_to_string(&mut script)?;
+        script
+    } else {
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read simulation script {}: {}", path, e))?
+    };
+    let commands = if script.trim_start().starts_with('<') {
+        parse_xml_script(&script)?
+    } else {
+        parse_script(&script)?
+    };
+    let running_sm = state_machine_ctxt.run_all();
+    tokio::pin!(running_sm);
+    let script = run_script(commands, tag_ctxt, alarm_ctxt, clip_queue);
+    tokio::pin!(script);
+    loop {
+        tokio::select! {
+            res = &mut running_sm => {
+                res?;
+                return Err("State machine stopped".into());
+            }
+            res = &mut script => return res,
+            Some(req) = pipe_send_rx.recv() => {
+                info!("Tag {} written with {}", req.tag_name, req.value);
+                let _ = req.done.send(Ok(()));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_script() {
+    let script =
+        "# Test\n\ntag Alarm_Horn 1\nwait 500ms\nalarm {\"name\": \"Fire\"}\ntag Empty\nat 2s\n";
+    let commands = parse_script(script).unwrap();
+    assert_eq!(
+        commands,
+        [
+            ScriptCommand::Tag {
+                name: "Alarm_Horn".to_string(),
+                value: "1".to_string()
+            },
+            ScriptCommand::Wait(Duration::from_millis(500)),
+            ScriptCommand::Alarm("{\"name\": \"Fire\"}".to_string()),
+            ScriptCommand::Tag {
+                name: "Empty".to_string(),
+                value: String::new()
+            },
+            ScriptCommand::At(Duration::from_secs(2)),
+        ]
+    );
+    assert!(parse_script("play x").is_err());
+    assert_eq!(
+        parse_alarm("{\"name\": \"Fire\", \"state\": 1}")
+            .unwrap()
+            .state,
+        1
+    );
+}
+
+#[test]
+fn test_parse_xml_script() {
+    let script = r#"<simulation>
+  <tag name="Alarm_Horn" at="1s">1</tag>
+  <wait>500ms</wait>
+  <alarm at="2s" name="Fire" state="1"/>
+  <at at="5s"/>
+</simulation>"#;
+    let commands = parse_xml_script(script).unwrap();
+    assert_eq!(
+        commands,
+        [
+            ScriptCommand::At(Duration::from_secs(1)),
+            ScriptCommand::Tag {
+                name: "Alarm_Horn".to_string(),
+                value: "1".to_string()
+            },
+            ScriptCommand::Wait(Duration::from_millis(500)),
+            ScriptCommand::At(Duration::from_secs(2)),
+            ScriptCommand::Alarm("{\"name\":\"Fire\",\"state\":1}".to_string()),
+            ScriptCommand::At(Duration::from_secs(5)),
+        ]
+    );
+    assert!(parse_xml_script("<simulation><alarm state=\"on\"/></simulation>").is_err());
+    assert!(parse_xml_script("<simulation><play/></simulation>").is_err());
+}
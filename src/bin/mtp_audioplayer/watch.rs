@@ -0,0 +1,155 @@
+use log::warn;
+use mtp_audioplayer::open_pipe::alarm_data::AlarmData;
+use mtp_audioplayer::open_pipe::connection::{self as open_pipe, MessageVariant};
+use mtp_audioplayer::util::error::DynResult;
+use mtp_audioplayer::util::glob::glob_match;
+use std::collections::BTreeMap;
+use std::io::Write;
+use tokio::signal;
+use tokio::time::{interval, Duration};
+
+struct TagRow {
+    value: String,
+    quality: String,
+    time_stamp: String,
+}
+
+struct WatchState {
+    pattern: String,
+    tags: BTreeMap<String, TagRow>,
+    alarms: BTreeMap<(i32, i32), AlarmData>,
+}
+
+impl WatchState {
+    fn handle_message(&mut self, msg: open_pipe::Message) -> DynResult<()> {
+        match msg.message {
+            MessageVariant::NotifySubscribeTag(notify) => {
+                for tag in notify.params.tags {
+                    if tag.error.error_code != 0 {
+                        warn!("Failed to subscribe to {}: {}", tag.data.name, tag.error);
+                        continue;
+                    }
+                    self.tags.insert(
+                        tag.data.name,
+                        TagRow {
+                            value: tag.data.value,
+                            quality: tag.data.quality,
+                            time_stamp: tag.time_stamp,
+                        },
+                    );
+                }
+            }
+            MessageVariant::NotifySubscribeAlarm(notify) => {
+                for alarm in notify.params.alarms {
+                    let alarm = AlarmData::from(alarm);
+                    if !glob_match(&self.pattern, &alarm.name) {
+                        continue;
+                    }
+                    let key = (alarm.id, alarm.instance_id);
+                    // State 128 means that the alarm was removed
+                    if alarm.state == 128 {
+                        self.alarms.remove(&key);
+                    } else {
+                        self.alarms.insert(key, alarm);
+                    }
+                }
+            }
+            MessageVariant::ErrorSubscribeTag(error) => return Err(error.into()),
+            MessageVariant::ErrorSubscribeAlarm(error) => return Err(error.into()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn render(&self, out: &mut impl Write) -> std::io::Result<()> {
+        // Clear screen and move cursor to top left corner
+        write!(out, "\x1b[2J\x1b[H")?;
+        writeln!(
+            out,
+            "Pattern: {}    {}",
+            self.pattern,
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+        )?;
+        writeln!(out)?;
+        let name_width = self
+            .tags
+            .keys()
+            .map(|n| n.len())
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        writeln!(
+            out,
+            "{:name_width$}  {:20}  {:8}  Time stamp",
+            "Tag", "Value", "Quality"
+        )?;
+        for (name, row) in &self.tags {
+            writeln!(
+                out,
+                "{:name_width$}  {:20}  {:8}  {}",
+                name, row.value, row.quality, row.time_stamp
+            )?;
+        }
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:>6} {:>5}  {:20} {:12} {:24} Modified",
+            "ID", "Inst", "Alarm", "Class", "State"
+        )?;
+        for alarm in self.alarms.values() {
+            writeln!(
+                out,
+                "{:>6} {:>5}  {:20} {:12} {:24} {}",
+                alarm.id,
+                alarm.instance_id,
+                alarm.name,
+                alarm.alarm_class_name,
+                alarm.state_text,
+                alarm.modification_time.format("%H:%M:%S%.3f")
+            )?;
+        }
+        out.flush()
+    }
+}
+
+/// Subscribe to all tags matching pattern and all alarms with a
+/// name matching pattern. Show the current values as a table until
+/// ctrl-c is pressed.
+pub async fn watch(bind: &str, tag_names: &[String], pattern: &str) -> DynResult<()> {
+    let mut pipe = open_pipe::Connection::connect(bind)
+        .await
+        .map_err(|e| format!("Failed open connection to {}: {}", bind, e))?;
+    let tags: Vec<&str> = tag_names
+        .iter()
+        .map(|t| t.as_str())
+        .filter(|t| glob_match(pattern, t))
+        .collect();
+    if !tags.is_empty() {
+        pipe.subscribe_tags(&tags).await?;
+    }
+    pipe.subscribe_alarms().await?;
+
+    let mut state = WatchState {
+        pattern: pattern.to_string(),
+        tags: BTreeMap::new(),
+        alarms: BTreeMap::new(),
+    };
+    let mut stdout = std::io::stdout();
+    let mut refresh = interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            res = signal::ctrl_c() => {
+                res?;
+                break;
+            }
+            res = pipe.get_message() => {
+                state.handle_message(res?)?;
+                state.render(&mut stdout)?;
+            }
+            _ = refresh.tick() => {
+                state.render(&mut stdout)?;
+            }
+        }
+    }
+    Ok(())
+}
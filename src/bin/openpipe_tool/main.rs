@@ -7,13 +7,13 @@ use log::{debug, error, info};
 use mtp_audioplayer::open_pipe::{
     alarm_server::AlarmServer,
     connection::{self, Connection, MessageVariant},
+    simulator::{queue_message, serve_connection, SEND_QUEUE_LEN},
     tag_server::{ReplyFn, TagServer},
 };
 use std::sync::{Arc, Mutex, Weak};
 use tokio::signal;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::Sender;
 //use tokio::time::{timeout, Duration};
-use mtp_audioplayer::util::error::DynResult;
 use std::env;
 use std::net::IpAddr;
 use std::path::PathBuf;
@@ -21,78 +21,12 @@ use tokio_util::sync::CancellationToken;
 use warp::ws::Message as WsMessage;
 use warp::{Filter, Reply};
 
-async fn open_pipe_handler(
-    mut conn: Connection,
-    tag_server: Arc<Mutex<TagServer>>,
-    alarm_server: Arc<Mutex<AlarmServer>>,
-) {
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-    let notify_fn: Arc<ReplyFn> = Arc::new(Mutex::new(move |msg| {
-        if let Err(err) = tx.send(msg) {
-            error!("Failed to queue reply: {}", err);
-        }
-        Ok(()) as DynResult<()>
-    }));
-    let notify_fn_weak = Arc::downgrade(&notify_fn);
-    loop {
-        tokio::select! {
-            res = conn.get_message() => {
-                match res {
-                    Ok(msg) => {
-                        let reply = match msg.message {
-                            MessageVariant::SubscribeTag(_) |
-                            MessageVariant::UnsubscribeTag |
-                            MessageVariant::ReadTag(_) |
-                            MessageVariant::WriteTag(_) => {
-                                let mut tag_server = tag_server.lock().unwrap();
-                                tag_server.handle_message(msg, &notify_fn_weak)
-                            },
-                            MessageVariant::SubscribeAlarm(_) |
-                            MessageVariant::UnsubscribeAlarm |
-                            MessageVariant::NotifySubscribeAlarm(_) |
-                            MessageVariant::ReadAlarm(_) => {
-                                let mut alarm_server = alarm_server.lock().unwrap();
-                                alarm_server.handle_message(msg, &notify_fn_weak)
-                            },
-
-                            _ => None
-                        };
-
-                        if let Some(reply) = reply {
-                            debug!("Reply: {:?}", &reply);
-                            if let Err(err) = conn.send_message(&reply).await {
-                                error!("Failed to send Open Pipe message: {}", err);
-
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        error!("Failed to get message: {}",e);
-                        break
-                    }
-                }
-            },
-            res = rx.recv() => {
-                match res {
-                    Some(notice) => {
-                        if let Err(err) = conn.send_message(&notice).await {
-                            error!("Failed to receive Open Pipe message: {}", err);
-                        }
-                    },
-                    None => break
-                }
-            }
-        }
-    }
-    debug!("Connection closed");
-}
-
 fn web_handler(
     ws_msg: WsMessage,
     tag_server: &Arc<Mutex<TagServer>>,
     alarm_server: &Arc<Mutex<AlarmServer>>,
     notify: &Weak<ReplyFn>,
-    tx: &mut UnboundedSender<connection::Message>,
+    tx: &Sender<connection::Message>,
 ) {
     if let Ok(json) = ws_msg.to_str() {
         match serde_json::from_str::<connection::Message>(json) {
@@ -100,7 +34,7 @@ fn web_handler(
                 MessageVariant::SubscribeTag(_)
                 | MessageVariant::NotifySubscribeTag(_)
                 | MessageVariant::ErrorSubscribeTag(_)
-                | MessageVariant::UnsubscribeTag
+                | MessageVariant::UnsubscribeTag(_)
                 | MessageVariant::NotifyUnsubscribeTag
                 | MessageVariant::ErrorUnsubscribeTag(_)
                 | MessageVariant::ReadTag(_)
@@ -112,9 +46,7 @@ fn web_handler(
                     let mut tag_server = tag_server.lock().unwrap();
 
                     if let Some(msg) = tag_server.handle_message(op_msg, notify) {
-                        if let Err(err) = tx.send(msg) {
-                            error!("Failed to queue reply: {}", err);
-                        }
+                        queue_message(tx, msg);
                     }
                 }
                 MessageVariant::SubscribeAlarm(_)
@@ -128,9 +60,7 @@ fn web_handler(
                 | MessageVariant::ErrorReadAlarm(_) => {
                     let mut alarm_server = alarm_server.lock().unwrap();
                     if let Some(msg) = alarm_server.handle_message(op_msg, notify) {
-                        if let Err(err) = tx.send(msg) {
-                            error!("Failed to queue reply: {}", err);
-                        }
+                        queue_message(tx, msg);
                     }
                 }
             },
@@ -281,13 +211,11 @@ fn setup_server(
         Box::new(ws.on_upgrade(|websocket| async move {
                 let (mut tx, rx) = websocket.split();
                 let (send_tx, mut recv_tx) =
-                    tokio::sync::mpsc::unbounded_channel::<connection::Message>();
+                    tokio::sync::mpsc::channel::<connection::Message>(SEND_QUEUE_LEN);
                 let send_tx_web = send_tx.clone();
                 let notify: Arc<ReplyFn> = Arc::new(Mutex::new(move |msg| {
                     debug!("Socket sent: {:?}", msg);
-                    if let Err(err) = send_tx_web.send(msg) {
-                        error!("Failed to queue reply: {}", err);
-                    }
+                    queue_message(&send_tx_web, msg);
                     Ok(())
                 }));
                 let notify_weak = Arc::downgrade(&notify);
@@ -308,13 +236,13 @@ fn setup_server(
                     _ = rx.for_each(move |res| {
                         let tag_server = tag_server.clone();
                         let notify_weak = notify_weak.clone();
-                        let mut send_tx = send_tx.clone();
+                        let send_tx = send_tx.clone();
                         let tag_server = tag_server.clone();
                         let alarm_server = alarm_server.clone();
                         async move {
-                            println!("Msg: {:?}",res);
+                            debug!("Msg: {:?}", res);
                             if let Ok(msg) = res {
-                                web_handler(msg, &tag_server, &alarm_server, &notify_weak, &mut send_tx);
+                                web_handler(msg, &tag_server, &alarm_server, &notify_weak, &send_tx);
                             }
                         }
                     }) => {}
@@ -398,7 +326,7 @@ async fn main() {
         open_pipe_connection = tokio::spawn(async move {
             connection::listen(
                 &open_pipe_path,
-                move |conn| open_pipe_handler(conn, tag_server.clone(), alarm_server.clone()),
+                move |conn| serve_connection(conn, tag_server.clone(), alarm_server.clone()),
                 shutdown_open_pipe,
             )
             .await
@@ -1,36 +1,137 @@
-use crate::sample_buffer::{self, AsSampleSlice, SampleBuffer};
-use cpal::traits::DeviceTrait;
-use cpal::traits::HostTrait;
-use cpal::traits::StreamTrait;
-use cpal::BuildStreamError;
-use cpal::Device;
-use cpal::SampleFormat;
-use cpal::SampleRate;
-use cpal::Stream;
-use cpal::StreamConfig;
-use cpal::SupportedStreamConfigRange;
-use log::{debug, error, info};
+#[cfg(any(feature = "no-audio", not(feature = "audio")))]
+use crate::null_output::{start_output, Output};
+use crate::sample_buffer::{self, AsSampleSlice, SampleBuffer, SampleFormat};
+use crate::sample_stream::{StreamReader, StreamRing};
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    BufferSize, BuildStreamError, Device, SampleRate, Stream, StreamConfig,
+    SupportedStreamConfigRange,
+};
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
+use log::info;
+use log::{debug, error};
 use std::future::{self, Future};
 use std::mem;
 use std::ops::DerefMut;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{
+    fence, AtomicBool, AtomicI16, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use std::sync::Arc;
 use std::sync::{Condvar, Mutex, MutexGuard};
 use std::task::{Context, Poll, Waker};
-use std::thread;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
 
 #[derive(Debug, Clone)]
 pub struct ClipPlayer {
+    // Value of the callback counter at the last health check
+    checked_callbacks: Arc<AtomicU32>,
     control: Arc<PlaybackControl>,
+    gain: Arc<SoftwareGain>,
+    stats: Arc<StreamStats>,
+}
+
+/// Counters updated by the audio stream
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    // Incremented by every stream callback
+    callbacks: AtomicU32,
+    /// Errors reported by the stream, usually underruns
+    pub errors: AtomicU32,
+    /// Callbacks arriving later than twice the buffer duration
+    pub late_callbacks: AtomicU32,
+    /// Microseconds from starting the last clip until its first samples
+    /// were handed to the device
+    pub last_latency_us: AtomicU64,
+}
+
+/// Gain applied to the samples when they are played. Used as volume
+/// control when the device has no mixer.
+#[derive(Debug)]
+pub struct SoftwareGain {
+    // One gain for each channel
+    channels: Vec<AtomicU32>,
+}
+
+impl SoftwareGain {
+    pub fn new(gain: f32, channels: usize) -> SoftwareGain {
+        SoftwareGain {
+            channels: (0..channels.max(1))
+                .map(|_| AtomicU32::new(gain.to_bits()))
+                .collect(),
+        }
+    }
+
+    /// Set the gain of all channels in the range 0.0 to 1.0
+    pub fn set(&self, gain: f32) {
+        for channel in 0..self.channels.len() {
+            self.set_channel(channel, gain);
+        }
+    }
+
+    /// Set the gain of a single channel. Unknown channels are ignored.
+    pub fn set_channel(&self, channel: usize, gain: f32) {
+        if let Some(c) = self.channels.get(channel) {
+            c.store(gain.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// The gain of the loudest channel
+    pub fn get(&self) -> f32 {
+        (0..self.channels.len())
+            .map(|c| self.get_channel(c))
+            .fold(0.0, f32::max)
+    }
+
+    /// The gain of each channel
+    pub fn levels(&self) -> Vec<f32> {
+        (0..self.channels.len())
+            .map(|c| self.get_channel(c))
+            .collect()
+    }
+
+    fn get_channel(&self, channel: usize) -> f32 {
+        f32::from_bits(self.channels[channel].load(Ordering::Relaxed))
+    }
+}
+
+pub(crate) trait ApplyGain {
+    fn apply_gain(self, gain: f32) -> Self;
+}
+
+impl ApplyGain for i16 {
+    fn apply_gain(self, gain: f32) -> i16 {
+        (self as f32 * gain) as i16
+    }
+}
+
+impl ApplyGain for u16 {
+    fn apply_gain(self, gain: f32) -> u16 {
+        ((self as f32 - 32768.0) * gain + 32768.0) as u16
+    }
+}
+
+impl ApplyGain for f32 {
+    fn apply_gain(self, gain: f32) -> f32 {
+        self * gain
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(all(feature = "audio", not(feature = "no-audio")))]
     Devices(cpal::DevicesError),
+    #[cfg(all(feature = "audio", not(feature = "no-audio")))]
     Name(cpal::DeviceNameError),
+    #[cfg(all(feature = "audio", not(feature = "no-audio")))]
     BuildStream(cpal::BuildStreamError),
+    #[cfg(all(feature = "audio", not(feature = "no-audio")))]
     PlayStream(cpal::PlayStreamError),
+    #[cfg(all(feature = "audio", not(feature = "no-audio")))]
     SupportedConfig(cpal::SupportedStreamConfigsError),
     NoMatchinConfig(String),
     ClipPlayer(String),
@@ -39,30 +140,35 @@ pub enum Error {
 
 impl std::error::Error for Error {}
 
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
 impl From<cpal::DevicesError> for Error {
     fn from(err: cpal::DevicesError) -> Error {
         Error::Devices(err)
     }
 }
 
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
 impl From<cpal::DeviceNameError> for Error {
     fn from(err: cpal::DeviceNameError) -> Error {
         Error::Name(err)
     }
 }
 
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
 impl From<cpal::BuildStreamError> for Error {
     fn from(err: cpal::BuildStreamError) -> Error {
         Error::BuildStream(err)
     }
 }
 
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
 impl From<cpal::SupportedStreamConfigsError> for Error {
     fn from(err: cpal::SupportedStreamConfigsError) -> Error {
         Error::SupportedConfig(err)
     }
 }
 
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
 impl From<cpal::PlayStreamError> for Error {
     fn from(err: cpal::PlayStreamError) -> Error {
         Error::PlayStream(err)
@@ -78,11 +184,16 @@ impl From<String> for Error {
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         match self {
+            #[cfg(all(feature = "audio", not(feature = "no-audio")))]
             Error::Devices(e) => e.fmt(f),
+            #[cfg(all(feature = "audio", not(feature = "no-audio")))]
             Error::Name(e) => e.fmt(f),
+            #[cfg(all(feature = "audio", not(feature = "no-audio")))]
             Error::BuildStream(e) => e.fmt(f),
+            #[cfg(all(feature = "audio", not(feature = "no-audio")))]
             Error::PlayStream(e) => e.fmt(f),
             Error::ClipPlayer(e) => e.fmt(f),
+            #[cfg(all(feature = "audio", not(feature = "no-audio")))]
             Error::SupportedConfig(e) => e.fmt(f),
             Error::NoMatchinConfig(e) => e.fmt(f),
             Error::Shutdown => {
@@ -102,6 +213,7 @@ enum PlaybackState {
     Playing {
         seqno: u32,
         samples: Arc<SampleBuffer>,
+        started: Instant,
     },
     Cancel, // Cancel current playback. Set by client
     #[allow(dead_code)]
@@ -115,7 +227,7 @@ impl std::fmt::Display for PlaybackState {
         match self {
             PlaybackState::Setup => write!(f, "Setup"),
             PlaybackState::Ready => write!(f, "Ready"),
-            PlaybackState::Playing { seqno, samples } => {
+            PlaybackState::Playing { seqno, samples, .. } => {
                 write!(f, "Playing(Seq: {}, Len: {}", seqno, samples.len())
             }
             PlaybackState::Cancel => write!(f, "Cancel"),
@@ -126,10 +238,130 @@ impl std::fmt::Display for PlaybackState {
     }
 }
 
+// Sent to the stream callback
+enum Command {
+    Play {
+        seqno: u32,
+        samples: Arc<SampleBuffer>,
+        // Filled by the playback thread for streamed clips
+        stream: Option<Arc<StreamRing>>,
+        started: Instant,
+    },
+    Cancel,
+}
+
+// Sent from the stream callback to the playback thread. Clips are handed
+// back so they are never freed in the callback.
+enum Event {
+    Finished {
+        seqno: u32,
+        #[allow(dead_code)]
+        samples: Arc<SampleBuffer>,
+    },
+    #[allow(dead_code)]
+    Cancelled(Option<Arc<SampleBuffer>>),
+}
+
+// Room for more events than can be outstanding, since each command
+// waits for the previous one to be acknowledged
+const QUEUE_LEN: usize = 8;
+
+// Channels between the stream callback and the playback thread. The
+// callback only uses the non-blocking ends.
+struct CallbackLink {
+    commands: Receiver<Command>,
+    events: SyncSender<Event>,
+    // The playback thread
+    notify: Thread,
+}
+
+impl CallbackLink {
+    fn send(&self, event: Event) {
+        // Can't fail, see QUEUE_LEN
+        let _ = self.events.try_send(event);
+        self.notify.unpark();
+    }
+}
+
+// The clip as seen by the stream callback
+struct CallbackClip {
+    seqno: u32,
+    samples: Arc<SampleBuffer>,
+    stream: Option<Arc<StreamRing>>,
+    pos: usize,
+}
+
+// Samples copied by the stream callback for the output taps. Works like a
+// seqlock: samples that were overwritten while being read are discarded
+// by the reader.
+struct TapRing {
+    // Set while there are taps
+    active: AtomicBool,
+    samples: Vec<AtomicI16>,
+    // Total number of samples written
+    written: AtomicUsize,
+    // Total number of samples written once the current write is done
+    reserved: AtomicUsize,
+}
+
+impl TapRing {
+    fn new(len: usize) -> TapRing {
+        TapRing {
+            active: AtomicBool::new(false),
+            samples: (0..len.max(1)).map(|_| AtomicI16::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            reserved: AtomicUsize::new(0),
+        }
+    }
+
+    // Called from the stream callback. Returns true if anything was
+    // written.
+    fn write<S: sample_buffer::Sample>(&self, buffer: &[S]) -> bool {
+        if !self.active.load(Ordering::Relaxed) {
+            return false;
+        }
+        let len = self.samples.len();
+        let start = self.written.load(Ordering::Relaxed);
+        self.reserved.store(start + buffer.len(), Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (i, s) in buffer.iter().enumerate() {
+            self.samples[(start + i) % len].store(s.to_i16(), Ordering::Relaxed);
+        }
+        self.written.store(start + buffer.len(), Ordering::Release);
+        true
+    }
+
+    // Samples written since pos. Samples that are no longer in the ring
+    // are skipped.
+    fn read(&self, pos: &mut usize) -> Vec<i16> {
+        let len = self.samples.len();
+        let written = self.written.load(Ordering::Acquire);
+        let start = (*pos).max(written.saturating_sub(len));
+        let mut pcm: Vec<i16> = (start..written)
+            .map(|i| self.samples[i % len].load(Ordering::Relaxed))
+            .collect();
+        fence(Ordering::Acquire);
+        let reserved = self.reserved.load(Ordering::Relaxed);
+        let overwritten = reserved.saturating_sub(len).saturating_sub(start);
+        pcm.drain(..overwritten.min(pcm.len()));
+        *pos = written;
+        pcm
+    }
+}
+
 struct PlaybackControl {
     state: Mutex<PlaybackState>,
     cond: Condvar,
     waker: Mutex<Option<Waker>>,
+    // Commands for the stream callback
+    commands: SyncSender<Command>,
+    // The playback thread, woken on shutdown
+    thread: Mutex<Option<Thread>>,
+    tap_ring: TapRing,
+    // Receivers of copies of the output
+    taps: Mutex<Vec<mpsc::Sender<Vec<i16>>>>,
+    // Streamed clips for the playback thread to start reading
+    new_streams: Mutex<Vec<(Arc<StreamRing>, Arc<SampleBuffer>)>>,
 }
 
 impl std::fmt::Debug for PlaybackControl {
@@ -148,6 +380,37 @@ impl PlaybackControl {
         guard: &mut MutexGuard<PlaybackState>,
         state: PlaybackState,
     ) -> PlaybackState {
+        let mut stream = None;
+        let command = match &state {
+            PlaybackState::Playing {
+                seqno,
+                samples,
+                started,
+            } => {
+                if let SampleBuffer::Stream(source) = &**samples {
+                    stream = Some((Arc::new(source.ring()), samples.clone()));
+                }
+                Some(Command::Play {
+                    seqno: *seqno,
+                    samples: samples.clone(),
+                    stream: stream.as_ref().map(|(ring, _)| ring.clone()),
+                    started: *started,
+                })
+            }
+            PlaybackState::Cancel => Some(Command::Cancel),
+            _ => None,
+        };
+        if let Some(command) = command {
+            if self.commands.try_send(command).is_err() {
+                error!("Failed to send command to audio stream");
+            }
+        }
+        let wake_thread = stream.is_some() || matches!(state, PlaybackState::Shutdown);
+        if let Some(stream) = stream {
+            if let Ok(mut new_streams) = self.new_streams.lock() {
+                new_streams.push(stream);
+            }
+        }
         let mut state = state;
         //debug!("State changed: {}", state);
         mem::swap(guard.deref_mut(), &mut state);
@@ -158,6 +421,13 @@ impl PlaybackControl {
                 waker.wake()
             }
         }
+        if wake_thread {
+            if let Ok(thread) = self.thread.lock() {
+                if let Some(thread) = &*thread {
+                    thread.unpark();
+                }
+            }
+        }
         state
     }
 
@@ -169,125 +439,386 @@ impl PlaybackControl {
             }
         }
     }
+
+    // Update the state from an event sent by the stream callback
+    fn handle_event(&self, event: Event) {
+        let mut guard = self.get_state_guard();
+        let done = match (&event, &*guard) {
+            (Event::Finished { seqno, .. }, PlaybackState::Playing { seqno: playing, .. }) => {
+                seqno == playing
+            }
+            (Event::Cancelled(_), PlaybackState::Cancel) => true,
+            _ => false,
+        };
+        if done {
+            self.change_state(&mut guard, PlaybackState::Ready);
+            //debug!("Stream callback: Done");
+        }
+    }
+
+    // Pass samples copied by the stream callback on to the taps
+    // Start reading new streamed clips and keep reading the others
+    fn read_streams(&self, streams: &mut Vec<(Arc<StreamRing>, Option<StreamReader>)>) {
+        let new_streams = match self.new_streams.lock() {
+            Ok(mut new_streams) => mem::take(&mut *new_streams),
+            Err(_) => Vec::new(),
+        };
+        for (ring, samples) in new_streams {
+            let reader = match &*samples {
+                SampleBuffer::Stream(source) => match StreamReader::open(source) {
+                    Ok(reader) => Some(reader),
+                    Err(e) => {
+                        error!("Failed to stream clip: {}", e);
+                        ring.end();
+                        None
+                    }
+                },
+                _ => None,
+            };
+            streams.push((ring, reader));
+        }
+        for (ring, reader) in streams.iter_mut() {
+            if let Some(r) = reader {
+                if let Err(e) = r.fill(ring) {
+                    error!("Failed to read streamed clip: {}", e);
+                    ring.end();
+                    *reader = None;
+                } else if r.is_done() {
+                    *reader = None;
+                }
+            }
+        }
+        // Only free rings the stream callback is done with, so it never
+        // frees memory
+        streams.retain(|(ring, _)| Arc::strong_count(ring) > 1);
+    }
+
+    fn forward_taps(&self, pos: &mut usize) {
+        let pcm = self.tap_ring.read(pos);
+        if let Ok(mut taps) = self.taps.lock() {
+            if !pcm.is_empty() {
+                // Full taps lose the samples and closed taps are removed
+                taps.retain(|tap| {
+                    !matches!(tap.try_send(pcm.clone()), Err(TrySendError::Closed(_)))
+                });
+            }
+            self.tap_ring
+                .active
+                .store(!taps.is_empty(), Ordering::Relaxed);
+        }
+    }
 }
+
+// Runs in the stream callback and must not block or allocate
 fn generate_samples<S>(
-    ctrl: &PlaybackControl,
+    link: &CallbackLink,
+    stats: &StreamStats,
     buffer: &mut [S],
-    current_seqno: &mut u32,
-    pos: &mut usize,
+    current: &mut Option<CallbackClip>,
 ) where
     S: sample_buffer::Sample + Copy,
     SampleBuffer: AsSampleSlice<S>,
 {
-    if let Ok(mut state) = ctrl.state.lock() {
-        match &mut *state {
-            PlaybackState::Playing { seqno, samples } => {
-                let samples: &[S] = samples.as_sample_slice();
-                if *seqno != *current_seqno {
-                    *current_seqno = *seqno;
-                    *pos = 0;
-                }
-                if *pos >= samples.len() {
-                    *pos = 0;
-                }
-                //debug!("{} @ {}", *seqno, pos);
-                if samples.len() - *pos >= buffer.len() {
-                    let end = *pos + buffer.len();
-                    buffer.copy_from_slice(&samples[*pos..end]);
-                    *pos = end;
-                } else {
-                    let end = samples.len();
-                    let copy_len = end - *pos;
-                    buffer[0..copy_len].copy_from_slice(&samples[*pos..end]);
-                    for s in buffer[copy_len..].iter_mut() {
-                        *s = S::SAMPLE_OFFSET;
-                    }
-                    *pos = end;
+    while let Ok(command) = link.commands.try_recv() {
+        match command {
+            Command::Play {
+                seqno,
+                samples,
+                stream,
+                started,
+            } => {
+                let latency = started.elapsed().as_micros() as u64;
+                stats.last_latency_us.store(latency, Ordering::Relaxed);
+                let clip = CallbackClip {
+                    seqno,
+                    samples,
+                    stream,
+                    pos: 0,
+                };
+                if let Some(old) = current.replace(clip) {
+                    link.send(Event::Finished {
+                        seqno: old.seqno,
+                        samples: old.samples,
+                    });
                 }
-                if *pos >= samples.len() {
-                    *pos = 0;
-                    ctrl.change_state(&mut state, PlaybackState::Ready);
-                    //debug!("Stream callback: Done");
+            }
+            Command::Cancel => {
+                link.send(Event::Cancelled(current.take().map(|c| c.samples)));
+            }
+        }
+    }
+    let finished = match current {
+        Some(CallbackClip {
+            stream: Some(ring), ..
+        }) => {
+            // Silence if the playback thread hasn't kept up
+            let copy_len = ring.pop(buffer);
+            for s in buffer[copy_len..].iter_mut() {
+                *s = S::SAMPLE_OFFSET;
+            }
+            // Read more
+            link.notify.unpark();
+            ring.is_done()
+        }
+        Some(clip) => {
+            let samples: &[S] = clip.samples.as_sample_slice();
+            //debug!("{} @ {}", clip.seqno, clip.pos);
+            let copy_len = (samples.len() - clip.pos).min(buffer.len());
+            let end = clip.pos + copy_len;
+            buffer[0..copy_len].copy_from_slice(&samples[clip.pos..end]);
+            for s in buffer[copy_len..].iter_mut() {
+                *s = S::SAMPLE_OFFSET;
+            }
+            clip.pos = end;
+            end >= samples.len()
+        }
+        None => {
+            //debug!("Stream callback: Silence");
+            for s in buffer {
+                *s = S::SAMPLE_OFFSET;
+            }
+            false
+        }
+    };
+    if finished {
+        if let Some(clip) = current.take() {
+            link.send(Event::Finished {
+                seqno: clip.seqno,
+                samples: clip.samples,
+            });
+        }
+    }
+}
+
+// Everything the stream callback works with
+pub(crate) struct StreamCallback {
+    link: CallbackLink,
+    ctrl: Arc<PlaybackControl>,
+    gain: Arc<SoftwareGain>,
+    stats: Arc<StreamStats>,
+    rate: u32,
+    current: Option<CallbackClip>,
+    last_callback: Option<Instant>,
+}
+
+impl StreamCallback {
+    /// Fill a buffer for the output. Runs in the stream callback and
+    /// must not block or allocate.
+    pub(crate) fn fill<S>(&mut self, buffer: &mut [S])
+    where
+        S: Copy + sample_buffer::Sample + ApplyGain,
+        SampleBuffer: AsSampleSlice<S>,
+    {
+        let stats = &self.stats;
+        stats.callbacks.fetch_add(1, Ordering::Relaxed);
+        let channels = self.gain.channels.len();
+        let now = Instant::now();
+        if let Some(last) = self.last_callback {
+            let frames = (buffer.len() / channels) as u64;
+            let period = Duration::from_micros(frames * 1_000_000 / u64::from(self.rate));
+            if now.duration_since(last) > period * 2 {
+                stats.late_callbacks.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.last_callback = Some(now);
+        generate_samples::<S>(&self.link, stats, buffer, &mut self.current);
+        for channel in 0..channels {
+            let g = self.gain.get_channel(channel);
+            if g != 1.0 {
+                for s in buffer.iter_mut().skip(channel).step_by(channels) {
+                    *s = s.apply_gain(g);
                 }
             }
-            PlaybackState::Cancel => {
-                *pos = 0;
-                ctrl.change_state(&mut state, PlaybackState::Ready);
+        }
+        if self.ctrl.tap_ring.write(buffer) {
+            self.link.notify.unpark();
+        }
+    }
+}
+
+// The audio device and the configuration to use
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
+struct Output {
+    device: Device,
+    config: StreamConfig,
+}
+
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
+impl Output {
+    fn open(
+        pcm_name: &str,
+        rate: u32,
+        channels: u16,
+        sample_format: SampleFormat,
+        buffer_size: Option<u32>,
+    ) -> Result<Output, Error> {
+        let sample_format = cpal_format(sample_format);
+        let host = cpal::default_host();
+        let device = if pcm_name == "default" {
+            host.default_output_device()
+                .ok_or_else(|| "No default device".to_string())?
+        } else {
+            let mut selected = None;
+            let devices = host.output_devices()?;
+            for device in devices {
+                debug!("Checking device {}", device.name()?);
+                if device.name()? == pcm_name {
+                    selected = Some(device);
+                    break;
+                }
             }
-            _ => {
-                //debug!("Stream callback: Silence");
-                for s in buffer {
-                    *s = S::SAMPLE_OFFSET;
+            selected.ok_or_else(|| format!("Playback device {} not found", pcm_name))?
+        };
+        info!("Audio playback on device {}", device.name()?);
+        let mut best_fit: Option<SupportedStreamConfigRange> = None;
+        let supported_configs = device.supported_output_configs()?;
+        for conf in supported_configs {
+            /*debug!(
+                "Config: {}ch, {}-{}samples/s {:?}",
+                conf.channels(),
+                conf.min_sample_rate().0,
+                conf.max_sample_rate().0,
+                conf.sample_format()
+            );*/
+            if let Some(prev) = &best_fit {
+                // Check if this conf matches better than the previous best conf
+                if (conf.channels() == channels && prev.channels() != channels)
+                    || (supports_samplerate(&conf, rate) && !supports_samplerate(prev, rate))
+                    || (conf.sample_format() == sample_format
+                        && prev.sample_format() != sample_format)
+                {
+                    best_fit = Some(conf);
                 }
+            } else {
+                best_fit = Some(conf);
             }
         }
+
+        let best_fit = best_fit
+            .ok_or_else(|| Error::NoMatchinConfig("No suitable configuration found".to_string()))?;
+        if best_fit.channels() != channels {
+            return Err(Error::NoMatchinConfig(format!(
+                "No configuration with {} channels found",
+                channels
+            )));
+        }
+        if !supports_samplerate(&best_fit, rate) {
+            return Err(Error::NoMatchinConfig(format!(
+                "No configuration that supports {} samples/s found",
+                rate
+            )));
+        }
+        if best_fit.sample_format() != sample_format {
+            return Err(Error::NoMatchinConfig(
+                "No configuration with signed 16-bit format found".to_string(),
+            ));
+        }
+        let mut config = best_fit.with_sample_rate(SampleRate(rate)).config();
+        if let Some(frames) = buffer_size {
+            config.buffer_size = BufferSize::Fixed(frames);
+        }
+        Ok(Output { device, config })
+    }
+
+    fn rate(&self) -> u32 {
+        self.config.sample_rate.0
     }
 }
 
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
 fn build_output_stream<S>(
-    device: Device,
-    stream_config: &StreamConfig,
+    output: &Output,
     sample_format: SampleFormat,
-    ctrl_cb: Arc<PlaybackControl>,
+    mut callback: StreamCallback,
 ) -> Result<Stream, BuildStreamError>
 where
-    S: cpal::Sample + Copy + sample_buffer::Sample,
+    S: cpal::Sample + Copy + sample_buffer::Sample + ApplyGain,
     SampleBuffer: AsSampleSlice<S>,
 {
-    let mut current_seqno = 0;
-    let mut pos = 0;
-    device.build_output_stream_raw(
-        stream_config,
-        sample_format,
-        move |data, _info| {
-            let buffer = data.as_slice_mut::<S>().unwrap();
-            generate_samples::<S>(ctrl_cb.as_ref(), buffer, &mut current_seqno, &mut pos);
-        },
-        |err| {
+    let error_stats = callback.stats.clone();
+    output.device.build_output_stream_raw(
+        &output.config,
+        cpal_format(sample_format),
+        move |data, _info| callback.fill(data.as_slice_mut::<S>().unwrap()),
+        move |err| {
+            error_stats.errors.fetch_add(1, Ordering::Relaxed);
+            crate::metrics::playback_underrun();
             error!("Stream error: {}", err);
         },
     )
 }
+
+// Start passing samples from the callback to the device. Playback stops
+// when the stream is dropped.
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
+fn start_output(
+    output: Output,
+    sample_format: SampleFormat,
+    callback: StreamCallback,
+) -> Result<Stream, Error> {
+    let stream = match sample_format {
+        SampleFormat::I16 => build_output_stream::<i16>(&output, sample_format, callback),
+        SampleFormat::U16 => build_output_stream::<u16>(&output, sample_format, callback),
+        SampleFormat::F32 => build_output_stream::<f32>(&output, sample_format, callback),
+    }?;
+    stream.play()?;
+    Ok(stream)
+}
+
 fn playback_thread(
-    device: Device,
-    stream_config: StreamConfig,
+    output: Output,
     sample_format: SampleFormat,
     ctrl: Arc<PlaybackControl>,
+    gain: Arc<SoftwareGain>,
+    stats: Arc<StreamStats>,
+    commands: Receiver<Command>,
 ) {
-    let ctrl_cb = ctrl.clone();
-    let stream = match match sample_format {
-        SampleFormat::I16 => {
-            build_output_stream::<i16>(device, &stream_config, sample_format, ctrl_cb)
-        }
-        SampleFormat::U16 => {
-            build_output_stream::<u16>(device, &stream_config, sample_format, ctrl_cb)
-        }
-        SampleFormat::F32 => {
-            build_output_stream::<f32>(device, &stream_config, sample_format, ctrl_cb)
-        }
-    } {
+    if let Ok(mut thread) = ctrl.thread.lock() {
+        *thread = Some(thread::current());
+    }
+    let (event_tx, events) = sync_channel(QUEUE_LEN);
+    let callback = StreamCallback {
+        link: CallbackLink {
+            commands,
+            events: event_tx,
+            notify: thread::current(),
+        },
+        ctrl: ctrl.clone(),
+        gain,
+        stats,
+        rate: output.rate(),
+        current: None,
+        last_callback: None,
+    };
+    // Playing until dropped
+    let _stream = match start_output(output, sample_format, callback) {
         Ok(s) => s,
         Err(e) => {
-            error!("Failed to initiate audio playback: {}", e);
+            error!("Failed to start audio playback: {}", e);
             return;
         }
     };
-    if let Err(e) = stream.play() {
-        error!("Failed to start audio playback: {}", e);
-        return;
-    }
 
-    let mut guard = ctrl.get_state_guard();
-    ctrl.change_state(&mut guard, PlaybackState::Ready);
+    {
+        let mut guard = ctrl.get_state_guard();
+        ctrl.change_state(&mut guard, PlaybackState::Ready);
+    }
+    let mut tap_pos = 0;
+    let mut streams = Vec::new();
     loop {
-        if let PlaybackState::Shutdown = &*guard {
+        // Woken by the stream callback, when a streamed clip is started
+        // and on shutdown
+        thread::park();
+        while let Ok(event) = events.try_recv() {
+            ctrl.handle_event(event);
+        }
+        ctrl.read_streams(&mut streams);
+        ctrl.forward_taps(&mut tap_pos);
+        if let PlaybackState::Shutdown = &*ctrl.get_state_guard() {
             break;
         }
-        guard = ctrl
-            .cond
-            .wait(guard)
-            .expect("Failed to wait for state change");
     }
+    let mut guard = ctrl.get_state_guard();
     ctrl.change_state(&mut guard, PlaybackState::Done);
     debug!("Playback thread exited");
 }
@@ -341,6 +872,16 @@ impl Drop for PlaybackFuture {
     }
 }
 
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
+fn cpal_format(format: SampleFormat) -> cpal::SampleFormat {
+    match format {
+        SampleFormat::I16 => cpal::SampleFormat::I16,
+        SampleFormat::U16 => cpal::SampleFormat::U16,
+        SampleFormat::F32 => cpal::SampleFormat::F32,
+    }
+}
+
+#[cfg(all(feature = "audio", not(feature = "no-audio")))]
 fn supports_samplerate(conf: &SupportedStreamConfigRange, rate: u32) -> bool {
     conf.min_sample_rate().0 <= rate && conf.max_sample_rate().0 >= rate
 }
@@ -354,77 +895,91 @@ impl ClipPlayer {
         channels: u8,
         sample_format: SampleFormat,
     ) -> Result<ClipPlayer, Error> {
-        let channels = channels as u16;
-        let host = cpal::default_host();
-        let device = if pcm_name == "default" {
-            host.default_output_device()
-                .ok_or_else(|| "No default device".to_string())?
-        } else {
-            let mut selected = None;
-            let devices = host.output_devices()?;
-            for device in devices {
-                debug!("Checking device {}", device.name()?);
-                if device.name()? == pcm_name {
-                    selected = Some(device);
-                    break;
-                }
-            }
-            selected.ok_or_else(|| format!("Playback device {} not found", pcm_name))?
-        };
-        info!("Audio playback on device {}", device.name()?);
-        let mut best_fit: Option<SupportedStreamConfigRange> = None;
-        let supported_configs = device.supported_output_configs()?;
-        for conf in supported_configs {
-            /*debug!(
-                "Config: {}ch, {}-{}samples/s {:?}",
-                conf.channels(),
-                conf.min_sample_rate().0,
-                conf.max_sample_rate().0,
-                conf.sample_format()
-            );*/
-            if let Some(prev) = &best_fit {
-                // Check if this conf matches better than the previous best conf
-                if (conf.channels() == channels && prev.channels() != channels)
-                    || (supports_samplerate(&conf, rate) && !supports_samplerate(prev, rate))
-                    || (conf.sample_format() == sample_format
-                        && prev.sample_format() != sample_format)
-                {
-                    best_fit = Some(conf);
-                }
-            } else {
-                best_fit = Some(conf);
-            }
-        }
+        Self::with_buffer_size(pcm_name, rate, channels, sample_format, None)
+    }
 
-        let best_fit = best_fit
-            .ok_or_else(|| Error::NoMatchinConfig("No suitable configuration found".to_string()))?;
-        if best_fit.channels() != channels {
-            return Err(Error::NoMatchinConfig(format!(
-                "No configuration with {} channels found",
-                channels
-            )));
-        }
-        if !supports_samplerate(&best_fit, rate) {
-            return Err(Error::NoMatchinConfig(format!(
-                "No configuration that supports {} samples/s found",
-                rate
-            )));
-        }
-        if best_fit.sample_format() != sample_format {
-            return Err(Error::NoMatchinConfig(
-                "No configuration with signed 16-bit format found".to_string(),
-            ));
-        }
-        let stream_config = best_fit.with_sample_rate(SampleRate(rate)).config();
+    /// Like [`ClipPlayer::new`] but with a fixed buffer size in frames.
+    /// The default size of the device is used if None.
+    pub fn with_buffer_size(
+        pcm_name: &str,
+        rate: u32,
+        channels: u8,
+        sample_format: SampleFormat,
+        buffer_size: Option<u32>,
+    ) -> Result<ClipPlayer, Error> {
+        let channels = channels as u16;
+        let output = Output::open(pcm_name, rate, channels, sample_format, buffer_size)?;
+        let (commands_tx, commands) = sync_channel(QUEUE_LEN);
         let control = Arc::new(PlaybackControl {
             state: Mutex::new(PlaybackState::Setup),
             cond: Condvar::new(),
             waker: Mutex::new(None),
+            commands: commands_tx,
+            thread: Mutex::new(None),
+            // One second of samples
+            tap_ring: TapRing::new(rate as usize * channels as usize),
+            taps: Mutex::new(Vec::new()),
+            new_streams: Mutex::new(Vec::new()),
         });
         let thread_ctrl = control.clone();
-        thread::spawn(move || playback_thread(device, stream_config, sample_format, thread_ctrl));
+        let gain = Arc::new(SoftwareGain::new(1.0, channels as usize));
+        let thread_gain = gain.clone();
+        let stats = Arc::new(StreamStats::default());
+        let thread_stats = stats.clone();
+        thread::spawn(move || {
+            playback_thread(
+                output,
+                sample_format,
+                thread_ctrl,
+                thread_gain,
+                thread_stats,
+                commands,
+            )
+        });
+
+        Ok(ClipPlayer {
+            control,
+            gain,
+            checked_callbacks: Arc::new(AtomicU32::new(0)),
+            stats,
+        })
+    }
+
+    /// True if the playback thread is running and the audio stream has
+    /// requested samples since the last call.
+    pub fn is_healthy(&self) -> bool {
+        let callbacks = self.stats.callbacks.load(Ordering::Relaxed);
+        let checked = self.checked_callbacks.swap(callbacks, Ordering::Relaxed);
+        let running = match self.control.state.lock() {
+            Ok(state) => !matches!(
+                *state,
+                PlaybackState::Setup | PlaybackState::Shutdown | PlaybackState::Done
+            ),
+            Err(_) => false,
+        };
+        running && callbacks != checked
+    }
+
+    /// Latency and underrun counters of the audio stream
+    pub fn stream_stats(&self) -> Arc<StreamStats> {
+        self.stats.clone()
+    }
+
+    /// Receive a copy of every buffer sent to the playback device as
+    /// interleaved 16-bit samples, silence included. At most `capacity`
+    /// buffers are queued, later ones are dropped until there's room.
+    pub fn add_output_tap(&self, capacity: usize) -> mpsc::Receiver<Vec<i16>> {
+        let (tx, rx) = mpsc::channel(capacity);
+        if let Ok(mut taps) = self.control.taps.lock() {
+            taps.push(tx);
+            self.control.tap_ring.active.store(true, Ordering::Relaxed);
+        }
+        rx
+    }
 
-        Ok(ClipPlayer { control })
+    /// Gain applied to all played samples
+    pub fn gain(&self) -> Arc<SoftwareGain> {
+        self.gain.clone()
     }
 
     pub fn start_clip(
@@ -467,6 +1022,7 @@ impl ClipPlayer {
                 PlaybackState::Playing {
                     seqno,
                     samples: clip,
+                    started: Instant::now(),
                 },
             );
         }
@@ -474,6 +1030,15 @@ impl ClipPlayer {
         Box::pin(PlaybackFuture::new(seqno, self.control.clone()))
     }
 
+    /// Stop the clip that is playing, if any. Its future completes
+    /// as if the clip had ended.
+    pub fn stop_clip(&self) {
+        let mut guard = self.control.get_state_guard();
+        if let PlaybackState::Playing { .. } = &*guard {
+            self.control.change_state(&mut guard, PlaybackState::Cancel);
+        }
+    }
+
     pub fn shutdown(&self) {
         let mut guard = self.control.get_state_guard();
 
@@ -495,3 +1060,17 @@ impl ClipPlayer {
         }
     }
 }
+
+#[test]
+fn test_tap_ring() {
+    let ring = TapRing::new(4);
+    assert!(!ring.write(&[1i16, 2]));
+    ring.active.store(true, Ordering::Relaxed);
+    let mut pos = 0;
+    assert!(ring.write(&[1i16, 2]));
+    assert_eq!(ring.read(&mut pos), vec![1, 2]);
+    // Only the last four samples are kept
+    ring.write(&[3i16, 4, 5, 6, 7, 8]);
+    assert_eq!(ring.read(&mut pos), vec![5, 6, 7, 8]);
+    assert!(ring.read(&mut pos).is_empty());
+}
@@ -1,13 +1,93 @@
+use crate::audit_log::{AuditEvent, AuditLog};
 use crate::clip_player::ClipPlayer;
+use crate::metrics;
 use crate::priority_scheduler::Scheduler;
 use crate::sample_buffer::SampleBuffer;
+use log::{debug, error};
+use std::cmp::Reverse;
 use std::error::Error;
-use std::sync::Arc;
-use tokio::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{self, Duration};
+
+// Time between gain changes when fading out
+const FADE_STEP: Duration = Duration::from_millis(20);
+
+// The clip that is currently playing
+#[derive(Clone)]
+struct CurrentClip {
+    id: u64,
+    name: String,
+    priority: i32,
+    source: String,
+}
+
+impl CurrentClip {
+    fn status(&self) -> ClipStatus {
+        ClipStatus {
+            name: self.name.clone(),
+            priority: self.priority,
+            source: self.source.clone(),
+        }
+    }
+}
+
+/// A clip that is playing or waiting to be played
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipStatus {
+    pub name: String,
+    pub priority: i32,
+    /// What started the clip
+    pub source: String,
+}
 
 pub struct ClipQueue {
     clip_player: ClipPlayer,
     scheduler: Arc<Scheduler>,
+    // No new clips are started when set
+    draining: AtomicBool,
+    // Number of clips currently playing
+    playing: AtomicUsize,
+    // Notified when a clip is done
+    idle: Notify,
+    // Name of the clip that was started last
+    last_played: Mutex<Option<String>>,
+    current: Mutex<Option<CurrentClip>>,
+    // Clips waiting for clips with higher priority to finish
+    waiting: Mutex<Vec<CurrentClip>>,
+    next_id: AtomicU64,
+    audit_log: Option<Arc<AuditLog>>,
+}
+
+// Keeps track of a playing clip, even if the play future is dropped
+struct PlayingGuard<'a> {
+    queue: &'a ClipQueue,
+    id: u64,
+    // How playback ended, cancelled if not set
+    result: Option<AuditEvent>,
+}
+
+// Removes a clip from the waiting list, even if the play future is dropped
+struct WaitingGuard<'a> {
+    queue: &'a ClipQueue,
+    id: u64,
+}
+
+impl<'a> Drop for WaitingGuard<'a> {
+    fn drop(&mut self) {
+        let mut waiting = self.queue.waiting.lock().unwrap();
+        waiting.retain(|clip| clip.id != self.id);
+    }
+}
+
+impl<'a> Drop for PlayingGuard<'a> {
+    fn drop(&mut self) {
+        let queue = self.queue;
+        queue.clip_stopped(self.id, self.result.unwrap_or(AuditEvent::Cancelled));
+        queue.playing.fetch_sub(1, Ordering::SeqCst);
+        queue.idle.notify_waiters();
+    }
 }
 
 impl ClipQueue {
@@ -15,15 +95,169 @@ impl ClipQueue {
         ClipQueue {
             clip_player,
             scheduler: Scheduler::new(),
+            draining: AtomicBool::new(false),
+            playing: AtomicUsize::new(0),
+            idle: Notify::new(),
+            last_played: Mutex::new(None),
+            current: Mutex::new(None),
+            waiting: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            audit_log: None,
+        }
+    }
+
+    /// Record all clips started and stopped in this log
+    pub fn set_audit_log(&mut self, audit_log: Arc<AuditLog>) {
+        self.audit_log = Some(audit_log);
+    }
+
+    fn audit(&self, event: AuditEvent, clip: &CurrentClip) {
+        if let Some(audit_log) = &self.audit_log {
+            if let Err(e) = audit_log.record(event, &clip.name, clip.priority, &clip.source) {
+                error!("Failed to write audit log: {}", e);
+            }
+        }
+    }
+
+    // A clip started playing, the current clip (if any) is preempted
+    fn clip_started(&self, clip: CurrentClip) {
+        let mut current = self.current.lock().unwrap();
+        if let Some(preempted) = current.take() {
+            self.audit(AuditEvent::Preempted, &preempted);
+        }
+        self.audit(AuditEvent::Start, &clip);
+        *current = Some(clip);
+    }
+
+    // Does nothing if the clip was already preempted
+    fn clip_stopped(&self, id: u64, event: AuditEvent) {
+        let mut current = self.current.lock().unwrap();
+        if matches!(&*current, Some(clip) if clip.id == id) {
+            self.audit(event, current.as_ref().unwrap());
+            *current = None;
         }
     }
 
+    /// True if clips can be played
+    pub fn is_healthy(&self) -> bool {
+        self.clip_player.is_healthy()
+    }
+
+    /// See [`ClipPlayer::add_output_tap`]
+    pub fn add_output_tap(&self, capacity: usize) -> mpsc::Receiver<Vec<i16>> {
+        self.clip_player.add_output_tap(capacity)
+    }
+
+    /// Stop starting new clips. Clips that are already playing are
+    /// finished.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Fade out and stop the playing clip, if any. The gain is left at
+    /// zero, so this is only useful when shutting down. Does nothing if
+    /// `fade` is zero.
+    pub async fn fade_out(&self, fade: Duration) {
+        if fade.is_zero() || self.is_idle() {
+            return;
+        }
+        let gain = self.clip_player.gain();
+        let levels = gain.levels();
+        let steps = fade.as_nanos().div_ceil(FADE_STEP.as_nanos()) as u32;
+        let mut interval = time::interval(fade / steps);
+        // The first tick is immediate
+        interval.tick().await;
+        for step in 1..=steps {
+            interval.tick().await;
+            let left = (steps - step) as f32 / steps as f32;
+            for (channel, level) in levels.iter().enumerate() {
+                gain.set_channel(channel, level * left);
+            }
+        }
+        self.clip_player.stop_clip();
+    }
+
+    /// True if no clip is playing
+    pub fn is_idle(&self) -> bool {
+        self.playing.load(Ordering::SeqCst) == 0
+    }
+
+    /// Wait until no clip is playing
+    pub async fn wait_idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            if self.is_idle() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Name of the clip that was started last
+    pub fn last_played(&self) -> Option<String> {
+        self.last_played.lock().unwrap().clone()
+    }
+
+    /// The clip that is playing, if any
+    pub fn playing(&self) -> Option<ClipStatus> {
+        self.current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(CurrentClip::status)
+    }
+
+    /// Clips waiting to be played, highest priority first
+    pub fn queued(&self) -> Vec<ClipStatus> {
+        let mut queued: Vec<ClipStatus> = self
+            .waiting
+            .lock()
+            .unwrap()
+            .iter()
+            .map(CurrentClip::status)
+            .collect();
+        queued.sort_by_key(|c| Reverse(c.priority));
+        queued
+    }
+
+    /// Stop the playing clip if its priority is at or below
+    /// `priority`. Returns true if a clip was stopped.
+    pub fn silence(&self, priority: i32) -> bool {
+        let mut current = self.current.lock().unwrap();
+        match current.take() {
+            Some(clip) if clip.priority <= priority => {
+                debug!("Silencing clip {}", clip.name);
+                self.audit(AuditEvent::Cancelled, &clip);
+                self.clip_player.stop_clip();
+                true
+            }
+            clip => {
+                *current = clip;
+                false
+            }
+        }
+    }
+
+    /// Play a clip when no clip with higher priority is playing.
+    /// `source` tells what started the clip and is only used for
+    /// logging.
     pub async fn play(
         &self,
+        name: &str,
+        source: &str,
         samples: Arc<SampleBuffer>,
         priority: i32,
         timeout: Option<Duration>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let clip = CurrentClip {
+            id,
+            name: name.to_string(),
+            priority,
+            source: source.to_string(),
+        };
+        self.waiting.lock().unwrap().push(clip.clone());
+        let waiting = WaitingGuard { queue: self, id };
         let token;
         if let Some(timeout) = timeout {
             token = match self.scheduler.get_token_timeout(priority, timeout).await {
@@ -33,7 +267,31 @@ impl ClipQueue {
         } else {
             token = self.scheduler.get_token(priority).await;
         }
-        self.clip_player.start_clip(samples).await?;
+        drop(waiting);
+        if self.draining.load(Ordering::SeqCst) {
+            // Block the caller so it doesn't continue with something else
+            debug!("Shutting down, clip not played");
+            drop(token);
+            return std::future::pending().await;
+        }
+        self.playing.fetch_add(1, Ordering::SeqCst);
+        let mut playing = PlayingGuard {
+            queue: self,
+            id,
+            result: None,
+        };
+        *self.last_played.lock().unwrap() = Some(name.to_string());
+        debug!(clip = name; "Playing clip {}", name);
+        // Record the start first so a preempted clip can't log its end
+        self.clip_started(clip);
+        metrics::clip_played(priority);
+        let res = self.clip_player.start_clip(samples).await;
+        playing.result = Some(if res.is_ok() {
+            AuditEvent::End
+        } else {
+            AuditEvent::Failed
+        });
+        res?;
         drop(token);
         Ok(())
     }
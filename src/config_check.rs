@@ -0,0 +1,414 @@
+//! Checks a configuration without opening the audio device or the pipe
+
+use crate::app_config;
+use crate::expr::Expr;
+use crate::read_config::{ActionType, ClipType, PlayerConfig, StateMachineConfig, TagOrConst};
+use crate::tag_source;
+use crate::util::template;
+use crate::volume_control::VolumeControl;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+    pub clips_loaded: usize,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl std::fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        for err in &self.errors {
+            writeln!(f, "error: {}", err)?;
+        }
+        for warn in &self.warnings {
+            writeln!(f, "warning: {}", warn)?;
+        }
+        write!(
+            f,
+            "{} clips loaded, {} errors, {} warnings",
+            self.clips_loaded,
+            self.errors.len(),
+            self.warnings.len()
+        )
+    }
+}
+
+struct CheckContext<'a> {
+    conf: &'a PlayerConfig,
+    clips: HashSet<String>,
+    tags: HashSet<&'a str>,
+    volume_controls: HashSet<&'a str>,
+}
+
+impl<'a> CheckContext<'a> {
+    fn check_tag(&self, report: &mut CheckReport, location: &str, tag: &str) {
+        if !self.tags.contains(tag) {
+            report.warnings.push(format!(
+                "{}: Tag '{}' is not in the tag list",
+                location, tag
+            ));
+        }
+    }
+
+    fn check_expr(&self, report: &mut CheckReport, location: &str, expr: &Expr) {
+        let mut tags: Vec<_> = expr.tags().into_iter().collect();
+        tags.sort();
+        for tag in &tags {
+            self.check_tag(report, location, tag);
+        }
+    }
+
+    fn check_filter(&self, report: &mut CheckReport, location: &str, filter: &str) {
+        if !self.conf.named_alarm_filters.contains_key(filter) {
+            report
+                .errors
+                .push(format!("{}: No alarm filter named '{}'", location, filter));
+        }
+    }
+
+    fn check_device(&self, report: &mut CheckReport, location: &str, device: &str) {
+        if !self.conf.playback_devices.contains_key(device) {
+            report.errors.push(format!(
+                "{}: No playback device with id '{}'",
+                location, device
+            ));
+        }
+    }
+
+    fn check_goto(
+        &self,
+        report: &mut CheckReport,
+        location: &str,
+        machine: &StateMachineConfig,
+        target: &str,
+    ) {
+        let (machine, state) = match target.split_once(':') {
+            Some((machine_name, state)) => {
+                match self
+                    .conf
+                    .state_machines
+                    .iter()
+                    .find(|m| m.id == machine_name)
+                {
+                    Some(m) => (m, state),
+                    None => {
+                        report.errors.push(format!(
+                            "{}: No state machine named '{}'",
+                            location, machine_name
+                        ));
+                        return;
+                    }
+                }
+            }
+            None => (machine, target),
+        };
+        if !machine.states.iter().any(|s| s.id == state) {
+            report.errors.push(format!(
+                "{}: No state named '{}' in state machine '{}'",
+                location, state, machine.id
+            ));
+        }
+    }
+
+    fn check_action(
+        &self,
+        report: &mut CheckReport,
+        location: &str,
+        machine: &StateMachineConfig,
+        action: &ActionType,
+    ) {
+        match action {
+            ActionType::Sequence(actions) | ActionType::Parallel(actions) => {
+                for action in actions {
+                    self.check_action(report, location, machine, action);
+                }
+            }
+            ActionType::Repeat { action, .. } => {
+                self.check_action(report, location, machine, action)
+            }
+            ActionType::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.check_expr(report, location, condition);
+                self.check_action(report, location, machine, then);
+                if let Some(action) = otherwise {
+                    self.check_action(report, location, machine, action);
+                }
+            }
+            ActionType::WaitExpr(expr) => self.check_expr(report, location, expr),
+            ActionType::Play { sound, device, .. } => {
+                if !self.clips.contains(sound) {
+                    report
+                        .errors
+                        .push(format!("{}: No clip named '{}'", location, sound));
+                }
+                if let Some(device) = device {
+                    self.check_device(report, location, device);
+                }
+            }
+            ActionType::Goto(target) => self.check_goto(report, location, machine, target),
+            ActionType::WaitTag { tag_name, .. } | ActionType::SetTag { tag_name, .. } => {
+                self.check_tag(report, location, tag_name)
+            }
+            ActionType::WaitAlarm { filter_name, .. } => {
+                self.check_filter(report, location, filter_name)
+            }
+            ActionType::IgnoreAlarms { filter, .. } | ActionType::RestoreAlarms { filter } => {
+                self.check_filter(report, location, filter)
+            }
+            ActionType::SetBalance { control, .. } | ActionType::ChangeVolume { control, .. } => {
+                if !self.volume_controls.contains(control.as_str()) {
+                    report.errors.push(format!(
+                        "{}: No volume control named '{}'",
+                        location, control
+                    ));
+                }
+            }
+            ActionType::SetVolume { control, value, .. } => {
+                if !self.volume_controls.contains(control.as_str()) {
+                    report.errors.push(format!(
+                        "{}: No volume control named '{}'",
+                        location, control
+                    ));
+                }
+                if let TagOrConst::Tag(tag) = value {
+                    self.check_tag(report, location, tag);
+                }
+            }
+            ActionType::Email { subject, body, .. } => {
+                if !cfg!(feature = "email") {
+                    report
+                        .errors
+                        .push(format!("{}: Built without email support", location));
+                } else if self.conf.smtp.is_none() {
+                    report
+                        .errors
+                        .push(format!("{}: No mail server configured", location));
+                }
+                for text in [subject, body] {
+                    for tag in template::template_tags(text).unwrap_or_default() {
+                        self.check_tag(report, location, &tag);
+                    }
+                }
+            }
+            ActionType::Exec { args, .. } => {
+                for arg in args {
+                    for tag in template::template_tags(arg).unwrap_or_default() {
+                        self.check_tag(report, location, &tag);
+                    }
+                }
+            }
+            ActionType::SetGpio { pin, .. } => {
+                if !self.conf.gpio_outputs.iter().any(|o| &o.name == pin) {
+                    report
+                        .errors
+                        .push(format!("{}: No GPIO output named '{}'", location, pin));
+                }
+            }
+            ActionType::Wait(_)
+            | ActionType::WaitTime(_)
+            | ActionType::Debug(_)
+            | ActionType::Plugin(_) => {}
+        }
+    }
+}
+
+/// Load all clips and check all references between parts of the
+/// configuration. Volume controls are opened but not changed.
+pub fn check_config(conf: &PlayerConfig, base_dir: &Path) -> CheckReport {
+    let mut report = CheckReport::default();
+
+    let clip_root = base_dir.join(&conf.clip_root);
+    let mut dir_clips = match app_config::scan_clip_dirs(&clip_root, &conf.clip_dirs) {
+        Ok(clips) => clips,
+        Err(e) => {
+            report.errors.push(e.to_string());
+            HashMap::new()
+        }
+    };
+    dir_clips.retain(|name, _| !conf.clips.contains_key(name));
+    let mut clips: Vec<(&String, &ClipType)> = conf.clips.iter().chain(dir_clips.iter()).collect();
+    clips.sort_by_key(|(name, _)| *name);
+    for (name, clip) in &clips {
+        match app_config::load_clip_type(
+            &clip_root,
+            clip,
+            &conf.clip_profiles,
+            conf.sample_format,
+            conf.rate,
+            conf.channels,
+        ) {
+            Ok(_) => report.clips_loaded += 1,
+            Err(e) => report.errors.push(format!("Clip '{}': {}", name, e)),
+        }
+    }
+    // Devices with another format than the default device get their
+    // own copy of each clip
+    let mut devices: Vec<_> = conf
+        .playback_devices
+        .iter()
+        .filter(|(_, d)| {
+            d.rate != conf.rate
+                || d.channels != conf.channels
+                || d.sample_format != conf.sample_format
+        })
+        .collect();
+    devices.sort_by_key(|(id, _)| *id);
+    for (id, device) in devices {
+        for (name, clip) in &clips {
+            if let Err(e) = app_config::load_clip_type(
+                &clip_root,
+                clip,
+                &conf.clip_profiles,
+                device.sample_format,
+                device.rate,
+                device.channels,
+            ) {
+                report
+                    .errors
+                    .push(format!("Clip '{}' for device '{}': {}", name, id, e));
+            }
+        }
+    }
+    let clip_names = clips.iter().map(|(name, _)| name.to_string()).collect();
+
+    let (tag_send_tx, _tag_send_rx) = app_config::tag_write_channel();
+    if let Err(e) = app_config::setup_tags(conf, base_dir, tag_send_tx) {
+        report.errors.push(format!("Tags: {}", e));
+    }
+
+    let mut volume_controls = HashSet::new();
+    for control in &conf.volume_config {
+        if !volume_controls.insert(control.id.as_str()) {
+            report
+                .errors
+                .push(format!("Volume control '{}' defined twice", control.id));
+        }
+        let element = control.control.as_ref().map(|(n, i)| (n.as_str(), *i));
+        if let Err(e) = VolumeControl::new(&control.device, element, control.scale) {
+            if element.is_some() {
+                report
+                    .errors
+                    .push(format!("Volume control '{}': {}", control.id, e));
+            } else {
+                report.warnings.push(format!(
+                    "Volume control '{}': Using software volume: {}",
+                    control.id, e
+                ));
+            }
+        }
+    }
+
+    let mut tags: HashSet<&str> = conf.tags.iter().map(|t| t.internal_name()).collect();
+    tags.extend(conf.derived_tags.iter().map(|t| t.name.as_str()));
+    for source in &conf.tag_sources {
+        if let Err(e) = tag_source::check_supported(source) {
+            report.errors.push(e.to_string());
+        }
+        tags.extend(source.tag_names());
+    }
+    for filter in conf.named_alarm_filters.values() {
+        tags.extend(filter.tag_matching.as_deref());
+        tags.extend(filter.tag_ignored.as_deref());
+    }
+    for machine in &conf.state_machines {
+        tags.extend(machine.state_tag.as_deref());
+        tags.extend(machine.command_tag.as_deref());
+    }
+    let ctxt = CheckContext {
+        conf,
+        clips: clip_names,
+        tags,
+        volume_controls,
+    };
+    if !conf.gpio_outputs.is_empty() && !cfg!(all(feature = "gpio", target_os = "linux")) {
+        report
+            .errors
+            .push("GPIO outputs configured, built without GPIO support".to_string());
+    }
+    for output in &conf.gpio_outputs {
+        if let Some(filter) = &output.follow_filter {
+            let location = format!("GPIO output '{}'", output.name);
+            ctxt.check_filter(&mut report, &location, filter);
+        }
+    }
+    if let Some(snmp) = &conf.snmp {
+        if !cfg!(feature = "snmp") {
+            report
+                .errors
+                .push("SNMP traps configured, built without SNMP support".to_string());
+        }
+        for filter in &snmp.filters {
+            ctxt.check_filter(&mut report, "SNMP", filter);
+        }
+    }
+    if let Some(mqtt) = &conf.mqtt {
+        if !cfg!(feature = "mqtt") {
+            report
+                .errors
+                .push("MQTT bridge configured, built without MQTT support".to_string());
+        }
+        for topic in mqtt.publish.iter().chain(&mqtt.subscribe) {
+            let location = format!("MQTT topic '{}'", topic.topic);
+            ctxt.check_tag(&mut report, &location, &topic.tag);
+        }
+    }
+    if conf.dbus.is_some() && !cfg!(feature = "dbus") {
+        report
+            .errors
+            .push("D-Bus service configured, built without D-Bus support".to_string());
+    }
+    for (clip, device) in &conf.clip_devices {
+        ctxt.check_device(&mut report, &format!("Clip '{}'", clip), device);
+    }
+    if conf.metrics.is_some() && !cfg!(feature = "metrics") {
+        report
+            .errors
+            .push("Metrics configured, built without metrics support".to_string());
+    }
+    if let Some(tag) = &conf.log_level_tag {
+        ctxt.check_tag(&mut report, "Log level", tag);
+    }
+    if let Some(tag) = &conf.reload_tag {
+        ctxt.check_tag(&mut report, "Reload", tag);
+    }
+    if let Some(tag) = &conf.state_change_limit.tag {
+        ctxt.check_tag(&mut report, "State change limit", tag);
+    }
+    if let Some(tag) = &conf.repeat_limit.tag {
+        ctxt.check_tag(&mut report, "Repeat limit", tag);
+    }
+    for control in &conf.volume_config {
+        if let Some(tag) = &control.tag_level {
+            let location = format!("Volume control '{}'", control.id);
+            ctxt.check_tag(&mut report, &location, tag);
+        }
+    }
+
+    let mut machines = HashSet::new();
+    for machine in &conf.state_machines {
+        if !machines.insert(machine.id.as_str()) {
+            report
+                .errors
+                .push(format!("State machine '{}' defined twice", machine.id));
+        }
+        let mut states = HashSet::new();
+        for state in &machine.states {
+            let location = format!("State machine '{}', state '{}'", machine.id, state.id);
+            if !states.insert(state.id.as_str()) {
+                report.errors.push(format!("{}: Defined twice", location));
+            }
+            ctxt.check_action(&mut report, &location, machine, &state.action);
+        }
+    }
+    report
+}
@@ -0,0 +1,336 @@
+//! Alternative configuration formats.
+//!
+//! TOML and YAML configurations are converted to the XML format and
+//! then parsed as usual. The conversion follows these rules:
+//!
+//! - The top level table is the content of the `audioplayer` element.
+//! - A key with a scalar value becomes an element with the value as text.
+//! - A key with a sequence value becomes one element per item.
+//! - In a table, keys starting with `@` are attributes, `#text` is the
+//!   text content and `#content` is a sequence of tables whose keys are
+//!   added as child elements in order. Other keys are child elements.
+//!
+//! ```yaml
+//! bind: /tmp/siemens/automation/HmiRunTime
+//! tags:
+//!   tag: [SoundAlarm, SoundInfo]
+//! state_machine:
+//!   - "@id": main
+//!     "#content":
+//!       - state: {"@id": idle, wait: "1s"}
+//! ```
+
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use std::fmt::Write;
+
+const NS: &str = "http://www.elektro-kapsel.se/audioplayer/v1";
+
+/// Generic configuration tree that keeps the order of table entries
+#[derive(Debug, PartialEq)]
+pub enum TreeValue {
+    Scalar(String),
+    Seq(Vec<TreeValue>),
+    Map(Vec<(String, TreeValue)>),
+}
+
+struct TreeVisitor;
+
+impl<'de> Visitor<'de> for TreeVisitor {
+    type Value = TreeValue;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a configuration value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<TreeValue, E> {
+        Ok(TreeValue::Scalar(v.to_string()))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<TreeValue, E> {
+        Ok(TreeValue::Scalar(v.to_string()))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<TreeValue, E> {
+        Ok(TreeValue::Scalar(v.to_string()))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<TreeValue, E> {
+        Ok(TreeValue::Scalar(v.to_string()))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<TreeValue, E> {
+        Ok(TreeValue::Scalar(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<TreeValue, E> {
+        Ok(TreeValue::Scalar(v))
+    }
+
+    fn visit_unit<E>(self) -> Result<TreeValue, E> {
+        Ok(TreeValue::Scalar(String::new()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<TreeValue, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(TreeValue::Seq(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<TreeValue, A::Error> {
+        let mut entries = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(TreeValue::Map(entries))
+    }
+}
+
+impl<'de> Deserialize<'de> for TreeValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TreeValue, D::Error> {
+        deserializer.deserialize_any(TreeVisitor)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "&#10;")
+        .replace('\r', "&#13;")
+}
+
+// Keys become element and attribute names, without namespace prefixes
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+// Path of a key in the source, e.g. state_machine[0].state
+fn child_location(location: &str, key: &str) -> String {
+    if location.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", location, key)
+    }
+}
+
+fn location_error(location: &str, msg: &str) -> String {
+    if location.is_empty() {
+        format!("Top level: {}", msg)
+    } else {
+        format!("{}: {}", location, msg)
+    }
+}
+
+/// A configuration converted to XML
+pub struct XmlConfig {
+    pub xml: String,
+    /// The location in the source of the element starting on each
+    /// line, so that errors can refer to the source
+    pub locations: Vec<String>,
+}
+
+#[derive(Default)]
+struct XmlWriter {
+    out: String,
+    locations: Vec<String>,
+}
+
+impl XmlWriter {
+    // Every element starts on a line of its own
+    fn start_line(&mut self, location: &str) {
+        if !self.locations.is_empty() {
+            self.out.push('\n');
+        }
+        self.locations.push(if location.is_empty() {
+            "top level".to_string()
+        } else {
+            location.to_string()
+        });
+    }
+
+    fn write_children(
+        &mut self,
+        location: &str,
+        entries: &[(String, TreeValue)],
+    ) -> Result<(), String> {
+        for (key, value) in entries {
+            if key.starts_with('@') {
+                continue;
+            }
+            let location = child_location(location, key);
+            match key.as_str() {
+                "#text" => match value {
+                    TreeValue::Scalar(text) => self.out.push_str(&escape(text)),
+                    _ => return Err(location_error(&location, "#text must be a scalar")),
+                },
+                "#content" => match value {
+                    TreeValue::Seq(items) => {
+                        for (i, item) in items.iter().enumerate() {
+                            let location = format!("{}[{}]", location, i);
+                            match item {
+                                TreeValue::Map(entries) => {
+                                    self.write_children(&location, entries)?
+                                }
+                                _ => {
+                                    return Err(location_error(
+                                        &location,
+                                        "#content items must be tables",
+                                    ))
+                                }
+                            }
+                        }
+                    }
+                    _ => return Err(location_error(&location, "#content must be a sequence")),
+                },
+                _ => self.write_element(&location, key, value)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_attributes(
+        &mut self,
+        location: &str,
+        entries: &[(String, TreeValue)],
+    ) -> Result<(), String> {
+        for (key, value) in entries {
+            if let Some(name) = key.strip_prefix('@') {
+                let location = child_location(location, key);
+                if !is_valid_name(name) {
+                    return Err(location_error(&location, "Not a valid attribute name"));
+                }
+                match value {
+                    TreeValue::Scalar(v) => {
+                        let _ = write!(self.out, " {}=\"{}\"", name, escape(v));
+                    }
+                    _ => return Err(location_error(&location, "Attributes must be scalars")),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_element(
+        &mut self,
+        location: &str,
+        name: &str,
+        value: &TreeValue,
+    ) -> Result<(), String> {
+        if !is_valid_name(name) {
+            return Err(location_error(location, "Not a valid element name"));
+        }
+        match value {
+            TreeValue::Scalar(text) => {
+                self.start_line(location);
+                let _ = write!(self.out, "<{}>{}</{}>", name, escape(text), name);
+            }
+            TreeValue::Seq(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    self.write_element(&format!("{}[{}]", location, i), name, item)?;
+                }
+            }
+            TreeValue::Map(entries) => {
+                self.start_line(location);
+                let _ = write!(self.out, "<{}", name);
+                self.write_attributes(location, entries)?;
+                self.out.push('>');
+                self.write_children(location, entries)?;
+                let _ = write!(self.out, "</{}>", name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert a configuration tree to an XML configuration document.
+/// Errors refer to keys by their path, e.g. `state_machine[0].@id`.
+pub fn to_xml(root: &TreeValue) -> Result<XmlConfig, String> {
+    let entries = match root {
+        TreeValue::Map(entries) => entries,
+        _ => return Err("The top level of the configuration must be a table".to_string()),
+    };
+    let mut writer = XmlWriter::default();
+    writer.start_line("");
+    let _ = write!(writer.out, "<audioplayer xmlns=\"{}\"", NS);
+    writer.write_attributes("", entries)?;
+    writer.out.push('>');
+    writer.write_children("", entries)?;
+    writer.out.push_str("</audioplayer>");
+    Ok(XmlConfig {
+        xml: writer.out,
+        locations: writer.locations,
+    })
+}
+
+#[cfg(feature = "toml")]
+pub fn toml_to_xml(input: &str) -> crate::util::error::DynResult<XmlConfig> {
+    let tree: TreeValue = toml::from_str(input)?;
+    Ok(to_xml(&tree)?)
+}
+
+#[cfg(feature = "serde_yaml")]
+pub fn yaml_to_xml(input: &str) -> crate::util::error::DynResult<XmlConfig> {
+    let tree: TreeValue = serde_yaml::from_str(input)?;
+    Ok(to_xml(&tree)?)
+}
+
+#[test]
+fn test_to_xml() {
+    use TreeValue::*;
+    let s = |v: &str| Scalar(v.to_string());
+    let tree = Map(vec![
+        ("bind".to_string(), s("/tmp/pipe")),
+        (
+            "tags".to_string(),
+            Map(vec![("tag".to_string(), Seq(vec![s("A"), s("B&C")]))]),
+        ),
+        (
+            "heartbeat".to_string(),
+            Map(vec![
+                ("@interval".to_string(), s("5s")),
+                ("#text".to_string(), s("HEARTBEAT")),
+            ]),
+        ),
+    ]);
+    let conf = to_xml(&tree).unwrap();
+    assert_eq!(
+        conf.xml,
+        format!(
+            "<audioplayer xmlns=\"{}\">\n<bind>/tmp/pipe</bind>\n\
+             <tags>\n<tag>A</tag>\n<tag>B&amp;C</tag></tags>\n\
+             <heartbeat interval=\"5s\">HEARTBEAT</heartbeat></audioplayer>",
+            NS
+        )
+    );
+    assert_eq!(
+        conf.locations,
+        [
+            "top level",
+            "bind",
+            "tags",
+            "tags.tag[0]",
+            "tags.tag[1]",
+            "heartbeat"
+        ]
+    );
+
+    let tree = Map(vec![(
+        "state_machine".to_string(),
+        Seq(vec![Map(vec![(
+            "#content".to_string(),
+            Seq(vec![Map(vec![("bad name".to_string(), s("1"))])]),
+        )])]),
+    )]);
+    assert_eq!(
+        to_xml(&tree).err().unwrap(),
+        "state_machine[0].#content[0].bad name: Not a valid element name"
+    );
+}
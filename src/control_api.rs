@@ -0,0 +1,310 @@
+//! HTTP API for controlling a running server
+//!
+//! All requests that change something are POSTs with a JSON body and
+//! must be authorized with the configured bearer token.
+//!
+//! | Path           | Body                                   |
+//! |----------------|----------------------------------------|
+//! | `/api/action`  | `{"state_machine": "M", "state": "S"}` |
+//! | `/api/goto`    | `{"state_machine": "M", "state": "S"}` |
+//! | `/api/tag`     | `{"tag": "T", "value": "V"}`           |
+//! | `/api/silence` | `{"priority": P}`                      |
+//!
+//! `action` runs the action of a state without changing the active
+//! state, `goto` makes the state active. `silence` stops the playing
+//! clip if its priority is at or below P.
+//!
+//! `GET /api/status` returns the active states, the playing and queued
+//! clips, alarm filter counts and tag values as JSON. It is read-only and
+//! accepts the status token as well as the bearer token. Without a status
+//! token it needs no authorization.
+
+use crate::actions::tag_setter::TagSetter;
+use crate::app_config::{AlarmContext, StateMachineContext, TagContext};
+use crate::clip_queue::{ClipQueue, ClipStatus};
+use crate::read_config::ControlApiConfig;
+use crate::state_machine::StateMachine;
+use crate::util::error::DynResult;
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::reply::Reply;
+use warp::{Filter, Rejection};
+
+type Response = warp::reply::Response;
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Deserialize)]
+struct StateRequest {
+    state_machine: String,
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct TagRequest {
+    tag: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct SilenceRequest {
+    priority: i32,
+}
+
+#[derive(Serialize)]
+struct ClipReply {
+    name: String,
+    priority: i32,
+    source: String,
+}
+
+impl From<ClipStatus> for ClipReply {
+    fn from(clip: ClipStatus) -> ClipReply {
+        ClipReply {
+            name: clip.name,
+            priority: clip.priority,
+            source: clip.source,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StatusReply {
+    // Active state of each state machine
+    states: BTreeMap<String, Option<String>>,
+    playing: Option<ClipReply>,
+    queued: Vec<ClipReply>,
+    // Matching alarms of each filter
+    alarm_filters: BTreeMap<String, u32>,
+    tags: BTreeMap<String, Option<String>>,
+}
+
+struct ControlApi {
+    tag_ctxt: Arc<TagContext>,
+    alarm_ctxt: Arc<AlarmContext>,
+    clip_queue: Arc<ClipQueue>,
+    state_machines: Vec<Arc<StateMachine>>,
+}
+
+impl ControlApi {
+    // Returns the reason if not found
+    fn find_state(&self, req: &StateRequest) -> Result<(Arc<StateMachine>, usize), String> {
+        let machine = self
+            .state_machines
+            .iter()
+            .find(|sm| sm.name == req.state_machine)
+            .ok_or_else(|| format!("No state machine named {}", req.state_machine))?;
+        let index = machine
+            .find_state_index(&req.state)
+            .ok_or_else(|| format!("No state named {} in {}", req.state, req.state_machine))?;
+        Ok((machine.clone(), index))
+    }
+}
+
+fn reply(status: StatusCode, text: String) -> Response {
+    warp::reply::with_status(text, status).into_response()
+}
+
+fn run_action(req: StateRequest, api: Arc<ControlApi>) -> Response {
+    let (machine, index) = match api.find_state(&req) {
+        Ok(found) => found,
+        Err(text) => return reply(StatusCode::NOT_FOUND, text),
+    };
+    let action = match machine.state_action(index) {
+        Some(a) => a,
+        None => return reply(StatusCode::NOT_FOUND, "The state has no action".to_string()),
+    };
+    info!(
+        "Control API: Running action of {}:{}",
+        req.state_machine, req.state
+    );
+    tokio::spawn(async move {
+        if let Err(e) = action.run().await {
+            error!(
+                "Action of {}:{} failed: {}",
+                req.state_machine, req.state, e
+            );
+        }
+    });
+    reply(StatusCode::ACCEPTED, "Action started".to_string())
+}
+
+async fn goto(req: StateRequest, api: Arc<ControlApi>) -> Response {
+    let (machine, index) = match api.find_state(&req) {
+        Ok(found) => found,
+        Err(text) => return reply(StatusCode::NOT_FOUND, text),
+    };
+    info!("Control API: Goto {}:{}", req.state_machine, req.state);
+    machine.goto(index).await;
+    reply(StatusCode::OK, "State changed".to_string())
+}
+
+async fn set_tag(req: TagRequest, api: Arc<ControlApi>) -> Response {
+    info!("Control API: Set tag {} = {}", req.tag, req.value);
+    match api.tag_ctxt.async_set_tag(&req.tag, &req.value).await {
+        Ok(()) => reply(StatusCode::OK, "Tag set".to_string()),
+        Err(e) => reply(StatusCode::BAD_REQUEST, e.to_string()),
+    }
+}
+
+fn silence(req: SilenceRequest, api: Arc<ControlApi>) -> Response {
+    info!("Control API: Silence priority {}", req.priority);
+    if api.clip_queue.silence(req.priority) {
+        reply(StatusCode::OK, "Clip stopped".to_string())
+    } else {
+        reply(StatusCode::OK, "No clip stopped".to_string())
+    }
+}
+
+fn status(api: Arc<ControlApi>) -> Response {
+    let status = StatusReply {
+        states: api
+            .state_machines
+            .iter()
+            .map(|sm| (sm.name.clone(), sm.active_state_name()))
+            .collect(),
+        playing: api.clip_queue.playing().map(ClipReply::from),
+        queued: api
+            .clip_queue
+            .queued()
+            .into_iter()
+            .map(ClipReply::from)
+            .collect(),
+        alarm_filters: api.alarm_ctxt.filter_counts(),
+        tags: api.tag_ctxt.values(),
+    };
+    warp::reply::json(&status).into_response()
+}
+
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+{
+    warp::body::content_length_limit(4096).and(warp::body::json())
+}
+
+// Compare without returning early, so that the time taken doesn't tell
+// how much of a guessed token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Requests must have one of the tokens. Any request is accepted if
+// there are none.
+fn authorized(tokens: &[&str]) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let expected: Vec<String> = tokens.iter().map(|t| format!("Bearer {}", t)).collect();
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let header = header.unwrap_or_default();
+            // Every token is compared so that the time doesn't depend on which one matched
+            let valid = expected.is_empty()
+                || expected.iter().fold(false, |valid, t| {
+                    valid | constant_time_eq(header.as_bytes(), t.as_bytes())
+                });
+            async move {
+                if valid {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn handle_rejection(err: Rejection) -> Result<Response, Infallible> {
+    let (status, text) = if err.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "Unauthorized".to_string())
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "Not found".to_string())
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request too large".to_string(),
+        )
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed".to_string(),
+        )
+    } else {
+        error!("Unhandled control API request: {:?}", err);
+        (StatusCode::BAD_REQUEST, "Bad request".to_string())
+    };
+    Ok(reply(status, text))
+}
+
+/// Start listening for requests
+pub fn start(
+    conf: &ControlApiConfig,
+    tag_ctxt: &Arc<TagContext>,
+    alarm_ctxt: &Arc<AlarmContext>,
+    clip_queue: &Arc<ClipQueue>,
+    state_machine_ctxt: &StateMachineContext,
+) -> DynResult<()> {
+    let addr: SocketAddr = conf
+        .bind
+        .parse()
+        .map_err(|e| format!("Invalid control API address {}: {}", conf.bind, e))?;
+    let api = Arc::new(ControlApi {
+        tag_ctxt: tag_ctxt.clone(),
+        alarm_ctxt: alarm_ctxt.clone(),
+        clip_queue: clip_queue.clone(),
+        state_machines: state_machine_ctxt.state_machines(),
+    });
+    let with_api = warp::any().map(move || api.clone());
+    let action_route = warp::path!("api" / "action")
+        .and(json_body())
+        .and(with_api.clone())
+        .map(run_action);
+    let goto_route = warp::path!("api" / "goto")
+        .and(json_body())
+        .and(with_api.clone())
+        .then(goto);
+    let tag_route = warp::path!("api" / "tag")
+        .and(json_body())
+        .and(with_api.clone())
+        .then(set_tag);
+    let silence_route = warp::path!("api" / "silence")
+        .and(json_body())
+        .and(with_api.clone())
+        .map(silence);
+    let status_tokens: Vec<&str> = match &conf.status_token {
+        Some(status_token) => vec![conf.token.as_str(), status_token.as_str()],
+        None => Vec::new(),
+    };
+    let status_route = warp::get()
+        .and(warp::path!("api" / "status"))
+        .and(authorized(&status_tokens))
+        .and(with_api)
+        .map(status);
+    let post_routes = warp::post().and(
+        action_route
+            .or(goto_route)
+            .unify()
+            .or(tag_route)
+            .unify()
+            .or(silence_route)
+            .unify(),
+    );
+    let routes = status_route
+        .or(authorized(&[conf.token.as_str()]).and(post_routes))
+        .unify()
+        .recover(handle_rejection);
+    let (addr, server) = warp::serve(routes).try_bind_ephemeral(addr)?;
+    info!("Control API listening on {}", addr);
+    tokio::spawn(server);
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! D-Bus control interface
+//!
+//! The service is registered as org.mtp.AudioPlayer with the object
+//! /org/mtp/AudioPlayer. On the system bus a policy file allowing the
+//! server to own the name is needed.
+
+use crate::actions::alarm_dispatcher::AlarmDispatcher;
+use crate::app_config::{AlarmContext, PlaybackContext, StateMachineContext};
+use crate::read_config::{DbusBus, DbusConfig};
+use crate::state_machine::StateMachine;
+use crate::util::error::DynResult;
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::sync::Arc;
+use zbus::fdo;
+use zbus::{dbus_interface, Connection, ConnectionBuilder, SignalContext};
+
+const SERVICE_NAME: &str = "org.mtp.AudioPlayer";
+const OBJECT_PATH: &str = "/org/mtp/AudioPlayer";
+
+struct AudioPlayer {
+    control: bool,
+    playback_ctxt: Arc<PlaybackContext>,
+    state_machines: Vec<Arc<StateMachine>>,
+    alarm_ctxt: Arc<AlarmContext>,
+}
+
+impl AudioPlayer {
+    fn check_control(&self) -> fdo::Result<()> {
+        if self.control {
+            Ok(())
+        } else {
+            Err(fdo::Error::AccessDenied("Control is disabled".to_string()))
+        }
+    }
+}
+
+#[dbus_interface(name = "org.mtp.AudioPlayer")]
+impl AudioPlayer {
+    /// Queue a clip. Returns without waiting for it to be played.
+    fn play_clip(&self, name: String, priority: i32) -> fdo::Result<()> {
+        self.check_control()?;
+        let samples = self
+            .playback_ctxt
+            .clips
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No clip named {}", name)))?;
+        let clip_queue = self.playback_ctxt.clip_queue.clone();
+        tokio::spawn(async move {
+            if let Err(e) = clip_queue
+                .play(&name, "D-Bus", samples, priority, None)
+                .await
+            {
+                error!("Failed to play clip {}: {}", name, e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Stop the playing clip if its priority is at or below the given.
+    /// Returns true if a clip was stopped.
+    fn silence(&self, priority: i32) -> fdo::Result<bool> {
+        self.check_control()?;
+        Ok(self.playback_ctxt.clip_queue.silence(priority))
+    }
+
+    /// Active state of each state machine, empty if not running
+    fn get_states(&self) -> HashMap<String, String> {
+        self.state_machines
+            .iter()
+            .map(|sm| (sm.name.clone(), sm.active_state_name().unwrap_or_default()))
+            .collect()
+    }
+
+    /// Number of alarms matching a named filter
+    fn get_alarm_filter_count(&self, filter: String) -> fdo::Result<u32> {
+        self.alarm_ctxt
+            .get_filter_count(&filter)
+            .map_err(|e| fdo::Error::InvalidArgs(format!("Alarm filter {}: {}", filter, e)))
+    }
+
+    #[dbus_interface(signal)]
+    async fn alarm_filter_changed(
+        ctxt: &SignalContext<'_>,
+        filter: &str,
+        count: u32,
+    ) -> zbus::Result<()>;
+}
+
+async fn monitor_filter(conn: Connection, alarm_ctxt: Arc<AlarmContext>, filter: String) {
+    let ctxt = match SignalContext::new(&conn, OBJECT_PATH) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Invalid D-Bus object path: {}", e);
+            return;
+        }
+    };
+    let mut last = None;
+    loop {
+        let (count, changed) = match alarm_ctxt.wait_alarm_filter(&filter) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Failed to wait for alarm filter {}: {}", filter, e);
+                return;
+            }
+        };
+        if last.is_some() && last != Some(count) {
+            debug!("D-Bus signal for alarm filter {} = {}", filter, count);
+            if let Err(e) = AudioPlayer::alarm_filter_changed(&ctxt, &filter, count).await {
+                error!("Failed to send D-Bus signal: {}", e);
+            }
+        }
+        last = Some(count);
+        if changed.await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Register the service and send signals when the named alarm filters
+/// change. The service is removed when the connection is dropped.
+pub async fn start(
+    conf: &DbusConfig,
+    playback_ctxt: &Arc<PlaybackContext>,
+    state_machine_ctxt: &StateMachineContext,
+    alarm_ctxt: &Arc<AlarmContext>,
+    filters: &[String],
+) -> DynResult<Connection> {
+    let service = AudioPlayer {
+        control: conf.control,
+        playback_ctxt: playback_ctxt.clone(),
+        state_machines: state_machine_ctxt.state_machines(),
+        alarm_ctxt: alarm_ctxt.clone(),
+    };
+    let builder = match conf.bus {
+        DbusBus::System => ConnectionBuilder::system()?,
+        DbusBus::Session => ConnectionBuilder::session()?,
+    };
+    let conn = builder
+        .name(SERVICE_NAME)?
+        .serve_at(OBJECT_PATH, service)?
+        .build()
+        .await?;
+    info!("Registered {} on D-Bus", SERVICE_NAME);
+    for filter in filters {
+        tokio::spawn(monitor_filter(
+            conn.clone(),
+            alarm_ctxt.clone(),
+            filter.clone(),
+        ));
+    }
+    Ok(conn)
+}
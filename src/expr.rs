@@ -0,0 +1,451 @@
+//! Expressions over tag values.
+//!
+//! Syntax:
+//! - Numbers: `1`, `0.5`, `1e3`, `true`, `false`
+//! - Strings: `'text'`
+//! - Tags: `TagName` or `"Tag name/with.special chars"`
+//! - Operators in order of increasing precedence:
+//!   `OR ||`, `AND &&`, `NOT !`, `= == != <> < <= > >=`, `+ -`, `* / %`,
+//!   unary `-`
+use nom::branch::alt;
+use nom::bytes::complete::{tag, tag_no_case, take_while, take_while1};
+use nom::character::complete::{char, digit0, digit1, multispace0, none_of, one_of, satisfy};
+use nom::combinator::{eof, map, map_res, not, opt, peek, recognize, value};
+use nom::multi::{fold_many0, many0};
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::IResult;
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Str(String),
+}
+
+impl Value {
+    /// Interpret a tag value. Numbers (including "true" and "false")
+    /// are converted to numbers, anything else is a string.
+    pub fn from_tag(tag_value: &str) -> Value {
+        let trimmed = tag_value.trim();
+        match trimmed.to_lowercase().as_str() {
+            "true" => Value::Number(1.0),
+            "false" => Value::Number(0.0),
+            _ => match trimmed.parse::<f64>() {
+                Ok(v) => Value::Number(v),
+                Err(_) => Value::Str(tag_value.to_string()),
+            },
+        }
+    }
+
+    pub fn as_bool(&self) -> bool {
+        match self {
+            Value::Number(v) => *v != 0.0,
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+
+    fn from_bool(b: bool) -> Value {
+        Value::Number(if b { 1.0 } else { 0.0 })
+    }
+
+    /// Format the value suitable for writing to a tag
+    pub fn to_tag_string(&self) -> String {
+        match self {
+            Value::Number(v) => v.to_string(),
+            Value::Str(s) => s.clone(),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Value::Number(v) => write!(f, "{}", v),
+            Value::Str(s) => write!(f, "'{}'", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BinOp::Or => "OR",
+            BinOp::And => "AND",
+            BinOp::Equal => "=",
+            BinOp::NotEqual => "!=",
+            BinOp::Less => "<",
+            BinOp::LessEqual => "<=",
+            BinOp::Greater => ">",
+            BinOp::GreaterEqual => ">=",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Const(Value),
+    Tag(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+pub enum ExprError {
+    Parse(String),
+    UnknownValue(String),
+    NotANumber(Value),
+}
+
+impl std::error::Error for ExprError {}
+
+impl Display for ExprError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            ExprError::Parse(msg) => write!(f, "Failed to parse expression: {}", msg),
+            ExprError::UnknownValue(tag) => write!(f, "Value of tag '{}' is unknown", tag),
+            ExprError::NotANumber(v) => write!(f, "Value {} is not a number", v),
+        }
+    }
+}
+
+fn number(v: Value) -> Result<f64, ExprError> {
+    match v {
+        Value::Number(v) => Ok(v),
+        v => Err(ExprError::NotANumber(v)),
+    }
+}
+
+impl Expr {
+    /// Evaluate the expression. Tag values are retrieved through lookup.
+    pub fn evaluate<F>(&self, lookup: &F) -> Result<Value, ExprError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        match self {
+            Expr::Const(v) => Ok(v.clone()),
+            Expr::Tag(name) => lookup(name)
+                .map(|v| Value::from_tag(&v))
+                .ok_or_else(|| ExprError::UnknownValue(name.clone())),
+            Expr::Not(arg) => Ok(Value::from_bool(!arg.evaluate(lookup)?.as_bool())),
+            Expr::Neg(arg) => Ok(Value::Number(-number(arg.evaluate(lookup)?)?)),
+            Expr::Binary(BinOp::Or, left, right) => Ok(Value::from_bool(
+                left.evaluate(lookup)?.as_bool() || right.evaluate(lookup)?.as_bool(),
+            )),
+            Expr::Binary(BinOp::And, left, right) => Ok(Value::from_bool(
+                left.evaluate(lookup)?.as_bool() && right.evaluate(lookup)?.as_bool(),
+            )),
+            Expr::Binary(op, left, right) => {
+                let left = left.evaluate(lookup)?;
+                let right = right.evaluate(lookup)?;
+                Self::binary(*op, left, right)
+            }
+        }
+    }
+
+    fn binary(op: BinOp, left: Value, right: Value) -> Result<Value, ExprError> {
+        use std::cmp::Ordering;
+        let ordering = || match (&left, &right) {
+            (Value::Number(l), Value::Number(r)) => l.partial_cmp(r),
+            (l, r) => Some(l.to_tag_string().cmp(&r.to_tag_string())),
+        };
+        Ok(match op {
+            BinOp::Equal => Value::from_bool(ordering() == Some(Ordering::Equal)),
+            BinOp::NotEqual => Value::from_bool(ordering() != Some(Ordering::Equal)),
+            BinOp::Less => Value::from_bool(ordering() == Some(Ordering::Less)),
+            BinOp::LessEqual => Value::from_bool(matches!(
+                ordering(),
+                Some(Ordering::Less | Ordering::Equal)
+            )),
+            BinOp::Greater => Value::from_bool(ordering() == Some(Ordering::Greater)),
+            BinOp::GreaterEqual => Value::from_bool(matches!(
+                ordering(),
+                Some(Ordering::Greater | Ordering::Equal)
+            )),
+            BinOp::Add => Value::Number(number(left)? + number(right)?),
+            BinOp::Sub => Value::Number(number(left)? - number(right)?),
+            BinOp::Mul => Value::Number(number(left)? * number(right)?),
+            BinOp::Div => Value::Number(number(left)? / number(right)?),
+            BinOp::Rem => Value::Number(number(left)? % number(right)?),
+            BinOp::Or | BinOp::And => unreachable!(),
+        })
+    }
+
+    /// Add the names of all tags used by the expression to the set
+    pub fn collect_tags(&self, tags: &mut HashSet<String>) {
+        match self {
+            Expr::Const(_) => {}
+            Expr::Tag(name) => {
+                tags.insert(name.clone());
+            }
+            Expr::Not(arg) | Expr::Neg(arg) => arg.collect_tags(tags),
+            Expr::Binary(_, left, right) => {
+                left.collect_tags(tags);
+                right.collect_tags(tags);
+            }
+        }
+    }
+
+    /// Names of all tags used by the expression
+    pub fn tags(&self) -> HashSet<String> {
+        let mut tags = HashSet::new();
+        self.collect_tags(&mut tags);
+        tags
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Expr::Const(v) => write!(f, "{}", v),
+            Expr::Tag(name) => write!(f, "\"{}\"", name),
+            Expr::Not(arg) => write!(f, "NOT ({})", arg),
+            Expr::Neg(arg) => write!(f, "-({})", arg),
+            Expr::Binary(op, left, right) => write!(f, "({}) {} ({})", left, op.as_str(), right),
+        }
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+fn ws<'a, O, F>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O>,
+{
+    delimited(multispace0, inner, multispace0)
+}
+
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(tag_no_case(word), not(peek(satisfy(is_ident_char))))
+}
+
+fn parse_number(input: &str) -> IResult<&str, Expr> {
+    map_res(
+        recognize(tuple((
+            digit1,
+            opt(pair(char('.'), digit0)),
+            opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+        ))),
+        |s: &str| s.parse::<f64>().map(|v| Expr::Const(Value::Number(v))),
+    )(input)
+}
+
+fn parse_string(input: &str) -> IResult<&str, Expr> {
+    map(
+        delimited(char('\''), recognize(many0(none_of("'"))), char('\'')),
+        |s: &str| Expr::Const(Value::Str(s.to_string())),
+    )(input)
+}
+
+fn parse_bool(input: &str) -> IResult<&str, Expr> {
+    alt((
+        value(Expr::Const(Value::Number(1.0)), keyword("true")),
+        value(Expr::Const(Value::Number(0.0)), keyword("false")),
+    ))(input)
+}
+
+fn parse_tag(input: &str) -> IResult<&str, Expr> {
+    alt((
+        map(
+            delimited(char('"'), take_while1(|c| c != '"'), char('"')),
+            |s: &str| Expr::Tag(s.to_string()),
+        ),
+        map(
+            recognize(pair(satisfy(is_ident_start), take_while(is_ident_char))),
+            |s: &str| Expr::Tag(s.to_string()),
+        ),
+    ))(input)
+}
+
+fn parse_primary(input: &str) -> IResult<&str, Expr> {
+    ws(alt((
+        parse_number,
+        parse_string,
+        parse_bool,
+        parse_tag,
+        delimited(char('('), parse_or, char(')')),
+    )))(input)
+}
+
+fn parse_unary(input: &str) -> IResult<&str, Expr> {
+    alt((
+        map(preceded(ws(char('-')), parse_unary), |e| Expr::Neg(Box::new(e))),
+        parse_primary,
+    ))(input)
+}
+
+fn binary_fold<'a, P, O>(
+    input: &'a str,
+    mut operand: P,
+    op: O,
+) -> IResult<&'a str, Expr>
+where
+    P: FnMut(&'a str) -> IResult<&'a str, Expr> + Copy,
+    O: FnMut(&'a str) -> IResult<&'a str, BinOp>,
+{
+    let (input, first) = operand(input)?;
+    fold_many0(
+        pair(ws(op), operand),
+        move || first.clone(),
+        |left, (op, right)| Expr::Binary(op, Box::new(left), Box::new(right)),
+    )(input)
+}
+
+fn parse_multiplicative(input: &str) -> IResult<&str, Expr> {
+    binary_fold(
+        input,
+        parse_unary,
+        alt((
+            value(BinOp::Mul, char('*')),
+            value(BinOp::Div, char('/')),
+            value(BinOp::Rem, char('%')),
+        )),
+    )
+}
+
+fn parse_additive(input: &str) -> IResult<&str, Expr> {
+    binary_fold(
+        input,
+        parse_multiplicative,
+        alt((value(BinOp::Add, char('+')), value(BinOp::Sub, char('-')))),
+    )
+}
+
+fn parse_compare_op(input: &str) -> IResult<&str, BinOp> {
+    alt((
+        value(BinOp::LessEqual, tag("<=")),
+        value(BinOp::GreaterEqual, tag(">=")),
+        value(BinOp::NotEqual, tag("!=")),
+        value(BinOp::NotEqual, tag("<>")),
+        value(BinOp::Equal, tag("==")),
+        value(BinOp::Equal, tag("=")),
+        value(BinOp::Less, tag("<")),
+        value(BinOp::Greater, tag(">")),
+    ))(input)
+}
+
+fn parse_compare(input: &str) -> IResult<&str, Expr> {
+    let (input, left) = parse_additive(input)?;
+    let (input, right) = opt(pair(ws(parse_compare_op), parse_additive))(input)?;
+    Ok((
+        input,
+        match right {
+            Some((op, right)) => Expr::Binary(op, Box::new(left), Box::new(right)),
+            None => left,
+        },
+    ))
+}
+
+fn parse_not(input: &str) -> IResult<&str, Expr> {
+    alt((
+        map(
+            preceded(
+                ws(alt((keyword("not"), terminated(tag("!"), not(char('=')))))),
+                parse_not,
+            ),
+            |e| Expr::Not(Box::new(e)),
+        ),
+        parse_compare,
+    ))(input)
+}
+
+fn parse_and(input: &str) -> IResult<&str, Expr> {
+    binary_fold(
+        input,
+        parse_not,
+        value(BinOp::And, alt((tag("&&"), keyword("and")))),
+    )
+}
+
+fn parse_or(input: &str) -> IResult<&str, Expr> {
+    binary_fold(
+        input,
+        parse_and,
+        value(BinOp::Or, alt((tag("||"), keyword("or")))),
+    )
+}
+
+pub fn parse_expr(input: &str) -> Result<Expr, ExprError> {
+    match terminated(parse_or, eof)(input) {
+        Ok((_, expr)) => Ok(expr),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(ExprError::Parse(format!(
+            "Unexpected input at \"{}\"",
+            e.input
+        ))),
+        Err(nom::Err::Incomplete(_)) => Err(ExprError::Parse("Incomplete input".to_string())),
+    }
+}
+
+#[test]
+fn test_expr_parser() {
+    assert_eq!(
+        parse_expr("AlarmCountA > 0 OR AlarmCountB > 0")
+            .unwrap()
+            .to_string(),
+        "((\"AlarmCountA\") > (0)) OR ((\"AlarmCountB\") > (0))"
+    );
+    assert_eq!(
+        parse_expr("TankLevel > 80 && PumpRunning == 0")
+            .unwrap()
+            .to_string(),
+        "((\"TankLevel\") > (80)) AND ((\"PumpRunning\") = (0))"
+    );
+    assert_eq!(
+        parse_expr("NOT \"Plant/Area 1\" = 'on'")
+            .unwrap()
+            .to_string(),
+        "NOT ((\"Plant/Area 1\") = ('on'))"
+    );
+    assert_eq!(
+        parse_expr("1 + 2 * -3").unwrap().to_string(),
+        "(1) + ((2) * (-(3)))"
+    );
+    assert!(parse_expr("A >").is_err());
+    assert!(parse_expr("A B").is_err());
+}
+
+#[test]
+fn test_expr_evaluate() {
+    let lookup = |name: &str| match name {
+        "A" => Some("3".to_string()),
+        "B" => Some("false".to_string()),
+        "S" => Some("on".to_string()),
+        _ => None,
+    };
+    let eval = |s: &str| parse_expr(s).unwrap().evaluate(&lookup);
+    assert_eq!(eval("A > 2 AND NOT B").unwrap(), Value::Number(1.0));
+    assert_eq!(eval("A * 2 - 1").unwrap(), Value::Number(5.0));
+    assert_eq!(eval("S = 'on'").unwrap(), Value::Number(1.0));
+    assert_eq!(eval("(A + 1) % 3").unwrap(), Value::Number(1.0));
+    assert!(matches!(eval("C > 0"), Err(ExprError::UnknownValue(_))));
+    assert!(matches!(eval("S + 1"), Err(ExprError::NotANumber(_))));
+}
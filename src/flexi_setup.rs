@@ -1,8 +1,42 @@
+use crate::syslog::{self, SyslogWriter};
 use clap::{Arg, ArgMatches, Command};
-use flexi_logger::{self, Cleanup, Criterion, FileSpec, LoggerHandle, Naming};
+use flexi_logger::{
+    self, Cleanup, Criterion, DeferredNow, Duplicate, FileSpec, LoggerHandle, Naming,
+};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Record};
+use serde_json::Map;
 use std::error::Error;
+use std::io::Write;
+use std::sync::OnceLock;
+
+// Name of this instance, included in every log record
+static INSTANCE: OnceLock<String> = OnceLock::new();
+
+// Instance names are used in file names
+fn valid_instance_name(name: &str) -> Result<(), String> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        Ok(())
+    } else {
+        Err("Only letters, digits, '_', '-' and '.' are allowed".to_string())
+    }
+}
 
 pub fn add_flexi_args<'a>(app_args: Command<'a>) -> Command<'a> {
+    let app_args = app_args.arg(
+        Arg::new("instance")
+            .long("instance")
+            .value_name("NAME")
+            .validator(valid_instance_name)
+            .help(
+                "Name of this instance when running several servers on one machine. \
+                 Selects mtp_audioplayer-NAME.xml as default configuration",
+            ),
+    );
     let app_args = app_args.arg(
         Arg::new("log_file")
             .long("log_file")
@@ -25,16 +59,120 @@ pub fn add_flexi_args<'a>(app_args: Command<'a>) -> Command<'a> {
             .value_parser(clap::value_parser!(usize))
             .help("Maximum number of log files"),
     );
+    let app_args = app_args.arg(
+        Arg::new("log_format")
+            .long("log-format")
+            .value_name("FORMAT")
+            .default_value("text")
+            .possible_values(["text", "json"])
+            .help("Write log records as text or as one JSON object per line"),
+    );
+    let app_args = app_args.arg(
+        Arg::new("syslog")
+            .long("syslog")
+            .value_name("URL")
+            .help("Also send log to a syslog server, e.g. udp://host:514 or tcp://host:601"),
+    );
     app_args
 }
 
+/// True if JSON log records are requested
+pub fn json_requested(args: &ArgMatches) -> bool {
+    args.value_of("log_format") == Some("json")
+}
+
+// Adds the key-value pairs of a record to a JSON object
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'a, 'kvs> VisitSource<'kvs> for JsonFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(v) = value.to_bool() {
+            serde_json::Value::from(v)
+        } else if let Some(v) = value.to_i64() {
+            serde_json::Value::from(v)
+        } else if let Some(v) = value.to_f64() {
+            serde_json::Value::from(v)
+        } else {
+            serde_json::Value::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+// Name given with `--instance`
+fn instance_name(args: &ArgMatches) -> Option<&str> {
+    args.value_of("instance")
+}
+
+/// Format a log record as text, prefixed by the instance name if set
+pub fn text_format(
+    w: &mut dyn Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    if let Some(instance) = INSTANCE.get() {
+        write!(w, "[{}] ", instance)?;
+    }
+    flexi_logger::detailed_format(w, now, record)
+}
+
+/// Format a log record as a single line JSON object
+pub fn json_format(
+    w: &mut dyn Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let mut object = Map::new();
+    object.insert("timestamp".into(), now.format_rfc3339().to_string().into());
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    if let Some(instance) = INSTANCE.get() {
+        object.insert("instance".into(), instance.as_str().into());
+    }
+    object.insert("message".into(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut JsonFields(&mut object));
+    write!(w, "{}", serde_json::Value::Object(object))
+}
+
+/// Temporarily replace the log specification with a single level. None
+/// restores the original specification.
+pub fn flexi_set_log_level(handle: &mut LoggerHandle, level: Option<LevelFilter>) {
+    handle.pop_temp_spec();
+    if let Some(level) = level {
+        if let Err(e) = handle.parse_and_push_temp_spec(level.as_str().to_lowercase()) {
+            log::error!("Failed to change log level: {}", e);
+        }
+    }
+}
+
 pub fn setup_flexi_loggger(args: &ArgMatches) -> Result<LoggerHandle, Box<dyn Error>> {
     let mut logger = flexi_logger::Logger::try_with_env_or_str("info")?;
+    let instance = instance_name(args);
+    if let Some(instance) = instance {
+        let _ = INSTANCE.set(instance.to_string());
+    }
+    // Sends nothing until a syslog server is set
+    let syslog_writer = Box::new(SyslogWriter::new(instance));
     if let Some(filepath) = args.get_one::<String>("log_file") {
-        let spec = FileSpec::try_from(filepath)?;
-        logger = logger.log_to_file(spec);
+        let mut spec = FileSpec::try_from(filepath)?;
+        if let Some(instance) = instance {
+            spec = spec.discriminant(instance);
+        }
+        logger = logger.log_to_file_and_writer(spec, syslog_writer);
+    } else {
+        logger = logger
+            .log_to_writer(syslog_writer)
+            .duplicate_to_stderr(Duplicate::All);
+    }
+    if let Some(url) = args.get_one::<String>("syslog") {
+        syslog::set_server(url).map_err(|e| -> Box<dyn Error> { e })?;
+    }
+    if json_requested(args) {
+        logger = logger.format(json_format);
+    } else {
+        logger = logger.format(text_format);
     }
-    logger = logger.format(flexi_logger::detailed_format);
     let size = args.try_get_one::<u64>("log_file_size")?.unwrap();
     let criterion = Criterion::Size(*size);
     let count = args.try_get_one::<usize>("log_file_count")?.unwrap();
@@ -0,0 +1,125 @@
+//! GPIO outputs driven by actions or following alarm filters
+//!
+//! The lines are requested through the Linux GPIO character device when
+//! the configuration is set up and are held until the server exits.
+
+use crate::actions::alarm_dispatcher::AlarmDispatcher;
+use crate::app_config::AlarmContext;
+use crate::read_config::GpioOutputConfig;
+use crate::util::error::DynResult;
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use log::{debug, error};
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::Arc;
+
+/// All configured outputs
+#[derive(Default)]
+pub struct GpioOutputs {
+    #[cfg(all(feature = "gpio", target_os = "linux"))]
+    lines: HashMap<String, LineHandle>,
+}
+
+impl GpioOutputs {
+    /// Request the lines of all outputs. They start off.
+    #[cfg(all(feature = "gpio", target_os = "linux"))]
+    pub fn new(confs: &[GpioOutputConfig]) -> DynResult<GpioOutputs> {
+        let mut chips = HashMap::new();
+        let mut lines = HashMap::new();
+        for conf in confs {
+            let chip = match chips.entry(conf.chip.as_str()) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(
+                    Chip::new(&conf.chip)
+                        .map_err(|e| format!("Failed to open {}: {}", conf.chip, e))?,
+                ),
+            };
+            let mut flags = LineRequestFlags::OUTPUT;
+            if conf.active_low {
+                flags |= LineRequestFlags::ACTIVE_LOW;
+            }
+            let handle = chip
+                .get_line(conf.line)
+                .and_then(|line| line.request(flags, 0, "mtp_audioplayer"))
+                .map_err(|e| format!("GPIO output {}: {}", conf.name, e))?;
+            lines.insert(conf.name.clone(), handle);
+        }
+        Ok(GpioOutputs { lines })
+    }
+
+    #[cfg(not(all(feature = "gpio", target_os = "linux")))]
+    pub fn new(confs: &[GpioOutputConfig]) -> DynResult<GpioOutputs> {
+        if !confs.is_empty() {
+            return Err("Built without GPIO support".into());
+        }
+        Ok(GpioOutputs {})
+    }
+
+    /// Turn an output on or off
+    #[cfg(all(feature = "gpio", target_os = "linux"))]
+    pub fn set(&self, name: &str, value: bool) -> DynResult<()> {
+        let line = self
+            .lines
+            .get(name)
+            .ok_or_else(|| format!("No GPIO output named {}", name))?;
+        debug!("GPIO output {} = {}", name, value);
+        line.set_value(value.into())?;
+        Ok(())
+    }
+
+    #[cfg(not(all(feature = "gpio", target_os = "linux")))]
+    pub fn set(&self, name: &str, value: bool) -> DynResult<()> {
+        debug!("GPIO output {} = {}", name, value);
+        Err("Built without GPIO support".into())
+    }
+}
+
+async fn follow_filter(
+    outputs: Arc<GpioOutputs>,
+    name: String,
+    filter: String,
+    alarm_ctxt: Arc<AlarmContext>,
+) {
+    let mut active = None;
+    loop {
+        let (count, changed) = match alarm_ctxt.wait_alarm_filter(&filter) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Failed to wait for alarm filter {}: {}", filter, e);
+                return;
+            }
+        };
+        if active != Some(count > 0) {
+            active = Some(count > 0);
+            if let Err(e) = outputs.set(&name, count > 0) {
+                error!("Failed to set GPIO output {}: {}", name, e);
+            }
+        }
+        if changed.await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Keep outputs with a filter on while the filter is active
+pub fn start_following(
+    outputs: &Arc<GpioOutputs>,
+    confs: &[GpioOutputConfig],
+    alarm_ctxt: &Arc<AlarmContext>,
+) -> DynResult<()> {
+    for conf in confs {
+        if let Some(filter) = &conf.follow_filter {
+            alarm_ctxt
+                .get_filter_count(filter)
+                .map_err(|e| format!("Alarm filter {} for GPIO output: {}", filter, e))?;
+            tokio::spawn(follow_filter(
+                outputs.clone(),
+                conf.name.clone(),
+                filter.clone(),
+                alarm_ctxt.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
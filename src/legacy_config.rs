@@ -0,0 +1,129 @@
+//! Reads the JSON configuration format used by older versions of the
+//! player.
+//!
+//! ```json
+//! {
+//!   "playback_device": "default",
+//!   "bind": "/tmp/siemens/automation/HmiRunTime",
+//!   "clips": {
+//!     "alarm": {"file": "alarm.wav", "tag": "SoundAlarm", "volume": 0.8}
+//!   }
+//! }
+//! ```
+//!
+//! Each clip is played every time its tag changes value. This is done
+//! by generating a state machine for every clip.
+
+use crate::actions::wait_tag::TagCondition;
+use crate::read_config::{
+    ActionType, ClipType, PlayerConfig, StateConfig, StateMachineConfig, TagConfig,
+};
+use crate::util::error::DynResult;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Deserialize)]
+struct LegacyClip {
+    file: String,
+    tag: String,
+    volume: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct LegacyConfig {
+    playback_device: Option<String>,
+    bind: Option<String>,
+    rate: Option<u32>,
+    channels: Option<u8>,
+    // Sorted so that the generated configuration is stable
+    #[serde(default)]
+    clips: BTreeMap<String, LegacyClip>,
+}
+
+// Play the clip every time the tag changes
+fn toggle_state_machine(clip_id: &str, tag: &str) -> StateMachineConfig {
+    let action = ActionType::Repeat {
+        count: None,
+        action: Box::new(ActionType::Sequence(vec![
+            ActionType::WaitTag {
+                tag_name: tag.to_string(),
+                condition: TagCondition::Changed,
+            },
+            ActionType::Play {
+                priority: 0,
+                timeout: None,
+                sound: clip_id.to_string(),
+                device: None,
+            },
+        ])),
+    };
+    StateMachineConfig {
+        id: format!("legacy_{}", clip_id),
+        states: vec![StateConfig {
+            id: "toggle".to_string(),
+            action,
+        }],
+        state_tag: None,
+        command_tag: None,
+    }
+}
+
+/// Translate a legacy JSON configuration and add it to player
+pub fn read_str_into(input: &str, player: &mut PlayerConfig) -> DynResult<()> {
+    let legacy: LegacyConfig = serde_json::from_str(input)?;
+    if let Some(bind) = legacy.bind {
+        player.bind = bind;
+    }
+    if let Some(device) = legacy.playback_device {
+        player.playback_device = device;
+    }
+    if let Some(rate) = legacy.rate {
+        player.rate = rate;
+    }
+    if let Some(channels) = legacy.channels {
+        player.channels = channels;
+    }
+    for (id, clip) in legacy.clips {
+        player.clips.insert(
+            id.clone(),
+            ClipType::File {
+                file_name: clip.file,
+                amplitude: clip.volume,
+                profile: None,
+            },
+        );
+        if !player.tags.iter().any(|t| t.internal_name() == clip.tag) {
+            player.tags.push(TagConfig::new(&clip.tag));
+        }
+        player
+            .state_machines
+            .push(toggle_state_machine(&id, &clip.tag));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_legacy_config() {
+    let input = r#"{
+  "playback_device": "hw:0",
+  "bind": "/tmp/pipe",
+  "clips": {
+    "alarm": {"file": "alarm.wav", "tag": "SoundAlarm", "volume": 0.5},
+    "info": {"file": "info.wav", "tag": "SoundInfo"}
+  }
+}"#;
+    let mut player = PlayerConfig::default();
+    read_str_into(input, &mut player).unwrap();
+    assert_eq!(player.bind, "/tmp/pipe");
+    assert_eq!(player.playback_device, "hw:0");
+    assert_eq!(player.tags.len(), 2);
+    assert_eq!(player.state_machines.len(), 2);
+    assert_eq!(player.state_machines[0].id, "legacy_alarm");
+    assert!(matches!(
+        player.clips.get("alarm"),
+        Some(ClipType::File {
+            amplitude: Some(a),
+            ..
+        }) if *a == 0.5
+    ));
+}
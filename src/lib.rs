@@ -1,37 +1,133 @@
+//! Plays sound clips controlled by tags and alarms from WinCC through Open Pipe
+//!
+//! The player is built with the `player` feature and `openpipe_tool` with
+//! the `tools` feature, both enabled by default. Sound cards are used
+//! through cpal with the `audio` feature, also enabled by default. Without
+//! it, or with `no-audio`, the player discards its output. With
+//! `default-features = false` only [`open_pipe`], [`alarm_filter`],
+//! [`expr`], [`testing`] and [`util`] are built, without the audio and
+//! web dependencies.
+
+#[cfg(feature = "player")]
 pub mod actions;
 pub mod alarm_filter;
+#[cfg(feature = "player")]
 pub mod app_config;
+#[cfg(feature = "player")]
+pub mod audio_file;
+#[cfg(feature = "player")]
+pub mod audit_log;
+#[cfg(feature = "player")]
 pub mod clip_player;
+#[cfg(feature = "player")]
 pub mod clip_queue;
+#[cfg(feature = "player")]
+pub mod config_check;
+#[cfg(feature = "player")]
+pub mod config_tree;
+#[cfg(feature = "player")]
+pub mod control_api;
+#[cfg(feature = "dbus")]
+pub mod dbus_service;
+pub mod expr;
+#[cfg(feature = "player")]
+pub mod gpio_output;
+#[cfg(feature = "player")]
+pub mod legacy_config;
+#[cfg(feature = "player")]
+pub mod monitor_stream;
+#[cfg(all(feature = "player", not(feature = "metrics")))]
+mod no_metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt_bridge;
+#[cfg(all(feature = "player", any(feature = "no-audio", not(feature = "audio"))))]
+mod null_output;
 pub mod open_pipe;
+#[cfg(feature = "player")]
 pub mod priority_scheduler;
+#[cfg(feature = "metrics")]
+mod prometheus_metrics;
+#[cfg(feature = "player")]
 pub mod read_config;
+#[cfg(feature = "player")]
 pub mod sample_buffer;
+#[cfg(feature = "player")]
+pub mod sample_stream;
+#[cfg(feature = "player")]
+pub mod snapcast;
+#[cfg(feature = "snmp")]
+pub mod snmp;
+#[cfg(feature = "player")]
 pub mod state_machine;
+#[cfg(feature = "player")]
+pub mod syslog;
+#[cfg(feature = "player")]
+pub mod tag_source;
+pub mod testing;
 pub mod util;
 
 #[cfg(feature = "systemd")]
 mod systemd;
 
-#[cfg(not(feature = "systemd"))]
+#[cfg(all(windows, feature = "windows-service"))]
+mod win_service;
+
+#[cfg(all(
+    feature = "player",
+    not(any(feature = "systemd", all(windows, feature = "windows-service")))
+))]
 mod no_systemd;
 
+#[cfg(feature = "player")]
 pub mod daemon {
-    #[cfg(not(feature = "systemd"))]
-    pub use crate::no_systemd::{add_args, exiting, ready, start};
+    #[cfg(not(any(feature = "systemd", all(windows, feature = "windows-service"))))]
+    pub use crate::no_systemd::{
+        add_args, exiting, panicked, ready, set_log_level, start, status, stop_requested,
+        watchdog, watchdog_interval,
+    };
     #[cfg(feature = "systemd")]
-    pub use crate::systemd::{add_args, exiting, ready, start};
+    pub use crate::systemd::{
+        add_args, exiting, panicked, ready, set_log_level, start, status, stop_requested,
+        watchdog, watchdog_interval,
+    };
+    #[cfg(all(windows, feature = "windows-service", not(feature = "systemd")))]
+    pub use crate::win_service::{
+        add_args, exiting, panicked, ready, set_log_level, start, status, stop_requested,
+        watchdog, watchdog_interval,
+    };
 }
+#[cfg(feature = "player")]
 mod flexi_setup;
 
+/// Prometheus metrics, enabled with the `metrics` feature. Without it
+/// nothing is recorded and [`start`](metrics::start) fails.
+#[cfg(feature = "player")]
+pub mod metrics {
+    #[cfg(not(feature = "metrics"))]
+    pub use crate::no_metrics::{
+        alarm_filter_matches, clip_played, openpipe_reconnected, playback_underrun, start,
+        state_entered,
+    };
+    #[cfg(feature = "metrics")]
+    pub use crate::prometheus_metrics::{
+        alarm_filter_matches, clip_played, openpipe_reconnected, playback_underrun, start,
+        state_entered,
+    };
+}
+
 #[cfg(feature = "alsa")]
 mod alsa;
-#[cfg(not(feature = "alsa"))]
+#[cfg(all(feature = "player", windows, not(feature = "alsa")))]
+mod wasapi;
+#[cfg(all(feature = "player", not(any(feature = "alsa", windows))))]
 mod volume_dummy;
 
+#[cfg(feature = "player")]
 pub mod volume_control {
     #[cfg(feature = "alsa")]
     pub use crate::alsa::volume_alsa::VolumeControl;
-    #[cfg(not(feature = "alsa"))]
+    #[cfg(not(any(feature = "alsa", windows)))]
     pub use crate::volume_dummy::VolumeControl;
+    #[cfg(all(windows, not(feature = "alsa")))]
+    pub use crate::wasapi::volume_wasapi::VolumeControl;
 }
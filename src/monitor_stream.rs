@@ -0,0 +1,93 @@
+//! HTTP stream of the played audio
+//!
+//! `GET /monitor.wav` returns an endless 16-bit WAV stream of everything
+//! sent to the playback device. It can be opened in most media players
+//! for listening in on the output remotely.
+
+use crate::app_config::PlaybackContext;
+use crate::read_config::MonitorStreamConfig;
+use crate::util::error::DynResult;
+use futures::stream::{self, StreamExt};
+use log::info;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use warp::http::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use warp::hyper::body::{Body, Bytes};
+use warp::reply::Response;
+use warp::Filter;
+
+// Buffers queued for each listener. More are dropped.
+const TAP_CAPACITY: usize = 64;
+
+// Header of a WAV file with unknown length
+fn wav_header(rate: u32, channels: u16) -> Vec<u8> {
+    let block_align = channels * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&rate.to_le_bytes());
+    header.extend_from_slice(&(rate * u32::from(block_align)).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    // Bits per sample
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+fn monitor(playback_ctxt: Arc<PlaybackContext>, addr: Option<SocketAddr>) -> Response {
+    info!("Monitor stream opened by {:?}", addr);
+    let header = wav_header(playback_ctxt.rate, playback_ctxt.channels.into());
+    // The tap is removed when the listener disconnects and the stream
+    // is dropped
+    let tap = playback_ctxt.clip_queue.add_output_tap(TAP_CAPACITY);
+    let samples = stream::unfold(tap, |mut tap| async move {
+        let pcm = tap.recv().await?;
+        let mut bytes = Vec::with_capacity(pcm.len() * 2);
+        for s in pcm {
+            bytes.extend_from_slice(&s.to_le_bytes());
+        }
+        Some((Ok::<_, Infallible>(Bytes::from(bytes)), tap))
+    });
+    let body = stream::once(async move { Ok(Bytes::from(header)) }).chain(samples);
+    let mut resp = Response::new(Body::wrap_stream(body));
+    let headers = resp.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("audio/wav"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    resp
+}
+
+/// Start listening for monitor requests
+pub fn start(conf: &MonitorStreamConfig, playback_ctxt: &Arc<PlaybackContext>) -> DynResult<()> {
+    let addr: SocketAddr = conf
+        .bind
+        .parse()
+        .map_err(|e| format!("Invalid monitor stream address {}: {}", conf.bind, e))?;
+    let playback_ctxt = playback_ctxt.clone();
+    let route = warp::get()
+        .and(warp::path!("monitor.wav"))
+        .map(move || playback_ctxt.clone())
+        .and(warp::addr::remote())
+        .map(monitor);
+    let (addr, server) = warp::serve(route).try_bind_ephemeral(addr)?;
+    info!("Monitor stream on http://{}/monitor.wav", addr);
+    tokio::spawn(server);
+    Ok(())
+}
+
+#[test]
+fn test_wav_header() {
+    let header = wav_header(44100, 2);
+    assert_eq!(header.len(), 44);
+    assert_eq!(&header[0..4], b"RIFF");
+    assert_eq!(&header[22..24], &2u16.to_le_bytes());
+    assert_eq!(&header[24..28], &44100u32.to_le_bytes());
+    assert_eq!(&header[28..32], &(44100u32 * 4).to_le_bytes());
+    assert_eq!(&header[36..40], b"data");
+}
@@ -0,0 +1,127 @@
+//! Mirrors tags to and from MQTT topics
+//!
+//! Changes of published tags are sent to their topics. Messages on
+//! subscribed topics are written to their tags, and from there to the
+//! pipe. A value received from a topic isn't published back to it.
+
+use crate::actions::tag_dispatcher::TagDispatcher;
+use crate::actions::tag_setter::TagSetter;
+use crate::app_config::TagContext;
+use crate::read_config::{MqttConfig, MqttTopicConfig};
+use crate::util::error::DynResult;
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Last value sent or received for each tag
+type LastValues = Arc<Mutex<HashMap<String, String>>>;
+
+// Returns true if the value differs from the last one and records it
+fn changed(last: &LastValues, tag: &str, value: &str) -> bool {
+    let mut last = last.lock().unwrap();
+    if last.get(tag).map(|v| v.as_str()) == Some(value) {
+        return false;
+    }
+    last.insert(tag.to_string(), value.to_string());
+    true
+}
+
+async fn publish_tag(
+    client: AsyncClient,
+    conf: MqttTopicConfig,
+    retain: bool,
+    tag_ctxt: Arc<TagContext>,
+    last: LastValues,
+) {
+    loop {
+        let (value, next) = match tag_ctxt.wait_value(&conf.tag) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Failed to observe tag {} for MQTT: {}", conf.tag, e);
+                return;
+            }
+        };
+        if let Some(value) = value {
+            if changed(&last, &conf.tag, &value) {
+                debug!("Publishing {} = {} to {}", conf.tag, value, conf.topic);
+                if let Err(e) = client
+                    .publish(&conf.topic, QoS::AtLeastOnce, retain, value)
+                    .await
+                {
+                    error!("Failed to publish to {}: {}", conf.topic, e);
+                }
+            }
+        }
+        if next.await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Connect to the broker and start mirroring tags
+pub fn start(conf: &MqttConfig, tag_ctxt: &Arc<TagContext>) -> DynResult<()> {
+    let mut options = MqttOptions::new(&conf.client_id, &conf.host, conf.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(user), Some(password)) = (&conf.user, &conf.password) {
+        options.set_credentials(user, password);
+    }
+    let (client, mut event_loop) = AsyncClient::new(options, 64);
+    let last = LastValues::default();
+    for topic in &conf.publish {
+        tokio::spawn(publish_tag(
+            client.clone(),
+            topic.clone(),
+            conf.retain,
+            tag_ctxt.clone(),
+            last.clone(),
+        ));
+    }
+    let subscriptions: HashMap<String, String> = conf
+        .subscribe
+        .iter()
+        .map(|s| (s.topic.clone(), s.tag.clone()))
+        .collect();
+    let tag_ctxt = Arc::downgrade(tag_ctxt);
+    let broker = format!("{}:{}", conf.host, conf.port);
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                // Subscriptions don't survive a new session
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker {}", broker);
+                    for topic in subscriptions.keys() {
+                        if let Err(e) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                            error!("Failed to subscribe to {}: {}", topic, e);
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(msg))) => {
+                    let tag = match subscriptions.get(&msg.topic) {
+                        Some(tag) => tag,
+                        None => continue,
+                    };
+                    let tag_ctxt = match tag_ctxt.upgrade() {
+                        Some(t) => t,
+                        None => return,
+                    };
+                    let value = String::from_utf8_lossy(&msg.payload);
+                    debug!("Received {} = {} from {}", tag, value, msg.topic);
+                    changed(&last, tag, &value);
+                    if let Err(e) = tag_ctxt.set_tag(tag, &value) {
+                        error!("Failed to set tag {} from MQTT: {}", tag, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection to {} failed: {}", broker, e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
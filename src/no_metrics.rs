@@ -0,0 +1,22 @@
+use crate::read_config::MetricsConfig;
+use crate::util::error::DynResult;
+
+// Built without metrics, nothing is recorded
+
+pub fn clip_played(_priority: i32) {}
+
+pub fn playback_underrun() {}
+
+pub fn openpipe_reconnected() {}
+
+pub fn alarm_filter_matches(_filter: &str, _count: usize) {}
+
+pub fn state_entered(_state_machine: &str, _state: &str) {}
+
+pub fn start(conf: &MetricsConfig) -> DynResult<()> {
+    Err(format!(
+        "Metrics on {} configured, built without metrics support",
+        conf.bind
+    )
+    .into())
+}
@@ -1,7 +1,7 @@
-use crate::flexi_setup::{add_flexi_args, setup_flexi_loggger};
+use crate::flexi_setup::{add_flexi_args, flexi_set_log_level, setup_flexi_loggger};
 use clap::{ArgMatches, Command};
 use flexi_logger::LoggerHandle;
-use log::info;
+use log::{info, LevelFilter};
 
 pub enum LogCtxt {
     None,                // No logging available
@@ -27,6 +27,31 @@ pub fn ready() {
     info!("Server ready");
 }
 
+/// Change the log level. None restores the level set at startup.
+pub fn set_log_level(ctxt: &mut LogCtxt, level: Option<LevelFilter>) {
+    if let LogCtxt::Flexi(handle) = ctxt {
+        flexi_set_log_level(handle, level);
+    }
+}
+
+/// Status is only reported to systemd
+pub fn status(_status: &str) {}
+
+/// The watchdog is only supported with systemd
+pub fn watchdog_interval() -> Option<std::time::Duration> {
+    None
+}
+
+pub fn watchdog() {}
+
+/// Never returns, there's no service manager that can stop the server
+pub async fn stop_requested() {
+    std::future::pending().await
+}
+
+/// There's no service manager to tell about a panic
+pub fn panicked(_message: &str) {}
+
 pub fn exiting(_ctxt: LogCtxt) {
     info!("Server exiting");
 }
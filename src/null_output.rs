@@ -0,0 +1,139 @@
+//! Audio output that discards all samples
+//!
+//! Used instead of a sound card when built with the `no-audio` feature.
+//! Samples are consumed in real time, or faster if the environment
+//! variable `MTP_AUDIOPLAYER_SPEED` is set to a factor, e.g. 10.
+
+use crate::clip_player::{ApplyGain, Error, StreamCallback};
+use crate::sample_buffer::{self, AsSampleSlice, SampleBuffer, SampleFormat};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Frames consumed at a time if no buffer size is configured
+const DEFAULT_PERIOD: u32 = 1024;
+
+pub struct Output {
+    rate: u32,
+    channels: u16,
+    // Frames per callback
+    period: u32,
+}
+
+impl Output {
+    pub fn open(
+        pcm_name: &str,
+        rate: u32,
+        channels: u16,
+        _sample_format: SampleFormat,
+        buffer_size: Option<u32>,
+    ) -> Result<Output, Error> {
+        info!(
+            "Built without audio support, discarding output for {}",
+            pcm_name
+        );
+        Ok(Output {
+            rate,
+            channels,
+            period: buffer_size.unwrap_or(DEFAULT_PERIOD).max(1),
+        })
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+}
+
+/// Stops the output when dropped
+pub struct NullStream {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for NullStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn parse_speed(value: Option<&str>) -> f64 {
+    match value.map(|v| v.trim().parse::<f64>()) {
+        None => 1.0,
+        Some(Ok(speed)) if speed > 0.0 && speed.is_finite() => speed,
+        Some(_) => {
+            warn!("Ignoring invalid playback speed, using real time");
+            1.0
+        }
+    }
+}
+
+// Time between callbacks
+fn period_duration(frames: u32, rate: u32, speed: f64) -> Duration {
+    Duration::from_secs_f64(f64::from(frames) / f64::from(rate.max(1)) / speed)
+}
+
+fn run<S>(output: Output, mut callback: StreamCallback, stop: Arc<AtomicBool>)
+where
+    S: Copy + sample_buffer::Sample + ApplyGain,
+    SampleBuffer: AsSampleSlice<S>,
+{
+    let speed = parse_speed(std::env::var("MTP_AUDIOPLAYER_SPEED").ok().as_deref());
+    let period = period_duration(output.period, output.rate, speed);
+    let mut buffer = vec![S::SAMPLE_OFFSET; output.period as usize * usize::from(output.channels)];
+    let mut next = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        callback.fill(&mut buffer);
+        next += period;
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        } else {
+            // Don't try to catch up after a stall
+            next = now;
+        }
+    }
+}
+
+/// Start consuming samples from the callback. Stops when the returned
+/// stream is dropped.
+pub fn start_output(
+    output: Output,
+    sample_format: SampleFormat,
+    callback: StreamCallback,
+) -> Result<NullStream, Error> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let thread = thread::Builder::new()
+        .name("null-output".to_string())
+        .spawn(move || match sample_format {
+            SampleFormat::I16 => run::<i16>(output, callback, thread_stop),
+            SampleFormat::U16 => run::<u16>(output, callback, thread_stop),
+            SampleFormat::F32 => run::<f32>(output, callback, thread_stop),
+        })
+        .map_err(|e| Error::ClipPlayer(format!("Failed to start output thread: {}", e)))?;
+    Ok(NullStream {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+#[test]
+fn test_period_duration() {
+    assert_eq!(parse_speed(None), 1.0);
+    assert_eq!(parse_speed(Some("10")), 10.0);
+    assert_eq!(parse_speed(Some("0")), 1.0);
+    assert_eq!(parse_speed(Some("fast")), 1.0);
+    assert_eq!(
+        period_duration(24000, 48000, 1.0),
+        Duration::from_millis(500)
+    );
+    assert_eq!(
+        period_duration(24000, 48000, 2.0),
+        Duration::from_millis(250)
+    );
+}
@@ -1,5 +1,6 @@
 use crate::open_pipe::connection::NotifyAlarm;
 use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 
 pub struct AlarmData {
@@ -84,7 +85,7 @@ impl From<&AlarmData> for NotifyAlarm {
 }
 
 /// Contains the parts from AlarmData that uniquely identifies an alarm
-#[derive(PartialEq, Eq, Hash, Clone)]
+#[derive(PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub struct AlarmId {
     pub id: i32,
 }
@@ -3,7 +3,8 @@ use super::connection::{
     ErrorInfo, Message, MessageVariant, NotifyAlarm, NotifyAlarms, ParamWrapperCap,
     SubscribeAlarmParams,
 };
-use log::{debug, error, warn};
+use crate::alarm_filter::{self, BoolOp};
+use log::{debug, error};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, Weak};
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
@@ -12,7 +13,7 @@ pub type ReplyFn = Mutex<dyn FnMut(Message) -> Result<()> + Send>;
 
 struct Subscription {
     system_names: Option<Vec<String>>,
-    filter: Option<String>,
+    filter: Option<BoolOp>,
     #[allow(dead_code)]
     language_id: Option<u32>,
     notify: Weak<ReplyFn>,
@@ -33,28 +34,47 @@ impl AlarmServer {
         }
     }
 
-    fn build_notify_alarms(
-        alarms: &[AlarmData],
-        system_names: &Option<&[String]>,
-        filter: &Option<&str>,
-    ) -> NotifyAlarms {
-        let mut alarm_notifications: Vec<NotifyAlarm> = Vec::new();
-        if system_names.is_some() {
-            warn!("Can't filter on system names");
+    // The system of an alarm is the part of the name before "::".
+    // Alarms without a system belong to every system.
+    fn in_systems(alarm: &AlarmData, system_names: &[String]) -> bool {
+        match alarm.name.split_once("::") {
+            Some((system, _)) => system_names.iter().any(|s| s == system),
+            None => true,
         }
+    }
 
-        if filter.is_some() {
-            warn!("Alarm filters not implemented");
-        }
-        for alarm in alarms {
-            // TODO Implement filtering
-            alarm_notifications.push(NotifyAlarm::from(alarm));
-        }
+    fn matches(alarm: &AlarmData, subscr: &Subscription) -> bool {
+        let in_systems = match &subscr.system_names {
+            Some(names) if !names.is_empty() => Self::in_systems(alarm, names),
+            _ => true,
+        };
+        in_systems
+            && match &subscr.filter {
+                Some(filter) => filter.evaluate(alarm),
+                None => true,
+            }
+    }
+
+    fn build_notify_alarms(alarms: &[AlarmData], subscr: &Subscription) -> NotifyAlarms {
+        let alarm_notifications: Vec<NotifyAlarm> = alarms
+            .iter()
+            .filter(|alarm| Self::matches(alarm, subscr))
+            .map(NotifyAlarm::from)
+            .collect();
         NotifyAlarms {
             alarms: alarm_notifications,
         }
     }
 
+    // The last known state of the alarm
+    fn previous(&self, alarm: &AlarmData) -> Option<&AlarmData> {
+        let id = AlarmId::from(alarm);
+        self.alarms
+            .binary_search_by(|a| AlarmId::from(a).cmp(&id))
+            .ok()
+            .map(|p| &self.alarms[p])
+    }
+
     fn subscribe(
         &mut self,
         params: SubscribeAlarmParams,
@@ -66,6 +86,21 @@ impl AlarmServer {
             filter,
             language_id,
         } = params;
+        let filter = match filter.as_deref().map(str::trim) {
+            None | Some("") => None,
+            Some(filter) => match alarm_filter::parse_filter(filter) {
+                Ok(filter) => Some(filter),
+                Err(e) => {
+                    return Message {
+                        message: MessageVariant::ErrorSubscribeAlarm(ErrorInfo {
+                            error_code: 2,
+                            error_description: format!("Invalid filter: {}", e),
+                        }),
+                        client_cookie: cookie.to_string(),
+                    }
+                }
+            },
+        };
         let subscr = Subscription {
             system_names,
             filter,
@@ -75,12 +110,7 @@ impl AlarmServer {
         };
         let msg = Message {
             message: MessageVariant::NotifySubscribeAlarm(
-                Self::build_notify_alarms(
-                    &self.alarms,
-                    &subscr.system_names.as_deref(),
-                    &subscr.filter.as_deref(),
-                )
-                .into(),
+                Self::build_notify_alarms(&self.alarms, &subscr).into(),
             ),
             client_cookie: subscr.cookie.clone(),
         };
@@ -114,18 +144,31 @@ impl AlarmServer {
             debug!("subscr: {}", subscr_cookie);
             let subscr = subscr.lock().unwrap();
             if subscr_cookie != cookie {
-                let notify = Self::build_notify_alarms(
-                    &alarms,
-                    &subscr.system_names.as_deref(),
-                    &subscr.filter.as_deref(),
-                );
+                // Alarms that stop matching are sent too, so that the
+                // subscriber sees them leave
+                let notify = NotifyAlarms {
+                    alarms: alarms
+                        .iter()
+                        .filter(|alarm| {
+                            Self::matches(alarm, &subscr)
+                                || self
+                                    .previous(alarm)
+                                    .is_some_and(|prev| Self::matches(prev, &subscr))
+                        })
+                        .map(NotifyAlarm::from)
+                        .collect(),
+                };
+                // Nothing this subscriber is interested in
+                if notify.alarms.is_empty() {
+                    continue;
+                }
                 if let Some(reply) = Weak::upgrade(&subscr.notify) {
-                    println!("Notified alarm: {} from {}", subscr_cookie, cookie);
+                    debug!("Notified alarm: {} from {}", subscr_cookie, cookie);
                     if let Err(e) = reply.lock().unwrap()(Message {
                         message: MessageVariant::NotifySubscribeAlarm(ParamWrapperCap {
                             params: notify,
                         }),
-                        client_cookie: cookie.to_string(),
+                        client_cookie: subscr.cookie.clone(),
                     }) {
                         error!("Failed to send alarm notify: {}", e);
                     }
@@ -150,6 +193,14 @@ impl AlarmServer {
         }
     }
 
+    /// Notify subscribers of new or changed alarms, as if sent by a client
+    pub fn update_alarms(&mut self, alarms: &[AlarmData]) {
+        let params = NotifyAlarms {
+            alarms: alarms.iter().map(NotifyAlarm::from).collect(),
+        };
+        self.notify_subscribe(params, "");
+    }
+
     pub fn handle_message(&mut self, msg: Message, notify_fn: &Weak<ReplyFn>) -> Option<Message> {
         match msg.message {
             MessageVariant::SubscribeAlarm(ParamWrapperCap { params }) => {
@@ -170,3 +221,90 @@ impl Default for AlarmServer {
         AlarmServer::new()
     }
 }
+
+#[test]
+fn test_filter() {
+    use crate::testing::AlarmBuilder;
+
+    let mut server = AlarmServer::new();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sent_notify = sent.clone();
+    let notify: Arc<ReplyFn> = Arc::new(Mutex::new(move |msg: Message| {
+        sent_notify.lock().unwrap().push(msg);
+        Ok(())
+    }));
+    let subscribe = |system_names: Option<Vec<String>>, filter: &str| Message {
+        message: MessageVariant::SubscribeAlarm(ParamWrapperCap {
+            params: SubscribeAlarmParams {
+                system_names,
+                filter: Some(filter.to_string()),
+                language_id: None,
+            },
+        }),
+        client_cookie: "sub".to_string(),
+    };
+    let reply = server.handle_message(subscribe(None, "Priority <"), &Arc::downgrade(&notify));
+    assert!(matches!(
+        reply.unwrap().message,
+        MessageVariant::ErrorSubscribeAlarm(_)
+    ));
+
+    let msg = subscribe(Some(vec!["Sys1".to_string()]), "Priority > 5");
+    server.handle_message(msg, &Arc::downgrade(&notify));
+    server.update_alarms(&[
+        AlarmBuilder::new(1).name("Sys1::Fire").priority(10).build(),
+        AlarmBuilder::new(2).name("Sys1::Door").priority(1).build(),
+        AlarmBuilder::new(3).name("Sys2::Fire").priority(10).build(),
+        AlarmBuilder::new(4).name("Local").priority(10).build(),
+    ]);
+    server.update_alarms(&[AlarmBuilder::new(5).name("Sys2::Door").build()]);
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 1);
+    match &sent[0].message {
+        MessageVariant::NotifySubscribeAlarm(n) => {
+            let ids: Vec<&str> = n.params.alarms.iter().map(|a| a.id.as_str()).collect();
+            assert_eq!(ids, ["1", "4"]);
+        }
+        m => panic!("Unexpected message {:?}", m),
+    }
+}
+
+#[test]
+fn test_filter_leave() {
+    use crate::testing::AlarmBuilder;
+
+    let mut server = AlarmServer::new();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sent_notify = sent.clone();
+    let notify: Arc<ReplyFn> = Arc::new(Mutex::new(move |msg: Message| {
+        sent_notify.lock().unwrap().push(msg);
+        Ok(())
+    }));
+    let msg = Message {
+        message: MessageVariant::SubscribeAlarm(ParamWrapperCap {
+            params: SubscribeAlarmParams {
+                system_names: None,
+                filter: Some("State = 1".to_string()),
+                language_id: None,
+            },
+        }),
+        client_cookie: "sub".to_string(),
+    };
+    server.handle_message(msg, &Arc::downgrade(&notify));
+    server.update_alarms(&[AlarmBuilder::new(1).state(1, "Raised").build()]);
+    // No longer matches, but the subscriber was told it did
+    server.update_alarms(&[AlarmBuilder::new(1).state(2, "Cleared").build()]);
+    // Didn't match before either
+    server.update_alarms(&[AlarmBuilder::new(1).state(6, "Acknowledged").build()]);
+    let sent = sent.lock().unwrap();
+    let states: Vec<Vec<&str>> = sent
+        .iter()
+        .map(|msg| match &msg.message {
+            MessageVariant::NotifySubscribeAlarm(n) => {
+                n.params.alarms.iter().map(|a| a.state.as_str()).collect()
+            }
+            m => panic!("Unexpected message {:?}", m),
+        })
+        .collect();
+    assert_eq!(states, [["1"], ["2"]]);
+}
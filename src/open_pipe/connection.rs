@@ -1,6 +1,7 @@
 use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use std::collections::VecDeque;
 use std::future::Future;
 use std::process;
 
@@ -12,6 +13,8 @@ pub struct Connection {
     low_level: ConnectionLowLevel,
     cookie_prefix: String,
     cookie_count: u32,
+    // Messages received while waiting for a reply
+    pending: VecDeque<Message>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -137,6 +140,14 @@ impl<T> From<T> for ParamWrapperCap<T> {
     }
 }
 
+// Serialize as 'Params: {...}', or nothing if None
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct OptParamWrapperCap<T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<T>,
+}
+
 // Serialize as 'param: {...}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ParamWrapperLow<T> {
@@ -156,7 +167,9 @@ pub enum MessageVariant {
     SubscribeTag(ParamWrapperCap<SubscribeTagParams>),
     NotifySubscribeTag(ParamWrapperCap<NotifyTags>),
     ErrorSubscribeTag(ErrorInfo),
-    UnsubscribeTag,
+    // Only the given tags are removed from the subscription if there
+    // are params
+    UnsubscribeTag(OptParamWrapperCap<SubscribeTagParams>),
     NotifyUnsubscribeTag,
     ErrorUnsubscribeTag(ErrorInfo),
     ReadTag(ParamWrapperCap<ReadTagParams>),
@@ -209,11 +222,19 @@ impl Connection {
         Ok(Self::from_low_level(low_level))
     }
 
+    /// Two connected ends that pass messages in memory, without a
+    /// named pipe. Used for testing against a simulated server.
+    pub fn pair() -> (Connection, Connection) {
+        let (a, b) = ConnectionLowLevel::pair();
+        (Self::from_low_level(a), Self::from_low_level(b))
+    }
+
     fn from_low_level(low_level: ConnectionLowLevel) -> Connection {
         Connection {
             low_level,
             cookie_prefix: format!("cookie_{}_", process::id()),
             cookie_count: 0,
+            pending: VecDeque::new(),
         }
     }
 
@@ -223,6 +244,13 @@ impl Connection {
     }
 
     pub async fn get_message(&mut self) -> Result<Message> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+        self.recv_message().await
+    }
+
+    async fn recv_message(&mut self) -> Result<Message> {
         let data = self.low_level.recv_data().await?;
         debug!("Got JSON: {}", String::from_utf8(data.clone()).unwrap());
         serde_json::from_slice(&data).map_err(|e| e.into())
@@ -274,7 +302,21 @@ impl Connection {
 
     pub async fn unsubscribe_tags(&mut self, cookie: &str) -> Result<String> {
         let cmd = Message {
-            message: MessageVariant::UnsubscribeTag,
+            message: MessageVariant::UnsubscribeTag(OptParamWrapperCap { params: None }),
+            client_cookie: cookie.to_string(),
+        };
+        send_cmd(&mut self.low_level, &cmd).await?;
+        Ok(cmd.client_cookie)
+    }
+
+    /// Remove some tags from a subscription, keeping the rest
+    pub async fn unsubscribe_some_tags(&mut self, cookie: &str, tags: &[&str]) -> Result<String> {
+        let cmd = Message {
+            message: MessageVariant::UnsubscribeTag(OptParamWrapperCap {
+                params: Some(SubscribeTagParams {
+                    tags: tags.iter().map(|t| String::from(*t)).collect(),
+                }),
+            }),
             client_cookie: cookie.to_string(),
         };
         send_cmd(&mut self.low_level, &cmd).await?;
@@ -303,6 +345,32 @@ impl Connection {
         Ok(())
     }
 
+    /// Read the current values of tags. Other messages received while
+    /// waiting for the reply are returned later by
+    /// [`get_message`](Connection::get_message).
+    pub async fn read_tags(&mut self, tags: &[&str]) -> Result<Vec<NotifyTag>> {
+        let cmd = Message {
+            message: MessageVariant::ReadTag(ParamWrapperCap {
+                params: ReadTagParams {
+                    tags: tags.iter().map(|t| String::from(*t)).collect(),
+                },
+            }),
+            client_cookie: self.get_cookie(),
+        };
+        send_cmd(&mut self.low_level, &cmd).await?;
+        loop {
+            let msg = self.recv_message().await?;
+            if msg.client_cookie == cmd.client_cookie {
+                match msg.message {
+                    MessageVariant::NotifyReadTag(reply) => return Ok(reply.params.tags),
+                    MessageVariant::ErrorReadTag(err) => return Err(err.into()),
+                    _ => {}
+                }
+            }
+            self.pending.push_back(msg);
+        }
+    }
+
     pub async fn subscribe_alarms(&mut self) -> Result<String> {
         let cmd = Message {
             message: MessageVariant::SubscribeAlarm(ParamWrapperCap {
@@ -11,8 +11,14 @@ use tokio::net::{unix::OwnedWriteHalf, UnixListener, UnixStream};
 use tokio::pin;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 
+enum Writer {
+    Stream(OwnedWriteHalf),
+    // The receiving queue of the other end of an in-memory pair
+    Memory(Sender<Vec<u8>>),
+}
+
 pub struct ConnectionUnix {
-    stream: OwnedWriteHalf,
+    writer: Writer,
     recv: Receiver<Vec<u8>>,
 }
 
@@ -45,11 +51,27 @@ impl ConnectionUnix {
         let (msg_in, msg_out) = mpsc::channel(10);
         tokio::spawn(read_connection(r, msg_in));
         ConnectionUnix {
-            stream: w,
+            writer: Writer::Stream(w),
             recv: msg_out,
         }
     }
 
+    /// Two connected ends that pass messages in memory
+    pub fn pair() -> (ConnectionUnix, ConnectionUnix) {
+        let (a_in, a_out) = mpsc::channel(10);
+        let (b_in, b_out) = mpsc::channel(10);
+        (
+            ConnectionUnix {
+                writer: Writer::Memory(b_in),
+                recv: a_out,
+            },
+            ConnectionUnix {
+                writer: Writer::Memory(a_in),
+                recv: b_out,
+            },
+        )
+    }
+
     pub async fn server<H, F, S>(path: &str, handler: H, shutdown: S) -> DynResult<()>
     where
         H: Fn(ConnectionUnix) -> F,
@@ -88,8 +110,20 @@ impl ConnectionUnix {
     }
 
     pub async fn send_data(&mut self, data: &[u8]) -> DynResult<()> {
-        self.stream.write_all(data).await?;
-        self.stream.flush().await?;
+        match &mut self.writer {
+            Writer::Stream(stream) => {
+                stream.write_all(data).await?;
+                stream.flush().await?;
+            }
+            Writer::Memory(send) => {
+                // Received line by line, like from a stream
+                for line in data.split(|&c| c == b'\n').filter(|l| !l.is_empty()) {
+                    send.send(line.to_vec())
+                        .await
+                        .map_err(|_| "Connection closed")?;
+                }
+            }
+        }
         Ok(())
     }
 
@@ -180,6 +180,22 @@ impl ConnectionWindows {
         Ok(conn)
     }
 
+    /// Two connected ends that pass messages in memory
+    pub fn pair() -> (ConnectionWindows, ConnectionWindows) {
+        let (a_send, a_recv) = mpsc::channel(3);
+        let (b_send, b_recv) = mpsc::channel(3);
+        (
+            ConnectionWindows {
+                send: a_send,
+                recv: b_recv,
+            },
+            ConnectionWindows {
+                send: b_send,
+                recv: a_recv,
+            },
+        )
+    }
+
     pub async fn send_data(&mut self, data: &[u8]) -> DynResult<()> {
         self.send.send(data.to_vec()).await?;
         Ok(())
@@ -11,4 +11,5 @@ pub use connection_windows::ConnectionWindows as ConnectionLowLevel;
 pub mod alarm_data;
 pub mod alarm_server;
 pub mod connection;
+pub mod simulator;
 pub mod tag_server;
@@ -0,0 +1,228 @@
+//! Open Pipe server simulated in memory
+//!
+//! Serves a [`TagServer`] and an [`AlarmServer`] over connections created
+//! with [`Connection::pair`], so the player, or any other Open Pipe
+//! client, can be tested in a single tokio runtime without a named pipe.
+//!
+//! ```no_run
+//! # async fn example() -> mtp_audioplayer::open_pipe::connection::Result<()> {
+//! use mtp_audioplayer::open_pipe::simulator::Simulator;
+//!
+//! let sim = Simulator::new(true);
+//! let mut conn = sim.connect();
+//! conn.subscribe_tags(&["Alarm"]).await?;
+//! sim.set_tag("Alarm", "1");
+//! let notification = conn.get_message().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use super::alarm_data::AlarmData;
+use super::alarm_server::AlarmServer;
+use super::connection::{Connection, Message, MessageVariant};
+use super::tag_server::{ReplyFn, TagServer};
+use crate::util::error::DynResult;
+use log::{debug, error, warn};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError, Sender};
+
+/// Messages queued for each connection before they are dropped
+pub const SEND_QUEUE_LEN: usize = 1024;
+
+// Messages dropped because a connection couldn't keep up
+static DROPPED_MESSAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Queue a message for a connection without waiting. The message is
+/// dropped if the queue is full.
+pub fn queue_message(tx: &Sender<Message>, msg: Message) {
+    match tx.try_send(msg) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            let dropped = DROPPED_MESSAGES.fetch_add(1, Ordering::Relaxed) + 1;
+            // Don't flood the log
+            if dropped.is_power_of_two() {
+                warn!("Send queue full, {} messages dropped in total", dropped);
+            }
+        }
+        Err(TrySendError::Closed(_)) => error!("Failed to queue reply: Connection closed"),
+    }
+}
+
+/// Handle requests from a client until the connection is closed
+pub async fn serve_connection(
+    mut conn: Connection,
+    tag_server: Arc<Mutex<TagServer>>,
+    alarm_server: Arc<Mutex<AlarmServer>>,
+) {
+    let (tx, mut rx) = mpsc::channel(SEND_QUEUE_LEN);
+    let notify_fn: Arc<ReplyFn> = Arc::new(Mutex::new(move |msg| {
+        queue_message(&tx, msg);
+        Ok(()) as DynResult<()>
+    }));
+    let notify_fn_weak = Arc::downgrade(&notify_fn);
+    loop {
+        tokio::select! {
+            res = conn.get_message() => {
+                match res {
+                    Ok(msg) => {
+                        let reply = match msg.message {
+                            MessageVariant::SubscribeTag(_) |
+                            MessageVariant::UnsubscribeTag(_) |
+                            MessageVariant::ReadTag(_) |
+                            MessageVariant::WriteTag(_) => {
+                                let mut tag_server = tag_server.lock().unwrap();
+                                tag_server.handle_message(msg, &notify_fn_weak)
+                            },
+                            MessageVariant::SubscribeAlarm(_) |
+                            MessageVariant::UnsubscribeAlarm |
+                            MessageVariant::NotifySubscribeAlarm(_) |
+                            MessageVariant::ReadAlarm(_) => {
+                                let mut alarm_server = alarm_server.lock().unwrap();
+                                alarm_server.handle_message(msg, &notify_fn_weak)
+                            },
+
+                            _ => None
+                        };
+
+                        if let Some(reply) = reply {
+                            debug!("Reply: {:?}", &reply);
+                            if let Err(err) = conn.send_message(&reply).await {
+                                error!("Failed to send Open Pipe message: {}", err);
+
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        error!("Failed to get message: {}",e);
+                        break
+                    }
+                }
+            },
+            res = rx.recv() => {
+                match res {
+                    Some(notice) => {
+                        if let Err(err) = conn.send_message(&notice).await {
+                            error!("Failed to receive Open Pipe message: {}", err);
+                        }
+                    },
+                    None => break
+                }
+            }
+        }
+    }
+    debug!("Connection closed");
+}
+
+/// Tag and alarm servers shared by all connections
+#[derive(Clone)]
+pub struct Simulator {
+    tag_server: Arc<Mutex<TagServer>>,
+    alarm_server: Arc<Mutex<AlarmServer>>,
+}
+
+impl Simulator {
+    /// If `populate` is true, subscribed tags that don't exist are
+    /// created with the value "0".
+    pub fn new(populate: bool) -> Simulator {
+        Simulator {
+            tag_server: Arc::new(Mutex::new(TagServer::new(populate))),
+            alarm_server: Arc::new(Mutex::new(AlarmServer::new())),
+        }
+    }
+
+    pub fn tag_server(&self) -> &Arc<Mutex<TagServer>> {
+        &self.tag_server
+    }
+
+    pub fn alarm_server(&self) -> &Arc<Mutex<AlarmServer>> {
+        &self.alarm_server
+    }
+
+    /// Open a new client connection. Requests are served by a task on the
+    /// current tokio runtime.
+    pub fn connect(&self) -> Connection {
+        let (client, server) = Connection::pair();
+        tokio::spawn(serve_connection(
+            server,
+            self.tag_server.clone(),
+            self.alarm_server.clone(),
+        ));
+        client
+    }
+
+    /// Change the value of a tag and notify subscribers
+    pub fn set_tag(&self, tag: &str, value: &str) {
+        let mut tag_server = self.tag_server.lock().unwrap();
+        let mut notifications = HashSet::new();
+        tag_server.set_tag_value(tag, value, &mut notifications);
+        tag_server.send_tag_notifications(&notifications, None);
+    }
+
+    /// Current value of a tag, including values written by clients
+    pub fn tag_value(&self, tag: &str) -> Option<String> {
+        let tag_server = self.tag_server.lock().unwrap();
+        tag_server.tag_value(tag).map(String::from)
+    }
+
+    /// Add or change alarms and notify subscribers
+    pub fn update_alarms(&self, alarms: &[AlarmData]) {
+        self.alarm_server.lock().unwrap().update_alarms(alarms);
+    }
+}
+
+#[tokio::test]
+async fn test_simulator() {
+    use super::connection::WriteTagValue;
+
+    let sim = Simulator::new(true);
+    let mut conn = sim.connect();
+    let cookie = conn.subscribe_tags(&["Tag0"]).await.unwrap();
+    let msg = conn.get_message().await.unwrap();
+    assert_eq!(msg.client_cookie, cookie);
+    match msg.message {
+        MessageVariant::NotifySubscribeTag(n) => assert_eq!(n.params.tags[0].data.value, "0"),
+        m => panic!("Unexpected message {:?}", m),
+    }
+    sim.set_tag("Tag0", "7");
+    match conn.get_message().await.unwrap().message {
+        MessageVariant::NotifySubscribeTag(n) => assert_eq!(n.params.tags[0].data.value, "7"),
+        m => panic!("Unexpected message {:?}", m),
+    }
+    conn.write_tags(&[WriteTagValue {
+        name: "Tag1".to_string(),
+        value: "3".to_string(),
+    }])
+    .await
+    .unwrap();
+    assert!(matches!(
+        conn.get_message().await.unwrap().message,
+        MessageVariant::NotifyWriteTag(_)
+    ));
+    assert_eq!(sim.tag_value("Tag1").as_deref(), Some("3"));
+
+    let cookie = conn.subscribe_alarms().await.unwrap();
+    conn.get_message().await.unwrap();
+    sim.update_alarms(&[crate::testing::AlarmBuilder::new(1).build()]);
+    let msg = conn.get_message().await.unwrap();
+    assert_eq!(msg.client_cookie, cookie);
+    match msg.message {
+        MessageVariant::NotifySubscribeAlarm(n) => assert_eq!(n.params.alarms[0].id, "1"),
+        m => panic!("Unexpected message {:?}", m),
+    }
+}
+
+#[tokio::test]
+async fn test_read_tags() {
+    let sim = Simulator::new(true);
+    sim.set_tag("Tag0", "5");
+    let mut conn = sim.connect();
+    let cookie = conn.subscribe_tags(&["Tag0"]).await.unwrap();
+    // The subscription reply arrives while waiting for the read
+    let tags = conn.read_tags(&["Tag0", "Missing"]).await.unwrap();
+    assert_eq!(tags[0].data.value, "5");
+    assert_eq!(tags[0].error.error_code, 0);
+    assert_ne!(tags[1].error.error_code, 0);
+    assert_eq!(conn.get_message().await.unwrap().client_cookie, cookie);
+}
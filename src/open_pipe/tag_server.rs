@@ -4,7 +4,8 @@ use std::sync::{Mutex, Weak};
 //use log::{debug};
 use super::connection::{
     ErrorInfo, Message, MessageVariant, NotifyTag, NotifyTags, NotifyWriteTag, NotifyWriteTags,
-    ParamWrapperCap, SubscribeTagParams, TagData, WriteTagParams, WriteTagValue,
+    OptParamWrapperCap, ParamWrapperCap, ReadTagParams, SubscribeTagParams, TagData,
+    WriteTagParams, WriteTagValue,
 };
 use chrono::offset::Utc;
 use std::collections::HashSet;
@@ -79,37 +80,71 @@ impl TagServer {
                 }
             }
         }
-        let subscr = Subscription {
-            tags: Vec::from(tags),
-            notify,
-            cookie: cookie.to_string(),
-        };
+        match self.subscriptions.get_mut(cookie) {
+            // Subscribing again with the same cookie adds the tags to
+            // the existing subscription
+            Some(subscr) => {
+                subscr.notify = notify;
+                if tags.is_empty() {
+                    subscr.tags.clear();
+                } else if !subscr.tags.is_empty() {
+                    for tag in tags {
+                        if !subscr.tags.contains(tag) {
+                            subscr.tags.push(tag.clone());
+                        }
+                    }
+                }
+            }
+            None => {
+                let subscr = Subscription {
+                    tags: Vec::from(tags),
+                    notify,
+                    cookie: cookie.to_string(),
+                };
+                self.subscriptions.insert(cookie.into(), subscr);
+            }
+        }
 
-        let tags = Self::build_notify_tags(&self.tags, &subscr.tags);
-        let msg = Message {
+        // Only the requested tags are notified
+        let tags = Self::build_notify_tags(&self.tags, tags);
+        Message {
             message: MessageVariant::NotifySubscribeTag(tags.into()),
-            client_cookie: subscr.cookie.clone(),
-        };
-        self.subscriptions.insert(cookie.into(), subscr);
-
-        msg
+            client_cookie: cookie.to_string(),
+        }
     }
 
-    fn unsubscribe(&mut self, cookie: &str) -> Message {
-        if self.subscriptions.remove(cookie).is_some() {
-            Message {
-                message: MessageVariant::NotifyUnsubscribeTag,
-                client_cookie: cookie.to_string(),
+    // Remove the whole subscription if tags is None
+    fn unsubscribe(&mut self, cookie: &str, tags: Option<&[String]>) -> Message {
+        let error = |error_code, error_description: &str| Message {
+            message: MessageVariant::ErrorUnsubscribeTag(ErrorInfo {
+                error_code,
+                error_description: error_description.to_string(),
+            }),
+            client_cookie: cookie.to_string(),
+        };
+        let subscr = match self.subscriptions.get_mut(cookie) {
+            Some(subscr) => subscr,
+            None => return error(4, "No matching subscription"),
+        };
+        match tags {
+            Some(tags) if !tags.is_empty() => {
+                if subscr.tags.is_empty() {
+                    return error(2, "Can't remove tags from a subscription of all tags");
+                }
+                subscr.tags.retain(|t| !tags.contains(t));
+                // An empty list would mean all tags
+                if subscr.tags.is_empty() {
+                    self.subscriptions.remove(cookie);
+                }
             }
-        } else {
-            Message {
-                message: MessageVariant::ErrorUnsubscribeTag(ErrorInfo {
-                    error_code: 4,
-                    error_description: "No matching subscription".to_string(),
-                }),
-                client_cookie: cookie.to_string(),
+            _ => {
+                self.subscriptions.remove(cookie);
             }
         }
+        Message {
+            message: MessageVariant::NotifyUnsubscribeTag,
+            client_cookie: cookie.to_string(),
+        }
     }
 
     pub fn set_tag_value(&mut self, tag: &str, value: &str, notifications: &mut HashSet<String>) {
@@ -131,7 +166,14 @@ impl TagServer {
         notifications.insert(tag.to_string());
     }
 
-    fn send_tag_notifications(
+    /// Current value of a tag
+    pub fn tag_value(&self, tag: &str) -> Option<&str> {
+        self.tags.get(tag).map(|t| t.value.as_str())
+    }
+
+    /// Notify subscribers of the tags changed by
+    /// [`set_tag_value`](TagServer::set_tag_value)
+    pub fn send_tag_notifications(
         &mut self,
         notifications: &HashSet<String>,
         exclude_cookie: Option<&str>,
@@ -204,12 +246,53 @@ impl TagServer {
         }
     }
 
+    fn read_tags(&self, tags: &[String], cookie: &str) -> Message {
+        let mut tag_result = Vec::new();
+        for tag in tags {
+            let notify = match self.tags.get(tag) {
+                Some(tag_data) => NotifyTag {
+                    data: tag_data.clone(),
+                    time_stamp: Utc::now().to_rfc3339(),
+                    error: ErrorInfo::default(),
+                },
+                None => NotifyTag {
+                    data: TagData {
+                        name: tag.clone(),
+                        value: String::new(),
+                        quality: "Bad".to_string(),
+                        quality_code: 0,
+                    },
+                    time_stamp: Utc::now().to_rfc3339(),
+                    error: ErrorInfo {
+                        error_code: 2,
+                        error_description: "No such tag".to_string(),
+                    },
+                },
+            };
+            tag_result.push(notify);
+        }
+        Message {
+            message: MessageVariant::NotifyReadTag(ParamWrapperCap {
+                params: NotifyTags { tags: tag_result },
+            }),
+            client_cookie: cookie.to_string(),
+        }
+    }
+
     pub fn handle_message(&mut self, msg: Message, notify_fn: &Weak<ReplyFn>) -> Option<Message> {
         match msg.message {
             MessageVariant::SubscribeTag(ParamWrapperCap {
                 params: SubscribeTagParams { tags },
             }) => Some(self.subscribe(&tags, &msg.client_cookie, notify_fn.clone())),
-            MessageVariant::UnsubscribeTag => Some(self.unsubscribe(&msg.client_cookie)),
+            MessageVariant::UnsubscribeTag(OptParamWrapperCap { params }) => {
+                Some(self.unsubscribe(
+                    &msg.client_cookie,
+                    params.as_ref().map(|p| p.tags.as_slice()),
+                ))
+            }
+            MessageVariant::ReadTag(ParamWrapperCap {
+                params: ReadTagParams { tags },
+            }) => Some(self.read_tags(&tags, &msg.client_cookie)),
             MessageVariant::WriteTag(ParamWrapperCap {
                 params: WriteTagParams { tags },
             }) => Some(self.write_tags(&tags, &msg.client_cookie)),
@@ -240,5 +323,83 @@ fn test_subscribe() {
     );
     server.set_tag_value("Tag1", "2", &mut notifications);
     server.send_tag_notifications(&notifications, None);
-    server.unsubscribe("dsjalk");
+    server.unsubscribe("dsjalk", None);
+}
+
+#[test]
+fn test_partial_unsubscribe() {
+    let mut server = TagServer::new(false);
+    let mut notifications = HashSet::new();
+    for tag in ["Tag0", "Tag1", "Tag2"] {
+        server.set_tag_value(tag, "0", &mut notifications);
+    }
+    let notified = Arc::new(Mutex::new(Vec::new()));
+    let notified_cb = notified.clone();
+    let notify: Arc<ReplyFn> = Arc::new(Mutex::new(move |msg: Message| {
+        if let MessageVariant::NotifySubscribeTag(tags) = msg.message {
+            let mut notified = notified_cb.lock().unwrap();
+            notified.extend(tags.params.tags.into_iter().map(|t| t.data.name));
+        }
+        Ok(())
+    }));
+    let changed = |server: &mut TagServer, tag: &str| {
+        notified.lock().unwrap().clear();
+        let mut notifications = HashSet::new();
+        server.set_tag_value(tag, "1", &mut notifications);
+        server.send_tag_notifications(&notifications, None);
+        !notified.lock().unwrap().is_empty()
+    };
+    let handle = |server: &mut TagServer, json: &str| {
+        let msg: Message = serde_json::from_str(json).unwrap();
+        server
+            .handle_message(msg, &Arc::downgrade(&notify))
+            .unwrap()
+    };
+
+    handle(
+        &mut server,
+        r#"{"Message":"SubscribeTag","Params":{"Tags":["Tag0"]},"ClientCookie":"c"}"#,
+    );
+    assert!(!changed(&mut server, "Tag1"));
+    // Same cookie adds to the subscription
+    let reply = handle(
+        &mut server,
+        r#"{"Message":"SubscribeTag","Params":{"Tags":["Tag1","Tag2"]},"ClientCookie":"c"}"#,
+    );
+    match reply.message {
+        MessageVariant::NotifySubscribeTag(tags) => assert_eq!(tags.params.tags.len(), 2),
+        _ => panic!("Unexpected reply"),
+    }
+    assert!(changed(&mut server, "Tag0"));
+    assert!(changed(&mut server, "Tag1"));
+
+    let reply = handle(
+        &mut server,
+        r#"{"Message":"UnsubscribeTag","Params":{"Tags":["Tag0","Tag1"]},"ClientCookie":"c"}"#,
+    );
+    assert!(matches!(
+        reply.message,
+        MessageVariant::NotifyUnsubscribeTag
+    ));
+    assert!(!changed(&mut server, "Tag0"));
+    assert!(!changed(&mut server, "Tag1"));
+    assert!(changed(&mut server, "Tag2"));
+
+    let reply = handle(
+        &mut server,
+        r#"{"Message":"UnsubscribeTag","ClientCookie":"c"}"#,
+    );
+    assert!(matches!(
+        reply.message,
+        MessageVariant::NotifyUnsubscribeTag
+    ));
+    assert!(!changed(&mut server, "Tag2"));
+    let reply = handle(
+        &mut server,
+        r#"{"Message":"UnsubscribeTag","ClientCookie":"c"}"#,
+    );
+    assert!(matches!(
+        reply.message,
+        MessageVariant::ErrorUnsubscribeTag(_)
+    ));
 }
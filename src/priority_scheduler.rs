@@ -159,7 +159,7 @@ impl Scheduler {
     }
 }
 
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_equal_prority() {
     let sched = Scheduler::new();
     let sched1 = sched.clone();
@@ -192,7 +192,7 @@ async fn test_equal_prority() {
     tokio::time::sleep(Duration::from_millis(2000)).await;
 }
 
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_higher_prority() {
     let sched = Scheduler::new();
     let sched1 = sched.clone();
@@ -0,0 +1,168 @@
+//! Prometheus metrics
+//!
+//! `GET /metrics` returns the counters and gauges below in the
+//! Prometheus text format.
+//!
+//! | Metric                                      | Labels                   |
+//! |---------------------------------------------|--------------------------|
+//! | `mtp_audioplayer_clips_played_total`        | `priority`               |
+//! | `mtp_audioplayer_playback_underruns_total`  |                          |
+//! | `mtp_audioplayer_openpipe_reconnects_total` |                          |
+//! | `mtp_audioplayer_alarm_filter_matches`      | `filter`                 |
+//! | `mtp_audioplayer_state_transitions_total`   | `state_machine`, `state` |
+
+use crate::read_config::MetricsConfig;
+use crate::util::error::DynResult;
+use log::{error, info};
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use warp::http::header::CONTENT_TYPE;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+struct Metrics {
+    registry: Registry,
+    clips_played: IntCounterVec,
+    underruns: IntCounter,
+    reconnects: IntCounter,
+    alarm_filter_matches: IntGaugeVec,
+    state_transitions: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Metrics> {
+        let registry = Registry::new_custom(Some("mtp_audioplayer".to_string()), None)?;
+        let clips_played = IntCounterVec::new(
+            Opts::new("clips_played_total", "Clips started, by priority"),
+            &["priority"],
+        )?;
+        registry.register(Box::new(clips_played.clone()))?;
+        let underruns = IntCounter::new(
+            "playback_underruns_total",
+            "Errors reported by the audio stream, usually underruns",
+        )?;
+        registry.register(Box::new(underruns.clone()))?;
+        let reconnects = IntCounter::new(
+            "openpipe_reconnects_total",
+            "Successful reconnects to Open Pipe",
+        )?;
+        registry.register(Box::new(reconnects.clone()))?;
+        let alarm_filter_matches = IntGaugeVec::new(
+            Opts::new(
+                "alarm_filter_matches",
+                "Matching alarms that aren't ignored, by filter",
+            ),
+            &["filter"],
+        )?;
+        registry.register(Box::new(alarm_filter_matches.clone()))?;
+        let state_transitions = IntCounterVec::new(
+            Opts::new(
+                "state_transitions_total",
+                "States entered by state machines",
+            ),
+            &["state_machine", "state"],
+        )?;
+        registry.register(Box::new(state_transitions.clone()))?;
+        Ok(Metrics {
+            registry,
+            clips_played,
+            underruns,
+            reconnects,
+            alarm_filter_matches,
+            state_transitions,
+        })
+    }
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("Invalid metric definition"))
+}
+
+/// A clip started playing
+pub fn clip_played(priority: i32) {
+    metrics()
+        .clips_played
+        .with_label_values(&[&priority.to_string()])
+        .inc();
+}
+
+/// The audio stream reported an error. Called from the audio thread.
+pub fn playback_underrun() {
+    metrics().underruns.inc();
+}
+
+/// The connection to Open Pipe was restored
+pub fn openpipe_reconnected() {
+    metrics().reconnects.inc();
+}
+
+/// The number of alarms matching a filter changed
+pub fn alarm_filter_matches(filter: &str, count: usize) {
+    metrics()
+        .alarm_filter_matches
+        .with_label_values(&[filter])
+        .set(count as i64);
+}
+
+/// A state machine entered a new state
+pub fn state_entered(state_machine: &str, state: &str) {
+    metrics()
+        .state_transitions
+        .with_label_values(&[state_machine, state])
+        .inc();
+}
+
+fn encode(encoder: &TextEncoder) -> prometheus::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    encoder.encode(&metrics().registry.gather(), &mut buffer)?;
+    Ok(buffer)
+}
+
+fn scrape() -> Response {
+    let encoder = TextEncoder::new();
+    match encode(&encoder) {
+        Ok(buffer) => {
+            let mut reply = Response::new(buffer.into());
+            if let Ok(content_type) = encoder.format_type().parse() {
+                reply.headers_mut().insert(CONTENT_TYPE, content_type);
+            }
+            reply
+        }
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Start serving metrics
+pub fn start(conf: &MetricsConfig) -> DynResult<()> {
+    let addr: SocketAddr = conf
+        .bind
+        .parse()
+        .map_err(|e| format!("Invalid metrics address {}: {}", conf.bind, e))?;
+    let route = warp::get().and(warp::path!("metrics")).map(scrape);
+    let (addr, server) = warp::serve(route).try_bind_ephemeral(addr)?;
+    info!("Metrics on http://{}/metrics", addr);
+    tokio::spawn(server);
+    Ok(())
+}
+
+#[test]
+fn test_metrics() {
+    // Unusual labels, other tests may update the same metrics
+    clip_played(-1234);
+    clip_played(-1234);
+    alarm_filter_matches("test_fire", 3);
+    state_entered("test_machine", "idle");
+    let text = String::from_utf8(encode(&TextEncoder::new()).unwrap()).unwrap();
+    assert!(text.contains("mtp_audioplayer_clips_played_total{priority=\"-1234\"} 2"));
+    assert!(text.contains("mtp_audioplayer_alarm_filter_matches{filter=\"test_fire\"} 3"));
+    assert!(text.contains(
+        "mtp_audioplayer_state_transitions_total{state=\"idle\",state_machine=\"test_machine\"} 1"
+    ));
+}
@@ -1,10 +1,19 @@
+use crate::actions::plugin;
 use crate::actions::wait_alarm::AlarmCondition;
 use crate::actions::wait_tag::TagCondition;
 use crate::alarm_filter;
+use crate::expr::{self, Expr};
+use crate::sample_buffer::SampleFormat;
 use crate::util::error::DynResult;
-use cpal::SampleFormat;
-use roxmltree::{Document, Node, TextPos};
-use std::collections::HashMap;
+use crate::util::glob;
+use crate::util::schedule::Schedule;
+use crate::util::template;
+use chrono::NaiveTime;
+use log::warn;
+use roxmltree::{Document, Node};
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
@@ -23,6 +32,9 @@ pub enum ConfigErrorKind {
     ExclusiveAttributes(&'static [&'static str]),
     ParseAttribute(String, Box<dyn Error + Send + Sync>),
     ParseFilter(Box<dyn Error + Send + Sync>),
+    ParseExpression(Box<dyn Error + Send + Sync>),
+    ExpandVariable(String),
+    Plugin(Box<dyn Error + Send + Sync>),
 }
 
 use ConfigErrorKind::*;
@@ -42,6 +54,9 @@ impl std::fmt::Display for ConfigErrorKind {
             ),
             ParseAttribute(name, err) => write!(f, "Failed to parse attribute '{}': {}", name, err),
             ParseFilter(err) => write!(f, "Failed to parse alarm filter: {}", err),
+            ParseExpression(err) => write!(f, "{}", err),
+            ExpandVariable(err) => write!(f, "{}", err),
+            Plugin(err) => write!(f, "{}", err),
         }
     }
 }
@@ -49,13 +64,13 @@ impl std::fmt::Display for ConfigErrorKind {
 #[derive(Debug)]
 pub struct ConfigError {
     kind: ConfigErrorKind,
-    pos: TextPos,
+    location: String,
 }
 
 impl ConfigError {
     pub fn new(node: &Node, kind: ConfigErrorKind) -> ConfigError {
         ConfigError {
-            pos: node.document().text_pos_at(node.range().start),
+            location: node_location(node),
             kind,
         }
     }
@@ -64,7 +79,175 @@ impl std::error::Error for ConfigError {}
 
 impl std::fmt::Display for ConfigError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        write!(f, "{}:{}: {}", self.pos.row, self.pos.col, self.kind)
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+/// All errors found when parsing a configuration
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<Box<dyn Error + Send + Sync>>);
+
+impl std::error::Error for ConfigErrors {}
+
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        for (i, err) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", err)?;
+        }
+        Ok(())
+    }
+}
+
+/// How unknown elements and attributes are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Unknown elements and attributes are errors
+    Strict,
+    /// Unknown elements and attributes are logged and ignored. Useful
+    /// for configurations written for a newer version of the player.
+    Lenient,
+}
+
+thread_local! {
+    static PARSE_MODE: Cell<ParseMode> = const { Cell::new(ParseMode::Strict) };
+    // Set when reading a configuration converted to XML. The location
+    // in the original file of the element on each line.
+    static SOURCE_LOCATIONS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+// Run f with the given parse mode
+fn with_parse_mode<T>(mode: ParseMode, f: impl FnOnce() -> T) -> T {
+    let old = PARSE_MODE.with(|m| m.replace(mode));
+    let res = f();
+    PARSE_MODE.with(|m| m.set(old));
+    res
+}
+
+// Run f with errors referring to the given source locations
+fn with_source_locations<T>(locations: Option<Vec<String>>, f: impl FnOnce() -> T) -> T {
+    let old = SOURCE_LOCATIONS.with(|l| l.replace(locations));
+    let res = f();
+    SOURCE_LOCATIONS.with(|l| *l.borrow_mut() = old);
+    res
+}
+
+// Position of the node as row:col, or its location in the original
+// file if the configuration was converted to XML
+fn node_location(node: &Node) -> String {
+    let pos = node.document().text_pos_at(node.range().start);
+    SOURCE_LOCATIONS.with(|l| match &*l.borrow() {
+        Some(locations) => locations
+            .get(pos.row as usize - 1)
+            .cloned()
+            .unwrap_or_else(|| format!("line {}", pos.row)),
+        None => format!("{}:{}", pos.row, pos.col),
+    })
+}
+
+// Returns true if the error should be ignored. In lenient mode
+// unknown elements and attributes are logged and then ignored.
+fn skip_unknown(err: &(dyn Error + Send + Sync + 'static)) -> bool {
+    if PARSE_MODE.with(|m| m.get()) != ParseMode::Lenient {
+        return false;
+    }
+    match err.downcast_ref::<ConfigError>() {
+        Some(
+            e @ ConfigError {
+                kind: UnexpectedElement | UnexpectedAttribute,
+                ..
+            },
+        ) => {
+            warn!("Ignored in configuration: {}", e);
+            true
+        }
+        _ => false,
+    }
+}
+
+// Accumulates errors from independent parts of the configuration so
+// that all of them can be reported at once
+#[derive(Default)]
+struct ErrorList(Vec<Box<dyn Error + Send + Sync>>);
+
+impl ErrorList {
+    fn push(&mut self, node: &Node, err: Box<dyn Error + Send + Sync>) {
+        if skip_unknown(&*err) {
+            return;
+        }
+        match err.downcast::<ConfigErrors>() {
+            Ok(errs) => self.0.extend(errs.0),
+            Err(err) if err.is::<ConfigError>() => self.0.push(err),
+            Err(err) => {
+                // Add the position of the node if the error has none
+                self.0
+                    .push(format!("{}: {}", node_location(node), err).into());
+            }
+        }
+    }
+
+    fn check<T>(&mut self, node: &Node, res: DynResult<T>) -> Option<T> {
+        match res {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.push(node, e);
+                None
+            }
+        }
+    }
+
+    /// Same as check_element_ns but records the error
+    fn is_element(&mut self, node: &Node) -> bool {
+        match check_element_ns(node) {
+            Ok(is_element) => is_element,
+            Err(e) => {
+                self.push(node, e.into());
+                false
+            }
+        }
+    }
+
+    fn into_result(mut self) -> DynResult<()> {
+        match self.0.len() {
+            0 => Ok(()),
+            1 => Err(self.0.pop().unwrap()),
+            _ => Err(ConfigErrors(self.0).into()),
+        }
+    }
+}
+
+/// Quality of the sample rate conversion when loading clips
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResamplerQuality {
+    High,
+    // Linear interpolation
+    Fast,
+}
+
+/// Processing applied to file clips when they are loaded
+#[derive(Debug, Clone)]
+pub struct ClipProfile {
+    pub amplitude: Option<f32>,
+    // Peak level in dBFS after normalization
+    pub normalize: Option<f32>,
+    // Samples below this level are removed from the start and end
+    pub trim: Option<f32>,
+    pub resampler: ResamplerQuality,
+    // Read WAV files while playing instead of loading them
+    pub stream: bool,
+}
+
+impl Default for ClipProfile {
+    fn default() -> Self {
+        ClipProfile {
+            amplitude: None,
+            normalize: None,
+            trim: None,
+            resampler: ResamplerQuality::High,
+            stream: false,
+        }
     }
 }
 
@@ -72,7 +255,9 @@ impl std::fmt::Display for ConfigError {
 pub enum ClipType {
     File {
         file_name: String,
-        amplitude: f32,
+        // Overrides the amplitude of the profile
+        amplitude: Option<f32>,
+        profile: Option<String>,
     },
     Sine {
         amplitude: f64,
@@ -81,6 +266,16 @@ pub enum ClipType {
     },
 }
 
+/// Every supported audio file in a directory is added as a clip
+#[derive(Debug)]
+pub struct ClipDirConfig {
+    pub id_prefix: String,
+    // Relative to the clip root
+    pub path: String,
+    pub amplitude: Option<f32>,
+    pub profile: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum TagOrConst<T> {
     Tag(String),
@@ -95,12 +290,18 @@ pub enum ActionType {
         priority: i32,
         timeout: Option<Duration>,
         sound: String,
+        // Overrides the device of the clip
+        device: Option<String>,
     },
     Wait(Duration),
+    // Wait until the next scheduled time of day
+    WaitTime(Schedule),
     WaitTag {
         tag_name: String,
         condition: TagCondition,
     },
+    // Wait until the expression is true
+    WaitExpr(Expr),
     WaitAlarm {
         filter_name: String,
         condition: AlarmCondition,
@@ -112,13 +313,30 @@ pub enum ActionType {
         action: Box<ActionType>,
     },
     Goto(String),
+    If {
+        condition: Expr,
+        then: Box<ActionType>,
+        otherwise: Option<Box<ActionType>>,
+    },
     SetTag {
         tag_name: String,
         value: String,
     },
+    // Ramp is the time for changing from the current volume
     SetVolume {
         control: String,
         value: TagOrConst<f32>,
+        ramp: Option<Duration>,
+    },
+    // Add step to the current volume
+    ChangeVolume {
+        control: String,
+        step: f32,
+    },
+    // Relative level of each channel
+    SetBalance {
+        control: String,
+        levels: Vec<f32>,
     },
 
     IgnoreAlarms {
@@ -128,6 +346,37 @@ pub enum ActionType {
     RestoreAlarms {
         filter: String,
     },
+    // Drive a configured GPIO output
+    SetGpio {
+        pin: String,
+        value: bool,
+    },
+    // Subject and body may contain tag values as {Tag}
+    Email {
+        to: Vec<String>,
+        subject: String,
+        body: String,
+    },
+    // Arguments may contain tag values as {Tag}
+    Exec {
+        program: String,
+        args: Vec<String>,
+        timeout: Duration,
+    },
+    // Element handled by a registered plugin
+    Plugin(PluginElement),
+}
+
+/// An action element handled by a plugin, see
+/// [`register_action`](crate::actions::plugin::register_action).
+/// Variables are expanded in attributes and text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginElement {
+    pub name: String,
+    pub attributes: BTreeMap<String, String>,
+    /// Text content with surrounding whitespace removed
+    pub text: String,
+    pub children: Vec<PluginElement>,
 }
 
 #[derive(Debug)]
@@ -140,588 +389,3251 @@ pub struct StateConfig {
 pub struct StateMachineConfig {
     pub id: String,
     pub states: Vec<StateConfig>,
-}
-#[derive(Debug)]
-pub struct VolumeConfig {
-    pub id: String,
-    pub device: String,
-    pub initial_volume: Option<f32>,
+    // Set to the name of the active state
+    pub state_tag: Option<String>,
+    // Writing a state name to this tag makes it the active state
+    pub command_tag: Option<String>,
 }
 
+/// Action run when the server receives a signal
 #[derive(Debug)]
-pub struct PlayerConfig {
-    pub bind: String,
-    pub playback_device: String,
-    pub rate: u32,
-    pub channels: u8,
-    pub sample_format: SampleFormat,
-    pub clip_root: String,
-    pub clips: HashMap<String, ClipType>,
-    pub tags: Vec<String>,
-    pub named_alarm_filters: HashMap<String, AlarmFilterConfig>,
-    pub state_machines: Vec<StateMachineConfig>,
-    pub volume_config: Vec<VolumeConfig>,
+pub struct SignalConfig {
+    // "usr1" or "usr2", on Windows also the name of an event object
+    pub name: String,
+    pub action: ActionType,
 }
 
-const NS: &str = "http://www.elektro-kapsel.se/audioplayer/v1";
+/// Tags connected to an OPC UA server
+#[derive(Debug, Clone)]
+pub struct OpcUaConfig {
+    // Endpoint, e.g. opc.tcp://plc:4840
+    pub url: String,
+    // How often the server reports changed values
+    pub publish_interval: Duration,
+    // Tag name and node id, e.g. ns=2;s=Horn
+    pub tags: Vec<(String, String)>,
+}
 
-fn required_attribute<T>(node: &Node, name: &str) -> Result<T, ConfigError>
-where
-    T: FromStr,
-    T::Err: std::error::Error + Send + Sync + 'static,
-{
-    let attr_str = node
-        .attribute(name)
-        .ok_or_else(|| ConfigError::new(node, MissingAttribute(name.to_string())))?;
-    let res: Result<T, <T as FromStr>::Err> = attr_str.parse();
-    res.map_err(|e| ConfigError::new(node, ParseAttribute(name.to_string(), e.into())))
+/// Kind of Modbus data a tag is mapped to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModbusRegister {
+    Coil,
+    DiscreteInput,
+    HoldingRegister,
+    InputRegister,
 }
 
-fn optional_attribute<T>(node: &Node, name: &str) -> Result<Option<T>, ConfigError>
-where
-    T: FromStr,
-    T::Err: std::error::Error + Send + Sync + 'static,
-{
-    let attr_str = match node.attribute(name) {
-        Some(v) => v,
-        None => return Ok(None),
-    };
-    let res: Result<T, <T as FromStr>::Err> = attr_str.parse();
-    match res {
-        Ok(res) => Ok(Some(res)),
-        Err(e) => Err(ConfigError::new(
-            node,
-            ParseAttribute(name.to_string(), e.into()),
-        )),
+impl ModbusRegister {
+    /// False for data that is read only
+    pub fn writable(&self) -> bool {
+        matches!(self, ModbusRegister::Coil | ModbusRegister::HoldingRegister)
     }
 }
 
-/// Get text content of an element with no element children.
-/// Non-text nodes are ignored
-fn text_content(node: &Node) -> Result<String, ConfigError> {
-    let mut content = String::new();
-    for child in node.children() {
-        if child.is_element() {
-            return Err(ConfigError::new(&child, UnexpectedElement));
-        }
-        if child.is_text() {
-            content.push_str(child.text().unwrap());
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModbusTagConfig {
+    pub name: String,
+    pub register: ModbusRegister,
+    pub address: u16,
+    // Register values are presented as raw * scale + offset
+    pub scale: f64,
+    pub offset: f64,
+    // Treat register values as two's complement
+    pub signed: bool,
+}
+
+/// Tags polled from a Modbus TCP server
+#[derive(Debug, Clone)]
+pub struct ModbusConfig {
+    // Host and port, e.g. plc:502
+    pub address: String,
+    pub unit: u8,
+    pub scan_interval: Duration,
+    pub tags: Vec<ModbusTagConfig>,
+}
+
+/// Data type of an S7 variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum S7Type {
+    Bool,
+    Byte,
+    Word,
+    Int,
+    DWord,
+    DInt,
+    Real,
+}
+
+impl S7Type {
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            S7Type::Bool | S7Type::Byte => 1,
+            S7Type::Word | S7Type::Int => 2,
+            S7Type::DWord | S7Type::DInt | S7Type::Real => 4,
         }
     }
-    Ok(content)
 }
 
-fn parse_duration(time_str: &str) -> DynResult<Duration> {
-    let time_str = time_str.trim();
-    let (value_str, unit_str) = time_str.split_at(time_str.len() - 1);
-    let value: f64 = value_str.trim().parse()?;
-    if value < 0.0 {
-        return Err("Negative duration not allowed".into());
-    }
-    let scale = match unit_str {
-        "s" => 1.0,
-        "m" => 60.0,
-        "h" => 60.0 * 60.0,
-        u => return Err(format!("Unknown time unit '{}'", u).into()),
-    };
-    Ok(Duration::from_secs_f64(value * scale))
+/// Variable in a data block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S7TagConfig {
+    pub name: String,
+    pub data_type: S7Type,
+    pub db: u16,
+    // Byte offset in the data block
+    pub offset: u32,
+    // Only used for Bool
+    pub bit: u8,
 }
 
-fn parse_bind(node: &Node) -> Result<String, ConfigError> {
-    text_content(node)
+/// Tags polled directly from an S7 PLC
+#[derive(Debug, Clone)]
+pub struct S7Config {
+    // Host, optionally with port. The default port is 102.
+    pub address: String,
+    pub rack: u8,
+    pub slot: u8,
+    pub scan_interval: Duration,
+    pub tags: Vec<S7TagConfig>,
 }
 
-fn parse_file_clip(node: &Node) -> Result<(String, ClipType), ConfigError> {
-    let id: String = required_attribute(node, "id")?;
-    let amplitude = optional_attribute(node, "amplitude")?.unwrap_or(1.0);
-    let file_name = text_content(node)?;
-    Ok((
-        id,
-        ClipType::File {
-            file_name,
-            amplitude,
-        },
-    ))
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpioInputLineConfig {
+    pub tag: String,
+    // Line offset on the chip
+    pub line: u32,
+    pub active_low: bool,
 }
 
-fn parse_sine_clip(node: &Node) -> DynResult<(String, ClipType)> {
-    let id = required_attribute(node, "id")?;
-    let amplitude = required_attribute(node, "amplitude")?;
-    let frequency = required_attribute(node, "frequency")?;
-    let dur_str: String = required_attribute(node, "duration")?;
-    let duration = parse_duration(&dur_str)
-        .map_err(|e| ConfigError::new(node, ParseAttribute("duration".to_string(), e)))?;
-    Ok((
-        id,
-        ClipType::Sine {
-            amplitude,
-            frequency,
-            duration,
-        },
-    ))
+/// GPIO lines whose state is written to tags
+#[derive(Debug, Clone)]
+pub struct GpioInputConfig {
+    // Character device, e.g. /dev/gpiochip0
+    pub chip: String,
+    // A changed level is reported when it has been stable this long
+    pub debounce: Duration,
+    pub lines: Vec<GpioInputLineConfig>,
 }
 
-fn parse_clips(parent: &Node) -> DynResult<HashMap<String, ClipType>> {
-    let mut clips = HashMap::new();
-    for node in parent.children() {
-        if check_element_ns(&node)? {
-            match node.tag_name().name() {
-                "file" => {
-                    let (id, clip) = parse_file_clip(&node)?;
-                    clips.insert(id, clip);
-                }
-                "sine" => {
-                    let (id, clip) = parse_sine_clip(&node)?;
-                    clips.insert(id, clip);
-                }
-                _ => return Err(ConfigError::new(&node, UnexpectedElement).into()),
-            }
+/// GPIO line driving a beacon, relay or similar
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpioOutputConfig {
+    pub name: String,
+    pub chip: String,
+    pub line: u32,
+    pub active_low: bool,
+    // The output is on while this alarm filter matches any alarms
+    pub follow_filter: Option<String>,
+}
+
+/// Tags read from and written to something else than Open Pipe
+#[derive(Debug, Clone)]
+pub enum TagSourceConfig {
+    OpcUa(OpcUaConfig),
+    Modbus(ModbusConfig),
+    GpioInput(GpioInputConfig),
+    S7(S7Config),
+}
+
+impl TagSourceConfig {
+    /// Names of all tags provided by the source
+    pub fn tag_names(&self) -> Vec<&str> {
+        match self {
+            TagSourceConfig::OpcUa(conf) => conf.tags.iter().map(|(t, _)| t.as_str()).collect(),
+            TagSourceConfig::Modbus(conf) => conf.tags.iter().map(|t| t.name.as_str()).collect(),
+            TagSourceConfig::GpioInput(conf) => conf.lines.iter().map(|l| l.tag.as_str()).collect(),
+            TagSourceConfig::S7(conf) => conf.tags.iter().map(|t| t.name.as_str()).collect(),
         }
     }
-    Ok(clips)
 }
 
-fn parse_action(node: &Node) -> DynResult<ActionType> {
-    let action = match node.tag_name().name() {
-        "sequence" => parse_sequence(node)?,
-        "parallel" => parse_parallel(node)?,
-        "play" => parse_play(node)?,
-        "wait" => parse_wait(node)?,
-        "wait_tag" => parse_wait_tag(node)?,
-        "wait_alarm" => parse_wait_alarm(node)?,
-
-        "goto" => parse_goto(node)?,
-        "repeat" => parse_repeat(node)?,
-        "set_tag" => parse_set_tag(node)?,
-        "set_volume" => parse_set_volume(node)?,
+/// How volume settings between 0.0 and 1.0 are mapped to the mixer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeScale {
+    // Linear in the raw range of the mixer
+    Linear,
+    // Perceptual curve using the dB range of the mixer
+    Db,
+}
 
-        "ignore_alarms" => parse_ignore_alarms(node)?,
-        "restore_alarms" => parse_restore_alarms(node)?,
-        "debug" => parse_debug(node)?,
-        _ => return Err(ConfigError::new(node, UnexpectedElement).into()),
-    };
-    Ok(action)
+/// Volume used during part of the day
+#[derive(Debug, Clone)]
+pub struct VolumeSchedule {
+    pub from: NaiveTime,
+    // May be earlier than from if the period passes midnight
+    pub to: NaiveTime,
+    pub volume: f32,
 }
 
-fn parse_play(node: &Node) -> DynResult<ActionType> {
-    let priority = optional_attribute(node, "priority")?.unwrap_or(0);
+impl VolumeSchedule {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
 
-    let timeout_str: Option<String> = optional_attribute(node, "timeout")?;
-    let timeout = timeout_str.map_or(Ok(None), |s| Some(parse_duration(&s)).transpose())?;
-    let sound = text_content(node)?;
-    Ok(ActionType::Play {
-        priority,
-        timeout,
-        sound,
-    })
+#[derive(Debug)]
+pub struct VolumeConfig {
+    pub id: String,
+    pub device: String,
+    pub initial_volume: Option<f32>,
+    // The current volume is written to this tag
+    pub tag_level: Option<String>,
+    // Name and index of the mixer element
+    pub control: Option<(String, u32)>,
+    pub scale: VolumeScale,
+    pub schedule: Vec<VolumeSchedule>,
+    // Allowed range of the volume
+    pub min_volume: f32,
+    pub max_volume: f32,
 }
 
-fn parse_wait(node: &Node) -> DynResult<ActionType> {
-    let time_str = text_content(node)?;
+impl VolumeConfig {
+    pub fn new(id: &str, device: &str) -> VolumeConfig {
+        VolumeConfig {
+            id: id.to_string(),
+            device: device.to_string(),
+            initial_volume: None,
+            tag_level: None,
+            control: None,
+            scale: VolumeScale::Linear,
+            schedule: Vec::new(),
+            min_volume: 0.0,
+            max_volume: 1.0,
+        }
+    }
+}
 
-    Ok(ActionType::Wait(parse_duration(&time_str)?))
+#[derive(Debug)]
+pub struct TagConfig {
+    // Name used by the pipe
+    pub name: String,
+    // Name used in the configuration, if different from the pipe name
+    pub alias: Option<String>,
+    // Only used internally, never subscribed or written to the pipe
+    pub local: bool,
+    // Save the value to the persistence file
+    pub persist: bool,
+    // Maximum number of writes per second to the pipe
+    pub max_rate: Option<f64>,
 }
 
-const CONDITION_ATTRIBUTES: &[&str] = &["eq", "ne", "lt", "le", "gt", "ge", "eq_str", "ne_str"];
-fn set_tag_condition(
-    node: &Node,
-    var: &mut Option<TagCondition>,
-    cond: TagCondition,
-) -> DynResult<()> {
-    if var.is_some() {
-        return Err(ConfigError::new(node, ExclusiveAttributes(CONDITION_ATTRIBUTES)).into());
+impl TagConfig {
+    /// The name used to refer to the tag in the configuration
+    pub fn internal_name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
     }
-    *var = Some(cond);
-    Ok(())
 }
 
-fn parse_wait_tag(node: &Node) -> DynResult<ActionType> {
-    let mut condition = None;
-    if let Some(v) = optional_attribute::<f64>(node, "eq")? {
-        set_tag_condition(node, &mut condition, TagCondition::EqualNumber(v))?;
-    }
-    if let Some(v) = optional_attribute::<f64>(node, "ne")? {
-        set_tag_condition(node, &mut condition, TagCondition::NotEqualNumber(v))?;
-    }
-    if let Some(v) = optional_attribute::<f64>(node, "lt")? {
-        set_tag_condition(node, &mut condition, TagCondition::Less(v))?;
-    }
-    if let Some(v) = optional_attribute::<f64>(node, "le")? {
-        set_tag_condition(node, &mut condition, TagCondition::LessEqual(v))?;
-    }
-    if let Some(v) = optional_attribute::<f64>(node, "gt")? {
-        set_tag_condition(node, &mut condition, TagCondition::Greater(v))?;
-    }
-    if let Some(v) = optional_attribute::<f64>(node, "ge")? {
-        set_tag_condition(node, &mut condition, TagCondition::GreaterEqual(v))?;
-    }
-    if let Some(v) = optional_attribute::<String>(node, "eq_str")? {
-        set_tag_condition(node, &mut condition, TagCondition::EqualString(v))?;
-    }
-    if let Some(_v) = optional_attribute::<String>(node, "changed")? {
-        set_tag_condition(node, &mut condition, TagCondition::Changed)?;
-    }
+/// Traps sent when alarm filters become active or cleared
+#[derive(Debug, Clone)]
+pub struct SnmpConfig {
+    // Receiver of the traps as host:port
+    pub target: String,
+    pub community: String,
+    // Prefix of the OIDs used in the traps
+    pub enterprise: Vec<u32>,
+    pub filters: Vec<String>,
+}
 
-    let condition = match condition {
-        Some(cond) => cond,
-        None => {
-            return Err(ConfigError::new(node, ExclusiveAttributes(CONDITION_ATTRIBUTES)).into())
-        }
-    };
+/// A tag mirrored to or from an MQTT topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttTopicConfig {
+    pub tag: String,
+    pub topic: String,
+}
+
+/// Bridge between tags and an MQTT broker
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // Published messages are retained by the broker
+    pub retain: bool,
+    // Tag changes are published to these topics
+    pub publish: Vec<MqttTopicConfig>,
+    // Messages on these topics are written to the tags
+    pub subscribe: Vec<MqttTopicConfig>,
+}
+
+/// Message bus the D-Bus service is registered on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbusBus {
+    System,
+    Session,
+}
+
+/// Which parts of the D-Bus control interface to provide
+#[derive(Debug, Clone)]
+pub struct DbusConfig {
+    pub bus: DbusBus,
+    // Clips can be played and silenced, otherwise it's read only
+    pub control: bool,
+}
+
+/// HTTP API for controlling the server
+#[derive(Debug, Clone)]
+pub struct ControlApiConfig {
+    // Address and port to listen on
+    pub bind: String,
+    // Requests must have the header "Authorization: Bearer <token>"
+    pub token: String,
+    // Token that only allows reading the status. Without it the status
+    // is readable by anyone.
+    pub status_token: Option<String>,
+}
+
+/// Where the Snapcast stream is sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapcastTarget {
+    // Host and port of a snapserver TCP source in server mode
+    Tcp(String),
+    // Path of a snapserver pipe source
+    Pipe(String),
+}
+
+/// Feed the played audio to a Snapcast server
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapcastConfig {
+    pub target: SnapcastTarget,
+    // Time between connection attempts
+    pub reconnect: Duration,
+}
+
+/// HTTP stream of the played audio for remote listening
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorStreamConfig {
+    // Address and port to listen on
+    pub bind: String,
+}
+
+/// HTTP server for Prometheus metrics
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsConfig {
+    // Address and port to listen on
+    pub bind: String,
+}
+
+/// How the connection to the mail server is protected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    None,
+    StartTls,
+    Tls,
+}
+
+/// Mail server used by email actions
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub server: String,
+    // The default port for the security mode is used if not set
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub from: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+/// A tag periodically incremented by the daemon
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    pub tag: String,
+    pub interval: Duration,
+}
+
+/// File recording played clips and alarm transitions
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditLogConfig {
+    pub path: String,
+    /// Size in bytes when the file is rotated, never rotated if None
+    pub max_size: Option<u64>,
+    /// Number of rotated files kept
+    pub keep: u32,
+    /// Record alarms starting and stopping to match alarm filters
+    pub alarms: bool,
+}
+
+/// Delays between attempts to reconnect to Open Pipe
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectConfig {
+    // Delay before the first attempt, doubled after each failure
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Protection against state machines or repeats looping too fast
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventLimitConfig {
+    // Events allowed within the window
+    pub max_events: u32,
+    pub window: Duration,
+    // How long an offending state machine is paused
+    pub cooldown: Duration,
+    // Tag counting how many times the limit has been exceeded
+    pub tag: Option<String>,
+}
+
+impl EventLimitConfig {
+    /// Default limit for state changes of a state machine
+    pub fn state_change_default() -> Self {
+        EventLimitConfig {
+            max_events: 100,
+            window: Duration::from_secs(1),
+            cooldown: Duration::from_secs(10),
+            tag: None,
+        }
+    }
+
+    /// Default limit for iterations of a repeat. Short clips may
+    /// legitimately be repeated quickly.
+    pub fn repeat_default() -> Self {
+        EventLimitConfig {
+            max_events: 1000,
+            ..Self::state_change_default()
+        }
+    }
+}
+
+/// An audio output device
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackDeviceConfig {
+    pub device: String,
+    pub rate: u32,
+    pub channels: u8,
+    pub sample_format: SampleFormat,
+    /// Frames per buffer, the device default if None
+    pub buffer_size: Option<u32>,
+}
+
+/// A tag whose value is computed from other tags
+#[derive(Debug)]
+pub struct DerivedTagConfig {
+    pub name: String,
+    pub expr: Expr,
+}
+
+#[derive(Debug)]
+pub struct PlayerConfig {
+    pub bind: String,
+    pub playback_device: String,
+    pub rate: u32,
+    pub channels: u8,
+    pub sample_format: SampleFormat,
+    // Frames per buffer of the default device
+    pub buffer_size: Option<u32>,
+    // Additional playback devices by id
+    pub playback_devices: HashMap<String, PlaybackDeviceConfig>,
+    pub clip_root: String,
+    pub clips: HashMap<String, ClipType>,
+    // Device id of clips that aren't played on the default device
+    pub clip_devices: HashMap<String, String>,
+    pub clip_dirs: Vec<ClipDirConfig>,
+    pub clip_profiles: HashMap<String, ClipProfile>,
+    pub tags: Vec<TagConfig>,
+    pub derived_tags: Vec<DerivedTagConfig>,
+    // File where persistent tag values are stored
+    pub tag_persist_file: Option<String>,
+    // Remote syslog server, e.g. udp://host:514
+    pub syslog: Option<String>,
+    // File recording all clips played
+    pub audit_log: Option<AuditLogConfig>,
+    // Tag that changes the log level
+    pub log_level_tag: Option<String>,
+    // Tag that makes the server reload the configuration
+    pub reload_tag: Option<String>,
+    pub heartbeat: Option<HeartbeatConfig>,
+    // How long to wait for playing clips and tag writes when shutting down
+    pub shutdown_drain: Duration,
+    // Clips still playing when shutting down are faded out over this time
+    pub shutdown_fade: Duration,
+    pub reconnect: ReconnectConfig,
+    pub state_change_limit: EventLimitConfig,
+    pub repeat_limit: EventLimitConfig,
+    pub named_alarm_filters: HashMap<String, AlarmFilterConfig>,
+    // File where ignored alarms are stored
+    pub alarm_persist_file: Option<String>,
+    pub state_machines: Vec<StateMachineConfig>,
+    pub signals: Vec<SignalConfig>,
+    pub tag_sources: Vec<TagSourceConfig>,
+    pub gpio_outputs: Vec<GpioOutputConfig>,
+    pub snmp: Option<SnmpConfig>,
+    pub smtp: Option<SmtpConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub dbus: Option<DbusConfig>,
+    pub control_api: Option<ControlApiConfig>,
+    pub snapcast: Option<SnapcastConfig>,
+    pub monitor_stream: Option<MonitorStreamConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub volume_config: Vec<VolumeConfig>,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        new_player_config()
+    }
+}
+
+impl PlayerConfig {
+    /// Build a configuration in code instead of reading a file
+    pub fn builder() -> PlayerConfigBuilder {
+        PlayerConfigBuilder {
+            conf: PlayerConfig::default(),
+        }
+    }
+
+    /// The device used when no device id is given
+    pub fn default_device(&self) -> PlaybackDeviceConfig {
+        PlaybackDeviceConfig {
+            device: self.playback_device.clone(),
+            rate: self.rate,
+            channels: self.channels,
+            sample_format: self.sample_format,
+            buffer_size: self.buffer_size,
+        }
+    }
+}
+
+impl TagConfig {
+    /// A tag with default settings
+    pub fn new(name: &str) -> TagConfig {
+        TagConfig {
+            name: name.to_string(),
+            alias: None,
+            local: false,
+            persist: false,
+            max_rate: None,
+        }
+    }
+}
+
+impl StateMachineConfig {
+    pub fn new(id: &str) -> StateMachineConfig {
+        StateMachineConfig {
+            id: id.to_string(),
+            states: Vec::new(),
+            state_tag: None,
+            command_tag: None,
+        }
+    }
+
+    /// Publish the active state in a tag
+    pub fn state_tag(mut self, tag: &str) -> StateMachineConfig {
+        self.state_tag = Some(tag.to_string());
+        self
+    }
+
+    /// Change state when a state name is written to a tag
+    pub fn command_tag(mut self, tag: &str) -> StateMachineConfig {
+        self.command_tag = Some(tag.to_string());
+        self
+    }
+
+    /// Add a state. The first state added is the initial state.
+    pub fn state(mut self, id: &str, action: ActionType) -> StateMachineConfig {
+        self.states.push(StateConfig {
+            id: id.to_string(),
+            action,
+        });
+        self
+    }
+}
+
+/// Builder for PlayerConfig. Values not set are the same as for an
+/// empty configuration file.
+pub struct PlayerConfigBuilder {
+    conf: PlayerConfig,
+}
+
+impl PlayerConfigBuilder {
+    pub fn bind(mut self, bind: &str) -> Self {
+        self.conf.bind = bind.to_string();
+        self
+    }
+
+    pub fn playback_device(mut self, device: &str, rate: u32, channels: u8) -> Self {
+        self.conf.playback_device = device.to_string();
+        self.conf.rate = rate;
+        self.conf.channels = channels;
+        self
+    }
+
+    pub fn sample_format(mut self, format: SampleFormat) -> Self {
+        self.conf.sample_format = format;
+        self
+    }
+
+    /// Frames per buffer of the default device
+    pub fn buffer_size(mut self, frames: u32) -> Self {
+        self.conf.buffer_size = Some(frames);
+        self
+    }
+
+    /// Add a playback device in addition to the default one
+    pub fn named_playback_device(mut self, id: &str, device: PlaybackDeviceConfig) -> Self {
+        self.conf.playback_devices.insert(id.to_string(), device);
+        self
+    }
+
+    /// Play a clip on the device with the given id instead of the
+    /// default device
+    pub fn clip_device(mut self, clip: &str, device: &str) -> Self {
+        self.conf
+            .clip_devices
+            .insert(clip.to_string(), device.to_string());
+        self
+    }
+
+    pub fn clip_root(mut self, root: &str) -> Self {
+        self.conf.clip_root = root.to_string();
+        self
+    }
+
+    pub fn clip(mut self, id: &str, clip: ClipType) -> Self {
+        self.conf.clips.insert(id.to_string(), clip);
+        self
+    }
+
+    /// Add a clip read from a file relative to the clip root
+    pub fn file_clip(self, id: &str, file_name: &str) -> Self {
+        self.clip(
+            id,
+            ClipType::File {
+                file_name: file_name.to_string(),
+                amplitude: None,
+                profile: None,
+            },
+        )
+    }
+
+    pub fn clip_profile(mut self, id: &str, profile: ClipProfile) -> Self {
+        self.conf.clip_profiles.insert(id.to_string(), profile);
+        self
+    }
+
+    pub fn tag(mut self, tag: TagConfig) -> Self {
+        self.conf.tags.push(tag);
+        self
+    }
+
+    pub fn tag_source(mut self, source: TagSourceConfig) -> Self {
+        self.conf.tag_sources.push(source);
+        self
+    }
+
+    pub fn gpio_output(mut self, output: GpioOutputConfig) -> Self {
+        self.conf.gpio_outputs.push(output);
+        self
+    }
+
+    pub fn snmp(mut self, snmp: SnmpConfig) -> Self {
+        self.conf.snmp = Some(snmp);
+        self
+    }
+
+    pub fn smtp(mut self, smtp: SmtpConfig) -> Self {
+        self.conf.smtp = Some(smtp);
+        self
+    }
+
+    pub fn mqtt(mut self, mqtt: MqttConfig) -> Self {
+        self.conf.mqtt = Some(mqtt);
+        self
+    }
+
+    pub fn dbus(mut self, dbus: DbusConfig) -> Self {
+        self.conf.dbus = Some(dbus);
+        self
+    }
+
+    pub fn control_api(mut self, control_api: ControlApiConfig) -> Self {
+        self.conf.control_api = Some(control_api);
+        self
+    }
+
+    pub fn snapcast(mut self, snapcast: SnapcastConfig) -> Self {
+        self.conf.snapcast = Some(snapcast);
+        self
+    }
+
+    pub fn monitor_stream(mut self, monitor_stream: MonitorStreamConfig) -> Self {
+        self.conf.monitor_stream = Some(monitor_stream);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: MetricsConfig) -> Self {
+        self.conf.metrics = Some(metrics);
+        self
+    }
+
+    pub fn reload_tag(mut self, tag: &str) -> Self {
+        self.conf.reload_tag = Some(tag.to_string());
+        self
+    }
+
+    pub fn heartbeat(mut self, tag: &str, interval: Duration) -> Self {
+        self.conf.heartbeat = Some(HeartbeatConfig {
+            tag: tag.to_string(),
+            interval,
+        });
+        self
+    }
+
+    pub fn audit_log(mut self, audit_log: AuditLogConfig) -> Self {
+        self.conf.audit_log = Some(audit_log);
+        self
+    }
+
+    pub fn shutdown_drain(mut self, drain: Duration) -> Self {
+        self.conf.shutdown_drain = drain;
+        self
+    }
+
+    pub fn shutdown_fade(mut self, fade: Duration) -> Self {
+        self.conf.shutdown_fade = fade;
+        self
+    }
+
+    pub fn reconnect(mut self, reconnect: ReconnectConfig) -> Self {
+        self.conf.reconnect = reconnect;
+        self
+    }
+
+    pub fn state_change_limit(mut self, limit: EventLimitConfig) -> Self {
+        self.conf.state_change_limit = limit;
+        self
+    }
+
+    pub fn repeat_limit(mut self, limit: EventLimitConfig) -> Self {
+        self.conf.repeat_limit = limit;
+        self
+    }
+
+    pub fn alarm_filter(mut self, id: &str, filter: AlarmFilterConfig) -> Self {
+        self.conf.named_alarm_filters.insert(id.to_string(), filter);
+        self
+    }
+
+    /// Store ignored alarms in this file, relative to the configuration
+    pub fn alarm_persist_file(mut self, path: &str) -> Self {
+        self.conf.alarm_persist_file = Some(path.to_string());
+        self
+    }
+
+    pub fn state_machine(mut self, machine: StateMachineConfig) -> Self {
+        self.conf.state_machines.push(machine);
+        self
+    }
+
+    pub fn signal(mut self, name: &str, action: ActionType) -> Self {
+        self.conf.signals.push(SignalConfig {
+            name: name.to_string(),
+            action,
+        });
+        self
+    }
+
+    pub fn volume_control(mut self, control: VolumeConfig) -> Self {
+        self.conf.volume_config.push(control);
+        self
+    }
+
+    pub fn build(self) -> PlayerConfig {
+        self.conf
+    }
+}
+
+const NS: &str = "http://www.elektro-kapsel.se/audioplayer/v1";
+
+/// Replace ${VAR} with the value returned by lookup. ${VAR:-default}
+/// uses default if the variable is undefined. $$ is replaced by $.
+fn expand_vars<F>(input: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(pos) = rest.find('$') {
+        output.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            output.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("Unterminated variable reference in '{}'", input))?;
+            let var = &after[..end];
+            let (name, default) = match var.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (var, None),
+            };
+            match lookup(name).or_else(|| default.map(str::to_string)) {
+                Some(value) => output.push_str(&value),
+                None => return Err(format!("Variable '{}' is not defined", name)),
+            }
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+thread_local! {
+    // Variables used for expansion before looking in the
+    // environment. The innermost scope is last.
+    static VAR_SCOPES: RefCell<Vec<HashMap<String, String>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run f with vars available for expansion
+fn with_vars<T>(vars: HashMap<String, String>, f: impl FnOnce() -> T) -> T {
+    VAR_SCOPES.with(|scopes| scopes.borrow_mut().push(vars));
+    let res = f();
+    VAR_SCOPES.with(|scopes| scopes.borrow_mut().pop());
+    res
+}
+
+// Add a variable to the innermost scope
+fn define_var(name: String, value: String) {
+    VAR_SCOPES.with(|scopes| {
+        if let Some(vars) = scopes.borrow_mut().last_mut() {
+            vars.insert(name, value);
+        }
+    });
+}
+
+fn lookup_var(name: &str) -> Option<String> {
+    VAR_SCOPES
+        .with(|scopes| {
+            scopes
+                .borrow()
+                .iter()
+                .rev()
+                .find_map(|vars| vars.get(name).cloned())
+        })
+        .or_else(|| std::env::var(name).ok())
+}
+
+fn expand_node_vars(node: &Node, input: &str) -> Result<String, ConfigError> {
+    if !input.contains('$') {
+        return Ok(input.to_string());
+    }
+    expand_vars(input, lookup_var).map_err(|e| ConfigError::new(node, ExpandVariable(e)))
+}
+
+fn required_attribute<T>(node: &Node, name: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let attr_str = node
+        .attribute(name)
+        .ok_or_else(|| ConfigError::new(node, MissingAttribute(name.to_string())))?;
+    let attr_str = expand_node_vars(node, attr_str)?;
+    let res: Result<T, <T as FromStr>::Err> = attr_str.parse();
+    res.map_err(|e| ConfigError::new(node, ParseAttribute(name.to_string(), e.into())))
+}
+
+fn optional_attribute<T>(node: &Node, name: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let attr_str = match node.attribute(name) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let attr_str = expand_node_vars(node, attr_str)?;
+    let res: Result<T, <T as FromStr>::Err> = attr_str.parse();
+    match res {
+        Ok(res) => Ok(Some(res)),
+        Err(e) => Err(ConfigError::new(
+            node,
+            ParseAttribute(name.to_string(), e.into()),
+        )),
+    }
+}
+
+/// Get text content of an element with no element children.
+/// Non-text nodes are ignored
+fn text_content(node: &Node) -> Result<String, ConfigError> {
+    let mut content = String::new();
+    for child in node.children() {
+        if child.is_element() {
+            return Err(ConfigError::new(&child, UnexpectedElement));
+        }
+        if child.is_text() {
+            content.push_str(child.text().unwrap());
+        }
+    }
+    expand_node_vars(node, &content)
+}
+
+/// Parse a duration like "1.5s", "200ms" or "1m30s". ISO-8601
+/// durations like "PT1M30S" are also accepted.
+pub fn parse_duration(time_str: &str) -> DynResult<Duration> {
+    let time_str = time_str.trim();
+    if let Some(iso) = time_str.strip_prefix('P') {
+        return parse_iso_duration(iso);
+    }
+    if time_str.starts_with('-') {
+        return Err("Negative duration not allowed".into());
+    }
+    if time_str.is_empty() {
+        return Err("Empty duration".into());
+    }
+    let mut seconds = 0.0;
+    let mut rest = time_str;
+    while !rest.is_empty() {
+        let value_end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(|| format!("Missing time unit in '{}'", time_str))?;
+        let value: f64 = rest[..value_end].parse()?;
+        rest = rest[value_end..].trim_start();
+        let unit_end = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        seconds += match &rest[..unit_end] {
+            "ms" => value / 1000.0,
+            "s" => value,
+            "m" => value * 60.0,
+            "h" => value * 60.0 * 60.0,
+            u => return Err(format!("Unknown time unit '{}'", u).into()),
+        };
+        rest = rest[unit_end..].trim_start();
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+// Split "1H30.5M" into [(1.0, 'H'), (30.5, 'M')]
+fn iso_duration_parts(s: &str) -> DynResult<Vec<(f64, char)>> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let unit_pos = rest
+            .find(|c: char| c.is_ascii_alphabetic())
+            .ok_or("Missing designator in ISO-8601 duration")?;
+        // Both comma and dot are allowed as decimal sign
+        let value: f64 = rest[..unit_pos].replace(',', ".").parse()?;
+        parts.push((value, rest[unit_pos..].chars().next().unwrap()));
+        rest = &rest[unit_pos + 1..];
+    }
+    Ok(parts)
+}
+
+// Parse an ISO-8601 duration without the leading 'P'. Years and
+// months have no fixed length and are not supported.
+fn parse_iso_duration(s: &str) -> DynResult<Duration> {
+    let (date, time) = match s.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+    if date.is_empty() && time.is_none_or(|t| t.is_empty()) {
+        return Err("Empty ISO-8601 duration".into());
+    }
+    let mut seconds = 0.0;
+    for (value, unit) in iso_duration_parts(date)? {
+        seconds += value
+            * match unit {
+                'W' => 7.0 * 24.0 * 60.0 * 60.0,
+                'D' => 24.0 * 60.0 * 60.0,
+                'Y' | 'M' => return Err("Years and months are not supported in durations".into()),
+                u => return Err(format!("Unknown designator '{}' in duration", u).into()),
+            };
+    }
+    if let Some(time) = time {
+        for (value, unit) in iso_duration_parts(time)? {
+            seconds += value
+                * match unit {
+                    'H' => 60.0 * 60.0,
+                    'M' => 60.0,
+                    'S' => 1.0,
+                    u => return Err(format!("Unknown designator '{}' in duration", u).into()),
+                };
+        }
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn parse_bind(node: &Node) -> Result<String, ConfigError> {
+    text_content(node)
+}
+
+fn parse_heartbeat(node: &Node) -> DynResult<HeartbeatConfig> {
+    let interval_str: String = required_attribute(node, "interval")?;
+    let interval = parse_duration(&interval_str)
+        .map_err(|e| ConfigError::new(node, ParseAttribute("interval".to_string(), e)))?;
+    if interval.is_zero() {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute("interval".to_string(), "Interval must not be zero".into()),
+        )
+        .into());
+    }
+    let tag = text_content(node)?.trim().to_string();
+    Ok(HeartbeatConfig { tag, interval })
+}
+
+fn parse_audit_log(node: &Node) -> DynResult<AuditLogConfig> {
+    let max_size = optional_attribute(node, "max_size")?;
+    if max_size == Some(0) {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute("max_size".to_string(), "Size must not be zero".into()),
+        )
+        .into());
+    }
+    Ok(AuditLogConfig {
+        path: text_content(node)?.trim().to_string(),
+        max_size,
+        keep: optional_attribute(node, "keep")?.unwrap_or(5),
+        alarms: optional_attribute(node, "alarms")?.unwrap_or(true),
+    })
+}
+
+// Drain and fade times
+fn parse_shutdown(node: &Node) -> DynResult<(Duration, Duration)> {
+    let duration = |name: &str| -> DynResult<Duration> {
+        match optional_attribute::<String>(node, name)? {
+            Some(d) => Ok(parse_duration(&d)
+                .map_err(|e| ConfigError::new(node, ParseAttribute(name.to_string(), e)))?),
+            None => Ok(Duration::ZERO),
+        }
+    };
+    Ok((duration("drain")?, duration("fade")?))
+}
+
+fn parse_reconnect(node: &Node) -> DynResult<ReconnectConfig> {
+    let mut reconnect = ReconnectConfig::default();
+    for (name, value) in [
+        ("initial", &mut reconnect.initial),
+        ("max", &mut reconnect.max),
+    ] {
+        if let Some(duration) = optional_attribute::<String>(node, name)? {
+            *value = parse_duration(&duration)
+                .map_err(|e| ConfigError::new(node, ParseAttribute(name.to_string(), e)))?;
+        }
+    }
+    if reconnect.initial.is_zero() || reconnect.max < reconnect.initial {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute(
+                "max".to_string(),
+                "Delays must be non-zero, with max at least initial".into(),
+            ),
+        )
+        .into());
+    }
+    Ok(reconnect)
+}
+
+fn parse_event_limit(node: &Node, default: EventLimitConfig) -> DynResult<EventLimitConfig> {
+    let mut limit = default;
+    if let Some(max_events) = optional_attribute(node, "max")? {
+        if max_events == 0 {
+            return Err(ConfigError::new(
+                node,
+                ParseAttribute("max".to_string(), "Must be at least 1".into()),
+            )
+            .into());
+        }
+        limit.max_events = max_events;
+    }
+    limit.tag = optional_attribute(node, "tag")?;
+    for (name, value) in [
+        ("window", &mut limit.window),
+        ("cooldown", &mut limit.cooldown),
+    ] {
+        if let Some(duration) = optional_attribute::<String>(node, name)? {
+            *value = parse_duration(&duration)
+                .map_err(|e| ConfigError::new(node, ParseAttribute(name.to_string(), e)))?;
+        }
+    }
+    Ok(limit)
+}
+
+fn parse_file_clip(node: &Node) -> Result<(String, ClipType), ConfigError> {
+    let id: String = required_attribute(node, "id")?;
+    let amplitude = optional_attribute(node, "amplitude")?;
+    let profile = optional_attribute(node, "profile")?;
+    let file_name = text_content(node)?;
+    Ok((
+        id,
+        ClipType::File {
+            file_name,
+            amplitude,
+            profile,
+        },
+    ))
+}
+
+fn parse_clip_profile(node: &Node) -> Result<(String, ClipProfile), ConfigError> {
+    let id: String = required_attribute(node, "id")?;
+    let profile = ClipProfile {
+        amplitude: optional_attribute(node, "amplitude")?,
+        normalize: optional_attribute(node, "normalize")?,
+        trim: optional_attribute(node, "trim")?,
+        resampler: match optional_attribute::<String>(node, "resampler")?.as_deref() {
+            None | Some("high") => ResamplerQuality::High,
+            Some("fast") => ResamplerQuality::Fast,
+            Some(_) => {
+                return Err(ConfigError::new(
+                    node,
+                    ParseAttribute("resampler".to_string(), "Must be 'high' or 'fast'".into()),
+                ))
+            }
+        },
+        stream: optional_attribute(node, "stream")?.unwrap_or(false),
+    };
+    // The whole clip is needed for these
+    if profile.stream && (profile.normalize.is_some() || profile.trim.is_some()) {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute(
+                "stream".to_string(),
+                "Streamed clips can't be normalized or trimmed".into(),
+            ),
+        ));
+    }
+    Ok((id, profile))
+}
+
+fn parse_sine_clip(node: &Node) -> DynResult<(String, ClipType)> {
+    let id = required_attribute(node, "id")?;
+    let amplitude = required_attribute(node, "amplitude")?;
+    let frequency = required_attribute(node, "frequency")?;
+    let dur_str: String = required_attribute(node, "duration")?;
+    let duration = parse_duration(&dur_str)
+        .map_err(|e| ConfigError::new(node, ParseAttribute("duration".to_string(), e)))?;
+    Ok((
+        id,
+        ClipType::Sine {
+            amplitude,
+            frequency,
+            duration,
+        },
+    ))
+}
+
+fn parse_clip_dir(node: &Node) -> Result<ClipDirConfig, ConfigError> {
+    let id_prefix = optional_attribute(node, "id_prefix")?.unwrap_or_default();
+    let path = required_attribute(node, "path")?;
+    let amplitude = optional_attribute(node, "amplitude")?;
+    let profile = optional_attribute(node, "profile")?;
+    Ok(ClipDirConfig {
+        id_prefix,
+        path,
+        amplitude,
+        profile,
+    })
+}
+
+fn parse_clips(parent: &Node, player: &mut PlayerConfig) -> DynResult<()> {
+    let mut clips = HashMap::new();
+    let mut errors = ErrorList::default();
+    for node in parent.children() {
+        if errors.is_element(&node) {
+            if node.tag_name().name() == "profile" {
+                if let Some((id, profile)) =
+                    errors.check(&node, parse_clip_profile(&node).map_err(|e| e.into()))
+                {
+                    player.clip_profiles.insert(id, profile);
+                }
+                continue;
+            }
+            if node.tag_name().name() == "dir" {
+                if let Some(dir) = errors.check(&node, parse_clip_dir(&node).map_err(|e| e.into()))
+                {
+                    player.clip_dirs.push(dir);
+                }
+                continue;
+            }
+            let res: DynResult<(String, ClipType)> = match node.tag_name().name() {
+                "file" => parse_file_clip(&node).map_err(|e| e.into()),
+                "sine" => parse_sine_clip(&node),
+                _ => Err(ConfigError::new(&node, UnexpectedElement).into()),
+            };
+            if let Some((id, clip)) = errors.check(&node, res) {
+                if clips.contains_key(&id) || player.clips.contains_key(&id) {
+                    let msg = format!("Clip '{}' is already defined", id);
+                    let kind = ParseAttribute("id".to_string(), msg.into());
+                    errors.push(&node, ConfigError::new(&node, kind).into());
+                    continue;
+                }
+                let device = optional_attribute(&node, "device").map_err(|e| e.into());
+                if let Some(Some(device)) = errors.check(&node, device) {
+                    player.clip_devices.insert(id.clone(), device);
+                }
+                clips.insert(id, clip);
+            }
+        }
+    }
+    errors.into_result()?;
+    player.clips.extend(clips);
+    Ok(())
+}
+
+/// Element names of the built-in actions
+pub(crate) const ACTION_ELEMENTS: &[&str] = &[
+    "sequence",
+    "parallel",
+    "play",
+    "wait",
+    "wait_time",
+    "wait_tag",
+    "wait_alarm",
+    "goto",
+    "if",
+    "repeat",
+    "set_tag",
+    "set_volume",
+    "set_balance",
+    "volume_up",
+    "volume_down",
+    "ignore_alarms",
+    "restore_alarms",
+    "debug",
+    "email",
+    "set_gpio",
+    "exec",
+];
+
+fn parse_action(node: &Node) -> DynResult<ActionType> {
+    let action = match node.tag_name().name() {
+        "sequence" => parse_sequence(node)?,
+        "parallel" => parse_parallel(node)?,
+        "play" => parse_play(node)?,
+        "wait" => parse_wait(node)?,
+        "wait_time" => parse_wait_time(node)?,
+        "wait_tag" => parse_wait_tag(node)?,
+        "wait_alarm" => parse_wait_alarm(node)?,
+
+        "goto" => parse_goto(node)?,
+        "if" => parse_if(node)?,
+        "repeat" => parse_repeat(node)?,
+        "set_tag" => parse_set_tag(node)?,
+        "set_volume" => parse_set_volume(node)?,
+        "set_balance" => parse_set_balance(node)?,
+        "volume_up" => parse_change_volume(node, 1.0)?,
+        "volume_down" => parse_change_volume(node, -1.0)?,
+
+        "ignore_alarms" => parse_ignore_alarms(node)?,
+        "restore_alarms" => parse_restore_alarms(node)?,
+        "debug" => parse_debug(node)?,
+        "email" => parse_email(node)?,
+        "set_gpio" => parse_set_gpio(node)?,
+        "exec" => parse_exec(node)?,
+        name => match plugin::find_action(name) {
+            Some(plugin) => {
+                let element = parse_plugin_element(node)?;
+                plugin
+                    .parse(&element)
+                    .map_err(|e| ConfigError::new(node, Plugin(e)))?;
+                ActionType::Plugin(element)
+            }
+            None => return Err(ConfigError::new(node, UnexpectedElement).into()),
+        },
+    };
+    Ok(action)
+}
+
+fn parse_plugin_element(node: &Node) -> Result<PluginElement, ConfigError> {
+    let mut attributes = BTreeMap::new();
+    for attr in node.attributes() {
+        attributes.insert(
+            attr.name().to_string(),
+            expand_node_vars(node, attr.value())?,
+        );
+    }
+    let mut text = String::new();
+    let mut children = Vec::new();
+    for child in node.children() {
+        if child.is_element() {
+            check_element_ns(&child)?;
+            children.push(parse_plugin_element(&child)?);
+        } else if let Some(t) = child.text() {
+            text.push_str(t);
+        }
+    }
+    Ok(PluginElement {
+        name: node.tag_name().name().to_string(),
+        attributes,
+        text: expand_node_vars(node, text.trim())?,
+        children,
+    })
+}
+
+fn parse_play(node: &Node) -> DynResult<ActionType> {
+    let priority = optional_attribute(node, "priority")?.unwrap_or(0);
+
+    let timeout_str: Option<String> = optional_attribute(node, "timeout")?;
+    let timeout = timeout_str.map_or(Ok(None), |s| Some(parse_duration(&s)).transpose())?;
+    let sound = text_content(node)?;
+    Ok(ActionType::Play {
+        priority,
+        timeout,
+        sound,
+        device: optional_attribute(node, "device")?,
+    })
+}
+
+fn parse_wait(node: &Node) -> DynResult<ActionType> {
+    let time_str = text_content(node)?;
+
+    Ok(ActionType::Wait(parse_duration(&time_str)?))
+}
+
+fn parse_wait_time(node: &Node) -> DynResult<ActionType> {
+    let schedule_str = text_content(node)?;
+    Ok(ActionType::WaitTime(Schedule::parse(&schedule_str)?))
+}
+
+const CONDITION_ATTRIBUTES: &[&str] = &[
+    "eq", "ne", "lt", "le", "gt", "ge", "eq_str", "ne_str", "changes", "rise", "fall", "expr",
+];
+fn set_tag_condition(
+    node: &Node,
+    var: &mut Option<TagCondition>,
+    cond: TagCondition,
+) -> DynResult<()> {
+    if var.is_some() {
+        return Err(ConfigError::new(node, ExclusiveAttributes(CONDITION_ATTRIBUTES)).into());
+    }
+    *var = Some(cond);
+    Ok(())
+}
+
+fn parse_wait_tag(node: &Node) -> DynResult<ActionType> {
+    let mut condition = None;
+    if let Some(v) = optional_attribute::<f64>(node, "eq")? {
+        set_tag_condition(node, &mut condition, TagCondition::EqualNumber(v))?;
+    }
+    if let Some(v) = optional_attribute::<f64>(node, "ne")? {
+        set_tag_condition(node, &mut condition, TagCondition::NotEqualNumber(v))?;
+    }
+    if let Some(v) = optional_attribute::<f64>(node, "lt")? {
+        set_tag_condition(node, &mut condition, TagCondition::Less(v))?;
+    }
+    if let Some(v) = optional_attribute::<f64>(node, "le")? {
+        set_tag_condition(node, &mut condition, TagCondition::LessEqual(v))?;
+    }
+    if let Some(v) = optional_attribute::<f64>(node, "gt")? {
+        set_tag_condition(node, &mut condition, TagCondition::Greater(v))?;
+    }
+    if let Some(v) = optional_attribute::<f64>(node, "ge")? {
+        set_tag_condition(node, &mut condition, TagCondition::GreaterEqual(v))?;
+    }
+    if let Some(v) = optional_attribute::<String>(node, "eq_str")? {
+        set_tag_condition(node, &mut condition, TagCondition::EqualString(v))?;
+    }
+    if let Some(_v) = optional_attribute::<String>(node, "changed")? {
+        set_tag_condition(node, &mut condition, TagCondition::Changed)?;
+    }
+    let within = || -> DynResult<Duration> {
+        let within_str: String = required_attribute(node, "within")?;
+        parse_duration(&within_str)
+            .map_err(|e| ConfigError::new(node, ParseAttribute("within".to_string(), e)).into())
+    };
+    if let Some(count) = optional_attribute::<u32>(node, "changes")? {
+        let period = within()?;
+        set_tag_condition(
+            node,
+            &mut condition,
+            TagCondition::ChangeCount { count, period },
+        )?;
+    }
+    if let Some(delta) = optional_attribute::<f64>(node, "rise")? {
+        let period = within()?;
+        set_tag_condition(node, &mut condition, TagCondition::Rise { delta, period })?;
+    }
+    if let Some(delta) = optional_attribute::<f64>(node, "fall")? {
+        let period = within()?;
+        set_tag_condition(node, &mut condition, TagCondition::Fall { delta, period })?;
+    }
+    if node.has_attribute("expr") {
+        if condition.is_some() {
+            return Err(ConfigError::new(node, ExclusiveAttributes(CONDITION_ATTRIBUTES)).into());
+        }
+        // The tags are given by the expression
+        if !text_content(node)?.trim().is_empty() {
+            return Err(ConfigError::new(node, UnexpectedText).into());
+        }
+        return Ok(ActionType::WaitExpr(expr_attribute(node, "expr")?));
+    }
+
+    let condition = match condition {
+        Some(cond) => cond,
+        None => {
+            return Err(ConfigError::new(node, ExclusiveAttributes(CONDITION_ATTRIBUTES)).into())
+        }
+    };
 
     let tag_name = text_content(node)?;
 
-    Ok(ActionType::WaitTag {
-        tag_name,
-        condition,
-    })
+    Ok(ActionType::WaitTag {
+        tag_name,
+        condition,
+    })
+}
+
+fn parse_wait_alarm(node: &Node) -> DynResult<ActionType> {
+    let filter_name = text_content(node)?;
+    let count = required_attribute::<String>(node, "count")?;
+    let condition = match count.as_str() {
+        "none" => AlarmCondition::None,
+        "any" => AlarmCondition::Any,
+        "inc" => AlarmCondition::Inc,
+        "dec" => AlarmCondition::Dec,
+        _ => {
+            return Err(ConfigError::new(
+                node,
+                ParseAttribute(
+                    "count".to_string(),
+                    "Must be one of 'none', 'any', 'inc' or 'dec'".into(),
+                ),
+            )
+            .into())
+        }
+    };
+    Ok(ActionType::WaitAlarm {
+        filter_name,
+        condition,
+    })
+}
+
+fn parse_goto(node: &Node) -> DynResult<ActionType> {
+    let state_name = text_content(node)?;
+    Ok(ActionType::Goto(state_name))
+}
+
+fn expr_attribute(node: &Node, name: &str) -> Result<Expr, ConfigError> {
+    let expr_str: String = required_attribute(node, name)?;
+    expr::parse_expr(&expr_str).map_err(|e| ConfigError::new(node, ParseExpression(e.into())))
+}
+
+// The actions to run if the condition is true are followed by an
+// optional else element
+fn parse_if(node: &Node) -> DynResult<ActionType> {
+    let condition = expr_attribute(node, "expr")?;
+    let mut actions = Vec::new();
+    let mut otherwise = None;
+    for child in node.children() {
+        if check_element_ns(&child)? {
+            if child.tag_name().name() == "else" {
+                if otherwise.is_some() {
+                    return Err(ConfigError::new(&child, UnexpectedElement).into());
+                }
+                otherwise = Some(Box::new(parse_sequence(&child)?));
+                continue;
+            }
+            match parse_action(&child) {
+                Ok(action) => actions.push(action),
+                Err(e) if skip_unknown(&*e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    let then = match actions.len() {
+        0 => return Err("No action in if".into()),
+        1 => actions.pop().unwrap(),
+        _ => ActionType::Sequence(actions),
+    };
+    Ok(ActionType::If {
+        condition,
+        then: Box::new(then),
+        otherwise,
+    })
+}
+
+fn parse_repeat(node: &Node) -> DynResult<ActionType> {
+    let count = optional_attribute(node, "count")?;
+    let action = parse_sequence(node)?;
+    Ok(ActionType::Repeat {
+        count,
+        action: Box::new(action),
+    })
+}
+
+fn parse_sequence(parent: &Node) -> DynResult<ActionType> {
+    let mut actions = Vec::new();
+    for child in parent.children() {
+        if check_element_ns(&child)? {
+            match parse_action(&child) {
+                Ok(action) => actions.push(action),
+                Err(e) if skip_unknown(&*e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    if actions.is_empty() {
+        return Err("No action in sequence".into());
+    }
+    if actions.len() == 1 {
+        Ok(actions.pop().unwrap())
+    } else {
+        Ok(ActionType::Sequence(actions))
+    }
+}
+
+fn parse_parallel(parent: &Node) -> DynResult<ActionType> {
+    let mut actions = Vec::new();
+    for child in parent.children() {
+        if check_element_ns(&child)? {
+            match parse_action(&child) {
+                Ok(action) => actions.push(action),
+                Err(e) if skip_unknown(&*e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    if actions.is_empty() {
+        return Err("No action in parallel".into());
+    }
+    if actions.len() == 1 {
+        Ok(actions.pop().unwrap())
+    } else {
+        Ok(ActionType::Parallel(actions))
+    }
+}
+fn parse_set_tag(node: &Node) -> DynResult<ActionType> {
+    let tag_name = required_attribute(node, "tag")?;
+    let value = text_content(node)?;
+    Ok(ActionType::SetTag { tag_name, value })
+}
+
+fn parse_tag_or_const<T>(node: &Node) -> DynResult<TagOrConst<T>>
+where
+    T: FromStr,
+    <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
+{
+    let mut content = String::new();
+    let mut tag: Option<String> = None;
+    for child in node.children() {
+        if child.is_element() {
+            if check_element_ns(&child)? && child.tag_name().name() == "tag_value" {
+                tag = Some(text_content(&child)?);
+            } else {
+                return Err(ConfigError::new(&child, UnexpectedElement).into());
+            }
+        }
+        if child.is_text() {
+            content.push_str(child.text().unwrap());
+        }
+    }
+    let content = expand_node_vars(node, &content)?;
+    let content = content.trim();
+    if let Some(tag) = tag {
+        if !content.is_empty() {
+            return Err("set_value tag must be the only content of element".into());
+        }
+        Ok(TagOrConst::Tag(tag))
+    } else {
+        Ok(TagOrConst::Const(content.parse::<T>()?))
+    }
+}
+
+fn parse_set_volume(node: &Node) -> DynResult<ActionType> {
+    let control = required_attribute(node, "control")?;
+    let value = parse_tag_or_const(node)?;
+    let ramp = match optional_attribute::<String>(node, "ramp")? {
+        Some(s) => Some(
+            parse_duration(&s)
+                .map_err(|e| ConfigError::new(node, ParseAttribute("ramp".to_string(), e)))?,
+        ),
+        None => None,
+    };
+    Ok(ActionType::SetVolume {
+        control,
+        value,
+        ramp,
+    })
+}
+
+// Step used by volume_up and volume_down if none is given
+const DEFAULT_VOLUME_STEP: f32 = 0.05;
+
+fn parse_change_volume(node: &Node, direction: f32) -> DynResult<ActionType> {
+    let control = required_attribute(node, "control")?;
+    let step: f32 = optional_attribute(node, "step")?.unwrap_or(DEFAULT_VOLUME_STEP);
+    Ok(ActionType::ChangeVolume {
+        control,
+        step: step * direction,
+    })
+}
+
+fn parse_set_balance(node: &Node) -> DynResult<ActionType> {
+    let control = required_attribute(node, "control")?;
+    let left = optional_attribute(node, "left")?.unwrap_or(1.0);
+    let right = optional_attribute(node, "right")?.unwrap_or(1.0);
+    Ok(ActionType::SetBalance {
+        control,
+        levels: vec![left, right],
+    })
+}
+
+fn parse_ignore_alarms(node: &Node) -> DynResult<ActionType> {
+    let permanent = optional_attribute(node, "permanent")?.unwrap_or(false);
+    let filter = text_content(node)?;
+    Ok(ActionType::IgnoreAlarms { filter, permanent })
+}
+
+fn parse_restore_alarms(node: &Node) -> DynResult<ActionType> {
+    let filter = text_content(node)?;
+    Ok(ActionType::RestoreAlarms { filter })
+}
+
+fn parse_debug(node: &Node) -> DynResult<ActionType> {
+    let text = text_content(node)?;
+    Ok(ActionType::Debug(text))
+}
+
+fn parse_email(node: &Node) -> DynResult<ActionType> {
+    let to: String = required_attribute(node, "to")?;
+    let to = to
+        .split(',')
+        .map(|addr| addr.trim().to_string())
+        .filter(|addr| !addr.is_empty())
+        .collect();
+    let subject: String = required_attribute(node, "subject")?;
+    template::template_tags(&subject)
+        .map_err(|e| ConfigError::new(node, ParseAttribute("subject".to_string(), e.into())))?;
+    let body = text_content(node)?.trim().to_string();
+    template::template_tags(&body)?;
+    Ok(ActionType::Email { to, subject, body })
+}
+
+fn parse_set_gpio(node: &Node) -> DynResult<ActionType> {
+    let pin = required_attribute(node, "pin")?;
+    let value = match required_attribute::<String>(node, "value")?.as_str() {
+        "1" | "true" | "on" => true,
+        "0" | "false" | "off" => false,
+        _ => {
+            return Err(ConfigError::new(
+                node,
+                ParseAttribute("value".to_string(), "Must be 1 or 0".into()),
+            )
+            .into())
+        }
+    };
+    Ok(ActionType::SetGpio { pin, value })
+}
+
+// Programs that don't exit within this time are killed
+const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn parse_exec(node: &Node) -> DynResult<ActionType> {
+    let program = required_attribute(node, "program")?;
+    let timeout = match optional_attribute::<String>(node, "timeout")? {
+        Some(s) => parse_duration(&s)
+            .map_err(|e| ConfigError::new(node, ParseAttribute("timeout".to_string(), e)))?,
+        None => DEFAULT_EXEC_TIMEOUT,
+    };
+    let mut args = Vec::new();
+    for child in node.children() {
+        if check_element_ns(&child)? {
+            if child.tag_name().name() != "arg" {
+                return Err(ConfigError::new(&child, UnexpectedElement).into());
+            }
+            let arg = text_content(&child)?;
+            template::template_tags(&arg)?;
+            args.push(arg);
+        }
+    }
+    Ok(ActionType::Exec {
+        program,
+        args,
+        timeout,
+    })
+}
+
+fn parse_smtp(node: &Node) -> DynResult<SmtpConfig> {
+    let security = match optional_attribute::<String>(node, "security")?.as_deref() {
+        None | Some("starttls") => SmtpSecurity::StartTls,
+        Some("tls") => SmtpSecurity::Tls,
+        Some("none") => SmtpSecurity::None,
+        Some(_) => {
+            return Err(ConfigError::new(
+                node,
+                ParseAttribute(
+                    "security".to_string(),
+                    "Must be none, starttls or tls".into(),
+                ),
+            )
+            .into())
+        }
+    };
+    Ok(SmtpConfig {
+        server: required_attribute(node, "server")?,
+        port: optional_attribute(node, "port")?,
+        security,
+        from: required_attribute(node, "from")?,
+        user: optional_attribute(node, "user")?,
+        password: optional_attribute(node, "password")?,
+    })
+}
+
+fn parse_dbus(node: &Node) -> DynResult<DbusConfig> {
+    let bus = match optional_attribute::<String>(node, "bus")?.as_deref() {
+        None | Some("system") => DbusBus::System,
+        Some("session") => DbusBus::Session,
+        Some(_) => {
+            return Err(ConfigError::new(
+                node,
+                ParseAttribute("bus".to_string(), "Must be system or session".into()),
+            )
+            .into())
+        }
+    };
+    Ok(DbusConfig {
+        bus,
+        control: optional_attribute(node, "control")?.unwrap_or(true),
+    })
+}
+
+fn parse_control_api(node: &Node) -> DynResult<ControlApiConfig> {
+    let token: String = required_attribute(node, "token")?;
+    if token.is_empty() {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute("token".to_string(), "Token must not be empty".into()),
+        )
+        .into());
+    }
+    Ok(ControlApiConfig {
+        bind: optional_attribute(node, "bind")?.unwrap_or_else(|| "127.0.0.1:8080".to_string()),
+        token,
+        status_token: optional_attribute(node, "status_token")?,
+    })
+}
+
+fn parse_snapcast(node: &Node) -> DynResult<SnapcastConfig> {
+    let target: String = required_attribute(node, "target")?;
+    let target = match target.strip_prefix("tcp://") {
+        Some(addr) => SnapcastTarget::Tcp(addr.to_string()),
+        None => SnapcastTarget::Pipe(target),
+    };
+    let reconnect = match optional_attribute::<String>(node, "reconnect")? {
+        Some(interval) => parse_duration(&interval)
+            .map_err(|e| ConfigError::new(node, ParseAttribute("reconnect".to_string(), e)))?,
+        None => Duration::from_secs(5),
+    };
+    Ok(SnapcastConfig { target, reconnect })
+}
+
+fn parse_monitor_stream(node: &Node) -> DynResult<MonitorStreamConfig> {
+    Ok(MonitorStreamConfig {
+        bind: optional_attribute(node, "bind")?.unwrap_or_else(|| "127.0.0.1:8000".to_string()),
+    })
+}
+
+fn parse_metrics(node: &Node) -> DynResult<MetricsConfig> {
+    Ok(MetricsConfig {
+        bind: optional_attribute(node, "bind")?.unwrap_or_else(|| "127.0.0.1:9180".to_string()),
+    })
+}
+
+fn parse_mqtt_topic(node: &Node) -> DynResult<MqttTopicConfig> {
+    Ok(MqttTopicConfig {
+        tag: required_attribute(node, "tag")?,
+        topic: required_attribute(node, "topic")?,
+    })
+}
+
+fn parse_mqtt(parent: &Node) -> DynResult<MqttConfig> {
+    let broker: String = required_attribute(parent, "broker")?;
+    // The port is optional
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) if !host.ends_with(':') => (
+            host.trim_start_matches('[').trim_end_matches(']'),
+            port.parse().map_err(|_| {
+                ConfigError::new(
+                    parent,
+                    ParseAttribute("broker".to_string(), "Invalid port".into()),
+                )
+            })?,
+        ),
+        _ => (broker.as_str(), 1883),
+    };
+    let mut conf = MqttConfig {
+        host: host.to_string(),
+        port,
+        client_id: optional_attribute(parent, "client_id")?
+            .unwrap_or_else(|| "mtp_audioplayer".to_string()),
+        user: optional_attribute(parent, "user")?,
+        password: optional_attribute(parent, "password")?,
+        retain: optional_attribute(parent, "retain")?.unwrap_or(true),
+        publish: Vec::new(),
+        subscribe: Vec::new(),
+    };
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            let list = match child.tag_name().name() {
+                "publish" => &mut conf.publish,
+                "subscribe" => &mut conf.subscribe,
+                _ => {
+                    errors.push(&child, ConfigError::new(&child, UnexpectedElement).into());
+                    continue;
+                }
+            };
+            if let Some(topic) = errors.check(&child, parse_mqtt_topic(&child)) {
+                list.push(topic);
+            }
+        }
+    }
+    errors.into_result()?;
+    Ok(conf)
+}
+
+fn parse_tag(node: &Node) -> DynResult<TagConfig> {
+    let local = optional_attribute(node, "local")?.unwrap_or(false);
+    let persist = optional_attribute(node, "persist")?.unwrap_or(false);
+    let alias = optional_attribute(node, "alias")?;
+    let max_rate: Option<f64> = optional_attribute(node, "max_rate")?;
+    // The rate is turned into a write interval, which must be representable
+    if let Some(r) = max_rate {
+        if !(r.is_finite() && r > 0.0) || Duration::try_from_secs_f64(1.0 / r).is_err() {
+            return Err(ConfigError::new(
+                node,
+                ParseAttribute("max_rate".to_string(), "Rate out of range".into()),
+            )
+            .into());
+        }
+    }
+    let name = text_content(node)?.trim().to_string();
+    // Tags matching a pattern are added when they are first seen,
+    // so there's nothing to attach attributes to
+    if glob::is_pattern(&name) && (local || persist || alias.is_some() || max_rate.is_some()) {
+        return Err(ConfigError::new(node, UnexpectedAttribute).into());
+    }
+    Ok(TagConfig {
+        name,
+        alias,
+        local,
+        persist,
+        max_rate,
+    })
+}
+
+fn parse_derived_tag(node: &Node) -> DynResult<DerivedTagConfig> {
+    let name = required_attribute(node, "id")?;
+    let expr_str = text_content(node)?;
+    let expr = expr::parse_expr(&expr_str)
+        .map_err(|e| ConfigError::new(node, ParseExpression(e.into())))?;
+    Ok(DerivedTagConfig { name, expr })
+}
+
+fn parse_tags(parent: &Node, player: &mut PlayerConfig) -> DynResult<()> {
+    player.tag_persist_file = optional_attribute(parent, "persist_file")?;
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            match child.tag_name().name() {
+                "tag" => {
+                    if let Some(tag) = errors.check(&child, parse_tag(&child)) {
+                        player.tags.push(tag);
+                    }
+                }
+                "derived" => {
+                    if let Some(derived) = errors.check(&child, parse_derived_tag(&child)) {
+                        player.derived_tags.push(derived);
+                    }
+                }
+                _ => errors.push(&child, ConfigError::new(&child, UnexpectedElement).into()),
+            }
+        }
+    }
+    errors.into_result()
+}
+
+#[derive(Debug)]
+pub struct AlarmFilterConfig {
+    pub filter_predicate: alarm_filter::BoolOp,
+    pub tag_matching: Option<String>,
+    pub tag_ignored: Option<String>,
 }
 
-fn parse_wait_alarm(node: &Node) -> DynResult<ActionType> {
-    let filter_name = text_content(node)?;
-    let count = required_attribute::<String>(node, "count")?;
-    let condition = match count.as_str() {
-        "none" => AlarmCondition::None,
-        "any" => AlarmCondition::Any,
-        "inc" => AlarmCondition::Inc,
-        "dec" => AlarmCondition::Dec,
+impl AlarmFilterConfig {
+    /// Parse a filter using the same syntax as in the configuration file
+    pub fn new(filter: &str) -> DynResult<AlarmFilterConfig> {
+        Ok(AlarmFilterConfig {
+            filter_predicate: alarm_filter::parse_filter(filter).map_err(|e| e.to_string())?,
+            tag_matching: None,
+            tag_ignored: None,
+        })
+    }
+}
+
+fn parse_alarms(
+    parent: &Node,
+    named_filters: &mut HashMap<String, AlarmFilterConfig>,
+) -> DynResult<()> {
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            match child.tag_name().name() {
+                "filter" => {
+                    if let Some((filter_id, filter)) = errors.check(&child, parse_filter(&child)) {
+                        named_filters.insert(filter_id, filter);
+                    }
+                }
+                _ => errors.push(&child, ConfigError::new(&child, UnexpectedElement).into()),
+            }
+        }
+    }
+    errors.into_result()
+}
+
+fn parse_filter(node: &Node) -> DynResult<(String, AlarmFilterConfig)> {
+    let filter_id = required_attribute(node, "id")?;
+    let tag_matching = optional_attribute(node, "tag_matching")?;
+    let tag_ignored = optional_attribute(node, "tag_ignored")?;
+    let filter_def = text_content(node)?.trim().to_owned();
+    let op = match alarm_filter::parse_filter(&filter_def) {
+        Ok(op) => op,
+        Err(e) => {
+            let text_node = node.children().next();
+            let text_node_ref = match text_node {
+                Some(ref node) => node,
+                None => node,
+            };
+            return Err(ConfigError::new(text_node_ref, ParseFilter(e.to_string().into())).into());
+        }
+    };
+    Ok((
+        filter_id,
+        AlarmFilterConfig {
+            filter_predicate: op,
+            tag_matching,
+            tag_ignored,
+        },
+    ))
+}
+
+fn parse_state(parent: &Node) -> DynResult<StateConfig> {
+    let id = required_attribute(parent, "id")?;
+    let action = parse_child_actions(parent)?;
+    Ok(StateConfig { id, action })
+}
+
+// All actions of an element, run in parallel if there are more than one
+fn parse_child_actions(parent: &Node) -> DynResult<ActionType> {
+    let mut actions = Vec::new();
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            if let Some(action) = errors.check(&child, parse_action(&child)) {
+                actions.push(action);
+            }
+        }
+    }
+    errors.into_result()?;
+    let action = if actions.len() == 1 {
+        actions.pop().unwrap()
+    } else {
+        ActionType::Parallel(actions)
+    };
+    Ok(action)
+}
+
+// Unix signals may be written with or without the SIG prefix
+fn parse_signal(node: &Node) -> DynResult<SignalConfig> {
+    let name: String = required_attribute(node, "name")?;
+    let lower = name.to_ascii_lowercase();
+    let name = match lower.strip_prefix("sig").unwrap_or(&lower) {
+        signal @ ("usr1" | "usr2") => signal.to_string(),
+        _ if cfg!(windows) => name,
         _ => {
             return Err(ConfigError::new(
                 node,
                 ParseAttribute(
-                    "count".to_string(),
-                    "Must be one of 'none', 'any', 'inc' or 'dec'".into(),
+                    "name".to_string(),
+                    "Only usr1 and usr2 can be bound to actions".into(),
                 ),
             )
             .into())
         }
     };
-    Ok(ActionType::WaitAlarm {
-        filter_name,
-        condition,
+    let action = parse_child_actions(node)?;
+    Ok(SignalConfig { name, action })
+}
+
+fn parse_opcua_tag(node: &Node) -> DynResult<(String, String)> {
+    Ok((
+        required_attribute(node, "name")?,
+        required_attribute(node, "node")?,
+    ))
+}
+
+fn parse_opcua(parent: &Node) -> DynResult<OpcUaConfig> {
+    let url = required_attribute(parent, "url")?;
+    let publish_interval = match optional_attribute::<String>(parent, "publish_interval")? {
+        Some(interval) => parse_duration(&interval).map_err(|e| {
+            ConfigError::new(parent, ParseAttribute("publish_interval".to_string(), e))
+        })?,
+        None => Duration::from_millis(500),
+    };
+    let mut tags = Vec::new();
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            match child.tag_name().name() {
+                "tag" => {
+                    if let Some(tag) = errors.check(&child, parse_opcua_tag(&child)) {
+                        tags.push(tag);
+                    }
+                }
+                _ => errors.push(&child, ConfigError::new(&child, UnexpectedElement).into()),
+            }
+        }
+    }
+    errors.into_result()?;
+    Ok(OpcUaConfig {
+        url,
+        publish_interval,
+        tags,
+    })
+}
+
+fn parse_modbus_tag(node: &Node) -> DynResult<ModbusTagConfig> {
+    let register = match node.tag_name().name() {
+        "coil" => ModbusRegister::Coil,
+        "discrete_input" => ModbusRegister::DiscreteInput,
+        "holding_register" => ModbusRegister::HoldingRegister,
+        "input_register" => ModbusRegister::InputRegister,
+        _ => return Err(ConfigError::new(node, UnexpectedElement).into()),
+    };
+    let scale = optional_attribute(node, "scale")?.unwrap_or(1.0);
+    if scale == 0.0 {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute("scale".to_string(), "Scale must not be zero".into()),
+        )
+        .into());
+    }
+    Ok(ModbusTagConfig {
+        name: required_attribute(node, "name")?,
+        register,
+        address: required_attribute(node, "address")?,
+        scale,
+        offset: optional_attribute(node, "offset")?.unwrap_or(0.0),
+        signed: optional_attribute(node, "signed")?.unwrap_or(false),
+    })
+}
+
+fn parse_modbus(parent: &Node) -> DynResult<ModbusConfig> {
+    let address = required_attribute(parent, "address")?;
+    let unit = optional_attribute(parent, "unit")?.unwrap_or(1);
+    let scan_interval = match optional_attribute::<String>(parent, "scan_interval")? {
+        Some(interval) => parse_duration(&interval).map_err(|e| {
+            ConfigError::new(parent, ParseAttribute("scan_interval".to_string(), e))
+        })?,
+        None => Duration::from_millis(500),
+    };
+    if scan_interval.is_zero() {
+        return Err(ConfigError::new(
+            parent,
+            ParseAttribute(
+                "scan_interval".to_string(),
+                "Interval must not be zero".into(),
+            ),
+        )
+        .into());
+    }
+    let mut tags = Vec::new();
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            if let Some(tag) = errors.check(&child, parse_modbus_tag(&child)) {
+                tags.push(tag);
+            }
+        }
+    }
+    errors.into_result()?;
+    Ok(ModbusConfig {
+        address,
+        unit,
+        scan_interval,
+        tags,
+    })
+}
+
+fn parse_s7_tag(node: &Node) -> DynResult<S7TagConfig> {
+    let data_type = match node.tag_name().name() {
+        "bool" => S7Type::Bool,
+        "byte" => S7Type::Byte,
+        "word" => S7Type::Word,
+        "int" => S7Type::Int,
+        "dword" => S7Type::DWord,
+        "dint" => S7Type::DInt,
+        "real" => S7Type::Real,
+        _ => return Err(ConfigError::new(node, UnexpectedElement).into()),
+    };
+    let bit = if data_type == S7Type::Bool {
+        required_attribute(node, "bit")?
+    } else {
+        0
+    };
+    if bit > 7 {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute("bit".to_string(), "Bit must be 0 to 7".into()),
+        )
+        .into());
+    }
+    Ok(S7TagConfig {
+        name: required_attribute(node, "name")?,
+        data_type,
+        db: required_attribute(node, "db")?,
+        offset: required_attribute(node, "offset")?,
+        bit,
+    })
+}
+
+fn parse_s7(parent: &Node) -> DynResult<S7Config> {
+    let scan_interval = match optional_attribute::<String>(parent, "scan_interval")? {
+        Some(interval) => parse_duration(&interval).map_err(|e| {
+            ConfigError::new(parent, ParseAttribute("scan_interval".to_string(), e))
+        })?,
+        None => Duration::from_millis(500),
+    };
+    if scan_interval.is_zero() {
+        return Err(ConfigError::new(
+            parent,
+            ParseAttribute(
+                "scan_interval".to_string(),
+                "Interval must not be zero".into(),
+            ),
+        )
+        .into());
+    }
+    let mut tags = Vec::new();
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            if let Some(tag) = errors.check(&child, parse_s7_tag(&child)) {
+                tags.push(tag);
+            }
+        }
+    }
+    errors.into_result()?;
+    Ok(S7Config {
+        address: required_attribute(parent, "address")?,
+        rack: optional_attribute(parent, "rack")?.unwrap_or(0),
+        slot: optional_attribute(parent, "slot")?.unwrap_or(1),
+        scan_interval,
+        tags,
+    })
+}
+
+/// Parse an OID in dotted notation
+pub fn parse_oid(oid: &str) -> DynResult<Vec<u32>> {
+    let oid = oid
+        .trim()
+        .trim_start_matches('.')
+        .split('.')
+        .map(|n| n.parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| format!("Invalid OID '{}'", oid))?;
+    if oid.len() < 2 || oid[0] > 2 || (oid[0] < 2 && oid[1] >= 40) {
+        return Err("An OID must start with 0, 1 or 2 followed by at least one number".into());
+    }
+    Ok(oid)
+}
+
+fn parse_snmp(parent: &Node) -> DynResult<SnmpConfig> {
+    let target = required_attribute(parent, "target")?;
+    let community = optional_attribute(parent, "community")?.unwrap_or_else(|| "public".into());
+    let enterprise: String = required_attribute(parent, "enterprise")?;
+    let enterprise = parse_oid(&enterprise)
+        .map_err(|e| ConfigError::new(parent, ParseAttribute("enterprise".to_string(), e)))?;
+    let mut filters = Vec::new();
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            match child.tag_name().name() {
+                "filter" => {
+                    let res = required_attribute(&child, "name").map_err(|e| e.into());
+                    if let Some(filter) = errors.check(&child, res) {
+                        filters.push(filter);
+                    }
+                }
+                _ => errors.push(&child, ConfigError::new(&child, UnexpectedElement).into()),
+            }
+        }
+    }
+    errors.into_result()?;
+    Ok(SnmpConfig {
+        target,
+        community,
+        enterprise,
+        filters,
+    })
+}
+
+fn parse_gpio_input_line(node: &Node) -> DynResult<GpioInputLineConfig> {
+    Ok(GpioInputLineConfig {
+        tag: required_attribute(node, "tag")?,
+        line: required_attribute(node, "line")?,
+        active_low: optional_attribute(node, "active_low")?.unwrap_or(false),
+    })
+}
+
+fn parse_gpio_inputs(parent: &Node) -> DynResult<GpioInputConfig> {
+    let chip = optional_attribute(parent, "chip")?.unwrap_or_else(|| "/dev/gpiochip0".into());
+    let debounce = match optional_attribute::<String>(parent, "debounce")? {
+        Some(debounce) => parse_duration(&debounce)
+            .map_err(|e| ConfigError::new(parent, ParseAttribute("debounce".to_string(), e)))?,
+        None => Duration::from_millis(20),
+    };
+    let mut lines = Vec::new();
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            match child.tag_name().name() {
+                "input" => {
+                    if let Some(line) = errors.check(&child, parse_gpio_input_line(&child)) {
+                        lines.push(line);
+                    }
+                }
+                _ => errors.push(&child, ConfigError::new(&child, UnexpectedElement).into()),
+            }
+        }
+    }
+    errors.into_result()?;
+    Ok(GpioInputConfig {
+        chip,
+        debounce,
+        lines,
+    })
+}
+
+fn parse_gpio_output(node: &Node, chip: &str) -> DynResult<GpioOutputConfig> {
+    Ok(GpioOutputConfig {
+        name: required_attribute(node, "name")?,
+        chip: chip.to_string(),
+        line: required_attribute(node, "line")?,
+        active_low: optional_attribute(node, "active_low")?.unwrap_or(false),
+        follow_filter: optional_attribute(node, "follow_filter")?,
+    })
+}
+
+fn parse_gpio_outputs(parent: &Node, outputs: &mut Vec<GpioOutputConfig>) -> DynResult<()> {
+    let chip: String =
+        optional_attribute(parent, "chip")?.unwrap_or_else(|| "/dev/gpiochip0".into());
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            match child.tag_name().name() {
+                "output" => {
+                    if let Some(output) = errors.check(&child, parse_gpio_output(&child, &chip)) {
+                        outputs.push(output);
+                    }
+                }
+                _ => errors.push(&child, ConfigError::new(&child, UnexpectedElement).into()),
+            }
+        }
+    }
+    errors.into_result()
+}
+
+fn parse_state_machine(parent: &Node) -> DynResult<StateMachineConfig> {
+    let id = required_attribute(parent, "id")?;
+    let states = parse_states(parent)?;
+    Ok(StateMachineConfig {
+        id,
+        states,
+        state_tag: optional_attribute(parent, "state_tag")?,
+        command_tag: optional_attribute(parent, "command_tag")?,
+    })
+}
+
+fn parse_states(parent: &Node) -> DynResult<Vec<StateConfig>> {
+    let mut states = Vec::new();
+    let mut errors = ErrorList::default();
+    for child in parent.children() {
+        if errors.is_element(&child) {
+            match child.tag_name().name() {
+                "state" => {
+                    if let Some(state) = errors.check(&child, parse_state(&child)) {
+                        states.push(state);
+                    }
+                }
+                _ => errors.push(&child, ConfigError::new(&child, UnexpectedElement).into()),
+            }
+        }
+    }
+    errors.into_result()?;
+    Ok(states)
+}
+
+// Buffer size in frames, given directly or as a latency
+fn parse_buffer_size(node: &Node, rate: u32) -> DynResult<Option<u32>> {
+    let frames = optional_attribute::<u32>(node, "buffer")?;
+    let latency = match optional_attribute::<String>(node, "latency")? {
+        Some(l) => Some(
+            parse_duration(&l)
+                .map_err(|e| ConfigError::new(node, ParseAttribute("latency".to_string(), e)))?,
+        ),
+        None => None,
+    };
+    let frames = match (frames, latency) {
+        (Some(_), Some(_)) => {
+            return Err(ConfigError::new(
+                node,
+                ParseAttribute(
+                    "latency".to_string(),
+                    "Only one of buffer and latency can be given".into(),
+                ),
+            )
+            .into())
+        }
+        (Some(frames), None) => Some(frames),
+        (None, Some(latency)) => Some((latency.as_secs_f64() * f64::from(rate)).round() as u32),
+        (None, None) => None,
+    };
+    if frames == Some(0) {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute(
+                "buffer".to_string(),
+                "Buffer size must be at least 1".into(),
+            ),
+        )
+        .into());
+    }
+    Ok(frames)
+}
+
+// A device without id is the default device
+fn parse_playback_device(node: &Node, player: &mut PlayerConfig) -> DynResult<()> {
+    let format = optional_attribute::<String>(node, "format")?;
+    let sample_format = match format.as_deref() {
+        Some("i16") => Some(SampleFormat::I16),
+        Some("u16") => Some(SampleFormat::U16),
+        Some("f32") => Some(SampleFormat::F32),
+        Some(_) => return Err("Invalid sample format".into()),
+        None => None,
+    };
+    let rate = required_attribute(node, "rate")?;
+    let device = PlaybackDeviceConfig {
+        device: text_content(node)?,
+        rate,
+        channels: required_attribute(node, "channels")?,
+        sample_format: sample_format.unwrap_or(SampleFormat::I16),
+        buffer_size: parse_buffer_size(node, rate)?,
+    };
+    match optional_attribute::<String>(node, "id")? {
+        Some(id) => {
+            if player.playback_devices.contains_key(&id) {
+                return Err(ConfigError::new(
+                    node,
+                    ParseAttribute("id".to_string(), "Duplicate device id".into()),
+                )
+                .into());
+            }
+            player.playback_devices.insert(id, device);
+        }
+        None => {
+            player.playback_device = device.device;
+            player.rate = device.rate;
+            player.channels = device.channels;
+            if let Some(format) = sample_format {
+                player.sample_format = format;
+            }
+            player.buffer_size = device.buffer_size;
+        }
+    }
+    Ok(())
+}
+
+// Parse a mixer element name like "Speaker" or "Speaker,1"
+fn parse_mixer_element(node: &Node, element: &str) -> Result<(String, u32), ConfigError> {
+    match element.rsplit_once(',') {
+        Some((name, index)) => match index.trim().parse() {
+            Ok(index) => Ok((name.to_string(), index)),
+            Err(e) => Err(ConfigError::new(
+                node,
+                ParseAttribute("control".to_string(), Box::new(e)),
+            )),
+        },
+        None => Ok((element.to_string(), 0)),
+    }
+}
+
+fn parse_time_of_day(node: &Node, name: &str) -> Result<NaiveTime, ConfigError> {
+    let time: String = required_attribute(node, name)?;
+    NaiveTime::parse_from_str(&time, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(&time, "%H:%M:%S"))
+        .map_err(|e| ConfigError::new(node, ParseAttribute(name.to_string(), e.into())))
+}
+
+fn parse_volume_schedule(node: &Node) -> Result<VolumeSchedule, ConfigError> {
+    Ok(VolumeSchedule {
+        from: parse_time_of_day(node, "from")?,
+        to: parse_time_of_day(node, "to")?,
+        volume: required_attribute(node, "volume")?,
     })
 }
 
-fn parse_goto(node: &Node) -> DynResult<ActionType> {
-    let state_name = text_content(node)?;
-    Ok(ActionType::Goto(state_name))
+fn parse_volume_control(node: &Node, controls: &mut Vec<VolumeConfig>) -> DynResult<()> {
+    let id = required_attribute(node, "id")?;
+    // The device name may be mixed with schedule elements
+    let mut device = String::new();
+    let mut schedule = Vec::new();
+    for child in node.children() {
+        if child.is_element() {
+            check_element_ns(&child)?;
+            match child.tag_name().name() {
+                "schedule" => schedule.push(parse_volume_schedule(&child)?),
+                _ => return Err(ConfigError::new(&child, UnexpectedElement).into()),
+            }
+        } else if child.is_text() {
+            device.push_str(child.text().unwrap());
+        }
+    }
+    let device = expand_node_vars(node, device.trim())?;
+    let initial_volume = optional_attribute::<f32>(node, "initial")?;
+    let tag_level = optional_attribute(node, "tag_level")?;
+    let control = optional_attribute::<String>(node, "control")?
+        .map(|c| parse_mixer_element(node, &c))
+        .transpose()?;
+    let scale = match optional_attribute::<String>(node, "scale")?.as_deref() {
+        None | Some("linear") => VolumeScale::Linear,
+        Some("db") => VolumeScale::Db,
+        Some(_) => {
+            return Err(ConfigError::new(
+                node,
+                ParseAttribute("scale".to_string(), "Must be 'linear' or 'db'".into()),
+            )
+            .into())
+        }
+    };
+    let min_volume = optional_attribute::<f32>(node, "min")?.unwrap_or(0.0);
+    let max_volume = optional_attribute::<f32>(node, "max")?.unwrap_or(1.0);
+    if !(0.0..=1.0).contains(&min_volume) || !(0.0..=1.0).contains(&max_volume) {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute(
+                "min".to_string(),
+                "Volume limits must be in the range 0.0 to 1.0".into(),
+            ),
+        )
+        .into());
+    }
+    if min_volume > max_volume {
+        return Err(ConfigError::new(
+            node,
+            ParseAttribute("min".to_string(), "Must not be greater than max".into()),
+        )
+        .into());
+    }
+    let control = VolumeConfig {
+        id,
+        device,
+        initial_volume,
+        tag_level,
+        control,
+        scale,
+        schedule,
+        min_volume,
+        max_volume,
+    };
+    controls.push(control);
+    Ok(())
+}
+
+fn check_element_ns(node: &Node) -> Result<bool, ConfigError> {
+    if node.is_element() {
+        if node.tag_name().namespace() != Some(NS) {
+            return Err(ConfigError::new(node, WrongNamespace));
+        }
+        return Ok(true);
+    } else if node.is_text() {
+        if let Some(text) = node.text() {
+            // Don't allow non-whitespace around elements
+            if text.find(|c: char| !c.is_whitespace()).is_some() {
+                return Err(ConfigError::new(node, UnexpectedText));
+            }
+        }
+    }
+    Ok(false)
+}
+
+// Maximum nesting of included files
+const MAX_INCLUDE_DEPTH: u32 = 16;
+
+pub(crate) fn new_player_config() -> PlayerConfig {
+    PlayerConfig {
+        bind: "/tmp/siemens/automation/HmiRunTime".to_string(),
+        playback_device: "".to_string(),
+        rate: 44100,
+        channels: 2,
+        sample_format: SampleFormat::I16,
+        buffer_size: None,
+        playback_devices: HashMap::new(),
+        clip_root: String::new(),
+        clips: HashMap::new(),
+        clip_devices: HashMap::new(),
+        clip_dirs: Vec::new(),
+        clip_profiles: HashMap::new(),
+        tags: Vec::new(),
+        derived_tags: Vec::new(),
+        tag_persist_file: None,
+        syslog: None,
+        audit_log: None,
+        log_level_tag: None,
+        reload_tag: None,
+        heartbeat: None,
+        shutdown_drain: Duration::ZERO,
+        shutdown_fade: Duration::ZERO,
+        reconnect: ReconnectConfig::default(),
+        state_change_limit: EventLimitConfig::state_change_default(),
+        repeat_limit: EventLimitConfig::repeat_default(),
+        named_alarm_filters: HashMap::new(),
+        alarm_persist_file: None,
+        state_machines: Vec::new(),
+        signals: Vec::new(),
+        tag_sources: Vec::new(),
+        gpio_outputs: Vec::new(),
+        snmp: None,
+        smtp: None,
+        mqtt: None,
+        dbus: None,
+        control_api: None,
+        snapcast: None,
+        monitor_stream: None,
+        metrics: None,
+        volume_config: Vec::new(),
+    }
 }
 
-fn parse_repeat(node: &Node) -> DynResult<ActionType> {
-    let count = optional_attribute(node, "count")?;
-    let action = parse_sequence(node)?;
-    Ok(ActionType::Repeat {
-        count,
-        action: Box::new(action),
-    })
+fn parse_define(node: &Node) -> DynResult<()> {
+    let name: String = required_attribute(node, "name")?;
+    let value = text_content(node)?;
+    define_var(name, value.trim().to_string());
+    Ok(())
 }
 
-fn parse_sequence(parent: &Node) -> DynResult<ActionType> {
-    let mut actions = Vec::new();
-    for child in parent.children() {
-        if check_element_ns(&child)? {
-            let action = parse_action(&child)?;
-            actions.push(action);
+fn host_name() -> Option<String> {
+    #[cfg(windows)]
+    let name = std::env::var("COMPUTERNAME").ok();
+    #[cfg(not(windows))]
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .ok();
+    name.map(|n| n.trim().to_string())
+}
+
+// True if all conditions of a when element are fulfilled. The host
+// attribute is a glob pattern matched against the host name. The env
+// attribute is either VAR, which must be defined, or VAR=value.
+fn when_condition(node: &Node) -> Result<bool, ConfigError> {
+    let mut has_condition = false;
+    if let Some(pattern) = optional_attribute::<String>(node, "host")? {
+        has_condition = true;
+        match host_name() {
+            Some(host) if glob::glob_match(&pattern, &host) => {}
+            _ => return Ok(false),
         }
     }
-    if actions.is_empty() {
-        return Err("No action in sequence".into());
+    if let Some(env) = optional_attribute::<String>(node, "env")? {
+        has_condition = true;
+        let matches = match env.split_once('=') {
+            Some((name, value)) => lookup_var(name).as_deref() == Some(value),
+            None => lookup_var(&env).is_some(),
+        };
+        if !matches {
+            return Ok(false);
+        }
     }
-    if actions.len() == 1 {
-        Ok(actions.pop().unwrap())
-    } else {
-        Ok(ActionType::Sequence(actions))
+    if !has_condition {
+        return Err(ConfigError::new(
+            node,
+            MissingAttribute("host' or 'env".to_string()),
+        ));
     }
+    Ok(true)
 }
 
-fn parse_parallel(parent: &Node) -> DynResult<ActionType> {
-    let mut actions = Vec::new();
+// Defines are available as ${NAME} in the rest of the configuration
+fn parse_defines(parent: &Node) -> DynResult<()> {
+    let mut errors = ErrorList::default();
     for child in parent.children() {
-        if check_element_ns(&child)? {
-            let action = parse_action(&child)?;
-            actions.push(action);
+        if errors.is_element(&child) {
+            let res = match child.tag_name().name() {
+                "define" => parse_define(&child),
+                _ => Err(ConfigError::new(&child, UnexpectedElement).into()),
+            };
+            errors.check(&child, res);
         }
     }
-    if actions.is_empty() {
-        return Err("No action in parallel".into());
-    }
-    if actions.len() == 1 {
-        Ok(actions.pop().unwrap())
-    } else {
-        Ok(ActionType::Parallel(actions))
-    }
+    errors.into_result()
 }
-fn parse_set_tag(node: &Node) -> DynResult<ActionType> {
-    let tag_name = required_attribute(node, "tag")?;
-    let value = text_content(node)?;
-    Ok(ActionType::SetTag { tag_name, value })
+
+/// A state machine with parameters that can be instantiated several times
+struct StateMachineTemplate {
+    params: Vec<String>,
+    // XML source of the template element
+    source: String,
 }
 
-fn parse_tag_or_const<T>(node: &Node) -> DynResult<TagOrConst<T>>
-where
-    T: FromStr,
-    <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
-{
-    let mut content = String::new();
-    let mut tag: Option<String> = None;
-    for child in node.children() {
-        if child.is_element() {
-            if check_element_ns(&child)? && child.tag_name().name() == "tag_value" {
-                tag = Some(text_content(&child)?);
-            } else {
-                return Err(ConfigError::new(&child, UnexpectedElement).into());
-            }
+// State kept while parsing a configuration and its included files
+#[derive(Default)]
+struct ParseContext {
+    include_depth: u32,
+    templates: HashMap<String, StateMachineTemplate>,
+}
+
+fn parse_template(node: &Node, input: &str, ctxt: &mut ParseContext) -> DynResult<()> {
+    let id: String = required_attribute(node, "id")?;
+    let params = optional_attribute::<String>(node, "params")?.unwrap_or_default();
+    let template = StateMachineTemplate {
+        params: params.split_whitespace().map(str::to_string).collect(),
+        source: input[node.range()].to_string(),
+    };
+    ctxt.templates.insert(id, template);
+    Ok(())
+}
+
+// Parse a state machine element with a template attribute. All other
+// attributes are template parameters.
+fn instantiate_template(node: &Node, ctxt: &ParseContext) -> DynResult<StateMachineConfig> {
+    let id: String = required_attribute(node, "id")?;
+    let template_id: String = required_attribute(node, "template")?;
+    let template = ctxt.templates.get(&template_id).ok_or_else(|| {
+        ConfigError::new(
+            node,
+            ParseAttribute(
+                "template".to_string(),
+                format!("No state machine template named '{}'", template_id).into(),
+            ),
+        )
+    })?;
+    if node.has_children() && !text_content(node)?.trim().is_empty() {
+        return Err(ConfigError::new(node, UnexpectedText).into());
+    }
+    let mut vars = HashMap::new();
+    vars.insert("id".to_string(), id.clone());
+    for attr in node.attributes() {
+        if ["id", "template", "state_tag", "command_tag"].contains(&attr.name()) {
+            continue;
         }
-        if child.is_text() {
-            content.push_str(child.text().unwrap());
+        if !template.params.iter().any(|p| p == attr.name()) {
+            return Err(ConfigError::new(node, UnexpectedAttribute).into());
         }
+        vars.insert(
+            attr.name().to_string(),
+            expand_node_vars(node, attr.value())?,
+        );
     }
-    let content = content.trim();
-    if let Some(tag) = tag {
-        if !content.is_empty() {
-            return Err("set_value tag must be the only content of element".into());
+    for param in &template.params {
+        if !vars.contains_key(param) {
+            return Err(ConfigError::new(node, MissingAttribute(param.clone())).into());
         }
-        Ok(TagOrConst::Tag(tag))
-    } else {
-        Ok(TagOrConst::Const(content.parse::<T>()?))
     }
+    let source = format!(
+        "<audioplayer xmlns=\"{}\">{}</audioplayer>",
+        NS, template.source
+    );
+    let document = Document::parse(&source)?;
+    let template_node = document
+        .root_element()
+        .first_element_child()
+        .ok_or("Empty template")?;
+    let states = with_vars(vars, || parse_states(&template_node))
+        .map_err(|e| format!("In template '{}': {}", template_id, e))?;
+    Ok(StateMachineConfig {
+        id,
+        states,
+        state_tag: optional_attribute(node, "state_tag")?,
+        command_tag: optional_attribute(node, "command_tag")?,
+    })
 }
 
-fn parse_set_volume(node: &Node) -> DynResult<ActionType> {
-    let control = required_attribute(node, "control")?;
-    let value = parse_tag_or_const(node)?;
-    Ok(ActionType::SetVolume { control, value })
+fn parse_include(
+    node: &Node,
+    base_dir: &Path,
+    player: &mut PlayerConfig,
+    ctxt: &mut ParseContext,
+) -> DynResult<()> {
+    if ctxt.include_depth >= MAX_INCLUDE_DEPTH {
+        return Err("Includes nested too deep".into());
+    }
+    let href: String = required_attribute(node, "href")?;
+    let path = base_dir.join(href);
+    let mut conf = new_player_config();
+    ctxt.include_depth += 1;
+    let res = read_file_into(&path, &mut conf, ctxt);
+    ctxt.include_depth -= 1;
+    res.map_err(|e| ConfigErrors(file_errors(&path, e)))?;
+    // The clip root of the including file is applied to the clip paths
+    // later, so make them absolute
+    let dir = std::env::current_dir()?.join(path.parent().unwrap_or_else(|| Path::new("")));
+    let duplicates = merge_config(player, conf, &dir);
+    if duplicates.is_empty() {
+        Ok(())
+    } else {
+        let path = path.to_string_lossy();
+        Err(ConfigErrors(
+            duplicates
+                .into_iter()
+                .map(|dup| format!("{}: {}", path, dup).into())
+                .collect(),
+        )
+        .into())
+    }
 }
 
-fn parse_ignore_alarms(node: &Node) -> DynResult<ActionType> {
-    let permanent = optional_attribute(node, "permanent")?.unwrap_or(false);
-    let filter = text_content(node)?;
-    Ok(ActionType::IgnoreAlarms { filter, permanent })
+// Prefix every error with the name of the file
+fn file_errors(
+    path: &Path,
+    err: Box<dyn Error + Send + Sync>,
+) -> Vec<Box<dyn Error + Send + Sync>> {
+    let path = path.to_string_lossy();
+    let errs = match err.downcast::<ConfigErrors>() {
+        Ok(errs) => errs.0,
+        Err(e) => vec![e],
+    };
+    errs.into_iter()
+        .map(|e| format!("{}: {}", path, e).into())
+        .collect()
 }
 
-fn parse_restore_alarms(node: &Node) -> DynResult<ActionType> {
-    let filter = text_content(node)?;
-    Ok(ActionType::RestoreAlarms { filter })
-}
+// Parse a document and add the result to player. Included files are
+// resolved relative to base_dir.
+fn read_str_into(
+    input: &str,
+    base_dir: &Path,
+    player: &mut PlayerConfig,
+    ctxt: &mut ParseContext,
+) -> DynResult<()> {
+    let document = Document::parse(input)?;
 
-fn parse_debug(node: &Node) -> DynResult<ActionType> {
-    let text = text_content(node)?;
-    Ok(ActionType::Debug(text))
+    let root = document.root_element();
+    if !root.has_tag_name((NS, "audioplayer")) {
+        return Err("The root node must be 'audioplayer'".into());
+    }
+    // The mode set in a file also applies to the files it includes
+    let mode = match optional_attribute::<String>(&root, "parse_mode")?.as_deref() {
+        None => None,
+        Some("strict") => Some(ParseMode::Strict),
+        Some("lenient") => Some(ParseMode::Lenient),
+        Some(_) => {
+            return Err(ConfigError::new(
+                &root,
+                ParseAttribute(
+                    "parse_mode".to_string(),
+                    "Must be 'strict' or 'lenient'".into(),
+                ),
+            )
+            .into())
+        }
+    };
+    if let Some(mode) = mode {
+        return with_parse_mode(mode, || {
+            read_root_children(&root, input, base_dir, player, ctxt)
+        });
+    }
+    read_root_children(&root, input, base_dir, player, ctxt)
 }
 
-fn parse_tag(node: &Node) -> DynResult<String> {
-    Ok(text_content(node)?)
+fn read_root_children(
+    root: &Node,
+    input: &str,
+    base_dir: &Path,
+    player: &mut PlayerConfig,
+    ctxt: &mut ParseContext,
+) -> DynResult<()> {
+    // Keep parsing after errors so that all errors are reported at once
+    let mut errors = ErrorList::default();
+    for node in root.children() {
+        if errors.is_element(&node) {
+            let res = parse_top_element(&node, input, base_dir, player, ctxt);
+            errors.check(&node, res);
+        }
+    }
+    errors.into_result()
 }
 
-fn parse_tags(parent: &Node) -> DynResult<Vec<String>> {
-    let mut tags = Vec::new();
-    for child in parent.children() {
-        if check_element_ns(&child)? {
-            match child.tag_name().name() {
-                "tag" => {
-                    let tag_name = parse_tag(&child)?;
-                    tags.push(tag_name);
+fn parse_top_element(
+    node: &Node,
+    input: &str,
+    base_dir: &Path,
+    player: &mut PlayerConfig,
+    ctxt: &mut ParseContext,
+) -> DynResult<()> {
+    match node.tag_name().name() {
+        "include" => {
+            parse_include(node, base_dir, player, ctxt)?;
+        }
+        "defines" => {
+            parse_defines(node)?;
+        }
+        "when" => {
+            if when_condition(node)? {
+                let mut errors = ErrorList::default();
+                for child in node.children() {
+                    if errors.is_element(&child) {
+                        let res = parse_top_element(&child, input, base_dir, player, ctxt);
+                        errors.check(&child, res);
+                    }
                 }
-                _ => return Err(ConfigError::new(&child, UnexpectedElement).into()),
+                errors.into_result()?;
             }
         }
+        "bind" => {
+            player.bind = parse_bind(node)?;
+        }
+        "playback_device" => {
+            parse_playback_device(node, player)?;
+        }
+        "clips" => {
+            player.clip_root = required_attribute(node, "path")?;
+            parse_clips(node, player)?;
+        }
+        "tags" => {
+            parse_tags(node, player)?;
+        }
+        "heartbeat" => {
+            player.heartbeat = Some(parse_heartbeat(node)?);
+        }
+        "log_level" => {
+            player.log_level_tag = Some(required_attribute(node, "tag")?);
+        }
+        "reload" => {
+            player.reload_tag = Some(required_attribute(node, "tag")?);
+        }
+        "audit_log" => {
+            player.audit_log = Some(parse_audit_log(node)?);
+        }
+        "syslog" => {
+            player.syslog = Some(text_content(node)?.trim().to_string());
+        }
+        "shutdown" => {
+            (player.shutdown_drain, player.shutdown_fade) = parse_shutdown(node)?;
+        }
+        "reconnect" => {
+            player.reconnect = parse_reconnect(node)?;
+        }
+        "state_change_limit" => {
+            player.state_change_limit =
+                parse_event_limit(node, EventLimitConfig::state_change_default())?;
+        }
+        "repeat_limit" => {
+            player.repeat_limit = parse_event_limit(node, EventLimitConfig::repeat_default())?;
+        }
+        "alarms" => {
+            player.alarm_persist_file = optional_attribute(node, "persist_file")?;
+            parse_alarms(node, &mut player.named_alarm_filters)?;
+        }
+        "state_machine_template" => {
+            parse_template(node, input, ctxt)?;
+        }
+        "state_machine" => {
+            let machine = if node.has_attribute("template") {
+                instantiate_template(node, ctxt)?
+            } else {
+                parse_state_machine(node)?
+            };
+            player.state_machines.push(machine);
+        }
+        "signal" => {
+            player.signals.push(parse_signal(node)?);
+        }
+        "opcua" => {
+            let conf = parse_opcua(node)?;
+            player.tag_sources.push(TagSourceConfig::OpcUa(conf));
+        }
+        "modbus" => {
+            let conf = parse_modbus(node)?;
+            player.tag_sources.push(TagSourceConfig::Modbus(conf));
+        }
+        "s7" => {
+            let conf = parse_s7(node)?;
+            player.tag_sources.push(TagSourceConfig::S7(conf));
+        }
+        "gpio_inputs" => {
+            let conf = parse_gpio_inputs(node)?;
+            player.tag_sources.push(TagSourceConfig::GpioInput(conf));
+        }
+        "gpio_outputs" => {
+            parse_gpio_outputs(node, &mut player.gpio_outputs)?;
+        }
+        "snmp" => {
+            player.snmp = Some(parse_snmp(node)?);
+        }
+        "smtp" => {
+            player.smtp = Some(parse_smtp(node)?);
+        }
+        "mqtt" => {
+            player.mqtt = Some(parse_mqtt(node)?);
+        }
+        "dbus" => {
+            player.dbus = Some(parse_dbus(node)?);
+        }
+        "control_api" => {
+            player.control_api = Some(parse_control_api(node)?);
+        }
+        "snapcast" => {
+            player.snapcast = Some(parse_snapcast(node)?);
+        }
+        "monitor_stream" => {
+            player.monitor_stream = Some(parse_monitor_stream(node)?);
+        }
+        "metrics" => {
+            player.metrics = Some(parse_metrics(node)?);
+        }
+        "volume_control" => {
+            parse_volume_control(node, &mut player.volume_config)?;
+        }
+        _ => return Err(ConfigError::new(node, UnexpectedElement).into()),
     }
-    Ok(tags)
+    Ok(())
 }
 
-#[derive(Debug)]
-pub struct AlarmFilterConfig {
-    pub filter_predicate: alarm_filter::BoolOp,
-    pub tag_matching: Option<String>,
-    pub tag_ignored: Option<String>,
+/// XML schema describing the configuration file format.
+/// Can be used by editors to validate configurations.
+pub fn xml_schema() -> &'static str {
+    include_str!("../test/mtp_audioplayer.xsd")
 }
 
-fn parse_alarms(
-    parent: &Node,
-    named_filters: &mut HashMap<String, AlarmFilterConfig>,
+fn read_file_into(
+    path: &Path,
+    player: &mut PlayerConfig,
+    ctxt: &mut ParseContext,
 ) -> DynResult<()> {
-    for child in parent.children() {
-        if check_element_ns(&child)? {
-            match child.tag_name().name() {
-                "filter" => {
-                    let filter_id = required_attribute(&child, "id")?;
-                    let tag_matching = optional_attribute(&child, "tag_matching")?;
-                    let tag_ignored = optional_attribute(&child, "tag_ignored")?;
-                    let filter_def = text_content(&child)?.trim().to_owned();
-                    let op = match alarm_filter::parse_filter(&filter_def) {
-                        Ok(op) => op,
-                        Err(e) => {
-                            let text_node = child.children().next();
-                            let text_node_ref = match text_node {
-                                Some(ref node) => node,
-                                None => &child,
-                            };
-                            return Err(ConfigError::new(
-                                text_node_ref,
-                                ParseFilter(e.to_string().into()),
-                            )
-                            .into());
-                        }
-                    };
-                    named_filters.insert(
-                        filter_id,
-                        AlarmFilterConfig {
-                            filter_predicate: op,
-                            tag_matching,
-                            tag_ignored,
-                        },
-                    );
-                }
-                _ => {
-                    return Err(ConfigError::new(&child, UnexpectedElement).into());
-                }
-            }
-        }
+    let mut file = File::open(path)?;
+    let mut file_content = String::new();
+    file.read_to_string(&mut file_content)?;
+    let extension = path.extension().and_then(|e| e.to_str());
+    // The old JSON format is translated directly
+    if let Some("conf") | Some("json") = extension {
+        return crate::legacy_config::read_str_into(&file_content, player);
+    }
+    // Other formats are converted to XML
+    let converted: Option<crate::config_tree::XmlConfig> = match extension {
+        #[cfg(feature = "toml")]
+        Some("toml") => Some(crate::config_tree::toml_to_xml(&file_content)?),
+        #[cfg(feature = "serde_yaml")]
+        Some("yaml") | Some("yml") => Some(crate::config_tree::yaml_to_xml(&file_content)?),
+        _ => None,
+    };
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    // Included XML files have positions of their own
+    match converted {
+        Some(conf) => with_source_locations(Some(conf.locations), || {
+            read_str_into(&conf.xml, base_dir, player, ctxt)
+        }),
+        None => with_source_locations(None, || {
+            read_str_into(&file_content, base_dir, player, ctxt)
+        }),
     }
-
-    Ok(())
 }
 
-fn parse_state(parent: &Node) -> DynResult<StateConfig> {
-    let id = required_attribute(parent, "id")?;
-    let mut actions = Vec::new();
-    for child in parent.children() {
-        if check_element_ns(&child)? {
-            let action = parse_action(&child)?;
-            actions.push(action);
+// Add the configuration read from an included file or a file in a
+// configuration directory. The clip root of conf is relative to dir.
+// Returns an error message for every id that is already defined.
+fn merge_config(player: &mut PlayerConfig, conf: PlayerConfig, dir: &Path) -> Vec<String> {
+    let default = new_player_config();
+    let mut duplicates = Vec::new();
+    if conf.bind != default.bind {
+        player.bind = conf.bind;
+    }
+    if !conf.playback_device.is_empty()
+        || conf.rate != default.rate
+        || conf.channels != default.channels
+        || conf.sample_format != default.sample_format
+        || conf.buffer_size != default.buffer_size
+    {
+        player.playback_device = conf.playback_device;
+        player.rate = conf.rate;
+        player.channels = conf.channels;
+        player.sample_format = conf.sample_format;
+        player.buffer_size = conf.buffer_size;
+    }
+    for (id, device) in conf.playback_devices {
+        match player.playback_devices.entry(id) {
+            Entry::Occupied(e) => {
+                duplicates.push(format!("Playback device '{}' is already defined", e.key()))
+            }
+            Entry::Vacant(e) => {
+                e.insert(device);
+            }
+        }
+    }
+    player.clip_devices.extend(conf.clip_devices);
+    // Each file may have its own clip root so make clip paths
+    // relative to the directory instead
+    let clip_root = dir.join(&conf.clip_root);
+    for (id, mut clip) in conf.clips {
+        if let ClipType::File { file_name, .. } = &mut clip {
+            *file_name = clip_root
+                .join(file_name.as_str())
+                .to_string_lossy()
+                .into_owned();
+        }
+        match player.clips.entry(id) {
+            Entry::Occupied(e) => duplicates.push(format!("Clip '{}' is already defined", e.key())),
+            Entry::Vacant(e) => {
+                e.insert(clip);
+            }
+        }
+    }
+    for mut dir in conf.clip_dirs {
+        dir.path = clip_root.join(&dir.path).to_string_lossy().into_owned();
+        player.clip_dirs.push(dir);
+    }
+    for (id, profile) in conf.clip_profiles {
+        match player.clip_profiles.entry(id) {
+            Entry::Occupied(e) => {
+                duplicates.push(format!("Clip profile '{}' is already defined", e.key()))
+            }
+            Entry::Vacant(e) => {
+                e.insert(profile);
+            }
+        }
+    }
+    for tag in conf.tags {
+        if player
+            .tags
+            .iter()
+            .any(|t| t.internal_name() == tag.internal_name())
+        {
+            duplicates.push(format!("Tag '{}' is already defined", tag.internal_name()));
+        } else {
+            player.tags.push(tag);
+        }
+    }
+    for tag in conf.derived_tags {
+        if player.derived_tags.iter().any(|t| t.name == tag.name) {
+            duplicates.push(format!("Derived tag '{}' is already defined", tag.name));
+        } else {
+            player.derived_tags.push(tag);
+        }
+    }
+    if conf.tag_persist_file.is_some() {
+        player.tag_persist_file = conf.tag_persist_file;
+    }
+    if conf.alarm_persist_file.is_some() {
+        player.alarm_persist_file = conf.alarm_persist_file;
+    }
+    if conf.syslog.is_some() {
+        player.syslog = conf.syslog;
+    }
+    if conf.audit_log.is_some() {
+        player.audit_log = conf.audit_log;
+    }
+    if conf.log_level_tag.is_some() {
+        player.log_level_tag = conf.log_level_tag;
+    }
+    if conf.reload_tag.is_some() {
+        player.reload_tag = conf.reload_tag;
+    }
+    if conf.heartbeat.is_some() {
+        player.heartbeat = conf.heartbeat;
+    }
+    for output in conf.gpio_outputs {
+        if player.gpio_outputs.iter().any(|o| o.name == output.name) {
+            duplicates.push(format!("GPIO output '{}' is already defined", output.name));
+        } else {
+            player.gpio_outputs.push(output);
+        }
+    }
+    if conf.snmp.is_some() {
+        player.snmp = conf.snmp;
+    }
+    if conf.smtp.is_some() {
+        player.smtp = conf.smtp;
+    }
+    if conf.mqtt.is_some() {
+        player.mqtt = conf.mqtt;
+    }
+    if conf.dbus.is_some() {
+        player.dbus = conf.dbus;
+    }
+    if conf.control_api.is_some() {
+        player.control_api = conf.control_api;
+    }
+    if conf.snapcast.is_some() {
+        player.snapcast = conf.snapcast;
+    }
+    if conf.monitor_stream.is_some() {
+        player.monitor_stream = conf.monitor_stream;
+    }
+    if conf.metrics.is_some() {
+        player.metrics = conf.metrics;
+    }
+    if !conf.shutdown_drain.is_zero() {
+        player.shutdown_drain = conf.shutdown_drain;
+    }
+    if !conf.shutdown_fade.is_zero() {
+        player.shutdown_fade = conf.shutdown_fade;
+    }
+    if conf.reconnect != default.reconnect {
+        player.reconnect = conf.reconnect;
+    }
+    if conf.state_change_limit != default.state_change_limit {
+        player.state_change_limit = conf.state_change_limit;
+    }
+    if conf.repeat_limit != default.repeat_limit {
+        player.repeat_limit = conf.repeat_limit;
+    }
+    for (id, filter) in conf.named_alarm_filters {
+        match player.named_alarm_filters.entry(id) {
+            Entry::Occupied(e) => {
+                duplicates.push(format!("Alarm filter '{}' is already defined", e.key()))
+            }
+            Entry::Vacant(e) => {
+                e.insert(filter);
+            }
+        }
+    }
+    for machine in conf.state_machines {
+        if player.state_machines.iter().any(|m| m.id == machine.id) {
+            duplicates.push(format!("State machine '{}' is already defined", machine.id));
+        } else {
+            player.state_machines.push(machine);
+        }
+    }
+    for signal in conf.signals {
+        if player.signals.iter().any(|s| s.name == signal.name) {
+            duplicates.push(format!("Signal '{}' is already bound", signal.name));
+        } else {
+            player.signals.push(signal);
+        }
+    }
+    for source in conf.tag_sources {
+        for tag in source.tag_names() {
+            if player
+                .tag_sources
+                .iter()
+                .any(|s| s.tag_names().contains(&tag))
+            {
+                duplicates.push(format!("Tag '{}' already has a source", tag));
+            }
+        }
+        player.tag_sources.push(source);
+    }
+    for control in conf.volume_config {
+        if player.volume_config.iter().any(|c| c.id == control.id) {
+            duplicates.push(format!(
+                "Volume control '{}' is already defined",
+                control.id
+            ));
+        } else {
+            player.volume_config.push(control);
         }
     }
-    let action = if actions.len() == 1 {
-        actions.pop().unwrap()
-    } else {
-        ActionType::Parallel(actions)
-    };
-
-    Ok(StateConfig { id, action })
+    duplicates
 }
 
-fn parse_state_machine(parent: &Node) -> DynResult<StateMachineConfig> {
-    let id = required_attribute(parent, "id")?;
-    let mut states = Vec::new();
-    for child in parent.children() {
-        if check_element_ns(&child)? {
-            match child.tag_name().name() {
-                "state" => {
-                    let state = parse_state(&child)?;
-                    states.push(state);
+// Read all XML files in a directory in alphabetical order and merge them
+fn read_dir_into(dir: &Path, player: &mut PlayerConfig, ctxt: &mut ParseContext) -> DynResult<()> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().and_then(|e| e.to_str()) == Some("xml") {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        return Err(format!("No configuration files in {}", dir.to_string_lossy()).into());
+    }
+    paths.sort();
+    let mut errors = Vec::new();
+    for path in paths {
+        let mut conf = new_player_config();
+        match read_file_into(&path, &mut conf, ctxt) {
+            Ok(()) => {
+                for dup in merge_config(player, conf, Path::new("")) {
+                    errors.push(format!("{}: {}", path.to_string_lossy(), dup).into());
                 }
-                _ => return Err(ConfigError::new(&child, UnexpectedElement).into()),
             }
+            Err(e) => errors.extend(file_errors(&path, e)),
         }
     }
-    Ok(StateMachineConfig { id, states })
-}
-
-fn parse_playback_device(node: &Node, player: &mut PlayerConfig) -> DynResult<()> {
-    player.rate = required_attribute(node, "rate")?;
-    player.channels = required_attribute(node, "channels")?;
-    let format = optional_attribute::<String>(node, "format")?;
-    match format.as_deref() {
-        Some("i16") => player.sample_format = SampleFormat::I16,
-        Some("u16") => player.sample_format = SampleFormat::U16,
-        Some("f32") => player.sample_format = SampleFormat::F32,
-        Some(_) => return Err("Invalid sample format".into()),
-        None => {}
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.pop().unwrap()),
+        _ => Err(ConfigErrors(errors).into()),
     }
-
-    player.playback_device = text_content(node)?;
-
-    Ok(())
-}
-
-fn parse_volume_control(node: &Node, controls: &mut Vec<VolumeConfig>) -> DynResult<()> {
-    let id = required_attribute(node, "id")?;
-    let device = text_content(node)?;
-    let initial_volume = optional_attribute::<f32>(node, "initial")?;
-    let control = VolumeConfig {
-        id,
-        device,
-        initial_volume,
-    };
-    controls.push(control);
-    Ok(())
 }
 
-fn check_element_ns(node: &Node) -> Result<bool, ConfigError> {
-    if node.is_element() {
-        if node.tag_name().namespace() != Some(NS) {
-            return Err(ConfigError::new(node, WrongNamespace));
-        }
-        return Ok(true);
-    } else if node.is_text() {
-        if let Some(text) = node.text() {
-            // Don't allow non-whitespace around elements
-            if text.find(|c: char| !c.is_whitespace()).is_some() {
-                return Err(ConfigError::new(node, UnexpectedText));
-            }
-        }
+/// Directory used as the base for relative paths in a configuration
+pub fn base_dir(path: &Path) -> &Path {
+    if path.is_dir() {
+        path
+    } else {
+        path.parent().unwrap_or_else(|| Path::new(""))
     }
-    Ok(false)
 }
 
+/// Parse a configuration. Included files are relative to the current directory.
+/// Values may refer to defines and environment variables as ${NAME}.
 pub fn read_str(input: &str) -> DynResult<PlayerConfig> {
-    let document = Document::parse(input)?;
-    let mut player = PlayerConfig {
-        bind: "/tmp/siemens/automation/HmiRunTime".to_string(),
-        playback_device: "".to_string(),
-        rate: 44100,
-        channels: 2,
-        sample_format: SampleFormat::I16,
-        clip_root: String::new(),
-        clips: HashMap::new(),
-        tags: Vec::new(),
-        named_alarm_filters: HashMap::new(),
-        state_machines: Vec::new(),
-        volume_config: Vec::new(),
-    };
-
-    let root = document.root_element();
-    if !root.has_tag_name((NS, "audioplayer")) {
-        return Err("The root node must be 'audioplayer'".into());
-    }
+    read_str_with_mode(input, ParseMode::Strict)
+}
 
-    for node in root.children() {
-        if check_element_ns(&node)? {
-            match node.tag_name().name() {
-                "bind" => {
-                    player.bind = parse_bind(&node)?;
-                }
-                "playback_device" => {
-                    parse_playback_device(&node, &mut player)?;
-                }
-                "clips" => {
-                    player.clip_root = required_attribute(&node, "path")?;
-                    player.clips = parse_clips(&node)?;
-                }
-                "tags" => {
-                    player.tags = parse_tags(&node)?;
-                }
-                "alarms" => {
-                    parse_alarms(&node, &mut player.named_alarm_filters)?;
-                }
-                "state_machine" => {
-                    player.state_machines.push(parse_state_machine(&node)?);
-                }
-                "volume_control" => {
-                    parse_volume_control(&node, &mut player.volume_config)?;
-                }
-                _ => return Err(ConfigError::new(&node, UnexpectedElement).into()),
-            }
-        }
-    }
+/// Same as read_str but with a default parse mode. The parse_mode
+/// attribute of the root element overrides the default.
+pub fn read_str_with_mode(input: &str, mode: ParseMode) -> DynResult<PlayerConfig> {
+    let mut player = new_player_config();
+    with_parse_mode(mode, || {
+        with_vars(HashMap::new(), || {
+            read_str_into(
+                input,
+                Path::new(""),
+                &mut player,
+                &mut ParseContext::default(),
+            )
+        })
+    })?;
     Ok(player)
 }
 
+/// Read a configuration file. Included files are relative to the
+/// directory of the including file. Files ending with .toml or .yaml
+/// are read as TOML or YAML if support for the format is enabled.
+/// Files ending with .conf or .json are read as legacy JSON configurations.
+/// If path is a directory all XML files in it are merged. Clip
+/// paths are then relative to the directory.
 pub fn read_file<P: AsRef<Path>>(path: P) -> DynResult<PlayerConfig> {
-    let mut file = File::open(path)?;
-    let mut file_content = String::new();
-    file.read_to_string(&mut file_content)?;
-    read_str(&file_content)
+    read_file_with_mode(path, ParseMode::Strict)
+}
+
+/// Same as read_file but with a default parse mode
+pub fn read_file_with_mode<P: AsRef<Path>>(path: P, mode: ParseMode) -> DynResult<PlayerConfig> {
+    let mut player = new_player_config();
+    with_parse_mode(mode, || {
+        with_vars(HashMap::new(), || {
+            let path = path.as_ref();
+            let mut ctxt = ParseContext::default();
+            if path.is_dir() {
+                read_dir_into(path, &mut player, &mut ctxt)
+            } else {
+                read_file_into(path, &mut player, &mut ctxt)
+            }
+        })
+    })?;
+    Ok(player)
 }
 
 #[test]
@@ -771,3 +3683,1002 @@ fn test_parser() {
 "#;
     read_str(&doc).unwrap();
 }
+
+#[test]
+fn test_include() {
+    let dir = std::env::temp_dir().join(format!("audioplayer_include_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(
+        dir.join("main.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <bind>/tmp/pipe</bind>
+  <include href="sub/tags.xml"/>
+</audioplayer>"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("sub/tags.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <tags><tag>Included</tag></tags>
+  <include href="more.xml"/>
+</audioplayer>"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("sub/more.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <tags><tag>More</tag></tags>
+</audioplayer>"#,
+    )
+    .unwrap();
+    let conf = read_file(dir.join("main.xml"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    let conf = conf.unwrap();
+    assert_eq!(conf.bind, "/tmp/pipe");
+    let names: Vec<&str> = conf.tags.iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(names, ["Included", "More"]);
+}
+
+#[test]
+fn test_include_clips() {
+    let dir =
+        std::env::temp_dir().join(format!("audioplayer_include_clips_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::create_dir_all(dir.join("b")).unwrap();
+    std::fs::write(
+        dir.join("main.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <clips path="main_clips"><file id="Main">main.wav</file></clips>
+  <include href="a/clips.xml"/>
+  <include href="b/clips.xml"/>
+</audioplayer>"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("a/clips.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <clips path="sounds"><file id="A">a.wav</file></clips>
+</audioplayer>"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("b/clips.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <clips path="/opt/clips"><file id="B">b.wav</file></clips>
+</audioplayer>"#,
+    )
+    .unwrap();
+    let conf = read_file(dir.join("main.xml"));
+    // The same id in another include is an error
+    std::fs::write(
+        dir.join("b/clips.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <clips path="."><file id="A">b.wav</file></clips>
+</audioplayer>"#,
+    )
+    .unwrap();
+    let dup_err = read_file(dir.join("main.xml"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    let conf = conf.unwrap();
+    assert_eq!(conf.clip_root, "main_clips");
+    let file_name = |id: &str| match &conf.clips[id] {
+        ClipType::File { file_name, .. } => std::path::PathBuf::from(file_name),
+        _ => panic!("Not a file clip"),
+    };
+    assert_eq!(file_name("Main"), Path::new("main.wav"));
+    assert_eq!(file_name("A"), dir.join("a/sounds/a.wav"));
+    assert_eq!(file_name("B"), Path::new("/opt/clips/b.wav"));
+    let err = dup_err.unwrap_err().to_string();
+    assert!(err.contains("Clip 'A' is already defined"), "{}", err);
+
+    // Also within a file
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <clips path="/"><file id="A">a.wav</file></clips>
+  <clips path="/"><file id="A">b.wav</file></clips>
+</audioplayer>"#;
+    let err = read_str(doc).unwrap_err().to_string();
+    assert!(err.contains("Clip 'A' is already defined"), "{}", err);
+}
+
+#[test]
+fn test_expand_vars() {
+    let lookup = |name: &str| match name {
+        "ROOT" => Some("/opt/clips".to_string()),
+        "EMPTY" => Some(String::new()),
+        _ => None,
+    };
+    assert_eq!(expand_vars("plain", lookup).unwrap(), "plain");
+    assert_eq!(
+        expand_vars("${ROOT}/a.wav", lookup).unwrap(),
+        "/opt/clips/a.wav"
+    );
+    assert_eq!(expand_vars("x${EMPTY}y", lookup).unwrap(), "xy");
+    assert_eq!(expand_vars("${DEV:-default}", lookup).unwrap(), "default");
+    assert_eq!(
+        expand_vars("${ROOT:-default}", lookup).unwrap(),
+        "/opt/clips"
+    );
+    assert_eq!(expand_vars("$$${ROOT}$x", lookup).unwrap(), "$/opt/clips$x");
+    assert!(expand_vars("${UNDEFINED}", lookup).is_err());
+    assert!(expand_vars("${ROOT", lookup).is_err());
+}
+
+#[test]
+fn test_collect_errors() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <clips path="/">
+    <sine id="A" amplitude="x" frequency="440" duration="1s"/>
+    <file>NoId.wav</file>
+  </clips>
+  <unknown/>
+  <tags><tag local="maybe">T</tag></tags>
+</audioplayer>"#;
+    let err = read_str(doc).unwrap_err();
+    let errs = err.downcast::<ConfigErrors>().unwrap();
+    assert_eq!(errs.0.len(), 4);
+    assert!(errs.0[0].to_string().starts_with("3:5:"));
+}
+
+#[test]
+fn test_xml_schema() {
+    let schema = Document::parse(xml_schema()).unwrap();
+    assert_eq!(schema.root_element().tag_name().name(), "schema");
+}
+
+// The schema is maintained by hand, so check that the examples match it.
+// Needs xmllint. Run with cargo test -- --ignored
+#[test]
+#[ignore = "needs xmllint"]
+fn test_examples_match_schema() {
+    use std::process::Command;
+    let schema_path =
+        std::env::temp_dir().join(format!("mtp_audioplayer_{}.xsd", std::process::id()));
+    std::fs::write(&schema_path, xml_schema()).unwrap();
+    let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+    for entry in std::fs::read_dir(examples).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() != Some("xml".as_ref()) {
+            continue;
+        }
+        let output = Command::new("xmllint")
+            .arg("--noout")
+            .arg("--schema")
+            .arg(&schema_path)
+            .arg(&path)
+            .output()
+            .expect("Failed to run xmllint");
+        assert!(
+            output.status.success(),
+            "{} doesn't match the schema: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    std::fs::remove_file(&schema_path).unwrap();
+}
+
+#[test]
+fn test_template() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine_template id="alarm" params="clip filter">
+    <state id="idle">
+      <wait_alarm count="any">${filter}</wait_alarm>
+      <goto>${id}_play</goto>
+    </state>
+    <state id="${id}_play">
+      <play>${clip}</play>
+      <goto>idle</goto>
+    </state>
+  </state_machine_template>
+  <state_machine id="high" template="alarm" clip="SoundAlarm" filter="HighAlarms"/>
+  <state_machine id="low" template="alarm" clip="SoundInfo" filter="LowAlarms"/>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    assert_eq!(conf.state_machines.len(), 2);
+    let low = &conf.state_machines[1];
+    assert_eq!(low.id, "low");
+    assert_eq!(low.states[1].id, "low_play");
+    match &low.states[1].action {
+        ActionType::Parallel(actions) => match &actions[0] {
+            ActionType::Play { sound, .. } => assert_eq!(sound, "SoundInfo"),
+            a => panic!("Unexpected action {:?}", a),
+        },
+        a => panic!("Unexpected action {:?}", a),
+    }
+    let missing = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine_template id="t" params="clip"><state id="s"><play>${clip}</play></state></state_machine_template>
+  <state_machine id="m" template="t"/>
+</audioplayer>"#;
+    assert!(read_str(missing).is_err());
+}
+
+#[test]
+fn test_defines() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <defines>
+    <define name="INTERVAL">5s</define>
+    <define name="CLIP">Sound${INTERVAL}</define>
+  </defines>
+  <clips path="/">
+    <sine id="${CLIP}" amplitude="0.5" frequency="440" duration="${INTERVAL}"/>
+  </clips>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    match &conf.clips["Sound5s"] {
+        ClipType::Sine { duration, .. } => assert_eq!(*duration, Duration::from_secs(5)),
+        c => panic!("Unexpected clip {:?}", c),
+    }
+}
+
+#[test]
+fn test_parse_duration() {
+    let ms = Duration::from_millis;
+    assert_eq!(parse_duration("2s").unwrap(), ms(2000));
+    assert_eq!(parse_duration("1.5 m").unwrap(), ms(90_000));
+    assert_eq!(parse_duration("250ms").unwrap(), ms(250));
+    assert_eq!(parse_duration("1m30s").unwrap(), ms(90_000));
+    assert_eq!(parse_duration("1h 2m 3s 500ms").unwrap(), ms(3_723_500));
+    assert_eq!(parse_duration("PT1M30S").unwrap(), ms(90_000));
+    assert_eq!(parse_duration("PT0.5S").unwrap(), ms(500));
+    assert_eq!(parse_duration("P1DT1H").unwrap(), ms(25 * 3_600_000));
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("5").is_err());
+    assert!(parse_duration("-1s").is_err());
+    assert!(parse_duration("5x").is_err());
+    assert!(parse_duration("P1M").is_err());
+    assert!(parse_duration("PT").is_err());
+}
+
+#[test]
+fn test_when() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <defines><define name="SITE">north</define></defines>
+  <bind>/tmp/default</bind>
+  <when env="SITE=north">
+    <bind>/tmp/north</bind>
+  </when>
+  <when env="SITE=south">
+    <bind>/tmp/south</bind>
+  </when>
+  <when env="UNDEFINED_VARIABLE_FOR_TEST">
+    <bind>/tmp/undefined</bind>
+  </when>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    assert_eq!(conf.bind, "/tmp/north");
+}
+
+#[test]
+fn test_parse_mode() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <bind>/tmp/pipe</bind>
+  <future_element/>
+  <tags>
+    <tag>Tag1</tag>
+    <future_tag>Tag2</future_tag>
+  </tags>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+    let conf = read_str_with_mode(doc, ParseMode::Lenient).unwrap();
+    assert_eq!(conf.bind, "/tmp/pipe");
+    assert_eq!(conf.tags.len(), 1);
+    let doc = doc.replace("/v1\">", "/v1\" parse_mode=\"lenient\">");
+    assert!(read_str(&doc).is_ok());
+}
+
+#[test]
+fn test_config_dir() {
+    let dir = std::env::temp_dir().join(format!("audioplayer_conf_d_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("10-main.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <bind>/tmp/pipe</bind>
+  <clips path="main"><file id="beep">beep.wav</file></clips>
+  <tags><tag>Tag1</tag></tags>
+</audioplayer>"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("20-extra.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <clips path="extra"><file id="alarm">alarm.wav</file></clips>
+  <tags><tag>Tag2</tag></tags>
+</audioplayer>"#,
+    )
+    .unwrap();
+    let conf = read_file(&dir);
+    std::fs::write(
+        dir.join("30-dup.xml"),
+        r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <tags><tag>Tag1</tag></tags>
+</audioplayer>"#,
+    )
+    .unwrap();
+    let dup = read_file(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let conf = conf.unwrap();
+    assert_eq!(conf.bind, "/tmp/pipe");
+    assert_eq!(conf.tags.len(), 2);
+    match conf.clips.get("alarm") {
+        Some(ClipType::File { file_name, .. }) => {
+            assert_eq!(Path::new(file_name), Path::new("extra/alarm.wav"))
+        }
+        _ => panic!("Clip missing"),
+    }
+    let err = dup.unwrap_err().to_string();
+    assert!(err.contains("30-dup.xml"));
+    assert!(err.contains("Tag 'Tag1' is already defined"));
+}
+
+#[test]
+fn test_builder() {
+    let conf = PlayerConfig::builder()
+        .bind("/tmp/pipe")
+        .file_clip("beep", "beep.wav")
+        .tag(TagConfig::new("Sound"))
+        .alarm_filter("all", AlarmFilterConfig::new("Priority < 8").unwrap())
+        .state_machine(StateMachineConfig::new("main").state(
+            "idle",
+            ActionType::Sequence(vec![
+                ActionType::WaitTag {
+                    tag_name: "Sound".to_string(),
+                    condition: TagCondition::Changed,
+                },
+                ActionType::Play {
+                    priority: 0,
+                    timeout: None,
+                    sound: "beep".to_string(),
+                    device: None,
+                },
+            ]),
+        ))
+        .build();
+    assert_eq!(conf.bind, "/tmp/pipe");
+    assert!(conf.clips.contains_key("beep"));
+    assert_eq!(conf.tags[0].name, "Sound");
+    assert!(conf.named_alarm_filters.contains_key("all"));
+    assert_eq!(conf.state_machines[0].states[0].id, "idle");
+}
+
+#[test]
+fn test_volume_schedule() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <volume_control id="main" initial="0.8" min="0.1">
+    default
+    <schedule from="22:00" to="06:00" volume="0.4"/>
+    <schedule from="12:00" to="13:00:30" volume="0.6"/>
+  </volume_control>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    let control = &conf.volume_config[0];
+    assert_eq!(control.device, "default");
+    assert_eq!(control.min_volume, 0.1);
+    assert_eq!(control.max_volume, 1.0);
+    let t = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    let night = &control.schedule[0];
+    assert!(night.contains(t(23, 0)));
+    assert!(night.contains(t(5, 59)));
+    assert!(!night.contains(t(6, 0)));
+    assert!(!night.contains(t(12, 0)));
+    assert!(control.schedule[1].contains(t(13, 0)));
+}
+
+#[test]
+fn test_signal() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <signal name="SIGUSR1"><goto>horn:test</goto></signal>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    assert_eq!(conf.signals[0].name, "usr1");
+    assert!(matches!(&conf.signals[0].action, ActionType::Goto(s) if s == "horn:test"));
+    #[cfg(unix)]
+    {
+        let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <signal name="hup"><goto>horn:test</goto></signal>
+</audioplayer>"#;
+        assert!(read_str(doc).is_err());
+    }
+}
+
+#[test]
+fn test_opcua() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <opcua url="opc.tcp://plc:4840" publish_interval="1s">
+    <tag name="Horn" node="ns=2;s=Horn"/>
+  </opcua>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    match &conf.tag_sources[0] {
+        TagSourceConfig::OpcUa(opcua) => {
+            assert_eq!(opcua.url, "opc.tcp://plc:4840");
+            assert_eq!(opcua.publish_interval, Duration::from_secs(1));
+            assert_eq!(
+                opcua.tags,
+                [("Horn".to_string(), "ns=2;s=Horn".to_string())]
+            );
+        }
+        _ => panic!("Not an OPC UA source"),
+    }
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <opcua url="opc.tcp://plc:4840"><tag name="Horn"/></opcua>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_modbus() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <modbus address="plc:502" scan_interval="100ms">
+    <coil name="Horn" address="0"/>
+    <input_register name="Level" address="7" scale="0.1" offset="-10" signed="true"/>
+  </modbus>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    match &conf.tag_sources[0] {
+        TagSourceConfig::Modbus(modbus) => {
+            assert_eq!(modbus.address, "plc:502");
+            assert_eq!(modbus.unit, 1);
+            assert_eq!(modbus.scan_interval, Duration::from_millis(100));
+            assert_eq!(modbus.tags[0].register, ModbusRegister::Coil);
+            assert_eq!(
+                modbus.tags[1],
+                ModbusTagConfig {
+                    name: "Level".to_string(),
+                    register: ModbusRegister::InputRegister,
+                    address: 7,
+                    scale: 0.1,
+                    offset: -10.0,
+                    signed: true,
+                }
+            );
+        }
+        _ => panic!("Not a Modbus source"),
+    }
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <modbus address="plc:502"><coil name="Horn" address="0" scale="0"/></modbus>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_s7() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <s7 address="plc" slot="2">
+    <bool name="Horn" db="10" offset="4" bit="3"/>
+    <real name="Level" db="10" offset="6"/>
+  </s7>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    match &conf.tag_sources[0] {
+        TagSourceConfig::S7(s7) => {
+            assert_eq!(s7.address, "plc");
+            assert_eq!((s7.rack, s7.slot), (0, 2));
+            assert_eq!(s7.tags[0].bit, 3);
+            assert_eq!(
+                s7.tags[1],
+                S7TagConfig {
+                    name: "Level".to_string(),
+                    data_type: S7Type::Real,
+                    db: 10,
+                    offset: 6,
+                    bit: 0,
+                }
+            );
+        }
+        _ => panic!("Not an S7 source"),
+    }
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <s7 address="plc"><bool name="Horn" db="10" offset="4" bit="8"/></s7>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_snmp() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <snmp target="nms:162" enterprise="1.3.6.1.4.1.99999">
+    <filter name="fire"/>
+  </snmp>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    let snmp = conf.snmp.unwrap();
+    assert_eq!(snmp.target, "nms:162");
+    assert_eq!(snmp.community, "public");
+    assert_eq!(snmp.enterprise, [1, 3, 6, 1, 4, 1, 99999]);
+    assert_eq!(snmp.filters, ["fire"]);
+    assert!(parse_oid("1.3.x").is_err());
+    assert!(parse_oid("1").is_err());
+    assert!(parse_oid("3.1").is_err());
+}
+
+#[test]
+fn test_email() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <smtp server="mail.example.com" from="player@example.com" security="tls"/>
+  <state_machine id="mail">
+    <state id="send">
+      <email to="a@example.com, b@example.com" subject="Level {Level}">
+        Level is {Level}
+      </email>
+    </state>
+  </state_machine>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    let smtp = conf.smtp.unwrap();
+    assert_eq!(smtp.security, SmtpSecurity::Tls);
+    assert_eq!(smtp.port, None);
+    match &conf.state_machines[0].states[0].action {
+        ActionType::Email { to, subject, body } => {
+            assert_eq!(to, &["a@example.com", "b@example.com"]);
+            assert_eq!(subject, "Level {Level}");
+            assert_eq!(body, "Level is {Level}");
+        }
+        _ => panic!("Not an email action"),
+    }
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="mail">
+    <state id="send"><email to="a@example.com" subject="{Level">x</email></state>
+  </state_machine>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_tag_max_rate() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <tags><tag max_rate="RATE">Level</tag></tags>
+</audioplayer>"#;
+    let conf = read_str(&doc.replace("RATE", "2.5")).unwrap();
+    assert_eq!(conf.tags[0].max_rate, Some(2.5));
+    for rate in ["0", "-1", "NaN", "inf", "1e-300"] {
+        assert!(read_str(&doc.replace("RATE", rate)).is_err(), "{}", rate);
+    }
+}
+
+#[test]
+fn test_set_volume_ramp() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="volume">
+    <state id="fade"><set_volume control="main" ramp="2s">0.2</set_volume></state>
+    <state id="jump"><set_volume control="main">0.8</set_volume></state>
+  </state_machine>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    let ramps: Vec<_> = conf.state_machines[0]
+        .states
+        .iter()
+        .map(|s| match &s.action {
+            ActionType::SetVolume { ramp, .. } => *ramp,
+            _ => panic!("Not a set_volume action"),
+        })
+        .collect();
+    assert_eq!(ramps, [Some(Duration::from_secs(2)), None]);
+}
+
+#[test]
+fn test_if() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="tank">
+    <state id="check">
+      <if expr="TankLevel > 80 AND PumpRunning = 0">
+        <debug>Overflow</debug>
+        <goto>wait</goto>
+        <else><debug>Ok</debug></else>
+      </if>
+    </state>
+    <state id="wait"><wait_tag expr="TankLevel &lt; 50 || PumpRunning"/></state>
+  </state_machine>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    match &conf.state_machines[0].states[0].action {
+        ActionType::If {
+            condition,
+            then,
+            otherwise,
+        } => {
+            assert_eq!(condition.tags().len(), 2);
+            assert!(matches!(**then, ActionType::Sequence(ref a) if a.len() == 2));
+            assert!(matches!(otherwise.as_deref(), Some(ActionType::Debug(_))));
+        }
+        _ => panic!("Not an if action"),
+    }
+    match &conf.state_machines[0].states[1].action {
+        ActionType::WaitExpr(expr) => assert_eq!(expr.tags().len(), 2),
+        _ => panic!("Not a wait_tag expression"),
+    }
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="tank">
+    <state id="wait"><wait_tag expr="TankLevel > 80" eq="1">TankLevel</wait_tag></state>
+  </state_machine>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_exec() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="tower">
+    <state id="red">
+      <exec program="/usr/local/bin/light_tower" timeout="2s">
+        <arg>red</arg>
+        <arg>{Level}</arg>
+      </exec>
+    </state>
+    <state id="off"><exec program="light_tower_off"/></state>
+  </state_machine>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    match &conf.state_machines[0].states[0].action {
+        ActionType::Exec {
+            program,
+            args,
+            timeout,
+        } => {
+            assert_eq!(program, "/usr/local/bin/light_tower");
+            assert_eq!(args, &["red", "{Level}"]);
+            assert_eq!(*timeout, Duration::from_secs(2));
+        }
+        _ => panic!("Not an exec action"),
+    }
+    match &conf.state_machines[0].states[1].action {
+        ActionType::Exec { args, timeout, .. } => {
+            assert!(args.is_empty());
+            assert_eq!(*timeout, DEFAULT_EXEC_TIMEOUT);
+        }
+        _ => panic!("Not an exec action"),
+    }
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="tower">
+    <state id="red"><exec program="light_tower"><flag>red</flag></exec></state>
+  </state_machine>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_mqtt() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <mqtt broker="broker.local:1884" retain="false">
+    <publish tag="Horn" topic="plant/horn"/>
+    <subscribe tag="Silence" topic="plant/silence"/>
+  </mqtt>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    let mqtt = conf.mqtt.unwrap();
+    assert_eq!(mqtt.host, "broker.local");
+    assert_eq!(mqtt.port, 1884);
+    assert_eq!(mqtt.client_id, "mtp_audioplayer");
+    assert!(!mqtt.retain);
+    assert_eq!(
+        mqtt.publish,
+        [MqttTopicConfig {
+            tag: "Horn".to_string(),
+            topic: "plant/horn".to_string()
+        }]
+    );
+    assert_eq!(mqtt.subscribe[0].tag, "Silence");
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <mqtt broker="broker.local"/>
+</audioplayer>"#;
+    assert_eq!(read_str(doc).unwrap().mqtt.unwrap().port, 1883);
+}
+
+#[test]
+fn test_gpio_inputs() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <gpio_inputs debounce="50ms">
+    <input tag="LampTest" line="17" active_low="true"/>
+  </gpio_inputs>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    match &conf.tag_sources[0] {
+        TagSourceConfig::GpioInput(gpio) => {
+            assert_eq!(gpio.chip, "/dev/gpiochip0");
+            assert_eq!(gpio.debounce, Duration::from_millis(50));
+            assert_eq!(
+                gpio.lines,
+                [GpioInputLineConfig {
+                    tag: "LampTest".to_string(),
+                    line: 17,
+                    active_low: true
+                }]
+            );
+        }
+        _ => panic!("Not a GPIO source"),
+    }
+}
+
+#[test]
+fn test_gpio_outputs() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <gpio_outputs chip="/dev/gpiochip1">
+    <output name="Beacon" line="22" follow_filter="Fire"/>
+  </gpio_outputs>
+  <state_machine id="main">
+    <state id="Start"><set_gpio pin="Beacon" value="1"/></state>
+  </state_machine>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    assert_eq!(
+        conf.gpio_outputs,
+        [GpioOutputConfig {
+            name: "Beacon".to_string(),
+            chip: "/dev/gpiochip1".to_string(),
+            line: 22,
+            active_low: false,
+            follow_filter: Some("Fire".to_string())
+        }]
+    );
+    match &conf.state_machines[0].states[0].action {
+        ActionType::SetGpio { pin, value } => {
+            assert_eq!(pin, "Beacon");
+            assert!(value);
+        }
+        _ => panic!("Not a set_gpio action"),
+    }
+}
+
+#[test]
+fn test_control_api() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <control_api token="secret"/>
+</audioplayer>"#;
+    let api = read_str(doc).unwrap().control_api.unwrap();
+    assert_eq!(api.bind, "127.0.0.1:8080");
+    assert_eq!(api.token, "secret");
+    assert_eq!(api.status_token, None);
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <control_api token="secret" status_token="public"/>
+</audioplayer>"#;
+    let api = read_str(doc).unwrap().control_api.unwrap();
+    assert_eq!(api.status_token.as_deref(), Some("public"));
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <control_api bind="0.0.0.0:80" token=""/>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_snapcast() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <snapcast target="tcp://snapserver:4953"/>
+</audioplayer>"#;
+    let snapcast = read_str(doc).unwrap().snapcast.unwrap();
+    assert_eq!(
+        snapcast.target,
+        SnapcastTarget::Tcp("snapserver:4953".to_string())
+    );
+    assert_eq!(snapcast.reconnect, Duration::from_secs(5));
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <snapcast target="/tmp/snapfifo" reconnect="1s"/>
+</audioplayer>"#;
+    let snapcast = read_str(doc).unwrap().snapcast.unwrap();
+    assert_eq!(
+        snapcast.target,
+        SnapcastTarget::Pipe("/tmp/snapfifo".to_string())
+    );
+    assert_eq!(snapcast.reconnect, Duration::from_secs(1));
+}
+
+#[test]
+fn test_playback_devices() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <playback_device rate="48000" channels="2">default</playback_device>
+  <playback_device id="horn" rate="22050" channels="1" format="f32" latency="100ms">hw:1</playback_device>
+  <clips path=".">
+    <sine id="beep" amplitude="0.5" frequency="1000" duration="1s" device="horn"/>
+    <file id="voice">voice.wav</file>
+  </clips>
+  <state_machine id="main">
+    <state id="idle"><play device="horn">voice</play></state>
+  </state_machine>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    assert_eq!(conf.playback_device, "default");
+    assert_eq!(conf.rate, 48000);
+    assert_eq!(
+        conf.playback_devices["horn"],
+        PlaybackDeviceConfig {
+            device: "hw:1".to_string(),
+            rate: 22050,
+            channels: 1,
+            sample_format: SampleFormat::F32,
+            buffer_size: Some(2205),
+        }
+    );
+    assert_eq!(conf.clip_devices["beep"], "horn");
+    assert!(!conf.clip_devices.contains_key("voice"));
+    match &conf.state_machines[0].states[0].action {
+        ActionType::Play { device, .. } => assert_eq!(device.as_deref(), Some("horn")),
+        _ => panic!("Not a play action"),
+    }
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <playback_device id="horn" rate="48000" channels="2">hw:1</playback_device>
+  <playback_device id="horn" rate="48000" channels="2">hw:2</playback_device>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <playback_device rate="48000" channels="2" buffer="256">default</playback_device>
+</audioplayer>"#;
+    assert_eq!(read_str(doc).unwrap().buffer_size, Some(256));
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <playback_device rate="48000" channels="2" buffer="256" latency="5ms">default</playback_device>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_wait_time() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="test">
+    <state id="daily"><wait_time>07:30</wait_time></state>
+    <state id="weekdays"><wait_time> 0 12 * * 1-5 </wait_time></state>
+  </state_machine>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    let states = &conf.state_machines[0].states;
+    match &states[0].action {
+        ActionType::WaitTime(schedule) => assert_eq!(schedule.to_string(), "07:30:00"),
+        _ => panic!("Not a wait_time action"),
+    }
+    match &states[1].action {
+        ActionType::WaitTime(schedule) => assert_eq!(schedule.to_string(), "0 12 * * 1-5"),
+        _ => panic!("Not a wait_time action"),
+    }
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine id="test">
+    <state id="bad"><wait_time>0 25 * * *</wait_time></state>
+  </state_machine>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_state_tags() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <state_machine_template id="toggle" params="tag">
+    <state id="off"><wait_tag eq="1">${tag}</wait_tag></state>
+  </state_machine_template>
+  <state_machine id="alarm" state_tag="SM_alarm_STATE" command_tag="SM_alarm_CMD">
+    <state id="idle"><wait_tag eq="1">Alarm</wait_tag></state>
+  </state_machine>
+  <state_machine id="light" template="toggle" tag="Light" state_tag="SM_light_STATE"/>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    let alarm = &conf.state_machines[0];
+    assert_eq!(alarm.state_tag.as_deref(), Some("SM_alarm_STATE"));
+    assert_eq!(alarm.command_tag.as_deref(), Some("SM_alarm_CMD"));
+    let light = &conf.state_machines[1];
+    assert_eq!(light.state_tag.as_deref(), Some("SM_light_STATE"));
+    assert_eq!(light.command_tag, None);
+}
+
+#[test]
+fn test_monitor_stream() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <monitor_stream/>
+</audioplayer>"#;
+    let monitor = read_str(doc).unwrap().monitor_stream.unwrap();
+    assert_eq!(monitor.bind, "127.0.0.1:8000");
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <monitor_stream bind="0.0.0.0:8000"/>
+</audioplayer>"#;
+    let monitor = read_str(doc).unwrap().monitor_stream.unwrap();
+    assert_eq!(monitor.bind, "0.0.0.0:8000");
+}
+
+#[test]
+fn test_metrics() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <metrics/>
+</audioplayer>"#;
+    let metrics = read_str(doc).unwrap().metrics.unwrap();
+    assert_eq!(metrics.bind, "127.0.0.1:9180");
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <metrics bind="0.0.0.0:9180"/>
+</audioplayer>"#;
+    let metrics = read_str(doc).unwrap().metrics.unwrap();
+    assert_eq!(metrics.bind, "0.0.0.0:9180");
+}
+
+#[test]
+fn test_reconnect() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+</audioplayer>"#;
+    assert_eq!(read_str(doc).unwrap().reconnect, ReconnectConfig::default());
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <reconnect initial="500ms" max="30s"/>
+</audioplayer>"#;
+    let reconnect = read_str(doc).unwrap().reconnect;
+    assert_eq!(reconnect.initial, Duration::from_millis(500));
+    assert_eq!(reconnect.max, Duration::from_secs(30));
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <reconnect initial="1m" max="30s"/>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_reload_tag() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <reload tag="AUDIO_SERVER_RELOAD"/>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    assert_eq!(conf.reload_tag.as_deref(), Some("AUDIO_SERVER_RELOAD"));
+}
+
+#[test]
+fn test_shutdown() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <shutdown drain="10s" fade="500ms"/>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    assert_eq!(conf.shutdown_drain, Duration::from_secs(10));
+    assert_eq!(conf.shutdown_fade, Duration::from_millis(500));
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <shutdown fade="soon"/>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_dbus() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <dbus bus="session" control="false"/>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    let dbus = conf.dbus.unwrap();
+    assert_eq!(dbus.bus, DbusBus::Session);
+    assert!(!dbus.control);
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <dbus bus="user"/>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_stream_profile() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <clips path="/">
+    <profile id="long" stream="true"/>
+    <profile id="short"/>
+  </clips>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    assert!(conf.clip_profiles["long"].stream);
+    assert!(!conf.clip_profiles["short"].stream);
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <clips path="/">
+    <profile id="long" stream="true" normalize="-3"/>
+  </clips>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
+
+#[test]
+fn test_audit_log() {
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <audit_log max_size="1000000" keep="3">audit.log</audit_log>
+</audioplayer>"#;
+    let conf = read_str(doc).unwrap();
+    assert_eq!(
+        conf.audit_log,
+        Some(AuditLogConfig {
+            path: "audit.log".to_string(),
+            max_size: Some(1000000),
+            keep: 3,
+            alarms: true,
+        })
+    );
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <audit_log alarms="false">audit.log</audit_log>
+</audioplayer>"#;
+    let audit_log = read_str(doc).unwrap().audit_log.unwrap();
+    assert_eq!(audit_log.max_size, None);
+    assert!(!audit_log.alarms);
+    let doc = r#"<audioplayer xmlns="http://www.elektro-kapsel.se/audioplayer/v1">
+  <audit_log max_size="0">audit.log</audit_log>
+</audioplayer>"#;
+    assert!(read_str(doc).is_err());
+}
@@ -1,24 +1,40 @@
+use crate::sample_stream::StreamSource;
+
+/// Format of the samples sent to a playback device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    I16,
+    U16,
+    F32,
+}
+
 #[derive(Debug)]
 pub enum SampleBuffer {
     I16(Vec<i16>),
     U16(Vec<u16>),
     F32(Vec<f32>),
+    /// Read from a file while playing
+    Stream(StreamSource),
 }
 
 impl SampleBuffer {
+    /// Number of samples in memory, zero for streamed clips
     pub fn len(&self) -> usize {
         match self {
             SampleBuffer::I16(buf) => buf.len(),
             SampleBuffer::U16(buf) => buf.len(),
             SampleBuffer::F32(buf) => buf.len(),
+            SampleBuffer::Stream(_) => 0,
         }
     }
 
+    /// True if there's nothing to play
     pub fn is_empty(&self) -> bool {
         match self {
             SampleBuffer::I16(buf) => buf.is_empty(),
             SampleBuffer::U16(buf) => buf.is_empty(),
             SampleBuffer::F32(buf) => buf.is_empty(),
+            SampleBuffer::Stream(_) => false,
         }
     }
 }
@@ -62,6 +78,10 @@ pub trait Sample {
     const SAMPLE_MIN: Self;
     const SAMPLE_MAX: Self;
     const SAMPLE_ABS_MAX: Self;
+
+    /// Convert from the range -1.0 to 1.0, clipping values outside it
+    fn from_f32(v: f32) -> Self;
+    fn to_i16(&self) -> i16;
 }
 
 impl Sample for i16 {
@@ -69,6 +89,14 @@ impl Sample for i16 {
     const SAMPLE_MIN: i16 = -32768;
     const SAMPLE_MAX: i16 = 32767;
     const SAMPLE_ABS_MAX: i16 = 32767;
+
+    fn from_f32(v: f32) -> i16 {
+        (v * 32767.0).round().clamp(-32768.0, 32767.0) as i16
+    }
+
+    fn to_i16(&self) -> i16 {
+        *self
+    }
 }
 
 impl Sample for u16 {
@@ -76,6 +104,14 @@ impl Sample for u16 {
     const SAMPLE_MIN: u16 = 0;
     const SAMPLE_MAX: u16 = 65535;
     const SAMPLE_ABS_MAX: u16 = 32767;
+
+    fn from_f32(v: f32) -> u16 {
+        (v * 32767.0 + 32768.0).round().clamp(0.0, 65535.0) as u16
+    }
+
+    fn to_i16(&self) -> i16 {
+        (i32::from(*self) - 32768) as i16
+    }
 }
 
 impl Sample for f32 {
@@ -83,4 +119,12 @@ impl Sample for f32 {
     const SAMPLE_MIN: f32 = -1.0;
     const SAMPLE_MAX: f32 = 1.0;
     const SAMPLE_ABS_MAX: f32 = 1.0;
+
+    fn from_f32(v: f32) -> f32 {
+        v
+    }
+
+    fn to_i16(&self) -> i16 {
+        i16::from_f32(*self)
+    }
 }
@@ -0,0 +1,297 @@
+//! Clips read from WAV files while they are played
+//!
+//! Long clips are never loaded into memory. The playback thread reads and
+//! resamples the file a chunk at a time into a [`StreamRing`], which the
+//! stream callback consumes without blocking.
+
+use crate::read_config::ResamplerQuality;
+use crate::sample_buffer::Sample;
+use crate::util::error::DynResult;
+use hound::WavReader;
+use simple_samplerate::samplerate::Samplerate;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+// Frames read from the file at a time
+const CHUNK_FRAMES: usize = 1024;
+
+/// A WAV file to read while playing
+#[derive(Debug)]
+pub struct StreamSource {
+    pub path: PathBuf,
+    /// Sample rate of the output
+    pub rate: u32,
+    pub channels: usize,
+    pub amplitude: f32,
+    pub resampler: ResamplerQuality,
+}
+
+impl StreamSource {
+    /// A ring holding one second of output
+    pub fn ring(&self) -> StreamRing {
+        StreamRing::new(self.rate as usize * self.channels)
+    }
+}
+
+/// Samples passed from the playback thread to the stream callback.
+/// There must be only one writer and one reader.
+pub struct StreamRing {
+    samples: Vec<AtomicU32>,
+    // Total number of samples written and read
+    written: AtomicUsize,
+    read: AtomicUsize,
+    // No more samples will be written
+    ended: AtomicBool,
+}
+
+impl StreamRing {
+    pub fn new(len: usize) -> StreamRing {
+        StreamRing {
+            samples: (0..len.max(1)).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            ended: AtomicBool::new(false),
+        }
+    }
+
+    /// Add as many samples as there is room for. Returns the number
+    /// of samples added.
+    pub fn push(&self, samples: &[f32]) -> usize {
+        let len = self.samples.len();
+        let written = self.written.load(Ordering::Relaxed);
+        let read = self.read.load(Ordering::Acquire);
+        let count = samples.len().min(len - (written - read));
+        for (i, s) in samples[..count].iter().enumerate() {
+            self.samples[(written + i) % len].store(s.to_bits(), Ordering::Relaxed);
+        }
+        self.written.store(written + count, Ordering::Release);
+        count
+    }
+
+    /// No more samples will be pushed
+    pub fn end(&self) {
+        self.ended.store(true, Ordering::Release);
+    }
+
+    /// Fill the start of `buffer` with the samples available. Returns
+    /// the number of samples copied. Called from the stream callback.
+    pub fn pop<S: Sample>(&self, buffer: &mut [S]) -> usize {
+        let len = self.samples.len();
+        let read = self.read.load(Ordering::Relaxed);
+        let written = self.written.load(Ordering::Acquire);
+        let count = buffer.len().min(written - read);
+        for (i, s) in buffer[..count].iter_mut().enumerate() {
+            let v = f32::from_bits(self.samples[(read + i) % len].load(Ordering::Relaxed));
+            *s = S::from_f32(v);
+        }
+        self.read.store(read + count, Ordering::Release);
+        count
+    }
+
+    /// True if all samples have been popped and no more will be pushed
+    pub fn is_done(&self) -> bool {
+        // Everything pushed before ending is visible after this
+        let ended = self.ended.load(Ordering::Acquire);
+        ended && self.read.load(Ordering::Relaxed) == self.written.load(Ordering::Acquire)
+    }
+}
+
+// Linear interpolation that keeps its position between chunks
+struct LinearResampler {
+    // Input frames per output frame
+    step: f64,
+    // Position of the next output frame, where 0 is the last frame of
+    // the previous chunk
+    pos: f64,
+    last: Vec<f32>,
+}
+
+impl LinearResampler {
+    fn new(from_rate: u32, to_rate: u32, channels: usize) -> LinearResampler {
+        LinearResampler {
+            step: f64::from(from_rate) / f64::from(to_rate),
+            // Start at the first frame of the first chunk
+            pos: 1.0,
+            last: vec![0.0; channels],
+        }
+    }
+
+    fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let channels = self.last.len();
+        let frames = input.len() / channels;
+        let frame = |i: usize| -> &[f32] {
+            if i == 0 {
+                &self.last
+            } else {
+                &input[(i - 1) * channels..i * channels]
+            }
+        };
+        while (self.pos as usize) < frames {
+            let i0 = self.pos as usize;
+            let frac = (self.pos - i0 as f64) as f32;
+            for (a, b) in frame(i0).iter().zip(frame(i0 + 1)) {
+                out.push(a + (b - a) * frac);
+            }
+            self.pos += self.step;
+        }
+        if frames > 0 {
+            self.pos -= frames as f64;
+            self.last
+                .copy_from_slice(&input[(frames - 1) * channels..frames * channels]);
+        }
+    }
+}
+
+enum Resampler {
+    High {
+        conv: Samplerate,
+        from_rate: u32,
+        to_rate: u32,
+        channels: usize,
+    },
+    Fast(LinearResampler),
+}
+
+impl Resampler {
+    fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        match self {
+            Resampler::High {
+                conv,
+                from_rate,
+                to_rate,
+                channels,
+            } => {
+                let start = out.len();
+                let out_len = input.len() * *to_rate as usize / *from_rate as usize + 8 * *channels;
+                out.resize(start + out_len, 0.0);
+                let count = conv.process_buffer(input, &mut out[start..]);
+                out.truncate(start + count);
+            }
+            Resampler::Fast(linear) => linear.process(input, out),
+        }
+    }
+}
+
+/// Reads a [`StreamSource`] a chunk at a time. Used by the playback
+/// thread.
+pub struct StreamReader {
+    reader: WavReader<BufReader<File>>,
+    channels: usize,
+    amplitude: f32,
+    resampler: Resampler,
+    // Resampled samples that didn't fit in the ring
+    pending: Vec<f32>,
+    pending_pos: usize,
+    // The whole file has been read
+    eof: bool,
+}
+
+impl StreamReader {
+    pub fn open(source: &StreamSource) -> DynResult<StreamReader> {
+        let reader = WavReader::open(&source.path).map_err(|e| {
+            format!(
+                "Failed to open audio file \"{}\": {}",
+                source.path.to_string_lossy(),
+                e
+            )
+        })?;
+        let from_rate = reader.spec().sample_rate;
+        let resampler = match source.resampler {
+            ResamplerQuality::High => Resampler::High {
+                conv: Samplerate::new(from_rate, source.rate, source.channels).map_err(|e| {
+                    format!(
+                        "Can't resample audio file \"{}\": {:?}",
+                        source.path.to_string_lossy(),
+                        e
+                    )
+                })?,
+                from_rate,
+                to_rate: source.rate,
+                channels: source.channels,
+            },
+            ResamplerQuality::Fast => Resampler::Fast(LinearResampler::new(
+                from_rate,
+                source.rate,
+                source.channels,
+            )),
+        };
+        Ok(StreamReader {
+            reader,
+            channels: source.channels,
+            amplitude: source.amplitude,
+            resampler,
+            pending: Vec::new(),
+            pending_pos: 0,
+            eof: false,
+        })
+    }
+
+    fn read_chunk(&mut self) -> DynResult<()> {
+        let len = CHUNK_FRAMES * self.channels;
+        let mut input = Vec::with_capacity(len);
+        for s in self.reader.samples::<i16>().take(len) {
+            input.push(f32::from(s?) / 32767.0 * self.amplitude);
+        }
+        self.eof = input.len() < len;
+        self.pending.clear();
+        self.pending_pos = 0;
+        self.resampler.process(&input, &mut self.pending);
+        Ok(())
+    }
+
+    /// Read from the file until the ring is full. The ring is ended
+    /// when the whole file has been pushed.
+    pub fn fill(&mut self, ring: &StreamRing) -> DynResult<()> {
+        loop {
+            self.pending_pos += ring.push(&self.pending[self.pending_pos..]);
+            if self.pending_pos < self.pending.len() {
+                return Ok(());
+            }
+            if self.eof {
+                ring.end();
+                return Ok(());
+            }
+            self.read_chunk()?;
+        }
+    }
+
+    /// True if everything has been pushed to the ring
+    pub fn is_done(&self) -> bool {
+        self.eof && self.pending_pos == self.pending.len()
+    }
+}
+
+#[test]
+fn test_stream_ring() {
+    let ring = StreamRing::new(4);
+    assert_eq!(ring.push(&[0.0, 0.5, -0.5, 1.0, 1.0]), 4);
+    let mut buffer = [1i16; 3];
+    assert_eq!(ring.pop(&mut buffer), 3);
+    assert_eq!(buffer[0], 0);
+    assert!(buffer[1] > 16000);
+    assert!(buffer[2] < -16000);
+    assert_eq!(ring.push(&[0.0, 0.0, 0.0, 0.0]), 3);
+    ring.end();
+    assert!(!ring.is_done());
+    let mut buffer = [0.5f32; 6];
+    assert_eq!(ring.pop(&mut buffer), 4);
+    assert_eq!(buffer[..4], [1.0, 0.0, 0.0, 0.0]);
+    assert!(ring.is_done());
+}
+
+#[test]
+fn test_linear_resampler() {
+    let mut linear = LinearResampler::new(1, 2, 1);
+    let mut out = Vec::new();
+    linear.process(&[0.0, 1.0], &mut out);
+    linear.process(&[2.0, 3.0], &mut out);
+    assert_eq!(out, [0.0, 0.5, 1.0, 1.5, 2.0, 2.5]);
+
+    let mut linear = LinearResampler::new(2, 1, 2);
+    let mut out = Vec::new();
+    linear.process(&[0.0, 0.0, 1.0, -1.0, 2.0, -2.0], &mut out);
+    linear.process(&[3.0, -3.0, 4.0, -4.0], &mut out);
+    assert_eq!(out, [0.0, 0.0, 2.0, -2.0]);
+}
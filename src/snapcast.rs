@@ -0,0 +1,76 @@
+//! Feed the played audio to a Snapcast server
+//!
+//! Everything sent to the playback device, silence included, is written
+//! as 16-bit little endian PCM. The snapserver source must be configured
+//! with the same rate and channel count as the player, e.g.
+//! `sampleformat=44100:16:2`. If there are no local speakers the ALSA
+//! device "null" can be used for playback.
+
+use crate::clip_queue::ClipQueue;
+use crate::read_config::{SnapcastConfig, SnapcastTarget};
+use crate::util::error::DynResult;
+use log::{debug, info, warn};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc::Receiver;
+
+// Buffers queued while the connection is slow
+const TAP_CAPACITY: usize = 32;
+
+fn open(target: &SnapcastTarget) -> std::io::Result<Box<dyn Write>> {
+    Ok(match target {
+        SnapcastTarget::Tcp(addr) => {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            Box::new(stream)
+        }
+        // Blocks until the server opens the pipe for reading
+        SnapcastTarget::Pipe(path) => Box::new(OpenOptions::new().write(true).open(path)?),
+    })
+}
+
+fn run(conf: SnapcastConfig, mut tap: Receiver<Vec<i16>>) {
+    let mut bytes = Vec::new();
+    loop {
+        match open(&conf.target) {
+            Ok(mut output) => {
+                info!("Streaming to Snapcast {:?}", conf.target);
+                // Skip what was played while not connected
+                while tap.try_recv().is_ok() {}
+                loop {
+                    let pcm = match tap.blocking_recv() {
+                        Some(pcm) => pcm,
+                        None => {
+                            debug!("Snapcast stream ended");
+                            return;
+                        }
+                    };
+                    bytes.clear();
+                    for s in pcm {
+                        bytes.extend_from_slice(&s.to_le_bytes());
+                    }
+                    if let Err(e) = output.write_all(&bytes) {
+                        warn!("Lost Snapcast {:?}: {}", conf.target, e);
+                        break;
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to open Snapcast {:?}: {}", conf.target, e),
+        }
+        thread::sleep(conf.reconnect);
+    }
+}
+
+/// Start streaming in a separate thread. Reconnects until the player is
+/// shut down.
+pub fn start(conf: &SnapcastConfig, clip_queue: &Arc<ClipQueue>) -> DynResult<()> {
+    let tap = clip_queue.add_output_tap(TAP_CAPACITY);
+    let conf = conf.clone();
+    thread::Builder::new()
+        .name("snapcast".to_string())
+        .spawn(move || run(conf, tap))?;
+    Ok(())
+}
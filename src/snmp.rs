@@ -0,0 +1,204 @@
+//! SNMPv2c traps sent when alarm filters become active or cleared
+//!
+//! With the enterprise OID E the traps are E.0.1 when a filter becomes
+//! active and E.0.2 when it's cleared. Each trap has the filter name as
+//! E.1.0 and the number of matching alarms as E.2.0.
+
+use crate::actions::alarm_dispatcher::AlarmDispatcher;
+use crate::app_config::AlarmContext;
+use crate::read_config::SnmpConfig;
+use crate::util::error::DynResult;
+use log::{debug, error};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+
+const SYS_UP_TIME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 3, 0];
+const SNMP_TRAP_OID: &[u32] = &[1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_TIME_TICKS: u8 = 0x43;
+const TAG_TRAP_V2: u8 = 0xa7;
+
+// BER encoded type, length and value
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut buf = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        buf.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        buf.push(0x80 | bytes.len() as u8);
+        buf.extend(bytes);
+    }
+    buf.extend_from_slice(value);
+    buf
+}
+
+// Shortest two's complement representation
+fn integer(tag: u8, value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < 7 {
+        let redundant = (bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    tlv(tag, &bytes[start..])
+}
+
+fn oid(oid: &[u32]) -> Vec<u8> {
+    let mut value = Vec::new();
+    let first = oid.first().copied().unwrap_or(0) * 40 + oid.get(1).copied().unwrap_or(0);
+    for &n in std::iter::once(&first).chain(oid.iter().skip(2)) {
+        let mut bytes = vec![(n & 0x7f) as u8];
+        let mut n = n >> 7;
+        while n > 0 {
+            bytes.push(0x80 | (n & 0x7f) as u8);
+            n >>= 7;
+        }
+        value.extend(bytes.iter().rev());
+    }
+    tlv(TAG_OID, &value)
+}
+
+fn var_bind(name: &[u32], value: Vec<u8>) -> Vec<u8> {
+    let mut bind = oid(name);
+    bind.extend(value);
+    tlv(TAG_SEQUENCE, &bind)
+}
+
+fn sub_oid(prefix: &[u32], suffix: &[u32]) -> Vec<u32> {
+    prefix.iter().chain(suffix).copied().collect()
+}
+
+// Complete trap message
+fn encode_trap(
+    conf: &SnmpConfig,
+    request_id: i32,
+    uptime_cs: u32,
+    filter: &str,
+    count: u32,
+) -> Vec<u8> {
+    let trap = if count > 0 { 1 } else { 2 };
+    let mut binds = var_bind(SYS_UP_TIME, integer(TAG_TIME_TICKS, uptime_cs.into()));
+    binds.extend(var_bind(
+        SNMP_TRAP_OID,
+        oid(&sub_oid(&conf.enterprise, &[0, trap])),
+    ));
+    binds.extend(var_bind(
+        &sub_oid(&conf.enterprise, &[1, 0]),
+        tlv(TAG_OCTET_STRING, filter.as_bytes()),
+    ));
+    binds.extend(var_bind(
+        &sub_oid(&conf.enterprise, &[2, 0]),
+        integer(TAG_INTEGER, count.into()),
+    ));
+    let mut pdu = integer(TAG_INTEGER, request_id.into());
+    pdu.extend(integer(TAG_INTEGER, 0)); // error-status
+    pdu.extend(integer(TAG_INTEGER, 0)); // error-index
+    pdu.extend(tlv(TAG_SEQUENCE, &binds));
+    let mut msg = integer(TAG_INTEGER, 1); // SNMPv2c
+    msg.extend(tlv(TAG_OCTET_STRING, conf.community.as_bytes()));
+    msg.extend(tlv(TAG_TRAP_V2, &pdu));
+    tlv(TAG_SEQUENCE, &msg)
+}
+
+async fn send(conf: &SnmpConfig, msg: &[u8]) -> DynResult<()> {
+    let target = tokio::net::lookup_host(&conf.target)
+        .await?
+        .next()
+        .ok_or("No address found")?;
+    let local = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.send_to(msg, target).await?;
+    Ok(())
+}
+
+async fn monitor_filter(
+    conf: Arc<SnmpConfig>,
+    alarm_ctxt: Arc<AlarmContext>,
+    filter: String,
+    started: Instant,
+) {
+    let mut active = false;
+    let mut request_id: i32 = 0;
+    loop {
+        let (count, changed) = match alarm_ctxt.wait_alarm_filter(&filter) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Failed to wait for alarm filter {}: {}", filter, e);
+                return;
+            }
+        };
+        if (count > 0) != active {
+            active = count > 0;
+            request_id = request_id.wrapping_add(1);
+            let uptime = (started.elapsed().as_millis() / 10) as u32;
+            let msg = encode_trap(&conf, request_id, uptime, &filter, count);
+            debug!("Sending SNMP trap for alarm filter {}", filter);
+            if let Err(e) = send(&conf, &msg).await {
+                error!("Failed to send SNMP trap to {}: {}", conf.target, e);
+            }
+        }
+        if changed.await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Send traps for all configured filters. Filters active when started
+/// are reported immediately.
+pub fn start(conf: &SnmpConfig, alarm_ctxt: &Arc<AlarmContext>) -> DynResult<()> {
+    let conf = Arc::new(conf.clone());
+    let started = Instant::now();
+    for filter in &conf.filters {
+        alarm_ctxt
+            .get_filter_count(filter)
+            .map_err(|e| format!("Alarm filter {} for SNMP traps: {}", filter, e))?;
+        tokio::spawn(monitor_filter(
+            conf.clone(),
+            alarm_ctxt.clone(),
+            filter.clone(),
+            started,
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_encoding() {
+    assert_eq!(
+        oid(SYS_UP_TIME),
+        [0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x03, 0x00]
+    );
+    assert_eq!(oid(&[1, 3, 6, 1, 4, 1, 99999])[7..], [0x86, 0x8d, 0x1f]);
+    assert_eq!(integer(TAG_INTEGER, 0), [0x02, 0x01, 0x00]);
+    assert_eq!(integer(TAG_INTEGER, 128), [0x02, 0x02, 0x00, 0x80]);
+    assert_eq!(integer(TAG_INTEGER, -129), [0x02, 0x02, 0xff, 0x7f]);
+    let long = tlv(TAG_OCTET_STRING, &[0; 200]);
+    assert_eq!(long[..3], [0x04, 0x81, 200]);
+    let conf = SnmpConfig {
+        target: "localhost:162".to_string(),
+        community: "public".to_string(),
+        enterprise: vec![1, 3, 6, 1, 4, 1, 99999],
+        filters: vec![],
+    };
+    let msg = encode_trap(&conf, 1, 100, "fire", 2);
+    assert_eq!(msg[0], TAG_SEQUENCE);
+    assert_eq!(usize::from(msg[1]) + 2, msg.len());
+}
@@ -1,6 +1,11 @@
 use crate::actions::action::Action;
+use crate::actions::tag_dispatcher::TagDispatcher;
+use crate::actions::tag_setter::TagSetter;
+use crate::app_config::TagContext;
+use crate::metrics;
 use crate::util::error::DynResult;
-use std::sync::{Arc, Mutex};
+use crate::util::event_limit::{EventLimit, EventLimitExceeded};
+use std::sync::{Arc, Mutex, Weak};
 use tokio::sync::Notify;
 
 struct State {
@@ -12,6 +17,17 @@ struct StateMachineMut {
     states: Vec<State>,
     active_state: Option<usize>,
     restart: bool, // Restart the state if it's already running
+    // Limits how often states are entered
+    event_limit: Option<EventLimit>,
+    tags: Option<StateTags>,
+}
+
+// Tags for observing and changing the state from outside
+#[derive(Clone)]
+struct StateTags {
+    state: Option<String>,
+    command: Option<String>,
+    tag_ctxt: Weak<TagContext>,
 }
 
 pub struct StateMachine {
@@ -29,6 +45,8 @@ impl StateMachine {
                 states: Vec::new(),
                 active_state: None,
                 restart: false,
+                event_limit: None,
+                tags: None,
             }),
         })
     }
@@ -53,11 +71,45 @@ impl StateMachine {
         None
     }
 
+    /// Name of the active state, None if the state machine isn't running
+    pub fn active_state_name(&self) -> Option<String> {
+        let current = self.current.lock().unwrap();
+        current
+            .active_state
+            .map(|index| current.states[index].name.clone())
+    }
+
+    /// Action run when entering a state
+    pub fn state_action(&self, state_index: usize) -> Option<Arc<dyn Action + Send + Sync>> {
+        let current = self.current.lock().unwrap();
+        current.states.get(state_index)?.action.clone()
+    }
+
     pub fn set_action(self: &Arc<Self>, state_index: usize, action: Arc<dyn Action + Send + Sync>) {
         let mut current = self.current.lock().unwrap();
         current.states[state_index].action = Some(action);
     }
 
+    /// Pause the state machine if it changes state too often
+    pub fn set_event_limit(self: &Arc<Self>, limit: EventLimit) {
+        self.current.lock().unwrap().event_limit = Some(limit);
+    }
+
+    /// Set `state_tag` to the name of each state entered, and go to
+    /// the state named by `command_tag` when it's written
+    pub fn set_state_tags(
+        self: &Arc<Self>,
+        state_tag: Option<String>,
+        command_tag: Option<String>,
+        tag_ctxt: &Arc<TagContext>,
+    ) {
+        self.current.lock().unwrap().tags = Some(StateTags {
+            state: state_tag,
+            command: command_tag,
+            tag_ctxt: Arc::downgrade(tag_ctxt),
+        });
+    }
+
     pub async fn stop(self: &Arc<Self>) {
         let mut current = self.current.lock().unwrap();
         current.active_state = None;
@@ -65,6 +117,13 @@ impl StateMachine {
     }
 
     pub async fn run(self: &Arc<Self>) -> DynResult<()> {
+        tokio::select! {
+            res = self.run_states() => res,
+            res = self.follow_command_tag() => res,
+        }
+    }
+
+    async fn run_states(self: &Arc<Self>) -> DynResult<()> {
         log::debug!("State machine {} running", self.name);
         {
             let mut current = self
@@ -76,26 +135,43 @@ impl StateMachine {
         let mut running_action = None;
         let mut running_state = None;
         loop {
+            let mut exceeded = None;
+            let mut entered = None;
             {
                 let mut current = self
                     .current
                     .lock()
                     .map_err(|_| "Failed to lock state-machine")?;
                 if running_state != current.active_state || current.restart {
-                    if let Some(active_state) = current.active_state {
-                        if let Some(action) = &current.states[active_state].action {
-                            running_action = Some(action.run());
-                            /*
-                            log::debug!(
-                                "Running action for state {}",
-                                &current.states[active_state].name
-                            );*/
+                    if let Some(limit) = &current.event_limit {
+                        if let Err(e) = limit.count() {
+                            exceeded = Some(e);
                         }
-                    } else {
-                        break;
                     }
-                    running_state = current.active_state;
-                    current.restart = false;
+                    if exceeded.is_none() {
+                        if let Some(active_state) = current.active_state {
+                            if running_state != current.active_state {
+                                let name = &current.states[active_state].name;
+                                metrics::state_entered(&self.name, name);
+                                entered = Some(name.clone());
+                            }
+                            if let Some(action) = &current.states[active_state].action {
+                                running_action = Some(action.run());
+                                /*
+                                log::debug!(
+                                    "Running action for state {}",
+                                    &current.states[active_state].name
+                                );*/
+                            }
+                        } else {
+                            break;
+                        }
+                        running_state = current.active_state;
+                        current.restart = false;
+                    }
+                }
+                if let (Some(state), Some(tags)) = (entered, &current.tags) {
+                    Self::publish_state(tags, &state);
                 }
             }
             if let Some(running) = &mut running_action {
@@ -106,7 +182,11 @@ impl StateMachine {
                                 Ok(_) => {
                     running_action = None;
                                 }
-                                Err(e) => return Err(e),
+                                // A repeat looping too fast
+                                Err(e) => match e.downcast::<EventLimitExceeded>() {
+                                    Ok(e) => exceeded = Some(*e),
+                                    Err(e) => return Err(e),
+                                }
                             }
                         }
                         _ = self.current_changed.notified() => {
@@ -115,14 +195,80 @@ impl StateMachine {
 
                         }
                     }
-            } else {
+            } else if exceeded.is_none() {
                 self.current_changed.notified().await;
             }
+            if let Some(e) = exceeded {
+                running_action = None;
+                self.pause(&e).await;
+            }
             //log::debug!("Loop done");
         }
         Ok(())
     }
 
+    fn publish_state(tags: &StateTags, state: &str) {
+        if let (Some(tag), Some(tag_ctxt)) = (&tags.state, tags.tag_ctxt.upgrade()) {
+            if let Err(e) = tag_ctxt.set_tag(tag, state) {
+                log::error!("Failed to set state tag {}: {}", tag, e);
+            }
+        }
+    }
+
+    // Go to the state written to the command tag. The tag is cleared
+    // afterwards so the same state can be commanded again. Never
+    // returns unless there's an error.
+    async fn follow_command_tag(self: &Arc<Self>) -> DynResult<()> {
+        let tags = self.current.lock().unwrap().tags.clone();
+        let (tag, tag_ctxt) = match tags {
+            Some(StateTags {
+                command: Some(tag),
+                tag_ctxt,
+                ..
+            }) => (tag, tag_ctxt),
+            _ => return std::future::pending().await,
+        };
+        // A value present at startup is an old command
+        let mut changed = {
+            let tag_ctxt = tag_ctxt.upgrade().ok_or("Tag context dropped")?;
+            tag_ctxt.wait_value(&tag)?.1
+        };
+        loop {
+            let command = changed.await?;
+            let tag_ctxt = tag_ctxt.upgrade().ok_or("Tag context dropped")?;
+            // Start waiting before clearing the tag so no write is missed
+            let (_, next) = tag_ctxt.wait_value(&tag)?;
+            changed = next;
+            if command.is_empty() {
+                continue;
+            }
+            match self.find_state_index(&command) {
+                Some(index) => {
+                    log::info!("State machine {} commanded to {}", self.name, command);
+                    self.goto(index).await;
+                }
+                None => log::warn!("State machine {} has no state {}", self.name, command),
+            }
+            if let Err(e) = tag_ctxt.set_tag(&tag, "") {
+                log::error!("Failed to clear command tag {}: {}", tag, e);
+            }
+        }
+    }
+
+    // Stop running actions for a while after looping too fast, then
+    // restart the active state
+    async fn pause(&self, err: &EventLimitExceeded) {
+        log::error!(
+            "State machine {} is looping too fast ({}), pausing for {} s",
+            self.name,
+            err,
+            err.cooldown.as_secs_f32()
+        );
+        tokio::time::sleep(err.cooldown).await;
+        log::warn!("State machine {} resumed", self.name);
+        self.current.lock().unwrap().restart = true;
+    }
+
     pub async fn goto(self: &Arc<Self>, state_index: usize) {
         let mut current = self.current.lock().unwrap();
         if current.states.len() <= state_index {
@@ -139,7 +285,7 @@ impl StateMachine {
 use test_log::test;
 
 #[cfg(test)]
-#[test(tokio::test)]
+#[test(tokio::test(start_paused = true))]
 pub async fn test_state_machine() {
     use crate::actions::*;
     let mut sm = StateMachine::new("SM1");
@@ -163,3 +309,38 @@ pub async fn test_state_machine() {
     sm.goto(0).await;
     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
 }
+
+#[cfg(test)]
+#[test(tokio::test(start_paused = true))]
+pub async fn test_state_tags() {
+    use tokio::time::{sleep, Duration};
+
+    let (tag_send_tx, _tag_send_rx) = crate::app_config::tag_write_channel();
+    let tag_ctxt = Arc::new(TagContext::new(tag_send_tx));
+    tag_ctxt.add_local_tag("SM1_STATE", None);
+    tag_ctxt.add_local_tag("SM1_CMD", Some("alarm".to_string()));
+    let sm = StateMachine::new("SM1");
+    sm.add_state("idle");
+    sm.add_state("alarm");
+    sm.set_state_tags(
+        Some("SM1_STATE".to_string()),
+        Some("SM1_CMD".to_string()),
+        &tag_ctxt,
+    );
+    let running = sm.clone();
+    tokio::spawn(async move { running.run().await });
+    sleep(Duration::from_millis(10)).await;
+    // The command present at startup is ignored
+    assert_eq!(tag_ctxt.get_value("SM1_STATE").as_deref(), Some("idle"));
+
+    tag_ctxt.set_tag("SM1_CMD", "alarm").unwrap();
+    sleep(Duration::from_millis(10)).await;
+    assert_eq!(sm.active_state_name().as_deref(), Some("alarm"));
+    assert_eq!(tag_ctxt.get_value("SM1_STATE").as_deref(), Some("alarm"));
+    assert_eq!(tag_ctxt.get_value("SM1_CMD").as_deref(), Some(""));
+
+    tag_ctxt.set_tag("SM1_CMD", "missing").unwrap();
+    sleep(Duration::from_millis(10)).await;
+    assert_eq!(sm.active_state_name().as_deref(), Some("alarm"));
+    assert_eq!(tag_ctxt.get_value("SM1_CMD").as_deref(), Some(""));
+}
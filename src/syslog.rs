@@ -0,0 +1,214 @@
+//! Sends log records to a remote syslog server
+//!
+//! Records are formatted according to RFC 5424. UDP sends one record per
+//! datagram, TCP uses octet counting framing (RFC 6587).
+
+use crate::util::error::DynResult;
+use flexi_logger::writers::LogWriter;
+use flexi_logger::DeferredNow;
+use log::{Level, Record};
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+use std::sync::Mutex;
+
+// Facility "daemon"
+const FACILITY: u8 = 3;
+
+#[derive(Debug, PartialEq)]
+enum Protocol {
+    Udp,
+    Tcp,
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+struct Target {
+    address: String,
+    connection: Option<Connection>,
+}
+
+// The server to send records to. Records are dropped if not set.
+static TARGET: Mutex<Option<Target>> = Mutex::new(None);
+
+// Parse "udp://host:port" or "tcp://host:port". The port defaults to
+// 514 for UDP and 601 for TCP.
+fn parse_url(url: &str) -> DynResult<(Protocol, String)> {
+    let (protocol, rest) = match url.split_once("://") {
+        Some(("udp", rest)) => (Protocol::Udp, rest),
+        Some(("tcp", rest)) => (Protocol::Tcp, rest),
+        Some((p, _)) => return Err(format!("Unsupported syslog protocol '{}'", p).into()),
+        None => (Protocol::Udp, url),
+    };
+    if rest.is_empty() {
+        return Err("No syslog server given".into());
+    }
+    let has_port = match rest.rsplit_once(':') {
+        // An IPv6 address without port is enclosed in brackets
+        Some((_, port)) => !port.ends_with(']'),
+        None => false,
+    };
+    let address = if has_port {
+        rest.to_string()
+    } else {
+        let port = match protocol {
+            Protocol::Udp => 514,
+            Protocol::Tcp => 601,
+        };
+        format!("{}:{}", rest, port)
+    };
+    Ok((protocol, address))
+}
+
+fn connect(protocol: &Protocol, address: &str) -> std::io::Result<Connection> {
+    match protocol {
+        Protocol::Udp => {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(address)?;
+            Ok(Connection::Udp(socket))
+        }
+        Protocol::Tcp => Ok(Connection::Tcp(TcpStream::connect(address)?)),
+    }
+}
+
+/// Start sending log records to the server at `url`
+pub fn set_server(url: &str) -> DynResult<()> {
+    let (protocol, address) = parse_url(url)?;
+    let connection = connect(&protocol, &address)
+        .map_err(|e| format!("Failed to connect to syslog server {}: {}", address, e))?;
+    *TARGET.lock().unwrap() = Some(Target {
+        address,
+        connection: Some(connection),
+    });
+    Ok(())
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn format_message(
+    timestamp: &str,
+    hostname: &str,
+    app_name: &str,
+    level: Level,
+    message: &str,
+) -> String {
+    format!(
+        "<{}>1 {} {} {} {} - - {}",
+        FACILITY * 8 + severity(level),
+        timestamp,
+        hostname,
+        app_name,
+        std::process::id(),
+        message
+    )
+}
+
+/// Writer for flexi_logger. Does nothing until a server is set.
+pub struct SyslogWriter {
+    hostname: String,
+    app_name: String,
+}
+
+impl SyslogWriter {
+    /// `instance` is added to the application name
+    pub fn new(instance: Option<&str>) -> SyslogWriter {
+        let app_name = match instance {
+            Some(instance) => format!("mtp_audioplayer@{}", instance),
+            None => "mtp_audioplayer".to_string(),
+        };
+        SyslogWriter {
+            hostname: hostname(),
+            app_name,
+        }
+    }
+}
+
+impl LogWriter for SyslogWriter {
+    fn write(&self, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+        let mut target = TARGET.lock().unwrap();
+        let target = match &mut *target {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        let message = format_message(
+            &now.format_rfc3339().to_string(),
+            &self.hostname,
+            &self.app_name,
+            record.level(),
+            &record.args().to_string(),
+        );
+        // Reconnect a TCP connection that failed earlier
+        if target.connection.is_none() {
+            target.connection = Some(connect(&Protocol::Tcp, &target.address)?);
+        }
+        let res = match target.connection.as_mut().unwrap() {
+            Connection::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Connection::Tcp(stream) => write!(stream, "{} {}", message.len(), message),
+        };
+        if res.is_err() {
+            if let Some(Connection::Tcp(_)) = target.connection {
+                target.connection = None;
+            }
+        }
+        res
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        if let Some(Target {
+            connection: Some(Connection::Tcp(stream)),
+            ..
+        }) = &mut *TARGET.lock().unwrap()
+        {
+            stream.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_url() {
+    assert_eq!(
+        parse_url("udp://log.example.com").unwrap(),
+        (Protocol::Udp, "log.example.com:514".to_string())
+    );
+    assert_eq!(
+        parse_url("tcp://10.0.0.1:1514").unwrap(),
+        (Protocol::Tcp, "10.0.0.1:1514".to_string())
+    );
+    assert_eq!(
+        parse_url("[::1]").unwrap(),
+        (Protocol::Udp, "[::1]:514".to_string())
+    );
+    assert!(parse_url("http://log.example.com").is_err());
+    assert_eq!(
+        format_message(
+            "2024-01-02T03:04:05+00:00",
+            "panel",
+            "mtp_audioplayer",
+            Level::Warn,
+            "Hi"
+        )
+        .split_once(' ')
+        .unwrap()
+        .0,
+        "<28>1"
+    );
+}
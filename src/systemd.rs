@@ -1,10 +1,13 @@
-use crate::flexi_setup::{add_flexi_args, setup_flexi_loggger};
+use crate::flexi_setup::{
+    add_flexi_args, flexi_set_log_level, json_requested, setup_flexi_loggger,
+};
 use clap::{Arg, ArgMatches, Command};
 use flexi_logger::LoggerHandle;
 use log::{info, warn, LevelFilter};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use systemd::daemon::notify;
-use systemd::daemon::{STATE_READY, STATE_STOPPING};
+use systemd::daemon::{STATE_READY, STATE_STATUS, STATE_STOPPING, STATE_WATCHDOG};
 use systemd::journal::JournalLog;
 
 static DAEMON: AtomicBool = AtomicBool::new(true);
@@ -28,7 +31,7 @@ pub enum LogCtxt {
 pub fn start(args: &ArgMatches) -> LogCtxt {
     let ctxt;
     DAEMON.store(!args.is_present("no_systemd"), Ordering::Relaxed);
-    if !DAEMON.load(Ordering::Relaxed) || args.is_present("log_file") {
+    if !DAEMON.load(Ordering::Relaxed) || args.is_present("log_file") || json_requested(args) {
         match setup_flexi_loggger(args) {
             Ok(handle) => {
                 ctxt = LogCtxt::Flexi(handle);
@@ -61,6 +64,69 @@ pub fn ready() {
     }
 }
 
+/// Watchdog timeout set by systemd, if the watchdog is enabled for this
+/// process. [`watchdog`] must be called more often than this.
+pub fn watchdog_interval() -> Option<Duration> {
+    if !DAEMON.load(Ordering::Relaxed) {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // The watchdog may be meant for another process
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// Tell systemd that the server is alive
+pub fn watchdog() {
+    if let Err(e) = notify(false, [(STATE_WATCHDOG, "1")].iter()) {
+        warn!("Failed to notify systemd watchdog: {}", e);
+    }
+}
+
+/// Change the log level. None restores the level set at startup.
+pub fn set_log_level(ctxt: &mut LogCtxt, level: Option<LevelFilter>) {
+    match ctxt {
+        LogCtxt::None => {}
+        LogCtxt::Journal => log::set_max_level(level.unwrap_or(LevelFilter::Info)),
+        LogCtxt::Flexi(handle) => flexi_set_log_level(handle, level),
+    }
+}
+
+/// Show a one line description of what the server is doing in
+/// `systemctl status`
+pub fn status(status: &str) {
+    if DAEMON.load(Ordering::Relaxed) {
+        if let Err(e) = notify(false, [(STATE_STATUS, status)].iter()) {
+            warn!("Failed to notify systemd of status: {}", e);
+        }
+    }
+}
+
+/// Never returns, systemd stops the server with a signal
+pub async fn stop_requested() {
+    std::future::pending().await
+}
+
+/// The server is about to abort after a panic
+pub fn panicked(message: &str) {
+    if DAEMON.load(Ordering::Relaxed) {
+        let status = format!("Panic: {}", message);
+        if let Err(e) = notify(
+            false,
+            [(STATE_STOPPING, "1"), (STATE_STATUS, status.as_str())].iter(),
+        ) {
+            warn!("Failed to notify systemd of panic: {}", e);
+        }
+    }
+}
+
 pub fn exiting(_ctxt: LogCtxt) {
     if DAEMON.load(Ordering::Relaxed) {
         if let Err(e) = notify(false, [(STATE_STOPPING, "1")].iter()) {
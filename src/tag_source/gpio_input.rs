@@ -0,0 +1,111 @@
+//! Tags following the level of GPIO input lines
+//!
+//! Each line is watched for edges through the Linux GPIO character
+//! device. A new level is written to the tag when the line has been
+//! stable for the debounce time. The tags are read only.
+
+use crate::app_config::{TagContext, TagSetRequest};
+use crate::read_config::{GpioInputConfig, GpioInputLineConfig};
+use crate::util::error::DynResult;
+use futures::StreamExt;
+use gpio_cdev::{AsyncLineEventHandle, Chip, EventRequestFlags, LineRequestFlags};
+use log::{debug, error};
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::timeout;
+
+const CONSUMER: &str = "mtp_audioplayer";
+
+fn request_line(chip: &mut Chip, conf: &GpioInputLineConfig) -> DynResult<AsyncLineEventHandle> {
+    let line = chip.get_line(conf.line)?;
+    let mut flags = LineRequestFlags::INPUT;
+    if conf.active_low {
+        flags |= LineRequestFlags::ACTIVE_LOW;
+    }
+    let handle = line.events(flags, EventRequestFlags::BOTH_EDGES, CONSUMER)?;
+    Ok(AsyncLineEventHandle::new(handle)?)
+}
+
+// Returns false if the line can't be watched any longer
+async fn wait_stable(events: &mut AsyncLineEventHandle, debounce: Duration) -> bool {
+    match events.next().await {
+        Some(Ok(_)) => {}
+        Some(Err(e)) => {
+            error!("Failed to read GPIO event: {}", e);
+            return false;
+        }
+        None => return false,
+    }
+    // Every new edge restarts the debounce time
+    loop {
+        match timeout(debounce, events.next()).await {
+            Err(_) => return true,
+            Ok(Some(Ok(_))) => {}
+            Ok(Some(Err(e))) => {
+                error!("Failed to read GPIO event: {}", e);
+                return false;
+            }
+            Ok(None) => return false,
+        }
+    }
+}
+
+async fn watch_line(
+    mut events: AsyncLineEventHandle,
+    tag: String,
+    debounce: Duration,
+    tag_ctxt: Weak<TagContext>,
+) {
+    let mut reported = None;
+    loop {
+        let value = match events.as_ref().get_value() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to read GPIO line for tag {}: {}", tag, e);
+                return;
+            }
+        };
+        if reported != Some(value) {
+            let tag_ctxt = match tag_ctxt.upgrade() {
+                Some(t) => t,
+                None => return,
+            };
+            debug!("GPIO input {} = {}", tag, value);
+            tag_ctxt.tag_changed(&tag, &value.to_string());
+            reported = Some(value);
+        }
+        if !wait_stable(&mut events, debounce).await {
+            return;
+        }
+    }
+}
+
+async fn reject_writes(mut writes: Receiver<TagSetRequest>) {
+    while let Some(req) = writes.recv().await {
+        let err = format!("Tag {} is a GPIO input", req.tag_name);
+        let _ = req.done.send(Err(err.into()));
+    }
+}
+
+/// Request all lines and start watching them. Fails if any line is
+/// unavailable.
+pub fn start(
+    conf: GpioInputConfig,
+    tag_ctxt: Weak<TagContext>,
+    writes: Receiver<TagSetRequest>,
+) -> DynResult<()> {
+    let mut chip =
+        Chip::new(&conf.chip).map_err(|e| format!("Failed to open {}: {}", conf.chip, e))?;
+    let mut handles = Vec::new();
+    for line in &conf.lines {
+        let events = request_line(&mut chip, line)
+            .map_err(|e| format!("GPIO line {} on {}: {}", line.line, conf.chip, e))?;
+        handles.push((events, line.tag.clone()));
+    }
+    for (events, tag) in handles {
+        tokio::spawn(watch_line(events, tag, conf.debounce, tag_ctxt.clone()));
+    }
+    tokio::spawn(reject_writes(writes));
+    Ok(())
+}
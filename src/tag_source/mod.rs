@@ -0,0 +1,88 @@
+//! Tags exchanged with other systems than Open Pipe
+//!
+//! Each source feeds values to the tag context and receives the writes
+//! to its tags. Protocol support is selected with features.
+
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+pub mod gpio_input;
+#[cfg(feature = "modbus")]
+pub mod modbus;
+#[cfg(feature = "opcua")]
+pub mod opc_ua;
+#[cfg(feature = "s7")]
+pub mod s7;
+
+use crate::app_config::{TagContext, TagSetRequest};
+use crate::read_config::TagSourceConfig;
+use crate::util::error::DynResult;
+use std::sync::Weak;
+use tokio::sync::mpsc::Receiver;
+
+// Not used when all protocols are included
+#[allow(dead_code)]
+fn unsupported(protocol: &str) -> DynResult<()> {
+    Err(format!("Built without {} support", protocol).into())
+}
+
+/// Fails if support for the source isn't included in this build
+pub fn check_supported(conf: &TagSourceConfig) -> DynResult<()> {
+    match conf {
+        #[cfg(feature = "opcua")]
+        TagSourceConfig::OpcUa(_) => Ok(()),
+        #[cfg(not(feature = "opcua"))]
+        TagSourceConfig::OpcUa(_) => unsupported("OPC UA"),
+        #[cfg(feature = "modbus")]
+        TagSourceConfig::Modbus(_) => Ok(()),
+        #[cfg(not(feature = "modbus"))]
+        TagSourceConfig::Modbus(_) => unsupported("Modbus"),
+        #[cfg(all(feature = "gpio", target_os = "linux"))]
+        TagSourceConfig::GpioInput(_) => Ok(()),
+        #[cfg(not(all(feature = "gpio", target_os = "linux")))]
+        TagSourceConfig::GpioInput(_) => unsupported("GPIO"),
+        #[cfg(feature = "s7")]
+        TagSourceConfig::S7(_) => Ok(()),
+        #[cfg(not(feature = "s7"))]
+        TagSourceConfig::S7(_) => unsupported("S7"),
+    }
+}
+
+/// Start the source. Runs until the tag context is dropped.
+#[cfg_attr(
+    not(any(
+        feature = "opcua",
+        feature = "modbus",
+        feature = "s7",
+        all(feature = "gpio", target_os = "linux")
+    )),
+    allow(unused_variables)
+)]
+pub fn start(
+    conf: TagSourceConfig,
+    tag_ctxt: Weak<TagContext>,
+    writes: Receiver<TagSetRequest>,
+) -> DynResult<()> {
+    match conf {
+        #[cfg(feature = "opcua")]
+        TagSourceConfig::OpcUa(conf) => opc_ua::start(conf, tag_ctxt, writes),
+        #[cfg(not(feature = "opcua"))]
+        TagSourceConfig::OpcUa(_) => unsupported("OPC UA"),
+        #[cfg(feature = "modbus")]
+        TagSourceConfig::Modbus(conf) => {
+            tokio::spawn(modbus::run(conf, tag_ctxt, writes));
+            Ok(())
+        }
+        #[cfg(not(feature = "modbus"))]
+        TagSourceConfig::Modbus(_) => unsupported("Modbus"),
+        #[cfg(all(feature = "gpio", target_os = "linux"))]
+        TagSourceConfig::GpioInput(conf) => gpio_input::start(conf, tag_ctxt, writes),
+        #[cfg(not(all(feature = "gpio", target_os = "linux")))]
+        TagSourceConfig::GpioInput(_) => unsupported("GPIO"),
+        #[cfg(feature = "s7")]
+        TagSourceConfig::S7(conf) => {
+            tokio::spawn(s7::run(conf, tag_ctxt, writes));
+            Ok(())
+        }
+        #[cfg(not(feature = "s7"))]
+        TagSourceConfig::S7(_) => unsupported("S7"),
+    }
+}
@@ -0,0 +1,221 @@
+//! Tags polled from a Modbus TCP server
+//!
+//! All tags are read every scan interval, tags are only updated when
+//! the value changes. The connection is retried until it succeeds.
+
+use crate::app_config::{TagContext, TagSetRequest};
+use crate::read_config::{ModbusConfig, ModbusRegister, ModbusTagConfig};
+use crate::util::error::DynResult;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{interval, sleep, MissedTickBehavior};
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::*;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+enum RawValue {
+    Coil(bool),
+    Register(u16),
+}
+
+fn bool_string(value: bool) -> String {
+    if value { "1" } else { "0" }.to_string()
+}
+
+// Scaled register value as a tag string
+fn register_string(tag: &ModbusTagConfig, raw: u16) -> String {
+    let raw = if tag.signed {
+        f64::from(raw as i16)
+    } else {
+        f64::from(raw)
+    };
+    // Round away errors from the scaling
+    let value = ((raw * tag.scale + tag.offset) * 1e6).round() / 1e6;
+    value.to_string()
+}
+
+fn raw_value(tag: &ModbusTagConfig, value: &str) -> DynResult<RawValue> {
+    let value = value.trim();
+    match tag.register {
+        ModbusRegister::Coil => Ok(RawValue::Coil(match value {
+            "1" | "true" => true,
+            "0" | "false" => false,
+            _ => return Err(format!("'{}' is not a boolean", value).into()),
+        })),
+        ModbusRegister::HoldingRegister => {
+            let value: f64 = value.parse()?;
+            let raw = ((value - tag.offset) / tag.scale).round();
+            let (min, max) = if tag.signed {
+                (f64::from(i16::MIN), f64::from(i16::MAX))
+            } else {
+                (0.0, f64::from(u16::MAX))
+            };
+            if !(min..=max).contains(&raw) {
+                return Err(format!("{} is out of range for the register", value).into());
+            }
+            Ok(RawValue::Register(if tag.signed {
+                raw as i16 as u16
+            } else {
+                raw as u16
+            }))
+        }
+        ModbusRegister::DiscreteInput | ModbusRegister::InputRegister => {
+            Err(format!("Tag {} is read only", tag.name).into())
+        }
+    }
+}
+
+fn first<T: Copy>(values: Vec<T>) -> io::Result<T> {
+    values
+        .first()
+        .copied()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Empty Modbus response"))
+}
+
+async fn read_tag(ctx: &mut Context, tag: &ModbusTagConfig) -> io::Result<String> {
+    Ok(match tag.register {
+        ModbusRegister::Coil => bool_string(first(ctx.read_coils(tag.address, 1).await?)?),
+        ModbusRegister::DiscreteInput => {
+            bool_string(first(ctx.read_discrete_inputs(tag.address, 1).await?)?)
+        }
+        ModbusRegister::HoldingRegister => register_string(
+            tag,
+            first(ctx.read_holding_registers(tag.address, 1).await?)?,
+        ),
+        ModbusRegister::InputRegister => {
+            register_string(tag, first(ctx.read_input_registers(tag.address, 1).await?)?)
+        }
+    })
+}
+
+async fn write_tag(ctx: &mut Context, address: u16, value: RawValue) -> io::Result<()> {
+    match value {
+        RawValue::Coil(value) => ctx.write_single_coil(address, value).await,
+        RawValue::Register(value) => ctx.write_single_register(address, value).await,
+    }
+}
+
+async fn connect(conf: &ModbusConfig) -> io::Result<Context> {
+    let addr = tokio::net::lookup_host(&conf.address)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address found"))?;
+    tcp::connect_slave(addr, Slave(conf.unit)).await
+}
+
+// Returns Ok when the tag context is gone
+async fn poll(
+    conf: &ModbusConfig,
+    ctx: &mut Context,
+    tag_ctxt: &Weak<TagContext>,
+    writes: &mut Receiver<TagSetRequest>,
+) -> io::Result<()> {
+    let mut scan = interval(conf.scan_interval);
+    scan.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Values last read, to only report changes
+    let mut last = HashMap::<&str, String>::new();
+    loop {
+        tokio::select! {
+            _ = scan.tick() => {
+                let tag_ctxt = match tag_ctxt.upgrade() {
+                    Some(t) => t,
+                    None => return Ok(()),
+                };
+                for tag in &conf.tags {
+                    let value = read_tag(ctx, tag).await?;
+                    if last.get(tag.name.as_str()) != Some(&value) {
+                        tag_ctxt.tag_changed(&tag.name, &value);
+                        last.insert(&tag.name, value);
+                    }
+                }
+            }
+            req = writes.recv() => {
+                let req = match req {
+                    Some(r) => r,
+                    None => return Ok(()),
+                };
+                let raw = match conf.tags.iter().find(|t| t.name == req.tag_name) {
+                    Some(tag) => raw_value(tag, &req.value).map(|raw| (tag.address, raw)),
+                    None => Err(format!("Tag {} has no Modbus address", req.tag_name).into()),
+                };
+                let (address, raw) = match raw {
+                    Ok(r) => r,
+                    Err(e) => {
+                        let _ = req.done.send(Err(e));
+                        continue;
+                    }
+                };
+                debug!("Writing {:?} to Modbus address {}", raw, address);
+                match write_tag(ctx, address, raw).await {
+                    Ok(()) => {
+                        let _ = req.done.send(Ok(()));
+                    }
+                    Err(e) => {
+                        let _ = req.done.send(Err(format!("Modbus write failed: {}", e).into()));
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Poll the server until the tag context is dropped
+pub async fn run(
+    conf: ModbusConfig,
+    tag_ctxt: Weak<TagContext>,
+    mut writes: Receiver<TagSetRequest>,
+) {
+    loop {
+        match connect(&conf).await {
+            Ok(mut ctx) => {
+                info!("Connected to Modbus server {}", conf.address);
+                match poll(&conf, &mut ctx, &tag_ctxt, &mut writes).await {
+                    Ok(()) => return,
+                    Err(e) => warn!("Modbus server {} failed: {}", conf.address, e),
+                }
+            }
+            Err(e) => warn!("Failed to connect to Modbus server {}: {}", conf.address, e),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[test]
+fn test_scaling() {
+    let tag = ModbusTagConfig {
+        name: "Level".to_string(),
+        register: ModbusRegister::HoldingRegister,
+        address: 0,
+        scale: 0.1,
+        offset: -10.0,
+        signed: true,
+    };
+    assert_eq!(register_string(&tag, 107), "0.7");
+    assert_eq!(register_string(&tag, 0xffff), "-10.1");
+    assert_eq!(raw_value(&tag, "0.7").unwrap(), RawValue::Register(107));
+    assert_eq!(
+        raw_value(&tag, "-10.1").unwrap(),
+        RawValue::Register(0xffff)
+    );
+    assert!(raw_value(&tag, "5000").is_err());
+    let coil = ModbusTagConfig {
+        register: ModbusRegister::Coil,
+        scale: 1.0,
+        offset: 0.0,
+        signed: false,
+        ..tag
+    };
+    assert_eq!(raw_value(&coil, "1").unwrap(), RawValue::Coil(true));
+    let input = ModbusTagConfig {
+        register: ModbusRegister::InputRegister,
+        ..coil
+    };
+    assert!(raw_value(&input, "1").is_err());
+}
@@ -0,0 +1,198 @@
+//! Tags subscribed from an OPC UA server
+//!
+//! Only anonymous connections without security are supported. The
+//! client library runs the session in threads of its own, reconnecting
+//! when the connection is lost.
+
+use crate::app_config::{TagContext, TagSetRequest};
+use crate::read_config::OpcUaConfig;
+use crate::util::error::DynResult;
+use log::{debug, error, info};
+use opcua::client::prelude::*;
+use opcua::sync::RwLock;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::mpsc::Receiver;
+
+// Tag values are strings, as on the pipe
+fn variant_to_string(value: &Variant) -> String {
+    match value {
+        Variant::Boolean(b) => if *b { "1" } else { "0" }.to_string(),
+        Variant::String(s) => s.as_ref().to_string(),
+        v => v.to_string(),
+    }
+}
+
+// Convert to the same type as the last value read from the node.
+// Strings are written if nothing has been read yet.
+fn string_to_variant(value: &str, last: Option<&Variant>) -> DynResult<Variant> {
+    let value = value.trim();
+    Ok(match last {
+        Some(Variant::Boolean(_)) => Variant::Boolean(match value {
+            "1" | "true" => true,
+            "0" | "false" => false,
+            _ => return Err(format!("'{}' is not a boolean", value).into()),
+        }),
+        Some(Variant::SByte(_)) => Variant::SByte(value.parse()?),
+        Some(Variant::Byte(_)) => Variant::Byte(value.parse()?),
+        Some(Variant::Int16(_)) => Variant::Int16(value.parse()?),
+        Some(Variant::UInt16(_)) => Variant::UInt16(value.parse()?),
+        Some(Variant::Int32(_)) => Variant::Int32(value.parse()?),
+        Some(Variant::UInt32(_)) => Variant::UInt32(value.parse()?),
+        Some(Variant::Int64(_)) => Variant::Int64(value.parse()?),
+        Some(Variant::UInt64(_)) => Variant::UInt64(value.parse()?),
+        Some(Variant::Float(_)) => Variant::Float(value.parse()?),
+        Some(Variant::Double(_)) => Variant::Double(value.parse()?),
+        _ => Variant::from(value),
+    })
+}
+
+fn write_node(
+    session: &RwLock<Session>,
+    node_id: &NodeId,
+    value: &str,
+    last: Option<&Variant>,
+) -> DynResult<()> {
+    let value = WriteValue {
+        node_id: node_id.clone(),
+        attribute_id: AttributeId::Value as u32,
+        index_range: UAString::null(),
+        value: DataValue::value_only(string_to_variant(value, last)?),
+    };
+    let results = session.read().write(&[value])?;
+    match results.first() {
+        Some(status) if status.is_good() => Ok(()),
+        Some(status) => Err(format!("Write rejected: {}", status).into()),
+        None => Err("No write result".into()),
+    }
+}
+
+fn subscribe(
+    session: &RwLock<Session>,
+    conf: &OpcUaConfig,
+    nodes: &HashMap<NodeId, String>,
+    last_values: &Arc<Mutex<HashMap<NodeId, Variant>>>,
+    tag_ctxt: Weak<TagContext>,
+) -> DynResult<()> {
+    let nodes_cb = nodes.clone();
+    let last_values = last_values.clone();
+    let callback = DataChangeCallback::new(move |items| {
+        let tag_ctxt = match tag_ctxt.upgrade() {
+            Some(t) => t,
+            None => return,
+        };
+        for item in items {
+            let node_id = &item.item_to_monitor().node_id;
+            if let (Some(tag), Some(value)) = (nodes_cb.get(node_id), &item.last_value().value) {
+                tag_ctxt.tag_changed(tag, &variant_to_string(value));
+                last_values
+                    .lock()
+                    .unwrap()
+                    .insert(node_id.clone(), value.clone());
+            }
+        }
+    });
+    let session = session.read();
+    let subscription = session.create_subscription(
+        conf.publish_interval.as_secs_f64() * 1000.0,
+        10,
+        30,
+        0,
+        0,
+        true,
+        callback,
+    )?;
+    let items: Vec<MonitoredItemCreateRequest> =
+        nodes.keys().map(|node_id| node_id.clone().into()).collect();
+    for result in
+        session.create_monitored_items(subscription, TimestampsToReturn::Neither, &items)?
+    {
+        if !result.status_code.is_good() {
+            return Err(format!("Failed to monitor node: {}", result.status_code).into());
+        }
+    }
+    Ok(())
+}
+
+/// Connect to the server and subscribe to all tags
+pub fn start(
+    conf: OpcUaConfig,
+    tag_ctxt: Weak<TagContext>,
+    mut writes: Receiver<TagSetRequest>,
+) -> DynResult<()> {
+    let mut nodes = HashMap::new();
+    let mut tag_nodes = HashMap::new();
+    for (tag, node) in &conf.tags {
+        let node_id = NodeId::from_str(node)
+            .map_err(|_| format!("Invalid OPC UA node id '{}' for tag {}", node, tag))?;
+        nodes.insert(node_id.clone(), tag.clone());
+        tag_nodes.insert(tag.clone(), node_id);
+    }
+    let mut client = ClientBuilder::new()
+        .application_name("MTP audio player")
+        .application_uri("urn:mtp_audioplayer")
+        .trust_server_certs(true)
+        .session_retry_limit(-1)
+        .client()
+        .ok_or("Invalid OPC UA client configuration")?;
+    let endpoint: EndpointDescription = (
+        conf.url.as_str(),
+        SecurityPolicy::None.to_str(),
+        MessageSecurityMode::None,
+        UserTokenPolicy::anonymous(),
+    )
+        .into();
+    // Connecting blocks, so do everything in a thread of our own
+    std::thread::spawn(move || {
+        let session = match client.connect_to_endpoint(endpoint, IdentityToken::Anonymous) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to connect to OPC UA server {}: {}", conf.url, e);
+                return;
+            }
+        };
+        info!("Connected to OPC UA server {}", conf.url);
+        let last_values = Arc::new(Mutex::new(HashMap::new()));
+        if let Err(e) = subscribe(&session, &conf, &nodes, &last_values, tag_ctxt) {
+            error!("Failed to subscribe to OPC UA tags: {}", e);
+            return;
+        }
+        let run_session = session.clone();
+        std::thread::spawn(move || Session::run(run_session));
+        while let Some(req) = writes.blocking_recv() {
+            let res = match tag_nodes.get(&req.tag_name) {
+                Some(node_id) => {
+                    debug!("Writing {} to OPC UA node {}", req.value, node_id);
+                    let last = last_values.lock().unwrap().get(node_id).cloned();
+                    write_node(&session, node_id, &req.value, last.as_ref())
+                }
+                None => Err(format!("Tag {} has no OPC UA node", req.tag_name).into()),
+            };
+            if let Err(e) = &res {
+                error!("Failed to write tag {} to OPC UA: {}", req.tag_name, e);
+            }
+            let _ = req.done.send(res);
+        }
+        // The tag context is gone, e.g. the configuration was reloaded
+        session.read().disconnect();
+        debug!("Disconnected from OPC UA server {}", conf.url);
+    });
+    Ok(())
+}
+
+#[test]
+fn test_variant_conversion() {
+    assert_eq!(variant_to_string(&Variant::Boolean(true)), "1");
+    assert_eq!(variant_to_string(&Variant::Int16(-3)), "-3");
+    assert_eq!(
+        string_to_variant("1", Some(&Variant::Boolean(false))).unwrap(),
+        Variant::Boolean(true)
+    );
+    assert_eq!(
+        string_to_variant(" 42", Some(&Variant::UInt16(0))).unwrap(),
+        Variant::UInt16(42)
+    );
+    assert!(string_to_variant("-1", Some(&Variant::Byte(0))).is_err());
+    assert_eq!(string_to_variant("on", None).unwrap(), Variant::from("on"));
+}
@@ -0,0 +1,368 @@
+//! Tags polled directly from an S7 PLC
+//!
+//! Data block variables are read and written with S7 communication over
+//! ISO-on-TCP (RFC 1006), the same protocol used by snap7. Each tag is
+//! read with a separate request every scan interval, tags are only
+//! updated when the value changes. The connection is retried until it
+//! succeeds.
+
+use crate::app_config::{TagContext, TagSetRequest};
+use crate::read_config::{S7Config, S7TagConfig, S7Type};
+use crate::util::error::DynResult;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{interval, sleep, timeout, MissedTickBehavior};
+
+const DEFAULT_PORT: u16 = 102;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// COTP PDU types
+const COTP_CONNECT_REQUEST: u8 = 0xe0;
+const COTP_CONNECT_CONFIRM: u8 = 0xd0;
+const COTP_DATA: u8 = 0xf0;
+
+// S7 message types and functions
+const S7_PROTOCOL_ID: u8 = 0x32;
+const S7_JOB: u8 = 0x01;
+const S7_ACK_DATA: u8 = 0x03;
+const S7_SETUP_COMMUNICATION: u8 = 0xf0;
+const S7_READ_VAR: u8 = 0x04;
+const S7_WRITE_VAR: u8 = 0x05;
+
+const AREA_DB: u8 = 0x84;
+const TRANSPORT_BIT: u8 = 0x01;
+const TRANSPORT_BYTE: u8 = 0x02;
+const DATA_BIT: u8 = 0x03;
+const DATA_BYTES: u8 = 0x04;
+const RETURN_SUCCESS: u8 = 0xff;
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Connection request with TSAPs selecting the CPU in rack and slot
+fn connect_request(rack: u8, slot: u8) -> Vec<u8> {
+    let mut cotp = vec![17, COTP_CONNECT_REQUEST];
+    cotp.extend([0x00, 0x00]); // Destination reference
+    cotp.extend([0x00, 0x01]); // Source reference
+    cotp.push(0x00); // Class 0
+    cotp.extend([0xc0, 0x01, 0x0a]); // TPDU size 1024
+    cotp.extend([0xc1, 0x02, 0x01, 0x00]); // Source TSAP
+    cotp.extend([0xc2, 0x02, 0x03, (rack << 5) | (slot & 0x1f)]); // Destination TSAP
+    tpkt(&cotp)
+}
+
+fn tpkt(payload: &[u8]) -> Vec<u8> {
+    let len = (payload.len() + 4) as u16;
+    let mut msg = vec![0x03, 0x00];
+    msg.extend(len.to_be_bytes());
+    msg.extend_from_slice(payload);
+    msg
+}
+
+// S7 job wrapped in a COTP data TPDU
+fn job(pdu_ref: u16, param: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut payload = vec![0x02, COTP_DATA, 0x80, S7_PROTOCOL_ID, S7_JOB, 0x00, 0x00];
+    payload.extend(pdu_ref.to_be_bytes());
+    payload.extend((param.len() as u16).to_be_bytes());
+    payload.extend((data.len() as u16).to_be_bytes());
+    payload.extend_from_slice(param);
+    payload.extend_from_slice(data);
+    tpkt(&payload)
+}
+
+// Address of a variable in the parameters of read and write requests
+fn var_item(tag: &S7TagConfig) -> Vec<u8> {
+    let (transport, count) = match tag.data_type {
+        S7Type::Bool => (TRANSPORT_BIT, 1),
+        t => (TRANSPORT_BYTE, t.size() as u16),
+    };
+    let address = tag.offset * 8 + u32::from(tag.bit);
+    let mut item = vec![0x12, 0x0a, 0x10, transport];
+    item.extend(count.to_be_bytes());
+    item.extend(tag.db.to_be_bytes());
+    item.push(AREA_DB);
+    item.extend(&address.to_be_bytes()[1..]);
+    item
+}
+
+fn read_param(tag: &S7TagConfig) -> Vec<u8> {
+    let mut param = vec![S7_READ_VAR, 1];
+    param.extend(var_item(tag));
+    param
+}
+
+fn write_request(tag: &S7TagConfig, value: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut param = vec![S7_WRITE_VAR, 1];
+    param.extend(var_item(tag));
+    let (transport, bits) = match tag.data_type {
+        S7Type::Bool => (DATA_BIT, 1),
+        _ => (DATA_BYTES, value.len() as u16 * 8),
+    };
+    let mut data = vec![0x00, transport];
+    data.extend(bits.to_be_bytes());
+    data.extend_from_slice(value);
+    (param, data)
+}
+
+// Split an acknowledge into parameters and data
+fn parse_ack(pdu: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    if pdu.len() < 12 || pdu[0] != S7_PROTOCOL_ID || pdu[1] != S7_ACK_DATA {
+        return Err(protocol_error("Unexpected S7 message".to_string()));
+    }
+    if pdu[10] != 0 || pdu[11] != 0 {
+        return Err(protocol_error(format!(
+            "S7 error class {:#04x}, code {:#04x}",
+            pdu[10], pdu[11]
+        )));
+    }
+    let param_len = usize::from(u16::from_be_bytes([pdu[6], pdu[7]]));
+    let data_len = usize::from(u16::from_be_bytes([pdu[8], pdu[9]]));
+    if pdu.len() < 12 + param_len + data_len {
+        return Err(protocol_error("Truncated S7 message".to_string()));
+    }
+    let data_start = 12 + param_len;
+    Ok((
+        &pdu[12..data_start],
+        &pdu[data_start..data_start + data_len],
+    ))
+}
+
+fn check_return_code(code: u8) -> io::Result<()> {
+    if code != RETURN_SUCCESS {
+        return Err(protocol_error(format!("S7 return code {:#04x}", code)));
+    }
+    Ok(())
+}
+
+// Value of the first item in a read acknowledge
+fn parse_read_data(data: &[u8], size: usize) -> io::Result<&[u8]> {
+    if data.len() < 4 {
+        return Err(protocol_error("Truncated S7 read reply".to_string()));
+    }
+    check_return_code(data[0])?;
+    if data.len() < 4 + size {
+        return Err(protocol_error("Truncated S7 read reply".to_string()));
+    }
+    Ok(&data[4..4 + size])
+}
+
+fn value_string(data_type: S7Type, raw: &[u8]) -> String {
+    match data_type {
+        S7Type::Bool => if raw[0] & 1 != 0 { "1" } else { "0" }.to_string(),
+        S7Type::Byte => raw[0].to_string(),
+        S7Type::Word => u16::from_be_bytes([raw[0], raw[1]]).to_string(),
+        S7Type::Int => i16::from_be_bytes([raw[0], raw[1]]).to_string(),
+        S7Type::DWord => u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]).to_string(),
+        S7Type::DInt => i32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]).to_string(),
+        S7Type::Real => f32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]).to_string(),
+    }
+}
+
+fn raw_value(data_type: S7Type, value: &str) -> DynResult<Vec<u8>> {
+    let value = value.trim();
+    Ok(match data_type {
+        S7Type::Bool => match value {
+            "1" | "true" => vec![1],
+            "0" | "false" => vec![0],
+            _ => return Err(format!("'{}' is not a boolean", value).into()),
+        },
+        S7Type::Byte => vec![value.parse::<u8>()?],
+        S7Type::Word => value.parse::<u16>()?.to_be_bytes().to_vec(),
+        S7Type::Int => value.parse::<i16>()?.to_be_bytes().to_vec(),
+        S7Type::DWord => value.parse::<u32>()?.to_be_bytes().to_vec(),
+        S7Type::DInt => value.parse::<i32>()?.to_be_bytes().to_vec(),
+        S7Type::Real => value.parse::<f32>()?.to_be_bytes().to_vec(),
+    })
+}
+
+struct Connection {
+    stream: TcpStream,
+    pdu_ref: u16,
+}
+
+impl Connection {
+    async fn connect(conf: &S7Config) -> io::Result<Connection> {
+        let addr = if conf.address.contains(':') {
+            conf.address.clone()
+        } else {
+            format!("{}:{}", conf.address, DEFAULT_PORT)
+        };
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let mut conn = Connection { stream, pdu_ref: 0 };
+        conn.stream
+            .write_all(&connect_request(conf.rack, conf.slot))
+            .await?;
+        let reply = conn.receive().await?;
+        if reply.len() < 2 || reply[1] != COTP_CONNECT_CONFIRM {
+            return Err(protocol_error("Connection refused by PLC".to_string()));
+        }
+        let mut param = vec![S7_SETUP_COMMUNICATION, 0x00];
+        param.extend(1u16.to_be_bytes()); // Parallel jobs calling
+        param.extend(1u16.to_be_bytes()); // Parallel jobs called
+        param.extend(480u16.to_be_bytes()); // PDU size, supported by all CPUs
+        conn.request(&param, &[]).await?;
+        Ok(conn)
+    }
+
+    // Read a TPKT and return its payload
+    async fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let mut header = [0u8; 4];
+        timeout(REPLY_TIMEOUT, self.stream.read_exact(&mut header))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No reply from PLC"))??;
+        let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+        if header[0] != 0x03 || len < 4 {
+            return Err(protocol_error("Invalid TPKT header".to_string()));
+        }
+        let mut payload = vec![0u8; len - 4];
+        self.stream.read_exact(&mut payload).await?;
+        Ok(payload)
+    }
+
+    // Send a job and return the acknowledge without the COTP header
+    async fn request(&mut self, param: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+        self.pdu_ref = self.pdu_ref.wrapping_add(1);
+        self.stream
+            .write_all(&job(self.pdu_ref, param, data))
+            .await?;
+        let mut reply = self.receive().await?;
+        let cotp_len = usize::from(*reply.first().unwrap_or(&0)) + 1;
+        if reply.len() < cotp_len + 1 || reply[1] != COTP_DATA {
+            return Err(protocol_error("Unexpected COTP message".to_string()));
+        }
+        let pdu = reply.split_off(cotp_len);
+        parse_ack(&pdu)?;
+        Ok(pdu)
+    }
+
+    async fn read_tag(&mut self, tag: &S7TagConfig) -> io::Result<String> {
+        let pdu = self.request(&read_param(tag), &[]).await?;
+        let (_, data) = parse_ack(&pdu)?;
+        let raw = parse_read_data(data, tag.data_type.size())?;
+        Ok(value_string(tag.data_type, raw))
+    }
+
+    async fn write_tag(&mut self, tag: &S7TagConfig, value: &[u8]) -> io::Result<()> {
+        let (param, data) = write_request(tag, value);
+        let pdu = self.request(&param, &data).await?;
+        let (_, data) = parse_ack(&pdu)?;
+        check_return_code(*data.first().unwrap_or(&0))
+    }
+}
+
+// Returns Ok when the tag context is gone
+async fn poll(
+    conf: &S7Config,
+    conn: &mut Connection,
+    tag_ctxt: &Weak<TagContext>,
+    writes: &mut Receiver<TagSetRequest>,
+) -> io::Result<()> {
+    let mut scan = interval(conf.scan_interval);
+    scan.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Values last read, to only report changes
+    let mut last = HashMap::<&str, String>::new();
+    loop {
+        tokio::select! {
+            _ = scan.tick() => {
+                let tag_ctxt = match tag_ctxt.upgrade() {
+                    Some(t) => t,
+                    None => return Ok(()),
+                };
+                for tag in &conf.tags {
+                    let value = conn.read_tag(tag).await?;
+                    if last.get(tag.name.as_str()) != Some(&value) {
+                        tag_ctxt.tag_changed(&tag.name, &value);
+                        last.insert(&tag.name, value);
+                    }
+                }
+            }
+            req = writes.recv() => {
+                let req = match req {
+                    Some(r) => r,
+                    None => return Ok(()),
+                };
+                let raw = match conf.tags.iter().find(|t| t.name == req.tag_name) {
+                    Some(tag) => raw_value(tag.data_type, &req.value).map(|raw| (tag, raw)),
+                    None => Err(format!("Tag {} has no S7 address", req.tag_name).into()),
+                };
+                let (tag, raw) = match raw {
+                    Ok(r) => r,
+                    Err(e) => {
+                        let _ = req.done.send(Err(e));
+                        continue;
+                    }
+                };
+                debug!("Writing {:?} to DB{}.{}", raw, tag.db, tag.offset);
+                match conn.write_tag(tag, &raw).await {
+                    Ok(()) => {
+                        let _ = req.done.send(Ok(()));
+                    }
+                    Err(e) => {
+                        let _ = req.done.send(Err(format!("S7 write failed: {}", e).into()));
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Poll the PLC until the tag context is dropped
+pub async fn run(conf: S7Config, tag_ctxt: Weak<TagContext>, mut writes: Receiver<TagSetRequest>) {
+    loop {
+        match Connection::connect(&conf).await {
+            Ok(mut conn) => {
+                info!("Connected to S7 PLC {}", conf.address);
+                match poll(&conf, &mut conn, &tag_ctxt, &mut writes).await {
+                    Ok(()) => return,
+                    Err(e) => warn!("S7 PLC {} failed: {}", conf.address, e),
+                }
+            }
+            Err(e) => warn!("Failed to connect to S7 PLC {}: {}", conf.address, e),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[test]
+fn test_encoding() {
+    let tag = S7TagConfig {
+        name: "Horn".to_string(),
+        data_type: S7Type::Bool,
+        db: 10,
+        offset: 4,
+        bit: 3,
+    };
+    assert_eq!(
+        var_item(&tag),
+        [0x12, 0x0a, 0x10, 0x01, 0x00, 0x01, 0x00, 0x0a, 0x84, 0x00, 0x00, 0x23]
+    );
+    let (_, data) = write_request(&tag, &[1]);
+    assert_eq!(data, [0x00, 0x03, 0x00, 0x01, 0x01]);
+    let request = connect_request(0, 1);
+    assert_eq!(request.len(), 22);
+    assert_eq!(request[21], 0x01);
+    let msg = job(1, &read_param(&tag), &[]);
+    assert_eq!(usize::from(u16::from_be_bytes([msg[2], msg[3]])), msg.len());
+
+    assert_eq!(value_string(S7Type::Int, &[0xff, 0xfe]), "-2");
+    assert_eq!(raw_value(S7Type::Int, "-2").unwrap(), [0xff, 0xfe]);
+    assert_eq!(value_string(S7Type::Real, &[0x3f, 0xc0, 0x00, 0x00]), "1.5");
+    assert!(raw_value(S7Type::Byte, "256").is_err());
+
+    let ack = [
+        0x32, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00, 0x06, 0x00, 0x00, 0x04, 0x01, 0xff,
+        0x04, 0x00, 0x10, 0x01, 0x02,
+    ];
+    let (_, data) = parse_ack(&ack).unwrap();
+    assert_eq!(parse_read_data(data, 2).unwrap(), [0x01, 0x02]);
+}
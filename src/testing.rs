@@ -0,0 +1,245 @@
+//! Builders for Open Pipe messages and alarms in tests
+//!
+//! All fields get usable defaults, so tests only need to set the fields
+//! they care about.
+//!
+//! ```
+//! use mtp_audioplayer::testing::{AlarmBuilder, MessageBuilder, TagBuilder};
+//!
+//! let alarm = AlarmBuilder::new(17).priority(10).class("Warning").build();
+//! let msg = MessageBuilder::new()
+//!     .cookie("c1")
+//!     .notify_tags(vec![TagBuilder::new("Level", "3").build()]);
+//! ```
+
+use crate::open_pipe::alarm_data::AlarmData;
+use crate::open_pipe::connection::{
+    ErrorInfo, Message, MessageVariant, NotifyAlarm, NotifyAlarms, NotifyTag, NotifyTags,
+    ParamWrapperCap, SubscribeAlarmParams, SubscribeTagParams, TagData, WriteTagParams,
+    WriteTagValue,
+};
+use chrono::{DateTime, Utc};
+
+/// Builds [`AlarmData`] or the corresponding [`NotifyAlarm`]. The alarm
+/// is raised by default.
+pub struct AlarmBuilder {
+    alarm: AlarmData,
+}
+
+impl AlarmBuilder {
+    pub fn new(id: i32) -> AlarmBuilder {
+        AlarmBuilder {
+            alarm: AlarmData {
+                name: format!("Alarm{}", id),
+                id,
+                alarm_class_name: "Alarm".to_string(),
+                alarm_class_symbol: "A".to_string(),
+                event_text: String::new(),
+                instance_id: 0,
+                priority: 0,
+                state: 1,
+                state_text: "Incoming".to_string(),
+                state_machine: 7,
+                modification_time: Utc::now(),
+            },
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.alarm.name = name.to_string();
+        self
+    }
+
+    /// Class name and symbol
+    pub fn class(mut self, name: &str) -> Self {
+        self.alarm.alarm_class_name = name.to_string();
+        self.alarm.alarm_class_symbol = name.chars().take(1).collect();
+        self
+    }
+
+    pub fn event_text(mut self, text: &str) -> Self {
+        self.alarm.event_text = text.to_string();
+        self
+    }
+
+    pub fn instance_id(mut self, instance_id: i32) -> Self {
+        self.alarm.instance_id = instance_id;
+        self
+    }
+
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.alarm.priority = priority;
+        self
+    }
+
+    /// State as a number, see [`AlarmState`](crate::alarm_filter::AlarmState)
+    pub fn state(mut self, state: i32, text: &str) -> Self {
+        self.alarm.state = state;
+        self.alarm.state_text = text.to_string();
+        self
+    }
+
+    pub fn state_machine(mut self, state_machine: i32) -> Self {
+        self.alarm.state_machine = state_machine;
+        self
+    }
+
+    pub fn modification_time(mut self, time: DateTime<Utc>) -> Self {
+        self.alarm.modification_time = time;
+        self
+    }
+
+    pub fn build(self) -> AlarmData {
+        self.alarm
+    }
+
+    pub fn notify(self) -> NotifyAlarm {
+        NotifyAlarm::from(self.alarm)
+    }
+}
+
+/// Builds a [`NotifyTag`] with good quality and no error
+pub struct TagBuilder {
+    tag: NotifyTag,
+}
+
+impl TagBuilder {
+    pub fn new(name: &str, value: &str) -> TagBuilder {
+        TagBuilder {
+            tag: NotifyTag {
+                data: TagData {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                    quality: "Good".to_string(),
+                    quality_code: 192,
+                },
+                time_stamp: Utc::now().to_rfc3339(),
+                error: ErrorInfo::default(),
+            },
+        }
+    }
+
+    pub fn quality(mut self, quality: &str, quality_code: i32) -> Self {
+        self.tag.data.quality = quality.to_string();
+        self.tag.data.quality_code = quality_code;
+        self
+    }
+
+    pub fn time_stamp(mut self, time: DateTime<Utc>) -> Self {
+        self.tag.time_stamp = time.to_rfc3339();
+        self
+    }
+
+    pub fn error(mut self, error_code: u32, error_description: &str) -> Self {
+        self.tag.error = ErrorInfo {
+            error_code,
+            error_description: error_description.to_string(),
+        };
+        self
+    }
+
+    pub fn build(self) -> NotifyTag {
+        self.tag
+    }
+}
+
+/// Builds a [`Message`]. Set the cookie first, then finish with one of
+/// the message types.
+pub struct MessageBuilder {
+    cookie: String,
+}
+
+impl MessageBuilder {
+    pub fn new() -> MessageBuilder {
+        MessageBuilder {
+            cookie: "test_cookie".to_string(),
+        }
+    }
+
+    pub fn cookie(mut self, cookie: &str) -> Self {
+        self.cookie = cookie.to_string();
+        self
+    }
+
+    pub fn message(self, message: MessageVariant) -> Message {
+        Message {
+            message,
+            client_cookie: self.cookie,
+        }
+    }
+
+    pub fn subscribe_tags(self, tags: &[&str]) -> Message {
+        let tags = tags.iter().map(|t| t.to_string()).collect();
+        self.message(MessageVariant::SubscribeTag(
+            SubscribeTagParams { tags }.into(),
+        ))
+    }
+
+    pub fn notify_tags(self, tags: Vec<NotifyTag>) -> Message {
+        self.message(MessageVariant::NotifySubscribeTag(
+            NotifyTags { tags }.into(),
+        ))
+    }
+
+    /// Tag names and values
+    pub fn write_tags(self, tags: &[(&str, &str)]) -> Message {
+        let tags = tags
+            .iter()
+            .map(|(name, value)| WriteTagValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect();
+        self.message(MessageVariant::WriteTag(WriteTagParams { tags }.into()))
+    }
+
+    pub fn subscribe_alarms(self) -> Message {
+        self.message(MessageVariant::SubscribeAlarm(ParamWrapperCap {
+            params: SubscribeAlarmParams {
+                system_names: None,
+                filter: None,
+                language_id: None,
+            },
+        }))
+    }
+
+    pub fn notify_alarms(self, alarms: Vec<NotifyAlarm>) -> Message {
+        self.message(MessageVariant::NotifySubscribeAlarm(
+            NotifyAlarms { alarms }.into(),
+        ))
+    }
+
+    pub fn error_subscribe_tags(self, error_code: u32, error_description: &str) -> Message {
+        self.message(MessageVariant::ErrorSubscribeTag(ErrorInfo {
+            error_code,
+            error_description: error_description.to_string(),
+        }))
+    }
+}
+
+impl Default for MessageBuilder {
+    fn default() -> MessageBuilder {
+        MessageBuilder::new()
+    }
+}
+
+#[test]
+fn test_builders() {
+    let notify = AlarmBuilder::new(3).name("Fire").priority(12).notify();
+    let alarm = AlarmData::from(notify.clone());
+    assert_eq!(alarm.name, "Fire");
+    assert_eq!(alarm.id, 3);
+    assert_eq!(alarm.priority, 12);
+    assert_eq!(alarm.state, 1);
+    let msg = MessageBuilder::new()
+        .cookie("c1")
+        .notify_alarms(vec![notify]);
+    assert_eq!(msg.client_cookie, "c1");
+    match msg.message {
+        MessageVariant::NotifySubscribeAlarm(n) => assert_eq!(n.params.alarms[0].id, "3"),
+        m => panic!("Unexpected message {:?}", m),
+    }
+    let tag = TagBuilder::new("Level", "5").quality("Bad", 0).build();
+    assert_eq!(tag.data.value, "5");
+    assert_eq!(tag.data.quality_code, 0);
+}
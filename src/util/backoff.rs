@@ -0,0 +1,40 @@
+//! Exponentially growing delays between retries
+
+use std::time::Duration;
+
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// Delay before the next attempt. Doubled each time, up to the maximum.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Start over from the initial delay, e.g. after succeeding
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+    let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+    assert_eq!(delays, [1, 2, 4, 5, 5]);
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+}
@@ -0,0 +1,169 @@
+//! Limits how often something may happen, to catch runaway loops
+//!
+//! Time is taken from tokio, so tests can pause and advance it.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct EventLimitExceeded {
+    pub max_events: u32,
+    pub window: Duration,
+    pub cooldown: Duration,
+}
+
+impl std::fmt::Display for EventLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "More than {} events in {} ms",
+            self.max_events,
+            self.window.as_millis()
+        )
+    }
+}
+
+impl Error for EventLimitExceeded {}
+
+/// How close a user of a limit has come to exceeding it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventLimitStats {
+    /// Number of times the limit was exceeded
+    pub triggered: u64,
+    /// Most events counted within one window
+    pub peak: u32,
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+/// Allows at most `max_events` events within a time window. Clones
+/// share the same count, users get a count of their own.
+#[derive(Clone)]
+pub struct EventLimit {
+    max_events: u32,
+    window: Duration,
+    /// How long to pause after the limit has been exceeded
+    pub cooldown: Duration,
+    current: Arc<Mutex<Window>>,
+    // Statistics are kept per user
+    user: String,
+    stats: Arc<Mutex<BTreeMap<String, EventLimitStats>>>,
+}
+
+impl EventLimit {
+    pub fn new(max_events: u32, window: Duration, cooldown: Duration) -> EventLimit {
+        EventLimit {
+            max_events,
+            window,
+            cooldown,
+            current: Arc::new(Mutex::new(Window {
+                start: Instant::now(),
+                count: 0,
+            })),
+            user: String::new(),
+            stats: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// A limit with the same settings but a separate count. Statistics
+    /// are kept under `user`, shared with this limit.
+    pub fn for_user(&self, user: &str) -> EventLimit {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(user.to_string())
+            .or_default();
+        EventLimit {
+            current: Arc::new(Mutex::new(Window {
+                start: Instant::now(),
+                count: 0,
+            })),
+            user: user.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// Statistics for all users of this limit
+    pub fn stats(&self) -> BTreeMap<String, EventLimitStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Count an event. Fails if there has been too many events in the
+    /// current window, the count then starts over.
+    pub fn count(&self) -> Result<(), EventLimitExceeded> {
+        let now = Instant::now();
+        let mut current = self.current.lock().unwrap();
+        if now.duration_since(current.start) >= self.window {
+            current.start = now;
+            current.count = 0;
+        }
+        current.count += 1;
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(self.user.clone()).or_default();
+        stats.peak = stats.peak.max(current.count.min(self.max_events));
+        if current.count > self.max_events {
+            stats.triggered += 1;
+            current.start = now;
+            current.count = 0;
+            return Err(EventLimitExceeded {
+                max_events: self.max_events,
+                window: self.window,
+                cooldown: self.cooldown,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_event_limit() {
+    let limit = EventLimit::new(3, Duration::from_secs(3600), Duration::ZERO);
+    let shared = limit.clone();
+    assert!(limit.count().is_ok());
+    assert!(limit.count().is_ok());
+    assert!(shared.count().is_ok());
+    assert!(limit.count().is_err());
+    assert!(limit.count().is_ok());
+
+    let limit = EventLimit::new(2, Duration::from_secs(3600), Duration::ZERO);
+    let machine = limit.for_user("machine");
+    let repeat = limit.for_user("repeat");
+    assert!(machine.count().is_ok());
+    assert!(repeat.count().is_ok());
+    assert!(repeat.count().is_ok());
+    assert!(repeat.count().is_err());
+    let stats = limit.stats();
+    assert_eq!(
+        stats["machine"],
+        EventLimitStats {
+            triggered: 0,
+            peak: 1
+        }
+    );
+    assert_eq!(
+        stats["repeat"],
+        EventLimitStats {
+            triggered: 1,
+            peak: 2
+        }
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_event_limit_window() {
+    let limit = EventLimit::new(2, Duration::from_secs(60), Duration::ZERO);
+    assert!(limit.count().is_ok());
+    assert!(limit.count().is_ok());
+    tokio::time::advance(Duration::from_secs(59)).await;
+    assert!(limit.count().is_err());
+    assert!(limit.count().is_ok());
+    assert!(limit.count().is_ok());
+    tokio::time::advance(Duration::from_secs(60)).await;
+    assert!(limit.count().is_ok());
+    assert!(limit.count().is_ok());
+}
@@ -0,0 +1,56 @@
+/// Match a name against a shell style pattern. '*' matches any
+/// sequence of characters (including none) and '?' matches exactly
+/// one character.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let mut p = 0;
+    let mut n = 0;
+    // Position in pattern after the last '*' and the position in
+    // name it was matched against
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => {
+                if let Some((bp, bn)) = backtrack {
+                    p = bp;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                } else {
+                    return false;
+                }
+            }
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Returns true if the string contains any wildcard characters
+pub fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*", ""));
+    assert!(glob_match("*", "Tag1"));
+    assert!(glob_match("Tag?", "Tag1"));
+    assert!(!glob_match("Tag?", "Tag12"));
+    assert!(glob_match("Plant/Area1/*", "Plant/Area1/Pump"));
+    assert!(!glob_match("Plant/Area1/*", "Plant/Area2/Pump"));
+    assert!(glob_match("*Alarm*Count", "AnyAlarmXCount"));
+    assert!(!glob_match("*Alarm*Count", "AnyAlarmXCounts"));
+    assert!(glob_match("Sound**", "Sound"));
+}
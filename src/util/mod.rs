@@ -1 +1,8 @@
+pub mod backoff;
 pub mod error;
+pub mod event_limit;
+pub mod glob;
+pub mod persist_writer;
+pub mod schedule;
+pub mod template;
+pub mod volume_mapping;
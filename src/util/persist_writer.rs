@@ -0,0 +1,141 @@
+//! Saves state to a JSON file without blocking the caller
+//!
+//! Files are written by a thread of their own. If state is saved again
+//! while a write is in progress, only the latest state is written after
+//! it, so frequent changes cost at most one write at a time.
+
+use log::error;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+#[derive(Default)]
+struct WriterState {
+    // Latest content that hasn't been written yet
+    pending: Option<String>,
+    writing: bool,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<WriterState>,
+    cond: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, WriterState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct PersistWriter {
+    path: PathBuf,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PersistWriter {
+    /// `what` describes the content in error messages
+    pub fn new(path: PathBuf, what: &'static str) -> PersistWriter {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(WriterState::default()),
+            cond: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        let thread_path = path.clone();
+        let thread = thread::spawn(move || write_thread(&thread_path, what, &thread_shared));
+        PersistWriter {
+            path,
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Queue `value` to be written, replacing anything not written yet
+    pub fn save<T: Serialize>(&self, value: &T) {
+        match serde_json::to_string_pretty(value) {
+            Ok(json) => {
+                self.shared.lock().pending = Some(json);
+                self.shared.cond.notify_all();
+            }
+            Err(e) => error!(
+                "Failed to serialize '{}': {}",
+                self.path.to_string_lossy(),
+                e
+            ),
+        }
+    }
+
+    /// Wait until everything saved so far has been written
+    pub fn flush(&self) {
+        let mut state = self.shared.lock();
+        while state.pending.is_some() || state.writing {
+            state = self
+                .shared
+                .cond
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Drop for PersistWriter {
+    // Pending state is written before returning
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.cond.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_thread(path: &Path, what: &str, shared: &Shared) {
+    let mut state = shared.lock();
+    loop {
+        if let Some(json) = state.pending.take() {
+            state.writing = true;
+            drop(state);
+            if let Err(e) = write_file(path, &json) {
+                error!(
+                    "Failed to save {} to '{}': {}",
+                    what,
+                    path.to_string_lossy(),
+                    e
+                );
+            }
+            state = shared.lock();
+            state.writing = false;
+            shared.cond.notify_all();
+        } else if state.closed {
+            break;
+        } else {
+            state = shared.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+// Write to a temporary file first so that a crash never leaves a truncated file
+fn write_file(path: &Path, content: &str) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    File::create(&tmp_path)?.write_all(content.as_bytes())?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[test]
+fn test_persist_writer() {
+    let path = std::env::temp_dir().join(format!("persist_writer_{}.json", std::process::id()));
+    let writer = PersistWriter::new(path.clone(), "test state");
+    for i in 0..10 {
+        writer.save(&vec![i]);
+    }
+    writer.flush();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "[\n  9\n]");
+    writer.save(&vec![10]);
+    drop(writer);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "[\n  10\n]");
+    std::fs::remove_file(&path).unwrap();
+}
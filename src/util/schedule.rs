@@ -0,0 +1,245 @@
+//! Wall-clock times, given as a time of day or a cron expression
+//!
+//! Cron expressions have five fields: minute, hour, day of month, month
+//! and day of week. Each field is `*`, a number, a range like `1-5` or
+//! a comma separated list of those, optionally followed by a step like
+//! `*/15`. Days of week are 0-7, where both 0 and 7 are Sunday. If both
+//! day fields are restricted, a day matching either of them is used.
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    /// Every day at this time
+    Daily(NaiveTime),
+    Cron(Cron),
+}
+
+/// Matching values of each field as bit sets
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl std::error::Error for ParseError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Bits set for all matching values of a field
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<u64, ParseError> {
+    let err = |msg: &str| ParseError(format!("Invalid {} '{}': {}", name, field, msg));
+    let number = |s: &str| -> Result<u32, ParseError> {
+        let n: u32 = s.parse().map_err(|_| err("not a number"))?;
+        if n < min || n > max {
+            return Err(err(&format!("must be {}-{}", min, max)));
+        }
+        Ok(n)
+    };
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| err("invalid step"))?;
+                if step == 0 {
+                    return Err(err("step must not be 0"));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some((first, last)) = range.split_once('-') {
+            (number(first)?, number(last)?)
+        } else {
+            let n = number(range)?;
+            // A single value with a step means from that value and up
+            (n, if step > 1 { max } else { n })
+        };
+        if first > last {
+            return Err(err("range is reversed"));
+        }
+        for n in (first..=last).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Cron, ParseError> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(ParseError(format!(
+                "Cron expression '{}' must have 5 fields",
+                expr
+            )));
+        }
+        let mut weekdays = parse_field(fields[4], "day of week", 0, 7)?;
+        // 7 is also Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & 0x7f;
+        }
+        Ok(Cron {
+            expr: fields.join(" "),
+            minutes: parse_field(fields[0], "minute", 0, 59)?,
+            hours: parse_field(fields[1], "hour", 0, 23)? as u32,
+            days: parse_field(fields[2], "day of month", 1, 31)? as u32,
+            months: parse_field(fields[3], "month", 1, 12)? as u16,
+            weekdays: weekdays as u8,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    // First matching minute at or after `time` on the same day
+    fn first_time(&self, from: NaiveTime) -> Option<NaiveTime> {
+        for hour in from.hour()..24 {
+            if self.hours & (1 << hour) == 0 {
+                continue;
+            }
+            let first_minute = if hour == from.hour() {
+                from.minute()
+            } else {
+                0
+            };
+            for minute in first_minute..60 {
+                if self.minutes & (1 << minute) != 0 {
+                    return NaiveTime::from_hms_opt(hour, minute, 0);
+                }
+            }
+        }
+        None
+    }
+
+    fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        // Start at the next whole minute
+        let start = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let mut date = start.date();
+        let mut from = start.time();
+        // Some dates, like February 30, never match
+        for _ in 0..(366 * 8) {
+            if self.matches_date(date) {
+                if let Some(time) = self.first_time(from) {
+                    return Some(date.and_time(time));
+                }
+            }
+            date = date.succ_opt()?;
+            from = NaiveTime::from_hms_opt(0, 0, 0)?;
+        }
+        None
+    }
+}
+
+impl Schedule {
+    /// A time of day like "07:30" or "07:30:15", or a cron expression
+    pub fn parse(s: &str) -> Result<Schedule, ParseError> {
+        let s = s.trim();
+        if let Ok(time) = NaiveTime::parse_from_str(s, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M:%S"))
+        {
+            return Ok(Schedule::Daily(time));
+        }
+        Ok(Schedule::Cron(Cron::parse(s)?))
+    }
+
+    /// The first scheduled time after `time`, in local time. None if
+    /// the schedule never matches.
+    pub fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        match self {
+            Schedule::Daily(at) => {
+                let today = time.date().and_time(*at);
+                if today > time {
+                    Some(today)
+                } else {
+                    Some(time.date().succ_opt()?.and_time(*at))
+                }
+            }
+            Schedule::Cron(cron) => cron.next_after(time),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Daily(at) => write!(f, "{}", at),
+            Schedule::Cron(cron) => write!(f, "{}", cron.expr),
+        }
+    }
+}
+
+#[test]
+fn test_daily() {
+    let schedule = Schedule::parse("07:30").unwrap();
+    let t = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+    assert_eq!(
+        schedule.next_after(t("2024-03-01 06:00:00")),
+        Some(t("2024-03-01 07:30:00"))
+    );
+    assert_eq!(
+        schedule.next_after(t("2024-03-01 07:30:00")),
+        Some(t("2024-03-02 07:30:00"))
+    );
+}
+
+#[test]
+fn test_cron() {
+    let t = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+    let next = |expr: &str, time: &str| Schedule::parse(expr).unwrap().next_after(t(time));
+
+    assert_eq!(
+        next("*/15 * * * *", "2024-03-01 10:07:12"),
+        Some(t("2024-03-01 10:15:00"))
+    );
+    assert_eq!(
+        next("0 12 * * 1-5", "2024-03-01 12:00:00"),
+        Some(t("2024-03-04 12:00:00")) // Friday to Monday
+    );
+    assert_eq!(
+        next("30 22 * * 7", "2024-03-01 00:00:00"),
+        Some(t("2024-03-03 22:30:00"))
+    );
+    assert_eq!(
+        next("0 0 29 2 *", "2024-03-01 00:00:00"),
+        Some(t("2028-02-29 00:00:00"))
+    );
+    // Either day field matches
+    assert_eq!(
+        next("0 8 15 * 1", "2024-03-05 09:00:00"),
+        Some(t("2024-03-11 08:00:00"))
+    );
+    assert_eq!(next("0 0 30 2 *", "2024-03-01 00:00:00"), None);
+
+    assert!(Schedule::parse("0 24 * * *").is_err());
+    assert!(Schedule::parse("0 12 * *").is_err());
+    assert!(Schedule::parse("*/0 * * * *").is_err());
+    assert!(Schedule::parse("5-1 * * * *").is_err());
+}
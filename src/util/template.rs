@@ -0,0 +1,80 @@
+//! Text with tag values inserted
+//!
+//! `{Name}` is replaced by the current value of the tag Name, `{{` and
+//! `}}` by single braces.
+
+enum Part<'a> {
+    Text(&'a str),
+    Tag(&'a str),
+}
+
+fn parse(template: &str) -> Result<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(pos) = rest.find(['{', '}']) {
+        parts.push(Part::Text(&rest[..pos]));
+        let brace = &rest[pos..pos + 1];
+        rest = &rest[pos + 1..];
+        if let Some(after) = rest.strip_prefix(brace) {
+            parts.push(Part::Text(brace));
+            rest = after;
+        } else if brace == "{" {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| format!("Unterminated tag reference in '{}'", template))?;
+            let name = rest[..end].trim();
+            if name.is_empty() {
+                return Err(format!("Empty tag reference in '{}'", template));
+            }
+            parts.push(Part::Tag(name));
+            rest = &rest[end + 1..];
+        } else {
+            return Err(format!("Unmatched '}}' in '{}'", template));
+        }
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+/// Names of all tags used in the template
+pub fn template_tags(template: &str) -> Result<Vec<String>, String> {
+    Ok(parse(template)?
+        .into_iter()
+        .filter_map(|part| match part {
+            Part::Tag(name) => Some(name.to_string()),
+            Part::Text(_) => None,
+        })
+        .collect())
+}
+
+/// Insert tag values. Tags without a value are replaced by an empty
+/// string.
+pub fn expand_template<F>(template: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut output = String::with_capacity(template.len());
+    for part in parse(template)? {
+        match part {
+            Part::Text(text) => output.push_str(text),
+            Part::Tag(name) => output.push_str(&lookup(name).unwrap_or_default()),
+        }
+    }
+    Ok(output)
+}
+
+#[test]
+fn test_template() {
+    let lookup = |name: &str| (name == "Level").then(|| "3".to_string());
+    assert_eq!(
+        expand_template("Level {Level}, {{x}} {Missing}.", lookup).unwrap(),
+        "Level 3, {x} ."
+    );
+    assert_eq!(
+        template_tags("{ A } and {B}").unwrap(),
+        ["A".to_string(), "B".to_string()]
+    );
+    assert!(template_tags("{A").is_err());
+    assert!(template_tags("A}").is_err());
+    assert!(template_tags("{}").is_err());
+}
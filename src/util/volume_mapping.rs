@@ -0,0 +1,67 @@
+//! Mapping between volume settings in the range 0.0 to 1.0 and gain
+//! in dB. Uses the same curve as alsamixer: ranges up to 24 dB are
+//! mapped linearly in dB, larger ranges so that halving the setting
+//! lowers the gain by a perceptually similar amount regardless of the
+//! range.
+
+const MAX_LINEAR_DB_RANGE: f64 = 24.0;
+
+/// Range used for software volume in the dB scale
+pub const SOFTWARE_MIN_DB: f64 = -60.0;
+
+pub fn volume_to_db(volume: f32, min_db: f64, max_db: f64) -> f64 {
+    let volume = f64::from(volume.clamp(0.0, 1.0));
+    if max_db - min_db <= MAX_LINEAR_DB_RANGE {
+        return min_db + volume * (max_db - min_db);
+    }
+    let min_norm = 10f64.powf((min_db - max_db) / 60.0);
+    60.0 * (volume * (1.0 - min_norm) + min_norm).log10() + max_db
+}
+
+pub fn db_to_volume(db: f64, min_db: f64, max_db: f64) -> f32 {
+    if max_db <= min_db {
+        return 0.0;
+    }
+    let volume = if max_db - min_db <= MAX_LINEAR_DB_RANGE {
+        (db - min_db) / (max_db - min_db)
+    } else {
+        let min_norm = 10f64.powf((min_db - max_db) / 60.0);
+        let norm = 10f64.powf((db - max_db) / 60.0);
+        (norm - min_norm) / (1.0 - min_norm)
+    };
+    volume.clamp(0.0, 1.0) as f32
+}
+
+/// Amplitude factor for a volume setting. Zero is always silent.
+pub fn volume_to_amplitude(volume: f32) -> f32 {
+    if volume <= 0.0 {
+        return 0.0;
+    }
+    10f64.powf(volume_to_db(volume, SOFTWARE_MIN_DB, 0.0) / 20.0) as f32
+}
+
+pub fn amplitude_to_volume(amplitude: f32) -> f32 {
+    if amplitude <= 0.0 {
+        return 0.0;
+    }
+    db_to_volume(20.0 * f64::from(amplitude).log10(), SOFTWARE_MIN_DB, 0.0)
+}
+
+#[test]
+fn test_volume_mapping() {
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-3;
+    // Small ranges are linear
+    assert!(close(volume_to_db(0.5, -20.0, 0.0), -10.0));
+    assert!(close(volume_to_db(0.0, -60.0, 0.0), -60.0));
+    assert!(close(volume_to_db(1.0, -60.0, 0.0), 0.0));
+    for v in [0.1, 0.25, 0.5, 0.9] {
+        let db = volume_to_db(v, -90.0, 6.0);
+        assert!(close(f64::from(db_to_volume(db, -90.0, 6.0)), f64::from(v)));
+        assert!(close(
+            f64::from(amplitude_to_volume(volume_to_amplitude(v))),
+            f64::from(v)
+        ));
+    }
+    assert_eq!(volume_to_amplitude(0.0), 0.0);
+    assert!(close(f64::from(volume_to_amplitude(1.0)), 1.0));
+}
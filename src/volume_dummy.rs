@@ -1,15 +1,31 @@
+use crate::read_config::VolumeScale;
 use crate::util::error::DynResult;
-use log::info;
 
+// Never created, so software volume is always used
 pub struct VolumeControl;
 
 impl VolumeControl {
-    pub fn new(_device: &str) -> DynResult<VolumeControl> {
-        info!("Volume control not supported");
-        Ok(VolumeControl)
+    pub fn new(
+        _device: &str,
+        _element: Option<(&str, u32)>,
+        _scale: VolumeScale,
+    ) -> DynResult<VolumeControl> {
+        Err("Hardware volume control not supported".into())
+    }
+
+    pub fn refresh(&self) -> DynResult<()> {
+        Ok(())
     }
 
     pub fn set_volume(&self, _volume: f32) -> DynResult<()> {
         Ok(())
     }
+
+    pub fn set_channel_volume(&self, _channel: usize, _volume: f32) -> DynResult<()> {
+        Ok(())
+    }
+
+    pub fn get_volume(&self) -> DynResult<f32> {
+        Ok(1.0)
+    }
 }
@@ -0,0 +1,274 @@
+use crate::read_config::VolumeScale;
+use crate::util::error::DynResult;
+use crate::util::volume_mapping;
+use log::{info, warn};
+use std::ffi::OsString;
+use std::os::windows::ffi::OsStringExt;
+use std::ptr;
+use winapi::shared::winerror::RPC_E_CHANGED_MODE;
+use winapi::um::combaseapi::{CoCreateInstance, CoInitializeEx, PropVariantClear, CLSCTX_ALL};
+use winapi::um::coml2api::STGM_READ;
+use winapi::um::endpointvolume::IAudioEndpointVolume;
+use winapi::um::functiondiscoverykeys_devpkey::PKEY_Device_FriendlyName;
+use winapi::um::mmdeviceapi::{
+    eConsole, eRender, CLSID_MMDeviceEnumerator, IMMDevice, IMMDeviceCollection,
+    IMMDeviceEnumerator, DEVICE_STATE_ACTIVE,
+};
+use winapi::um::objbase::COINIT_MULTITHREADED;
+use winapi::um::propidl::PROPVARIANT;
+use winapi::um::propsys::IPropertyStore;
+use winapi::um::unknwnbase::IUnknown;
+use winapi::um::winnt::HRESULT;
+use winapi::Interface;
+
+// Owned COM interface, released when dropped
+struct ComPtr<T: Interface>(*mut T);
+
+impl<T: Interface> ComPtr<T> {
+    fn get(&self) -> &T {
+        unsafe { &*self.0 }
+    }
+}
+
+impl<T: Interface> Drop for ComPtr<T> {
+    fn drop(&mut self) {
+        unsafe {
+            (*(self.0 as *mut IUnknown)).Release();
+        }
+    }
+}
+
+fn check(hr: HRESULT, what: &str) -> DynResult<()> {
+    if hr < 0 {
+        return Err(format!("{} failed: 0x{:08x}", what, hr as u32).into());
+    }
+    Ok(())
+}
+
+fn wide_to_string(wide: *const u16) -> String {
+    if wide.is_null() {
+        return String::new();
+    }
+    unsafe {
+        let len = (0..).take_while(|&i| *wide.offset(i) != 0).count();
+        OsString::from_wide(std::slice::from_raw_parts(wide, len))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+fn device_enumerator() -> DynResult<ComPtr<IMMDeviceEnumerator>> {
+    let hr = unsafe { CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED) };
+    // Threads that already use COM keep their apartment
+    if hr != RPC_E_CHANGED_MODE {
+        check(hr, "Initializing COM")?;
+    }
+    let mut enumerator: *mut IMMDeviceEnumerator = ptr::null_mut();
+    check(
+        unsafe {
+            CoCreateInstance(
+                &CLSID_MMDeviceEnumerator,
+                ptr::null_mut(),
+                CLSCTX_ALL,
+                &IMMDeviceEnumerator::uuidof(),
+                &mut enumerator as *mut _ as *mut _,
+            )
+        },
+        "Creating device enumerator",
+    )?;
+    Ok(ComPtr(enumerator))
+}
+
+fn friendly_name(device: &IMMDevice) -> DynResult<String> {
+    let mut store: *mut IPropertyStore = ptr::null_mut();
+    check(
+        unsafe { device.OpenPropertyStore(STGM_READ, &mut store) },
+        "Opening device properties",
+    )?;
+    let store = ComPtr(store);
+    unsafe {
+        let mut value: PROPVARIANT = std::mem::zeroed();
+        check(
+            store.get().GetValue(&PKEY_Device_FriendlyName, &mut value),
+            "Reading device name",
+        )?;
+        let name = wide_to_string(*value.data.pwszVal());
+        PropVariantClear(&mut value);
+        Ok(name)
+    }
+}
+
+// The render device with the same name as cpal uses
+fn find_device(enumerator: &IMMDeviceEnumerator, name: &str) -> DynResult<ComPtr<IMMDevice>> {
+    let mut device: *mut IMMDevice = ptr::null_mut();
+    if name == "default" {
+        check(
+            unsafe { enumerator.GetDefaultAudioEndpoint(eRender, eConsole, &mut device) },
+            "Getting default output device",
+        )?;
+        return Ok(ComPtr(device));
+    }
+    let mut collection: *mut IMMDeviceCollection = ptr::null_mut();
+    check(
+        unsafe { enumerator.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE, &mut collection) },
+        "Listing output devices",
+    )?;
+    let collection = ComPtr(collection);
+    let mut count: u32 = 0;
+    check(
+        unsafe { collection.get().GetCount(&mut count) },
+        "Counting output devices",
+    )?;
+    let mut names = Vec::new();
+    for i in 0..count {
+        check(
+            unsafe { collection.get().Item(i, &mut device) },
+            "Getting output device",
+        )?;
+        let device = ComPtr(device);
+        let device_name = friendly_name(device.get())?;
+        if device_name == name {
+            return Ok(device);
+        }
+        names.push(device_name);
+    }
+    Err(format!(
+        "No output device named '{}'. Available devices: '{}'",
+        name,
+        names.join("', '")
+    )
+    .into())
+}
+
+/// Controls the endpoint volume of a render device. The linear scale
+/// uses the volume curve of Windows, the dB scale the same mapping as
+/// for ALSA.
+pub struct VolumeControl {
+    endpoint: ComPtr<IAudioEndpointVolume>,
+    scale: VolumeScale,
+}
+
+// Endpoint volume objects may be used from any thread in the
+// multithreaded apartment
+unsafe impl Send for VolumeControl {}
+
+impl VolumeControl {
+    /// Use the output device with the given name, or the default
+    /// device. Windows has no mixer elements, so `element` is ignored.
+    pub fn new(
+        device: &str,
+        element: Option<(&str, u32)>,
+        scale: VolumeScale,
+    ) -> DynResult<VolumeControl> {
+        if let Some((name, index)) = element {
+            warn!(
+                "Ignoring mixer element '{}',{}: Windows only has a device volume",
+                name, index
+            );
+        }
+        let enumerator = device_enumerator()?;
+        let device = find_device(enumerator.get(), device)?;
+        let mut endpoint: *mut IAudioEndpointVolume = ptr::null_mut();
+        check(
+            unsafe {
+                device.get().Activate(
+                    &IAudioEndpointVolume::uuidof(),
+                    CLSCTX_ALL,
+                    ptr::null_mut(),
+                    &mut endpoint as *mut _ as *mut _,
+                )
+            },
+            "Opening endpoint volume",
+        )?;
+        info!(
+            "Using the volume of {} as volume control",
+            friendly_name(device.get())?
+        );
+        Ok(VolumeControl {
+            endpoint: ComPtr(endpoint),
+            scale,
+        })
+    }
+
+    /// The volume is read from the device every time, so there is
+    /// nothing to update
+    pub fn refresh(&self) -> DynResult<()> {
+        Ok(())
+    }
+
+    fn channel_count(&self) -> DynResult<u32> {
+        let mut count: u32 = 0;
+        check(
+            unsafe { self.endpoint.get().GetChannelCount(&mut count) },
+            "Getting channel count",
+        )?;
+        Ok(count)
+    }
+
+    fn db_range(&self) -> DynResult<(f64, f64)> {
+        let (mut min, mut max, mut step) = (0.0f32, 0.0f32, 0.0f32);
+        check(
+            unsafe {
+                self.endpoint
+                    .get()
+                    .GetVolumeRange(&mut min, &mut max, &mut step)
+            },
+            "Getting volume range",
+        )?;
+        Ok((f64::from(min), f64::from(max)))
+    }
+
+    pub fn set_volume(&self, volume: f32) -> DynResult<()> {
+        for channel in 0..self.channel_count()? {
+            self.set_channel_volume(channel as usize, volume)?;
+        }
+        Ok(())
+    }
+
+    /// Set the volume of a single channel. Channels are numbered in
+    /// speaker order, starting with front left and front right.
+    /// Channels the device doesn't have are ignored.
+    pub fn set_channel_volume(&self, channel: usize, volume: f32) -> DynResult<()> {
+        let channel = match u32::try_from(channel) {
+            Ok(c) if c < self.channel_count()? => c,
+            _ => return Ok(()),
+        };
+        let volume = volume.clamp(0.0, 1.0);
+        let endpoint = self.endpoint.get();
+        let hr = if self.scale == VolumeScale::Db {
+            let (min, max) = self.db_range()?;
+            let db = volume_mapping::volume_to_db(volume, min, max);
+            unsafe { endpoint.SetChannelVolumeLevel(channel, db as f32, ptr::null()) }
+        } else {
+            unsafe { endpoint.SetChannelVolumeLevelScalar(channel, volume, ptr::null()) }
+        };
+        check(hr, "Setting volume")
+    }
+
+    /// The volume of the loudest channel
+    pub fn get_volume(&self) -> DynResult<f32> {
+        let mut volume: f32 = 0.0;
+        for channel in 0..self.channel_count()? {
+            volume = volume.max(self.get_channel_volume(channel)?);
+        }
+        Ok(volume)
+    }
+
+    fn get_channel_volume(&self, channel: u32) -> DynResult<f32> {
+        let endpoint = self.endpoint.get();
+        let mut level: f32 = 0.0;
+        if self.scale == VolumeScale::Db {
+            let (min, max) = self.db_range()?;
+            check(
+                unsafe { endpoint.GetChannelVolumeLevel(channel, &mut level) },
+                "Getting volume",
+            )?;
+            return Ok(volume_mapping::db_to_volume(f64::from(level), min, max));
+        }
+        check(
+            unsafe { endpoint.GetChannelVolumeLevelScalar(channel, &mut level) },
+            "Getting volume",
+        )?;
+        Ok(level)
+    }
+}
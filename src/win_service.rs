@@ -0,0 +1,286 @@
+//! Running as a Windows service
+//!
+//! The service is installed with `--install_service` and removed with
+//! `--uninstall_service`. The service manager starts the server with
+//! `--service`, logging goes to the event log unless a log file is
+//! given.
+
+use crate::flexi_setup::{
+    add_flexi_args, flexi_set_log_level, json_requested, setup_flexi_loggger,
+};
+use clap::{Arg, ArgMatches, Command};
+use flexi_logger::LoggerHandle;
+use log::{error, info, LevelFilter};
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::service_dispatcher;
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+const SERVICE_NAME: &str = "mtp_audioplayer";
+const SERVICE_DISPLAY_NAME: &str = "MTP audio player";
+const SERVICE_DESCRIPTION: &str = "Plays sound clips controlled by tags and alarms from WinCC";
+
+static SERVICE: AtomicBool = AtomicBool::new(false);
+
+// The server and the service manager become ready in any order. Whichever
+// is last reports the service as running.
+struct StatusState {
+    ready: bool,
+    // Set when the service manager has connected to the service
+    handle: Option<ServiceStatusHandle>,
+}
+
+static STATUS: Mutex<StatusState> = Mutex::new(StatusState {
+    ready: false,
+    handle: None,
+});
+// Set to true when the service manager asks the service to stop
+static STOP_TX: Mutex<Option<watch::Sender<bool>>> = Mutex::new(None);
+static STOP_RX: Mutex<Option<watch::Receiver<bool>>> = Mutex::new(None);
+
+pub fn add_args(app_args: Command) -> Command {
+    let app_args = app_args
+        .arg(
+            Arg::new("service")
+                .long("service")
+                .help("Run as a Windows service, only used by the service manager"),
+        )
+        .arg(
+            Arg::new("install_service")
+                .long("install_service")
+                .help("Install as a Windows service using the current configuration file"),
+        )
+        .arg(
+            Arg::new("uninstall_service")
+                .long("uninstall_service")
+                .help("Remove the Windows service"),
+        );
+    add_flexi_args(app_args)
+}
+
+pub enum LogCtxt {
+    None,                // No logging available
+    EventLog,            // Logging to the Windows event log
+    Flexi(LoggerHandle), // Logging with flexi logger
+}
+
+fn set_status(handle: &ServiceStatusHandle, state: ServiceState) {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    let status = ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::from_secs(10),
+        process_id: None,
+    };
+    if let Err(e) = handle.set_service_status(status) {
+        error!("Failed to set service status: {}", e);
+    }
+}
+
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+// Called by the service dispatcher. Returns once the control handler
+// is registered, the server keeps running in the main thread.
+fn service_main(_args: Vec<OsString>) {
+    let stop_tx = STOP_TX.lock().unwrap().take();
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(stop_tx) = &stop_tx {
+                let _ = stop_tx.send(true);
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let handle = match service_control_handler::register(SERVICE_NAME, handler) {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Failed to register service control handler: {}", e);
+            return;
+        }
+    };
+    let mut status = STATUS.lock().unwrap();
+    let state = if status.ready {
+        ServiceState::Running
+    } else {
+        ServiceState::StartPending
+    };
+    set_status(&handle, state);
+    status.handle = Some(handle);
+}
+
+fn install_service(args: &ArgMatches) -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    // The service runs in the system directory so the configuration
+    // path must be absolute
+    let conf = args.value_of("CONF").unwrap_or_default();
+    let conf = std::fs::canonicalize(conf).map_err(windows_service::Error::Winapi)?;
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_DISPLAY_NAME),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments: vec![OsString::from("--service"), conf.into_os_string()],
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description(SERVICE_DESCRIPTION)?;
+    if let Err(e) = eventlog::register(SERVICE_NAME) {
+        eprintln!("Failed to register event log source: {}", e);
+    }
+    Ok(())
+}
+
+fn uninstall_service() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::DELETE)?;
+    service.delete()?;
+    if let Err(e) = eventlog::deregister(SERVICE_NAME) {
+        eprintln!("Failed to deregister event log source: {}", e);
+    }
+    Ok(())
+}
+
+pub fn start(args: &ArgMatches) -> LogCtxt {
+    if args.is_present("install_service") {
+        match install_service(args) {
+            Ok(()) => {
+                println!("Installed service {}", SERVICE_NAME);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Failed to install service: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if args.is_present("uninstall_service") {
+        match uninstall_service() {
+            Ok(()) => {
+                println!("Removed service {}", SERVICE_NAME);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Failed to remove service: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    SERVICE.store(args.is_present("service"), Ordering::Relaxed);
+    let ctxt;
+    if !SERVICE.load(Ordering::Relaxed) || args.is_present("log_file") || json_requested(args) {
+        match setup_flexi_loggger(args) {
+            Ok(handle) => {
+                ctxt = LogCtxt::Flexi(handle);
+            }
+            Err(e) => {
+                eprintln!("Failed to start logging: {}", e);
+                ctxt = LogCtxt::None;
+            }
+        }
+    } else if let Err(e) = eventlog::init(SERVICE_NAME, log::Level::Info) {
+        eprintln!("Failed to start logging: {}", e);
+        ctxt = LogCtxt::None;
+    } else {
+        ctxt = LogCtxt::EventLog;
+    }
+    if SERVICE.load(Ordering::Relaxed) {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        *STOP_TX.lock().unwrap() = Some(stop_tx);
+        *STOP_RX.lock().unwrap() = Some(stop_rx);
+        // Blocks until the service is stopped
+        std::thread::spawn(|| {
+            if let Err(e) = service_dispatcher::start(SERVICE_NAME, ffi_service_main) {
+                error!("Failed to start service dispatcher: {}", e);
+            }
+        });
+    }
+    info!("Server starting");
+    ctxt
+}
+
+pub fn ready() {
+    let mut status = STATUS.lock().unwrap();
+    status.ready = true;
+    if let Some(handle) = &status.handle {
+        set_status(handle, ServiceState::Running);
+    }
+    drop(status);
+    info!("Server ready");
+}
+
+/// Change the log level. None restores the level set at startup.
+pub fn set_log_level(ctxt: &mut LogCtxt, level: Option<LevelFilter>) {
+    match ctxt {
+        LogCtxt::None => {}
+        LogCtxt::EventLog => log::set_max_level(level.unwrap_or(LevelFilter::Info)),
+        LogCtxt::Flexi(handle) => flexi_set_log_level(handle, level),
+    }
+}
+
+/// Status is only reported to systemd
+pub fn status(_status: &str) {}
+
+/// The watchdog is only supported with systemd
+pub fn watchdog_interval() -> Option<std::time::Duration> {
+    None
+}
+
+pub fn watchdog() {}
+
+/// Returns when the service manager asks the server to stop. Never
+/// returns if not running as a service.
+pub async fn stop_requested() {
+    let stop_rx = STOP_RX.lock().unwrap().clone();
+    match stop_rx {
+        Some(mut stop_rx) => {
+            while !*stop_rx.borrow() {
+                if stop_rx.changed().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            }
+            info!("Stop requested by service manager");
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// The server is about to abort after a panic
+pub fn panicked(_message: &str) {
+    if let Ok(status) = STATUS.try_lock() {
+        if let Some(handle) = &status.handle {
+            set_status(handle, ServiceState::Stopped);
+        }
+    }
+}
+
+pub fn exiting(_ctxt: LogCtxt) {
+    info!("Server exiting");
+    if let Some(handle) = &STATUS.lock().unwrap().handle {
+        set_status(handle, ServiceState::Stopped);
+    }
+}